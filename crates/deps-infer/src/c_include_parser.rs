@@ -1,22 +1,110 @@
 use crate::gcc_include_parser;
+use crate::normalize::normalize_path;
+use crate::vfs_overlay::VfsOverlay;
 use anyhow::Result;
-use include_graph::dependencies::cparse;
-use std::collections::{HashSet, VecDeque};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
 
-pub fn retrieve_c_includes(cmdline: &str, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
-    let includes = gcc_include_parser::parse_include_dirs(cmdline)?;
-    bfs_parse_includes(files, &includes)
+pub use crate::gcc_include_parser::SearchDirs;
+
+// Bounds the expansion of macros defined in terms of other macros.
+const MAX_MACRO_EXPANSIONS: usize = 16;
+
+/// A header named by an `#include` directive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Include {
+    /// `#include "path"`
+    Quoted(PathBuf),
+    /// `#include <path>`
+    Angled(PathBuf),
+    /// `#include_next "path"` or `#include_next <path>`, searched in the
+    /// directories after the one the including file was found in.
+    Next(PathBuf),
+}
+
+/// Retrieve `files` and the headers they include, recursively, searching the
+/// directories of a gcc `cmdline`. Relative files and search directories are
+/// resolved against `working_dir`, where the compiler runs, rather than the
+/// current directory.
+pub fn retrieve_c_includes(
+    cmdline: &str,
+    files: Vec<PathBuf>,
+    working_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut search_dirs = gcc_include_parser::parse_search_dirs(cmdline)?.resolve_in(working_dir);
+    search_dirs.overlay = VfsOverlay::from_cmdline(cmdline, working_dir);
+    let defines = gcc_include_parser::parse_defines(cmdline)?;
+    let files = files.iter().map(|file| working_dir.join(file)).collect();
+    bfs_parse_includes(files, &search_dirs, &defines)
+}
+
+/// Resolve the header named by `include` in `including_file` following gcc's
+/// search order: quoted includes are searched in the directory of the
+/// including file and then the `-iquote` directories, before the `-I`,
+/// `-isystem` and `-idirafter` directories that angled includes are searched
+/// in. Headers at virtual paths of VFS overlays resolve to their real files.
+/// Returns `None` if the header isn't found, e.g. because it is in the
+/// compiler's default system directories.
+pub fn resolve_include(
+    include: &Include,
+    including_file: &Path,
+    search_dirs: &SearchDirs,
+) -> Option<PathBuf> {
+    let find = |header: PathBuf| {
+        let real = search_dirs.overlay.resolve(&header);
+        real.filter(|real| real.is_file())
+            .or_else(|| header.is_file().then_some(header))
+    };
+    let (path, local_dir, quote_dirs) = match include {
+        Include::Quoted(path) => (path, including_file.parent(), &search_dirs.quote[..]),
+        Include::Angled(path) => (path, None, &[][..]),
+        Include::Next(path) if path.is_absolute() => return find(path.clone()),
+        Include::Next(path) => {
+            // Like gcc, the search starts from the beginning if the including
+            // file wasn't found in a search directory.
+            let dirs: Vec<&Path> = search_dirs
+                .quote
+                .iter()
+                .chain(&search_dirs.include)
+                .chain(&search_dirs.system)
+                .chain(&search_dirs.after)
+                .map(PathBuf::as_path)
+                .collect();
+            let start = dirs
+                .iter()
+                .position(|dir| including_file.starts_with(normalize_path(dir)))
+                .map_or(0, |i| i + 1);
+            return dirs[start..].iter().find_map(|dir| find(dir.join(path)));
+        }
+    };
+    if path.is_absolute() {
+        return find(path.clone());
+    }
+
+    local_dir
+        .into_iter()
+        .chain(quote_dirs.iter().map(PathBuf::as_path))
+        .chain(search_dirs.include.iter().map(PathBuf::as_path))
+        .chain(search_dirs.system.iter().map(PathBuf::as_path))
+        .chain(search_dirs.after.iter().map(PathBuf::as_path))
+        .find_map(|dir| find(dir.join(path)))
 }
 
 /// Recursively collect all dependencies using BFS
-fn bfs_parse_includes(files: Vec<PathBuf>, include_dirs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+fn bfs_parse_includes(
+    files: Vec<PathBuf>,
+    search_dirs: &SearchDirs,
+    defines: &HashMap<String, String>,
+) -> Result<Vec<PathBuf>> {
     let mut visited = HashSet::new();
     let mut result = Vec::new();
     let mut queue = VecDeque::new();
 
     // Initialize queue with starting files
     for file in files {
+        let file = normalize_path(&file);
         if visited.insert(file.clone()) {
             queue.push_back(file.clone());
             result.push(file);
@@ -28,17 +116,8 @@ fn bfs_parse_includes(files: Vec<PathBuf>, include_dirs: &[PathBuf]) -> Result<V
         // Get all files currently in the queue
         let current_batch: Vec<PathBuf> = queue.drain(..).collect();
 
-        // Process all files in the current batch in parallel
-        let sources_with_includes = cparse::all_sources_and_includes(
-            current_batch
-                .into_iter()
-                .map(|p| Ok::<_, std::io::Error>(p)),
-            include_dirs,
-        )?;
-
-        // Process each source's includes
-        for source in sources_with_includes {
-            for include in source.includes {
+        for includes in scan_batch(&current_batch, search_dirs, defines)? {
+            for include in includes {
                 if visited.insert(include.clone()) {
                     queue.push_back(include.clone());
                     result.push(include);
@@ -49,3 +128,437 @@ fn bfs_parse_includes(files: Vec<PathBuf>, include_dirs: &[PathBuf]) -> Result<V
 
     Ok(result)
 }
+
+/// Resolve the includes of every file of `batch` in parallel, returning them
+/// in the order of the files.
+fn scan_batch(
+    batch: &[PathBuf],
+    search_dirs: &SearchDirs,
+    defines: &HashMap<String, String>,
+) -> Result<Vec<Vec<PathBuf>>> {
+    let parallelism = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = batch.len().div_ceil(parallelism).max(1);
+    thread::scope(|scope| {
+        let handles: Vec<_> = batch
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|file| file_includes(file, search_dirs, defines))
+                        .collect::<Result<Vec<_>>>()
+                })
+            })
+            .collect();
+
+        let mut includes = Vec::with_capacity(batch.len());
+        for handle in handles {
+            includes.extend(handle.join().expect("include scanner panicked")?);
+        }
+        Ok(includes)
+    })
+}
+
+/// The headers included by `file` that could be resolved.
+fn file_includes(
+    file: &Path,
+    search_dirs: &SearchDirs,
+    defines: &HashMap<String, String>,
+) -> Result<Vec<PathBuf>> {
+    let source = fs::read(file)?;
+    Ok(scan_includes(&String::from_utf8_lossy(&source), defines)
+        .iter()
+        .filter_map(|include| resolve_include(include, file, search_dirs))
+        .map(|header| normalize_path(&header))
+        .collect())
+}
+
+/// Scan the `#include` directives of a source. Includes naming a macro are
+/// expanded through `defines`, the object-like macros of the cmdline: macros
+/// defined in sources and function-like macros are left unresolved.
+///
+/// Directives are matched line by line after removing comments, without
+/// preprocessing, so includes in conditional blocks are all returned.
+fn scan_includes(source: &str, defines: &HashMap<String, String>) -> Vec<Include> {
+    strip_comments(source)
+        .lines()
+        .filter_map(|line| parse_include(line, defines))
+        .collect()
+}
+
+fn parse_include(line: &str, defines: &HashMap<String, String>) -> Option<Include> {
+    let rest = line.trim_start().strip_prefix('#')?.trim_start();
+    let (rest, next) = match rest.strip_prefix("include_next") {
+        Some(rest) => (rest, true),
+        None => (rest.strip_prefix("include")?, false),
+    };
+    if !rest.starts_with(|c: char| c.is_whitespace() || c == '"' || c == '<') {
+        return None;
+    }
+
+    let rest = rest.trim_start();
+    let include = match include_path(rest) {
+        Some(include) => include,
+        None => include_path(expand_macro(macro_name(rest)?, defines)?)?,
+    };
+    match include {
+        Include::Quoted(path) | Include::Angled(path) if next => Some(Include::Next(path)),
+        include => Some(include),
+    }
+}
+
+// Replace the `/* */` and `//` comments of a source by a space, keeping the
+// newlines of multi-line comments so that directives stay on their own line.
+// Comment markers in string and character literals are left alone.
+fn strip_comments(source: &str) -> String {
+    let mut out = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                out.push(c);
+                while let Some(d) = chars.next() {
+                    out.push(d);
+                    if d == '\\' {
+                        out.extend(chars.next());
+                    } else if d == c || d == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                out.push(' ');
+                let mut prev = ' ';
+                for d in chars.by_ref() {
+                    if d == '\n' {
+                        out.push('\n');
+                    } else if prev == '*' && d == '/' {
+                        break;
+                    }
+                    prev = d;
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                out.push(' ');
+                while chars.next_if(|&d| d != '\n').is_some() {}
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// Parse a `"path"` or `<path>` include, ignoring what follows.
+fn include_path(s: &str) -> Option<Include> {
+    if let Some(rest) = s.strip_prefix('"') {
+        let end = rest.find('"')?;
+        Some(Include::Quoted(PathBuf::from(&rest[..end])))
+    } else if let Some(rest) = s.strip_prefix('<') {
+        let end = rest.find('>')?;
+        Some(Include::Angled(PathBuf::from(&rest[..end])))
+    } else {
+        None
+    }
+}
+
+fn macro_name(s: &str) -> Option<&str> {
+    let end = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(s.len());
+    let name = &s[..end];
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(name)
+}
+
+/// Expand an object-like macro until it is no longer a macro name.
+fn expand_macro<'a>(name: &str, defines: &'a HashMap<String, String>) -> Option<&'a str> {
+    let mut expanded = defines.get(name)?.trim();
+    for _ in 0..MAX_MACRO_EXPANSIONS {
+        match defines.get(expanded) {
+            Some(next) => expanded = next.trim(),
+            None => return Some(expanded),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::test_dir;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_parse_include() {
+        let defines: HashMap<String, String> = [
+            ("HDR".to_string(), "\"foo.h\"".to_string()),
+            ("CONFIG_HEADER".to_string(), "<config.h>".to_string()),
+        ]
+        .into();
+        let quoted = |path: &str| Some(Include::Quoted(PathBuf::from(path)));
+        let angled = |path: &str| Some(Include::Angled(PathBuf::from(path)));
+
+        assert_eq!(
+            parse_include("#include \"foo.h\"", &defines),
+            quoted("foo.h")
+        );
+        assert_eq!(
+            parse_include("#include<sys/types.h>", &defines),
+            angled("sys/types.h")
+        );
+        assert_eq!(
+            parse_include("  #  include \"a.h\" // \"b.h\"", &defines),
+            quoted("a.h")
+        );
+        assert_eq!(parse_include("#include HDR", &defines), quoted("foo.h"));
+        assert_eq!(
+            parse_include("#include CONFIG_HEADER // config", &defines),
+            angled("config.h")
+        );
+        assert_eq!(parse_include("#include UNDEFINED", &defines), None);
+        assert_eq!(
+            parse_include("#include_next <foo.h>", &defines),
+            Some(Include::Next(PathBuf::from("foo.h")))
+        );
+        assert_eq!(
+            parse_include("#include_next HDR", &defines),
+            Some(Include::Next(PathBuf::from("foo.h")))
+        );
+        assert_eq!(parse_include("#include_nextfoo.h", &defines), None);
+        assert_eq!(parse_include("#define HDR", &defines), None);
+        assert_eq!(parse_include("int include;", &defines), None);
+    }
+
+    #[test]
+    fn test_resolve_include_precedence() {
+        let dir = test_dir("precedence");
+        let main = dir.join("src/main.c");
+        for header in [
+            "src/a.h",
+            "quote/a.h",
+            "quote/q.h",
+            "inc/a.h",
+            "inc/q.h",
+            "sys/s.h",
+            "after/s.h",
+            "after/late.h",
+        ] {
+            write(&dir.join(header), "");
+        }
+        let search_dirs = SearchDirs {
+            quote: vec![dir.join("quote")],
+            include: vec![dir.join("inc")],
+            system: vec![dir.join("sys")],
+            after: vec![dir.join("after")],
+            ..SearchDirs::default()
+        };
+        let resolve = |include: Include| resolve_include(&include, &main, &search_dirs);
+        let quoted = |path: &str| Include::Quoted(PathBuf::from(path));
+        let angled = |path: &str| Include::Angled(PathBuf::from(path));
+
+        // Quoted includes look next to the including file, then in the quote
+        // directories.
+        assert_eq!(resolve(quoted("a.h")), Some(dir.join("src/a.h")));
+        assert_eq!(resolve(quoted("q.h")), Some(dir.join("quote/q.h")));
+        // Angled includes skip both.
+        assert_eq!(resolve(angled("a.h")), Some(dir.join("inc/a.h")));
+        assert_eq!(resolve(angled("q.h")), Some(dir.join("inc/q.h")));
+        // -isystem comes before -idirafter.
+        assert_eq!(resolve(angled("s.h")), Some(dir.join("sys/s.h")));
+        assert_eq!(resolve(quoted("late.h")), Some(dir.join("after/late.h")));
+        assert_eq!(resolve(angled("missing.h")), None);
+        assert_eq!(
+            resolve(angled(&dir.join("inc/a.h").to_string_lossy())),
+            Some(dir.join("inc/a.h"))
+        );
+        // #include_next searches the directories after the one the including
+        // file is in, or all of them otherwise.
+        let next = |path: &str, including_file: &Path| {
+            resolve_include(
+                &Include::Next(PathBuf::from(path)),
+                including_file,
+                &search_dirs,
+            )
+        };
+        assert_eq!(
+            next("s.h", &dir.join("sys/s.h")),
+            Some(dir.join("after/s.h"))
+        );
+        assert_eq!(
+            next("a.h", &dir.join("quote/a.h")),
+            Some(dir.join("inc/a.h"))
+        );
+        assert_eq!(next("a.h", &dir.join("inc/a.h")), None);
+        assert_eq!(next("a.h", &main), Some(dir.join("quote/a.h")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_scan_includes() {
+        let source = "#include \"a.h\"\n\
+                      /* #include \"commented.h\"\n\
+                      #include \"still-commented.h\" */\n\
+                      #include /* inline */ <b.h>\n\
+                      // #include \"line-comment.h\" /*\n\
+                      const char *s = \"/*\";\n\
+                      #include_next <c.h>\n";
+        assert_eq!(
+            scan_includes(source, &HashMap::new()),
+            vec![
+                Include::Quoted(PathBuf::from("a.h")),
+                Include::Angled(PathBuf::from("b.h")),
+                Include::Next(PathBuf::from("c.h")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retrieve_c_includes() {
+        let dir = test_dir("includes");
+        write(
+            &dir.join("src/main.c"),
+            "#include \"util.h\"\n#include <stdio.h>\n#include \"../include/api.h\"\n",
+        );
+        write(
+            &dir.join("src/util.h"),
+            "#include <api.h>\n#include \"util.h\"\n",
+        );
+        write(&dir.join("include/api.h"), "#include <internal/types.h>\n");
+        write(&dir.join("include/internal/types.h"), "");
+
+        let cmdline = format!("gcc -I{} -c src/main.c", dir.join("include").display());
+        let includes =
+            retrieve_c_includes(&cmdline, vec![dir.join("src/main.c")], Path::new(".")).unwrap();
+        assert_eq!(
+            includes,
+            vec![
+                dir.join("src/main.c"),
+                dir.join("src/util.h"),
+                dir.join("include/api.h"),
+                dir.join("include/internal/types.h"),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retrieve_c_includes_working_dir() {
+        // An out-of-tree build, where the compiler runs in `build` rather than
+        // the current directory.
+        let dir = test_dir("working-dir");
+        write(
+            &dir.join("src/main.c"),
+            "#include <api.h>\n#include \"gen.h\"\n",
+        );
+        write(&dir.join("include/api.h"), "");
+        write(&dir.join("build/gen/gen.h"), "");
+
+        let cmdline = "gcc -I../include -iquote gen -c ../src/main.c";
+        let includes = retrieve_c_includes(
+            cmdline,
+            vec![PathBuf::from("../src/main.c")],
+            &dir.join("build"),
+        )
+        .unwrap();
+        assert_eq!(
+            includes,
+            vec![
+                dir.join("src/main.c"),
+                dir.join("include/api.h"),
+                dir.join("build/gen/gen.h"),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retrieve_vfs_overlay_includes() {
+        let dir = test_dir("vfs-overlay");
+        write(
+            &dir.join("src/main.c"),
+            "#include <foo.h>\n#include \"util.h\"\n",
+        );
+        write(&dir.join("src/util.h"), "");
+        write(&dir.join("store/include/foo.h"), "#include <bar.h>\n");
+        write(&dir.join("store/include/bar.h"), "");
+        // foo.h lives at a virtual path mapped from the store, while bar.h,
+        // which it includes, is found in the real include directory.
+        write(
+            &dir.join("overlay.yaml"),
+            "{ 'version': 0, 'overlay-relative': true, 'roots': [\n  \
+               { 'type': 'directory', 'name': '/virtual/include', 'contents': [\n    \
+                 { 'type': 'file', 'name': 'foo.h', 'external-contents': 'store/include/foo.h' }\n  \
+               ] }\n\
+             ] }\n",
+        );
+
+        let cmdline = format!(
+            "clang -ivfsoverlay {} -I/virtual/include -I{} -c src/main.c",
+            dir.join("overlay.yaml").display(),
+            dir.join("store/include").display()
+        );
+        let includes =
+            retrieve_c_includes(&cmdline, vec![PathBuf::from("src/main.c")], &dir).unwrap();
+        assert_eq!(
+            includes,
+            vec![
+                dir.join("src/main.c"),
+                dir.join("store/include/foo.h"),
+                dir.join("src/util.h"),
+                dir.join("store/include/bar.h"),
+            ]
+        );
+
+        // An overlay that can't be parsed only leaves its headers unresolved.
+        fs::write(dir.join("overlay.yaml"), "roots: [").unwrap();
+        let cmdline = "clang -ivfsoverlay overlay.yaml -I/virtual/include -c src/main.c";
+        let includes =
+            retrieve_c_includes(cmdline, vec![PathBuf::from("src/main.c")], &dir).unwrap();
+        assert_eq!(
+            includes,
+            vec![dir.join("src/main.c"), dir.join("src/util.h")]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retrieve_macro_includes() {
+        let dir = test_dir("macros");
+        fs::create_dir_all(dir.join("include")).unwrap();
+        fs::write(
+            dir.join("main.c"),
+            "#include HDR\n#include CONFIG\n#include MISSING\n",
+        )
+        .unwrap();
+        fs::write(dir.join("foo.h"), "").unwrap();
+        fs::write(dir.join("include/config.h"), "").unwrap();
+
+        let cmdline = format!(
+            r#"gcc -DHDR=\"foo.h\" -DCONFIG=CONFIG_H -DCONFIG_H="<config.h>" -DMISSING=\"missing.h\" -I{} -c main.c"#,
+            dir.join("include").display()
+        );
+        let includes =
+            retrieve_c_includes(&cmdline, vec![dir.join("main.c")], Path::new(".")).unwrap();
+        assert_eq!(
+            includes,
+            vec![
+                dir.join("main.c"),
+                dir.join("foo.h"),
+                dir.join("include/config.h")
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use crate::normalize::normalize_path;
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Prefix of the lines naming included headers in `/showIncludes` output,
+/// unless the rule sets `msvc_deps_prefix`.
+pub const DEFAULT_DEPS_PREFIX: &str = "Note: including file:";
+
+/// Retrieve the headers included by the sources of a cl-style `cmdline`, by
+/// running it in `dir` with `/showIncludes` and `/Zs` so that the sources are
+/// only checked for syntax and nothing is written.
+pub fn retrieve_cl_includes(cmdline: &str, dir: &Path, deps_prefix: &str) -> Result<Vec<PathBuf>> {
+    let args = shell_words::split(cmdline)
+        .map_err(|err| anyhow!("Invalid command line syntax: {}", err))?;
+    let (program, args) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("No command found in cmdline"))?;
+
+    let mut command = Command::new(program);
+    command.current_dir(dir);
+    for arg in args {
+        // Drop the outputs, since nothing is compiled.
+        if is_output_arg(arg) {
+            continue;
+        }
+        command.arg(arg);
+    }
+    if !args.iter().any(|arg| is_show_includes_arg(arg)) {
+        command.arg("/showIncludes");
+    }
+    command.arg("/Zs");
+
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Failed to retrieve includes of {}:\n{}{}",
+            cmdline,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    // cl writes the notes to stdout and clang-cl to stderr.
+    let mut includes = parse_show_includes(&String::from_utf8_lossy(&output.stdout), deps_prefix);
+    includes.extend(parse_show_includes(
+        &String::from_utf8_lossy(&output.stderr),
+        deps_prefix,
+    ));
+
+    let mut seen = HashSet::new();
+    Ok(includes
+        .into_iter()
+        .map(|include| normalize_path(&dir.join(include)))
+        .filter(|include| seen.insert(include.clone()))
+        .collect())
+}
+
+/// Parse the headers named by lines starting with `deps_prefix` in
+/// `/showIncludes` output, in order of appearance. Nested includes are
+/// indented after the prefix.
+pub fn parse_show_includes(output: &str, deps_prefix: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix(deps_prefix))
+        .map(|include| PathBuf::from(include.trim()))
+        .filter(|include| !include.as_os_str().is_empty())
+        .collect()
+}
+
+fn is_output_arg(arg: &str) -> bool {
+    ["/Fo", "-Fo", "/Fd", "-Fd", "/Fe", "-Fe"]
+        .iter()
+        .any(|prefix| arg.starts_with(prefix))
+}
+
+fn is_show_includes_arg(arg: &str) -> bool {
+    arg == "/showIncludes" || arg == "-showIncludes"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_show_includes() {
+        let output = "main.c\n\
+                      Note: including file: include/util.h\n\
+                      Note: including file:  C:\\sdk\\stdio.h\n\
+                      main.c(3): warning C4996: deprecated\n";
+        assert_eq!(
+            parse_show_includes(output, DEFAULT_DEPS_PREFIX),
+            vec![
+                PathBuf::from("include/util.h"),
+                PathBuf::from("C:\\sdk\\stdio.h")
+            ]
+        );
+
+        // Localized compilers use another prefix.
+        let output = "Hinweis: Einlesen der Datei: include/util.h\n";
+        assert!(parse_show_includes(output, DEFAULT_DEPS_PREFIX).is_empty());
+        assert_eq!(
+            parse_show_includes(output, "Hinweis: Einlesen der Datei:"),
+            vec![PathBuf::from("include/util.h")]
+        );
+    }
+
+    #[test]
+    fn test_output_args() {
+        assert!(is_output_arg("/Fomain.obj"));
+        assert!(is_output_arg("-Fdmain.pdb"));
+        assert!(!is_output_arg("/Iinclude"));
+        assert!(!is_output_arg("/FImain.h"));
+    }
+}
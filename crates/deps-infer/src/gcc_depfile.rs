@@ -1,27 +1,95 @@
-use crate::gcc_depfile_parser::{spawn_gcc_generate_depfile, DepsConfig};
+use crate::gcc_depfile_parser::{
+    spawn_gcc_generate_depfile, system_include_dirs, DepsConfig, SystemHeaders,
+};
+use crate::normalize::normalize_path;
 use anyhow::{anyhow, Result};
 use n2::scanner;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Distinguishes depfiles of concurrent calls within the same process.
+static DEPFILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Dependencies of a compile command, split by whether they were found in a
+/// system include directory.
+#[derive(Debug, Default, PartialEq)]
+pub struct CIncludes {
+    /// The source and the headers outside of system include directories.
+    pub headers: Vec<PathBuf>,
+    /// Headers in system include directories.
+    pub system_headers: Vec<PathBuf>,
+}
 
 pub fn retrieve_c_includes(cmdline: &str) -> Result<Vec<PathBuf>> {
-    let depfile_path = Path::new("/tmp/foo.d");
+    retrieve_c_includes_in(cmdline, &std::env::temp_dir())
+}
+
+/// Like `retrieve_c_includes`, but writes the intermediate depfile under
+/// `temp_dir` instead of the system temp dir.
+pub fn retrieve_c_includes_in(cmdline: &str, temp_dir: &Path) -> Result<Vec<PathBuf>> {
+    generate_deps(cmdline, temp_dir, SystemHeaders::Exclude)
+}
+
+/// Retrieve both the headers and the system headers of a compile command
+/// with a single `-M` pass, rather than one with `-MM` and one with `-M`.
+pub fn retrieve_partitioned_c_includes(cmdline: &str) -> Result<CIncludes> {
+    retrieve_partitioned_c_includes_in(cmdline, &std::env::temp_dir())
+}
+
+/// Like `retrieve_partitioned_c_includes`, but writes the intermediate
+/// depfile under `temp_dir` instead of the system temp dir.
+pub fn retrieve_partitioned_c_includes_in(cmdline: &str, temp_dir: &Path) -> Result<CIncludes> {
+    let system_dirs = system_include_dirs(cmdline)?;
+    let deps = generate_deps(cmdline, temp_dir, SystemHeaders::Partition)?;
+    Ok(partition_includes(deps, &system_dirs))
+}
+
+/// Split dependencies by whether they are under one of `system_dirs`.
+///
+/// Unlike `-MM`, headers outside of system directories that are only
+/// included by system headers end up in `headers`.
+pub fn partition_includes(deps: Vec<PathBuf>, system_dirs: &[PathBuf]) -> CIncludes {
+    let mut includes = CIncludes::default();
+    for dep in deps {
+        let normalized = normalize_path(&dep);
+        if system_dirs.iter().any(|dir| normalized.starts_with(dir)) {
+            includes.system_headers.push(dep);
+        } else {
+            includes.headers.push(dep);
+        }
+    }
+    includes
+}
+
+fn generate_deps(
+    cmdline: &str,
+    temp_dir: &Path,
+    system_headers: SystemHeaders,
+) -> Result<Vec<PathBuf>> {
+    let depfile = Depfile::new(temp_dir);
 
     spawn_gcc_generate_depfile(
         cmdline,
         &DepsConfig {
-            output_path: depfile_path.into(),
-            include_system_headers: false,
+            output_path: depfile.path.clone(),
+            system_headers,
+            probe_compiler: true,
         },
     )?;
 
-    let buf = scanner::read_file_with_nul(&depfile_path)?;
+    read_depfile(&depfile.path)
+}
+
+// The dependencies listed by a depfile, in order.
+fn read_depfile(path: &Path) -> Result<Vec<PathBuf>> {
+    let buf = scanner::read_file_with_nul(path)?;
     let mut scanner = scanner::Scanner::new(&buf);
 
-    let depfile = n2::depfile::parse(&mut scanner)
-        .map_err(|err| anyhow!(scanner.format_parse_error(&depfile_path, err)))?;
+    let parsed = n2::depfile::parse(&mut scanner)
+        .map_err(|err| anyhow!(scanner.format_parse_error(path, err)))?;
 
     let mut deps: Vec<PathBuf> = Vec::new();
-    for (_, values) in depfile.iter() {
+    for (_, values) in parsed.iter() {
         for value in values {
             deps.push(value.into());
         }
@@ -29,3 +97,66 @@ pub fn retrieve_c_includes(cmdline: &str) -> Result<Vec<PathBuf>> {
 
     Ok(deps)
 }
+
+// A uniquely named depfile that is removed when dropped, whether or not the
+// compiler managed to write it.
+struct Depfile {
+    path: PathBuf,
+}
+
+impl Depfile {
+    fn new(temp_dir: &Path) -> Self {
+        let id = DEPFILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = temp_dir.join(format!("deps-infer-{}-{}.d", std::process::id(), id));
+        Depfile { path }
+    }
+}
+
+impl Drop for Depfile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_depfile() {
+        let depfile = Depfile::new(&std::env::temp_dir());
+        std::fs::write(
+            &depfile.path,
+            "main.o: main.c include/util.h /usr/include/stdio.h \\\n  \
+             /nix/store/x-gcc/lib/gcc/x86_64-linux/14/../../../../include/c++/14/vector \\\n  \
+             ../vendor/sys/lib.h ../vendor/util.h /usr/include-extra/config.h\n",
+        )
+        .unwrap();
+
+        let deps = read_depfile(&depfile.path).unwrap();
+        assert_eq!(deps.len(), 7);
+        let system_dirs = [
+            PathBuf::from("../vendor/sys"),
+            PathBuf::from("/nix/store/x-gcc/include/c++/14"),
+            PathBuf::from("/usr/include"),
+        ];
+        assert_eq!(
+            partition_includes(deps, &system_dirs),
+            CIncludes {
+                headers: vec![
+                    PathBuf::from("main.c"),
+                    PathBuf::from("include/util.h"),
+                    PathBuf::from("../vendor/util.h"),
+                    PathBuf::from("/usr/include-extra/config.h"),
+                ],
+                system_headers: vec![
+                    PathBuf::from("/usr/include/stdio.h"),
+                    PathBuf::from(
+                        "/nix/store/x-gcc/lib/gcc/x86_64-linux/14/../../../../include/c++/14/vector"
+                    ),
+                    PathBuf::from("../vendor/sys/lib.h"),
+                ],
+            }
+        );
+    }
+}
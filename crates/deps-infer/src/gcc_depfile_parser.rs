@@ -1,5 +1,8 @@
+use crate::normalize::normalize_path;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, OnceLock};
 
 /// Error types for dependency extraction
 #[derive(Debug)]
@@ -38,23 +41,164 @@ pub struct DepsConfig {
     /// Path where the dependency file should be written
     pub output_path: PathBuf,
 
-    /// Whether to include system headers in dependencies
-    pub include_system_headers: bool,
+    /// Which headers to include in dependencies
+    pub system_headers: SystemHeaders,
+
+    /// Whether to run the compiler with `--version` to classify it when its
+    /// name doesn't tell, like `cc`
+    pub probe_compiler: bool,
 }
 
 impl Default for DepsConfig {
     fn default() -> Self {
         Self {
             output_path: PathBuf::from("deps.d"),
-            include_system_headers: false,
+            system_headers: SystemHeaders::default(),
+            probe_compiler: false,
         }
     }
 }
 
-/// List of supported GCC-compatible compilers
-static SUPPORTED_COMPILERS: &[&str] = &[
-    "gcc", "g++", "clang", "clang++", "cc", "c++", "emcc", "em++",
-];
+/// Whether the generated dependencies list system headers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SystemHeaders {
+    /// Only headers outside of system include directories, with `-MM`
+    #[default]
+    Exclude,
+    /// Every header, with `-M`, for the caller to partition by
+    /// `system_include_dirs` instead of generating dependencies twice
+    Partition,
+}
+
+/// Family of a GCC-compatible compiler, which decides the flags used to only
+/// generate dependencies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompilerKind {
+    Gcc,
+    Clang,
+}
+
+/// Launchers that run the compiler given as their first argument
+static COMPILER_LAUNCHERS: &[&str] = &["ccache", "sccache", "distcc", "icecc"];
+
+/// Compilers classified by probing, by program
+static PROBED_COMPILERS: OnceLock<Mutex<HashMap<String, Option<CompilerKind>>>> = OnceLock::new();
+
+/// Default system include directories of compilers, by program, language and
+/// the flags changing them
+static DEFAULT_SYSTEM_DIRS: OnceLock<Mutex<SystemDirs>> = OnceLock::new();
+
+type SystemDirs = HashMap<(String, String, Vec<String>), Vec<PathBuf>>;
+
+/// Flags changing the default system include directories, which are passed on
+/// to the probe. Those taking the next argument may also have it joined with
+/// `=`, like `--sysroot=/sdk`.
+static SYSTEM_DIRS_FLAGS: &[&str] = &["-nostdinc", "-nostdinc++"];
+static SYSTEM_DIRS_FLAGS_WITH_ARG: &[&str] = &["--sysroot", "-isysroot", "-target", "--target"];
+
+/// Extensions of C++ sources and headers, whose default system include
+/// directories differ from C
+static CXX_EXTENSIONS: &[&str] = &["cc", "cp", "cpp", "cxx", "c++", "C", "hh", "hpp", "hxx"];
+
+/// Index of the compiler in `args`, after any launchers like `ccache`
+fn compiler_index(args: &[String]) -> usize {
+    args.iter()
+        .position(|arg| !COMPILER_LAUNCHERS.contains(&program_name(arg)))
+        .unwrap_or(args.len())
+}
+
+fn program_name(program: &str) -> &str {
+    Path::new(program)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(program)
+}
+
+/// Classify a compiler by its name, like `clang++` or the nixpkgs wrapper
+/// `x86_64-unknown-linux-gnu-g++`, probing it with `--version` if `probe` is
+/// set and the name is generic or unknown. Generic names default to GCC.
+pub fn classify_compiler(compiler: &str, probe: bool) -> Option<CompilerKind> {
+    let name = program_name(compiler);
+    // Check clang first, since `clang++` contains `g++`. Intel's icx and
+    // icpx are based on clang, while its classic icc and icpc and nvcc take
+    // gcc's dependency flags.
+    if ["clang", "emcc", "em++", "icx", "icpx"]
+        .iter()
+        .any(|c| name.contains(c))
+    {
+        return Some(CompilerKind::Clang);
+    }
+    if ["gcc", "g++", "icc", "icpc", "nvcc"]
+        .iter()
+        .any(|c| name.contains(c))
+    {
+        return Some(CompilerKind::Gcc);
+    }
+
+    let generic = ["cc", "c++"]
+        .iter()
+        .any(|c| name == *c || name.ends_with(&format!("-{}", c)));
+    let probed = if probe {
+        probe_compiler(compiler)
+    } else {
+        None
+    };
+    match probed {
+        Some(kind) => Some(kind),
+        None if generic => Some(CompilerKind::Gcc),
+        None => None,
+    }
+}
+
+// Classify a compiler from the output of `--version`, caching the result.
+fn probe_compiler(compiler: &str) -> Option<CompilerKind> {
+    let probed = PROBED_COMPILERS.get_or_init(Default::default);
+    if let Some(kind) = probed.lock().unwrap().get(compiler) {
+        return *kind;
+    }
+
+    let kind = Command::new(compiler)
+        .arg("--version")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            let version = String::from_utf8_lossy(&output.stdout).to_lowercase();
+            if version.contains("clang") {
+                Some(CompilerKind::Clang)
+            } else if version.contains("gcc") || version.contains("free software foundation") {
+                Some(CompilerKind::Gcc)
+            } else {
+                None
+            }
+        });
+    probed.lock().unwrap().insert(compiler.to_string(), kind);
+    kind
+}
+
+/// Dependency generation flags taking the next argument, like `-MT target`
+static DEPS_FLAGS_WITH_ARG: &[&str] = &["-MF", "-MT", "-MQ"];
+
+/// Dependency generation flags of the compile command, which are replaced by
+/// ours. Those taking an argument may also have it joined, like `-MTtarget`.
+fn is_deps_flag(arg: &str) -> bool {
+    matches!(arg, "-M" | "-MM" | "-MD" | "-MMD" | "-MG" | "-MP")
+        || DEPS_FLAGS_WITH_ARG.iter().any(|flag| arg.starts_with(flag))
+}
+
+/// Flags making the compiler only write the dependencies of its input
+fn deps_flags(kind: CompilerKind, system_headers: SystemHeaders) -> Vec<&'static str> {
+    let mut flags = vec![match system_headers {
+        SystemHeaders::Exclude => "-MM",
+        SystemHeaders::Partition => "-M",
+    }];
+    // Flags kept from the compile command, like `-std`, may not apply to the
+    // input when only preprocessing, which clang warns about.
+    if kind == CompilerKind::Clang {
+        flags.push("-Qunused-arguments");
+    }
+    flags
+}
 
 /// Creates a command that will only generate dependencies from a compiler command
 pub fn create_deps_command(cmdline: &str, config: &DepsConfig) -> Result<Command, DepsError> {
@@ -68,19 +212,17 @@ pub fn create_deps_command(cmdline: &str, config: &DepsConfig) -> Result<Command
         return Err(DepsError::ParseError("Empty command".to_string()));
     }
 
-    // Check if compiler is supported
-    let compiler = &args[0];
-    let compiler_name = Path::new(compiler)
-        .file_name()
-        .and_then(|name| name.to_str())
-        .unwrap_or(compiler);
-
-    if !SUPPORTED_COMPILERS
-        .iter()
-        .any(|&c| compiler_name == c || compiler_name.contains(c))
-    {
+    // Launchers like ccache are dropped, since only the compiler is needed
+    // to generate dependencies.
+    let compiler_index = compiler_index(&args);
+    let Some(compiler) = args.get(compiler_index) else {
+        return Err(DepsError::ParseError(
+            "No compiler after launcher".to_string(),
+        ));
+    };
+    let Some(kind) = classify_compiler(compiler, config.probe_compiler) else {
         return Err(DepsError::UnsupportedCompiler(compiler.clone()));
-    }
+    };
 
     let mut cmd = Command::new(compiler);
 
@@ -88,9 +230,13 @@ pub fn create_deps_command(cmdline: &str, config: &DepsConfig) -> Result<Command
     let mut std_flag = None;
     let mut define_flags = Vec::new();
     let mut input_file = None;
+    // Language forced by `-x` for the input files after it, which may not
+    // have an extension matching their language.
+    let mut language: Option<String> = None;
+    let mut input_language = None;
 
     // Process arguments
-    let mut i = 1;
+    let mut i = compiler_index + 1;
     while i < args.len() {
         let arg = &args[i];
 
@@ -125,12 +271,33 @@ pub fn create_deps_command(cmdline: &str, config: &DepsConfig) -> Result<Command
                 i += 1;
             }
         }
+        // Drop dependency generation flags, with the argument of `-MF`,
+        // `-MT` and `-MQ` so it isn't mistaken for the input file
+        else if is_deps_flag(arg) {
+            if DEPS_FLAGS_WITH_ARG.contains(&arg.as_str()) {
+                i += 1;
+            }
+        }
+        // Handle forced source language
+        else if let Some(lang) = arg.strip_prefix("-x") {
+            let lang = if !lang.is_empty() {
+                Some(lang.to_string())
+            } else if i + 1 < args.len() {
+                i += 1;
+                Some(args[i].clone())
+            } else {
+                None
+            };
+            // `-x none` goes back to detecting the language by extension.
+            language = lang.filter(|lang| lang != "none");
+        }
         // Find input file
-        else if !arg.starts_with("-") && arg.contains(".") {
+        else if !arg.starts_with("-") && (arg.contains(".") || language.is_some()) {
             input_file = Some(arg.clone());
+            input_language = language.clone();
         }
         // Skip output file specification
-        else if (arg == "-o" || arg == "-MF" || arg == "-MQ") && i + 1 < args.len() {
+        else if arg == "-o" && i + 1 < args.len() {
             i += 1; // Skip the argument too
         }
 
@@ -158,17 +325,130 @@ pub fn create_deps_command(cmdline: &str, config: &DepsConfig) -> Result<Command
     }
 
     // Add dependency generation flags
-    if config.include_system_headers {
-        cmd.arg("-M");
-    } else {
-        cmd.arg("-MM");
-    }
+    cmd.args(deps_flags(kind, config.system_headers));
     cmd.arg("-MF").arg(&config.output_path);
+    if let Some(lang) = input_language {
+        cmd.arg("-x").arg(lang);
+    }
     cmd.arg(input_file);
 
     Ok(cmd)
 }
 
+/// System include directories of a compiler command, normalized and in search
+/// order: those given with `-isystem` and then the defaults of the compiler
+/// for the language of the input, which are probed once per compiler.
+pub fn system_include_dirs(cmdline: &str) -> Result<Vec<PathBuf>, DepsError> {
+    let args = shell_words::split(cmdline).map_err(|e| DepsError::ParseError(e.to_string()))?;
+    let compiler_index = compiler_index(&args);
+    let Some(compiler) = args.get(compiler_index) else {
+        return Err(DepsError::ParseError(
+            "No compiler after launcher".to_string(),
+        ));
+    };
+
+    let mut dirs = Vec::new();
+    // g++ and clang++ compile C sources as C++.
+    let mut cxx = program_name(compiler).contains("++");
+    let mut language: Option<String> = None;
+    let mut probe_flags = Vec::new();
+    let mut i = compiler_index + 1;
+    while i < args.len() {
+        let arg = &args[i];
+        if SYSTEM_DIRS_FLAGS.contains(&arg.as_str()) {
+            probe_flags.push(arg.clone());
+        } else if SYSTEM_DIRS_FLAGS_WITH_ARG.contains(&arg.as_str()) {
+            probe_flags.push(arg.clone());
+            probe_flags.extend(args.get(i + 1).cloned());
+            i += 1;
+        } else if SYSTEM_DIRS_FLAGS_WITH_ARG.iter().any(|flag| {
+            arg.strip_prefix(flag)
+                .is_some_and(|rest| rest.starts_with('='))
+        }) {
+            probe_flags.push(arg.clone());
+        } else if let Some(dir) = arg.strip_prefix("-isystem") {
+            if !dir.is_empty() {
+                dirs.push(normalize_path(Path::new(dir)));
+            } else if let Some(dir) = args.get(i + 1) {
+                dirs.push(normalize_path(Path::new(dir)));
+                i += 1;
+            }
+        } else if let Some(lang) = arg.strip_prefix("-x") {
+            let lang = match lang {
+                "" => {
+                    i += 1;
+                    args.get(i).cloned()
+                }
+                lang => Some(lang.to_string()),
+            };
+            language = lang.filter(|lang| lang != "none");
+        } else if DEPS_FLAGS_WITH_ARG.contains(&arg.as_str()) || arg == "-o" {
+            i += 1;
+        } else if !arg.starts_with('-') {
+            cxx |= match &language {
+                Some(lang) => lang.starts_with("c++"),
+                None => Path::new(arg)
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| CXX_EXTENSIONS.contains(&ext)),
+            };
+        }
+        i += 1;
+    }
+
+    dirs.extend(default_system_dirs(
+        compiler,
+        if cxx { "c++" } else { "c" },
+        &probe_flags,
+    )?);
+    Ok(dirs)
+}
+
+// Probe the default system include directories of a compiler, given the flags
+// of the command changing them, from its verbose output when preprocessing
+// nothing, caching the result.
+fn default_system_dirs(
+    compiler: &str,
+    language: &str,
+    flags: &[String],
+) -> Result<Vec<PathBuf>, DepsError> {
+    let probed = DEFAULT_SYSTEM_DIRS.get_or_init(Default::default);
+    let key = (compiler.to_string(), language.to_string(), flags.to_vec());
+    if let Some(dirs) = probed.lock().unwrap().get(&key) {
+        return Ok(dirs.clone());
+    }
+
+    let output = Command::new(compiler)
+        .args(["-x", language, "-E", "-v"])
+        .args(flags)
+        .arg("-")
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(DepsError::ProcessFailed(
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+
+    let dirs = parse_search_dirs(&String::from_utf8_lossy(&output.stderr));
+    probed.lock().unwrap().insert(key, dirs.clone());
+    Ok(dirs)
+}
+
+/// Parse the `#include <...>` search list of a compiler's `-v` output
+fn parse_search_dirs(output: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .skip_while(|line| !line.starts_with("#include <...> search starts here:"))
+        .skip(1)
+        .take_while(|line| !line.starts_with("End of search list."))
+        .map(|line| line.trim().trim_end_matches(" (framework directory)"))
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| normalize_path(Path::new(dir)))
+        .collect()
+}
+
 /// Spawn a process that will only generate gcc-style dependency information
 /// without compiling
 pub fn spawn_gcc_generate_depfile(cmdline: &str, config: &DepsConfig) -> Result<(), DepsError> {
@@ -189,6 +469,7 @@ pub fn spawn_gcc_generate_depfile(cmdline: &str, config: &DepsConfig) -> Result<
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutil::{test_dir, write_executable};
 
     // Helper function to get command as a string for testing
     fn cmd_to_string(cmd: &Command) -> String {
@@ -233,16 +514,88 @@ mod tests {
                 input: "g++ -isystem /usr/include/boost -c file.cpp",
                 config: DepsConfig {
                     output_path: PathBuf::from("system.d"),
-                    include_system_headers: true,
+                    system_headers: SystemHeaders::Partition,
+                    ..DepsConfig::default()
                 },
                 expected: Ok("g++ -isystem/usr/include/boost -M -MF system.d file.cpp"),
             },
+            TestCase {
+                name: "partition system headers",
+                input: "clang -isystem /usr/include/boost -c file.c",
+                config: DepsConfig {
+                    system_headers: SystemHeaders::Partition,
+                    ..DepsConfig::default()
+                },
+                expected: Ok(
+                    "clang -isystem/usr/include/boost -M -Qunused-arguments -MF deps.d file.c",
+                ),
+            },
             TestCase {
                 name: "MQ MF flags removal",
                 input: "g++ -c file.cpp -MQ file.o -MF file.d",
                 config: DepsConfig::default(),
                 expected: Ok("g++ -MM -MF deps.d file.cpp"),
             },
+            TestCase {
+                name: "MT target",
+                input: "gcc -MD -MT main.o -MF main.d -c main.c -o main.o",
+                config: DepsConfig::default(),
+                expected: Ok("gcc -MM -MF deps.d main.c"),
+            },
+            TestCase {
+                name: "MT target after input",
+                input: "gcc -c main.c -MMD -MP -MT out/main.o -MQ out/main.o",
+                config: DepsConfig::default(),
+                expected: Ok("gcc -MM -MF deps.d main.c"),
+            },
+            TestCase {
+                name: "joined and bare dependency flags",
+                input: "g++ -M -MM -MG -MTmain.o -MFmain.d -MP -c main.cpp",
+                config: DepsConfig::default(),
+                expected: Ok("g++ -MM -MF deps.d main.cpp"),
+            },
+            TestCase {
+                name: "forced language",
+                input: "g++ -I. -x c++ -c foo.inc -o foo.o",
+                config: DepsConfig::default(),
+                expected: Ok("g++ -I. -MM -MF deps.d -x c++ foo.inc"),
+            },
+            TestCase {
+                name: "forced language without extension",
+                input: "gcc -xc -c generated -o generated.o",
+                config: DepsConfig::default(),
+                expected: Ok("gcc -MM -MF deps.d -x c generated"),
+            },
+            TestCase {
+                name: "forced language reset",
+                input: "gcc -x c -x none -c main.c",
+                config: DepsConfig::default(),
+                expected: Ok("gcc -MM -MF deps.d main.c"),
+            },
+            TestCase {
+                name: "ccache launcher",
+                input: "ccache g++ -I. -c main.cpp -o main.o",
+                config: DepsConfig::default(),
+                expected: Ok("g++ -I. -MM -MF deps.d main.cpp"),
+            },
+            TestCase {
+                name: "nested launchers",
+                input: "/usr/bin/sccache distcc clang++ -c main.cpp",
+                config: DepsConfig::default(),
+                expected: Ok("clang++ -MM -Qunused-arguments -MF deps.d main.cpp"),
+            },
+            TestCase {
+                name: "launcher without compiler",
+                input: "ccache",
+                config: DepsConfig::default(),
+                expected: Err(DepsError::ParseError("No compiler after launcher".to_string())),
+            },
+            TestCase {
+                name: "generic cc without probing",
+                input: "cc -c main.c",
+                config: DepsConfig::default(),
+                expected: Ok("cc -MM -MF deps.d main.c"),
+            },
             TestCase {
                 name: "real world example",
                 input: "g++ -Ihello.p -I. -I.. -I/nix/store/b2zcd1z08y0bgiiradpk34g03ny5765y-boost-1.87.0-dev/include -fdiagnostics-color=always -D_GLIBCXX_ASSERTIONS=1 -D_FILE_OFFSET_BITS=64 -Wall -Winvalid-pch -std=c++14 -O0 -g -DBOOST_ALL_NO_LIB -MD -MQ hello.p/main.cpp.o -MF hello.p/main.cpp.o.d -o hello.p/main.cpp.o -c ../main.cpp",
@@ -292,4 +645,133 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_classify_compiler() {
+        assert_eq!(
+            classify_compiler("clang++", false),
+            Some(CompilerKind::Clang)
+        );
+        assert_eq!(
+            classify_compiler(
+                "/nix/store/x-gcc-wrapper/bin/x86_64-unknown-linux-gnu-g++",
+                false
+            ),
+            Some(CompilerKind::Gcc)
+        );
+        assert_eq!(classify_compiler("em++", false), Some(CompilerKind::Clang));
+        assert_eq!(
+            classify_compiler("/opt/cuda/bin/nvcc", false),
+            Some(CompilerKind::Gcc)
+        );
+        assert_eq!(classify_compiler("icc", false), Some(CompilerKind::Gcc));
+        assert_eq!(classify_compiler("icpc", false), Some(CompilerKind::Gcc));
+        assert_eq!(classify_compiler("icpx", false), Some(CompilerKind::Clang));
+        assert_eq!(classify_compiler("cc", false), Some(CompilerKind::Gcc));
+        assert_eq!(classify_compiler("rustc", false), None);
+
+        // A generic or unknown name is classified by its `--version`.
+        let dir = test_dir("probe");
+        let fake = |name: &str, version: &str| {
+            let path = dir.join(name);
+            write_executable(&path, &format!("#!/bin/sh\necho '{}'\n", version));
+            path.to_string_lossy().into_owned()
+        };
+        let cc = fake("cc", "clang version 18.1.8");
+        assert_eq!(classify_compiler(&cc, true), Some(CompilerKind::Clang));
+        let cc = fake(
+            "c++",
+            "c++ (GCC) 14.2.1\nCopyright (C) 2024 Free Software Foundation, Inc.",
+        );
+        assert_eq!(classify_compiler(&cc, true), Some(CompilerKind::Gcc));
+        let wrapper = fake("my-compiler", "clang version 18.1.8");
+        assert_eq!(classify_compiler(&wrapper, true), Some(CompilerKind::Clang));
+        let other = fake("tool", "tool 1.0");
+        assert_eq!(classify_compiler(&other, true), None);
+
+        let cmd = create_deps_command(
+            &format!("ccache {} -c main.c", dir.join("cc").display()),
+            &DepsConfig {
+                probe_compiler: true,
+                ..DepsConfig::default()
+            },
+        )
+        .unwrap();
+        assert!(cmd_to_string(&cmd).ends_with("cc -MM -Qunused-arguments -MF deps.d main.c"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_search_dirs() {
+        let output = "Using built-in specs.\n\
+                      #include \"...\" search starts here:\n \
+                       include\n\
+                      #include <...> search starts here:\n \
+                       /nix/store/x-glibc-dev/include\n \
+                       /nix/store/x-gcc/lib/gcc/x86_64-linux/14/../../../../include/c++/14\n \
+                       /Library/Frameworks (framework directory)\n\
+                      End of search list.\n";
+        assert_eq!(
+            parse_search_dirs(output),
+            vec![
+                PathBuf::from("/nix/store/x-glibc-dev/include"),
+                PathBuf::from("/nix/store/x-gcc/include/c++/14"),
+                PathBuf::from("/Library/Frameworks"),
+            ]
+        );
+        assert!(parse_search_dirs("gcc: error: unrecognized option\n").is_empty());
+    }
+
+    #[test]
+    fn test_system_include_dirs() {
+        let dir = test_dir("sysdirs");
+        // Lists the C++ headers only for C++.
+        let cc = dir.join("cc");
+        write_executable(
+            &cc,
+            "#!/bin/sh\n\
+             echo '#include <...> search starts here:' >&2\n\
+             for arg; do case $arg in -nostdinc) nostdinc=1;; --sysroot=*) sysroot=${arg#*=};; esac; done\n\
+             [ -n \"$nostdinc\" ] || {\n\
+             [ \"$2\" = c++ ] && echo \" $sysroot/sys/include/c++\" >&2\n\
+             echo \" $sysroot/sys/include\" >&2\n\
+             }\n\
+             echo 'End of search list.' >&2\n",
+        );
+        let cc = cc.display();
+
+        assert_eq!(
+            system_include_dirs(&format!("{} -isystem ./vendor -Iinclude -c main.c", cc)).unwrap(),
+            vec![PathBuf::from("vendor"), PathBuf::from("/sys/include")]
+        );
+        assert_eq!(
+            system_include_dirs(&format!("ccache {} -isystem/opt/include -c main.cpp", cc))
+                .unwrap(),
+            vec![
+                PathBuf::from("/opt/include"),
+                PathBuf::from("/sys/include/c++"),
+                PathBuf::from("/sys/include")
+            ]
+        );
+        assert_eq!(
+            system_include_dirs(&format!("{} -x c++ -c main.inc", cc)).unwrap(),
+            vec![
+                PathBuf::from("/sys/include/c++"),
+                PathBuf::from("/sys/include")
+            ]
+        );
+
+        // The flags changing the defaults are passed on to the probe.
+        assert_eq!(
+            system_include_dirs(&format!("{} --sysroot=/sdk -c main.c", cc)).unwrap(),
+            vec![PathBuf::from("/sdk/sys/include")]
+        );
+        assert_eq!(
+            system_include_dirs(&format!("{} -nostdinc -isystem inc -c main.c", cc)).unwrap(),
+            vec![PathBuf::from("inc")]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
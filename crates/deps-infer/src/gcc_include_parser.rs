@@ -1,5 +1,7 @@
+use crate::vfs_overlay::VfsOverlay;
 use anyhow::{anyhow, Result};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 /// Parse include directories from a gcc cmdline.
 pub fn parse_include_dirs(cmdline: &str) -> Result<Vec<PathBuf>> {
@@ -35,6 +37,111 @@ pub fn parse_include_dirs(cmdline: &str) -> Result<Vec<PathBuf>> {
     Ok(include_dirs)
 }
 
+/// Directories searched for headers, by kind of flag. gcc searches them in
+/// the order of the fields.
+#[derive(Debug, Default, PartialEq)]
+pub struct SearchDirs {
+    /// `-iquote` directories, only searched for `#include "..."`.
+    pub quote: Vec<PathBuf>,
+    /// `-I` directories.
+    pub include: Vec<PathBuf>,
+    /// `-isystem` directories.
+    pub system: Vec<PathBuf>,
+    /// `-idirafter` directories.
+    pub after: Vec<PathBuf>,
+    /// Virtual headers of clang `-ivfsoverlay` files, found before the real
+    /// ones.
+    pub overlay: VfsOverlay,
+}
+
+impl SearchDirs {
+    /// Resolve relative directories against `working_dir`, the directory the
+    /// compiler runs in.
+    pub fn resolve_in(self, working_dir: &Path) -> Self {
+        let resolve = |dirs: Vec<PathBuf>| -> Vec<PathBuf> {
+            dirs.into_iter().map(|dir| working_dir.join(dir)).collect()
+        };
+        SearchDirs {
+            quote: resolve(self.quote),
+            include: resolve(self.include),
+            system: resolve(self.system),
+            after: resolve(self.after),
+            overlay: self.overlay,
+        }
+    }
+}
+
+/// Parse the directories searched for headers from a gcc cmdline.
+pub fn parse_search_dirs(cmdline: &str) -> Result<SearchDirs> {
+    let args =
+        shell_words::split(cmdline).map_err(|e| anyhow!("Invalid command line syntax: {}", e))?;
+
+    Ok(SearchDirs {
+        quote: flag_values(&args, "-iquote"),
+        include: parse_include_dirs(cmdline)?,
+        system: flag_values(&args, "-isystem"),
+        after: flag_values(&args, "-idirafter"),
+        overlay: VfsOverlay::default(),
+    })
+}
+
+// Values of `flag` given either as `-flagdir` or `-flag dir`.
+pub(crate) fn flag_values(args: &[String], flag: &str) -> Vec<PathBuf> {
+    let mut values = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == flag && i + 1 < args.len() {
+            values.push(PathBuf::from(&args[i + 1]));
+            i += 1;
+        } else if let Some(value) = args[i].strip_prefix(flag).filter(|v| !v.is_empty()) {
+            values.push(PathBuf::from(value));
+        }
+        i += 1;
+    }
+    values
+}
+
+/// Parse the object-like macros defined by `-D` flags of a gcc cmdline, in
+/// order so that later `-D` and `-U` flags override earlier ones. A macro
+/// defined without a value expands to `1`, like gcc does.
+///
+/// Function-like macros (`-DNAME(args)=...`) are skipped since their
+/// arguments aren't expanded.
+pub fn parse_defines(cmdline: &str) -> Result<HashMap<String, String>> {
+    let args =
+        shell_words::split(cmdline).map_err(|e| anyhow!("Invalid command line syntax: {}", e))?;
+
+    let mut defines = HashMap::new();
+    let mut i = 0;
+
+    while i < args.len() {
+        let arg = &args[i];
+
+        let (flag, value) = if (arg == "-D" || arg == "-U") && i + 1 < args.len() {
+            i += 1;
+            (&arg[..], &args[i][..])
+        } else if arg.starts_with("-D") || arg.starts_with("-U") {
+            (&arg[..2], &arg[2..])
+        } else {
+            i += 1;
+            continue;
+        };
+        i += 1;
+
+        let (name, definition) = value.split_once('=').unwrap_or((value, "1"));
+        if name.is_empty() || name.contains('(') {
+            continue;
+        }
+        if flag == "-D" {
+            defines.insert(name.to_string(), definition.to_string());
+        } else {
+            defines.remove(name);
+        }
+    }
+
+    Ok(defines)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -130,4 +237,36 @@ mod tests {
         // Test with unmatched quotes
         assert!(parse_include_dirs("g++ -I\"unclosed quote file.cpp").is_err());
     }
+
+    #[test]
+    fn test_parse_search_dirs() {
+        let dirs = parse_search_dirs(
+            "g++ -iquote quoted -iquoteq2 -Iinc -isystem /sys -isysroot /root -idirafter after -c file.cpp",
+        )
+        .unwrap();
+        assert_eq!(
+            dirs,
+            SearchDirs {
+                quote: paths(&["quoted", "q2"]),
+                include: paths(&["inc"]),
+                system: paths(&["/sys"]),
+                after: paths(&["after"]),
+                ..SearchDirs::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_defines() {
+        let defines = parse_defines(
+            "g++ -DDEBUG -D NDEBUG=0 -DHDR=\\\"foo.h\\\" -DMAX(a,b)=a -DGONE -UGONE -c file.cpp",
+        )
+        .unwrap();
+        assert_eq!(defines.get("DEBUG").map(String::as_str), Some("1"));
+        assert_eq!(defines.get("NDEBUG").map(String::as_str), Some("0"));
+        assert_eq!(defines.get("HDR").map(String::as_str), Some("\"foo.h\""));
+        assert!(!defines.contains_key("MAX"));
+        assert!(!defines.contains_key("MAX(a,b)"));
+        assert!(!defines.contains_key("GONE"));
+    }
 }
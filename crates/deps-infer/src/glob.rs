@@ -0,0 +1,90 @@
+use std::path::Path;
+
+/// Whether `text`, like an environment variable name or a path, matches a
+/// glob pattern where `*` matches any sequence of characters and `?` matches
+/// a single character.
+///
+/// Runs in `O(pattern * text)` time by only backtracking to the last `*`.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and the text position it's
+    // currently matched up to, to backtrack to on a mismatch.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Whether `path` matches a glob pattern by its whole path or, for patterns
+/// without a `/`, by its file name.
+pub fn path_matches(pattern: &str, path: &Path) -> bool {
+    if glob_matches(pattern, &path.to_string_lossy()) {
+        return true;
+    }
+    !pattern.contains('/')
+        && path
+            .file_name()
+            .is_some_and(|name| glob_matches(pattern, &name.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("NIX_LDFLAGS", "NIX_LDFLAGS"));
+        assert!(!glob_matches("NIX_LDFLAGS", "NIX_LDFLAGS_FOR_TARGET"));
+        assert!(glob_matches(
+            "NIX_CC_WRAPPER*",
+            "NIX_CC_WRAPPER_TARGET_HOST_x86_64_unknown_linux_gnu"
+        ));
+        assert!(glob_matches("NIX_CC_WRAPPER*", "NIX_CC_WRAPPER"));
+        assert!(!glob_matches("NIX_CC_WRAPPER*", "NIX_CC"));
+        assert!(glob_matches("*_PATH", "PKG_CONFIG_PATH"));
+        assert!(glob_matches("*_PATH", "ACLOCAL_PATH"));
+        assert!(!glob_matches("*_PATH", "PATH"));
+        assert!(glob_matches("RUST*FLAGS", "RUSTFLAGS"));
+        assert!(glob_matches("RUST*FLAGS", "RUSTDOCFLAGS"));
+        assert!(glob_matches("NIX_?FLAGS", "NIX_LFLAGS"));
+        assert!(glob_matches("*", "ANYTHING"));
+        assert!(glob_matches("", ""));
+        assert!(!glob_matches("", "a"));
+
+        // Patterns with many stars don't backtrack exponentially.
+        let text = "a".repeat(100);
+        let pattern = format!("{}b", "a*".repeat(50));
+        assert!(!glob_matches(&pattern, &text));
+    }
+
+    #[test]
+    fn test_path_matches() {
+        let path = Path::new("/usr/include/bits/stdc-predef.h");
+        assert!(path_matches("stdc-predef.h", path));
+        assert!(path_matches("*.h", path));
+        assert!(path_matches("/usr/include/*", path));
+        // Patterns with a slash only match the whole path.
+        assert!(!path_matches("bits/stdc-predef.h", path));
+        assert!(path_matches("*/bits/*", path));
+        assert!(!path_matches("stdio.h", path));
+    }
+}
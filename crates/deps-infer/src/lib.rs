@@ -1,4 +1,10 @@
 pub mod c_include_parser;
+pub mod cl_include_parser;
 pub mod gcc_depfile;
 mod gcc_depfile_parser;
 mod gcc_include_parser;
+pub mod glob;
+pub mod normalize;
+#[cfg(test)]
+mod testutil;
+pub mod vfs_overlay;
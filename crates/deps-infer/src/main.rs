@@ -1,6 +1,7 @@
 use anyhow::{anyhow, bail, Result};
 use clap::Parser;
-use deps_infer::{c_include_parser, gcc_depfile};
+use deps_infer::normalize::normalize_path;
+use deps_infer::{c_include_parser, gcc_depfile, glob};
 use n2::{canon, load, scanner};
 use std::{
     path::{Path, PathBuf},
@@ -26,6 +27,16 @@ struct Args {
 
     #[arg(long = "target")]
     pub target: Option<String>,
+
+    /// Directory to write depfiles under in benchmark mode, e.g. a tmpfs
+    /// [default: system temp dir]
+    #[arg(long = "temp-dir")]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Ignore includes matching GLOB in correctness mode, e.g. known system
+    /// headers like `stdc-predef.h`. Globs without a slash match file names
+    #[arg(long = "ignore-include", value_name = "GLOB")]
+    pub ignore_includes: Vec<String>,
 }
 
 #[derive(Parser, Debug, Clone, clap::ValueEnum)]
@@ -73,8 +84,11 @@ fn main() -> Result<()> {
             }
             Err(anyhow!("Failed to find target: {}", target_name))
         }
-        Mode::Benchmark => run_benchmark_mode(targets),
-        Mode::Correctness => run_correctness_mode(targets),
+        Mode::Benchmark => {
+            let temp_dir = args.temp_dir.unwrap_or_else(std::env::temp_dir);
+            run_benchmark_mode(targets, &temp_dir)
+        }
+        Mode::Correctness => run_correctness_mode(targets, &args.ignore_includes),
     }
 }
 
@@ -143,9 +157,13 @@ fn load_targets(build_filename: &str) -> Result<Vec<Target>> {
 }
 
 fn run_scan_mode(target: Target) -> Result<()> {
-    let gcc_includes = gcc_depfile::retrieve_c_includes(&target.cmdline)?;
+    let gcc_includes = gcc_depfile::retrieve_partitioned_c_includes(&target.cmdline)?;
     println!("GCC depfile method:");
-    for include in gcc_includes {
+    for include in gcc_includes.headers {
+        println!("{}", include.display());
+    }
+    println!("GCC depfile method, system headers:");
+    for include in gcc_includes.system_headers {
         println!("{}", include.display());
     }
 
@@ -153,6 +171,7 @@ fn run_scan_mode(target: Target) -> Result<()> {
     let c_includes = c_include_parser::retrieve_c_includes(
         &target.cmdline,
         vec![target.filename.clone().into()],
+        Path::new("."),
     )?;
     println!("C include parser method:");
     for include in c_includes {
@@ -162,11 +181,23 @@ fn run_scan_mode(target: Target) -> Result<()> {
     Ok(())
 }
 
-fn run_benchmark_mode(targets: Vec<Target>) -> Result<()> {
+fn run_benchmark_mode(targets: Vec<Target>, temp_dir: &Path) -> Result<()> {
+    // Keep all depfiles in one directory so that IO is consistent between
+    // runs and cleanup is a single removal.
+    let depfile_dir = temp_dir.join(format!("deps-infer-benchmark-{}", std::process::id()));
+    std::fs::create_dir_all(&depfile_dir)?;
+    let result = run_benchmark(&targets, &depfile_dir);
+    // Failing to clean up must not hide why the benchmark failed.
+    let cleanup = std::fs::remove_dir_all(&depfile_dir);
+    result?;
+    Ok(cleanup?)
+}
+
+fn run_benchmark(targets: &[Target], depfile_dir: &Path) -> Result<()> {
     // Benchmark gcc_depfile method
     let gcc_start = Instant::now();
-    for target in &targets {
-        gcc_depfile::retrieve_c_includes(&target.cmdline)?;
+    for target in targets {
+        gcc_depfile::retrieve_c_includes_in(&target.cmdline, depfile_dir)?;
     }
     let gcc_duration = gcc_start.elapsed();
     println!(
@@ -176,10 +207,11 @@ fn run_benchmark_mode(targets: Vec<Target>) -> Result<()> {
 
     // Benchmark c_include_parser method
     let c_start = Instant::now();
-    for target in &targets {
+    for target in targets {
         c_include_parser::retrieve_c_includes(
             &target.cmdline,
             vec![target.filename.clone().into()],
+            Path::new("."),
         )?;
     }
     let c_duration = c_start.elapsed();
@@ -210,17 +242,21 @@ fn run_benchmark_mode(targets: Vec<Target>) -> Result<()> {
     Ok(())
 }
 
-fn run_correctness_mode(targets: Vec<Target>) -> Result<()> {
+fn run_correctness_mode(targets: Vec<Target>, ignore_includes: &[String]) -> Result<()> {
     let current_dir = std::env::current_dir()?;
+    let mut mismatches = 0;
     for target in targets {
         let mut c_includes = c_include_parser::retrieve_c_includes(
             &target.cmdline,
             vec![target.filename.clone().into()],
+            Path::new("."),
         )?;
         c_includes = normalize_paths(c_includes, &current_dir);
+        c_includes.retain(|path| !is_ignored(path, ignore_includes));
 
         let mut gcc_includes = gcc_depfile::retrieve_c_includes(&target.cmdline)?;
         gcc_includes = normalize_paths(gcc_includes, &current_dir);
+        gcc_includes.retain(|path| !is_ignored(path, ignore_includes));
 
         println!(
             "{}: c {}, gcc {}",
@@ -258,10 +294,14 @@ fn run_correctness_mode(targets: Vec<Target>) -> Result<()> {
                 }
             }
 
-            return Err(anyhow!("Include mismatch for {}", target.filename));
+            mismatches += 1;
         }
     }
 
+    if mismatches > 0 {
+        return Err(anyhow!("Include mismatch for {} targets", mismatches));
+    }
+
     println!(
         "c_include_parser is fully correct for {}",
         current_dir.display()
@@ -270,21 +310,43 @@ fn run_correctness_mode(targets: Vec<Target>) -> Result<()> {
     Ok(())
 }
 
-// Helper function to normalize and canonicalize paths
+// Make paths absolute and normalize them lexically, so that the same include
+// reached through `..` compares equal without needing it to exist locally.
 fn normalize_paths(paths: Vec<PathBuf>, current_dir: &Path) -> Vec<PathBuf> {
     paths
         .into_iter()
-        .map(|path| {
-            let path = if path.is_absolute() {
-                path
-            } else {
-                current_dir.join(path)
-            };
-            // Normalize the path to remove components like ".." and "."
-            match path.canonicalize() {
-                Ok(canonical) => canonical,
-                Err(_) => path, // Keep original if canonicalization fails
-            }
-        })
+        .map(|path| normalize_path(&current_dir.join(path)))
         .collect()
 }
+
+// Whether an include matches one of the `--ignore-include` globs, where `*`
+// matches any sequence of characters and `?` a single one.
+fn is_ignored(path: &Path, ignore_includes: &[String]) -> bool {
+    ignore_includes
+        .iter()
+        .any(|pattern| glob::path_matches(pattern, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ignore_includes() {
+        let args = Args::try_parse_from([
+            "deps-infer",
+            "--ignore-include",
+            "stdc-predef.h",
+            "--ignore-include",
+            "/nix/store/*-glibc-*/include/*",
+        ])
+        .unwrap();
+        let ignored = |path: &str| is_ignored(Path::new(path), &args.ignore_includes);
+
+        assert!(ignored("/usr/include/stdc-predef.h"));
+        assert!(ignored("/nix/store/x-glibc-2.40-dev/include/bits/types.h"));
+        assert!(!ignored("/nix/store/x-zlib-dev/include/zlib.h"));
+        assert!(!ignored("src/predef.h"));
+        assert!(!is_ignored(Path::new("stdc-predef.h"), &[]));
+    }
+}
@@ -0,0 +1,71 @@
+use std::path::{Component, Path, PathBuf};
+
+/// Normalize a path lexically, dropping `.` components and resolving `..`
+/// against the preceding component, without touching the filesystem.
+///
+/// Unlike `fs::canonicalize`, this works for paths that don't exist locally
+/// and doesn't resolve symlinks, so `a/link/../b` becomes `a/b` even if
+/// `link` points elsewhere. Leading `..` of relative paths are kept, and `..`
+/// at the root is dropped.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut components: Vec<Component> = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match components.last() {
+                Some(Component::Normal(_)) => {
+                    components.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => components.push(component),
+            },
+            _ => components.push(component),
+        }
+    }
+
+    if components.is_empty() {
+        return PathBuf::from(".");
+    }
+    components.iter().map(|c| c.as_os_str()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_path() {
+        for (path, expected) in [
+            ("src/x.h", "src/x.h"),
+            ("build/../src/x.h", "src/x.h"),
+            ("./src/./x.h", "src/x.h"),
+            ("src/include/../../src/x.h", "src/x.h"),
+            ("../src/x.h", "../src/x.h"),
+            ("../../src/../x.h", "../../x.h"),
+            (
+                "/nix/store/abc-gcc/include/../lib/x.h",
+                "/nix/store/abc-gcc/lib/x.h",
+            ),
+            ("/../x.h", "/x.h"),
+            ("src/..", "."),
+            (".", "."),
+        ] {
+            assert_eq!(
+                normalize_path(Path::new(path)),
+                PathBuf::from(expected),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalize_path_ignores_filesystem() {
+        // Paths that don't exist are normalized too, and symlinks are never
+        // followed.
+        assert_eq!(
+            normalize_path(Path::new("/does/not/exist/../x.h")),
+            PathBuf::from("/does/not/x.h")
+        );
+    }
+}
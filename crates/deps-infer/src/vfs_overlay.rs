@@ -0,0 +1,307 @@
+use crate::gcc_include_parser::flag_values;
+use crate::normalize::normalize_path;
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Headers at virtual paths, mapped to real files by clang `-ivfsoverlay`
+/// files, so that includes of virtual paths resolve to the files the compiler
+/// actually reads.
+#[derive(Debug, Default, PartialEq)]
+pub struct VfsOverlay {
+    /// The mappings of each overlay, in the order of the overlays.
+    overlays: Vec<Vec<Mapping>>,
+}
+
+/// A virtual path mapped to a real one.
+#[derive(Debug, PartialEq)]
+enum Mapping {
+    /// A virtual file.
+    File(PathBuf, PathBuf),
+    /// A virtual directory remapped as a whole.
+    Dir(PathBuf, PathBuf),
+}
+
+impl Mapping {
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        match self {
+            Mapping::File(virtual_file, real_file) => {
+                (path == virtual_file).then(|| real_file.clone())
+            }
+            Mapping::Dir(virtual_dir, real_dir) => {
+                let rest = path.strip_prefix(virtual_dir).ok()?;
+                Some(real_dir.join(rest))
+            }
+        }
+    }
+}
+
+// The parts of clang's overlay format that map paths. Unknown keys, like
+// `case-sensitive` or `use-external-names`, are ignored.
+#[derive(Deserialize)]
+struct OverlayFile {
+    #[serde(rename = "overlay-relative", default)]
+    overlay_relative: Option<Value>,
+    roots: Vec<Entry>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Entry {
+    Directory {
+        name: String,
+        #[serde(default)]
+        contents: Vec<Entry>,
+    },
+    File {
+        name: String,
+        #[serde(rename = "external-contents")]
+        external_contents: String,
+    },
+    DirectoryRemap {
+        name: String,
+        #[serde(rename = "external-contents")]
+        external_contents: String,
+    },
+}
+
+impl Entry {
+    fn name(&self) -> &str {
+        match self {
+            Entry::Directory { name, .. }
+            | Entry::File { name, .. }
+            | Entry::DirectoryRemap { name, .. } => name,
+        }
+    }
+}
+
+impl VfsOverlay {
+    /// Load the overlays of the `-ivfsoverlay` flags of a clang `cmdline`,
+    /// resolving their paths against `working_dir`. An overlay that can't be
+    /// read or parsed is skipped with a warning, which leaves its virtual
+    /// headers unresolved.
+    pub fn from_cmdline(cmdline: &str, working_dir: &Path) -> Self {
+        let mut overlay = VfsOverlay::default();
+        let Ok(args) = shell_words::split(cmdline) else {
+            return overlay;
+        };
+
+        for path in flag_values(&args, "-ivfsoverlay") {
+            let path = working_dir.join(path);
+            let overlay_dir = path.parent().unwrap_or(working_dir);
+            let added = fs::read_to_string(&path)
+                .map_err(|err| anyhow!(err))
+                .and_then(|contents| overlay.add(&contents, overlay_dir, working_dir));
+            if let Err(err) = added {
+                tracing::warn!(
+                    "deps-infer: warning: ignoring VFS overlay {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+        overlay
+    }
+
+    /// Add the mappings of an overlay, taking precedence over those added
+    /// before. Relative real paths are resolved against `overlay_dir` if the
+    /// overlay sets `overlay-relative`, otherwise against `working_dir`.
+    ///
+    /// Overlays are read in the JSON-like YAML flow style that clang writes,
+    /// where strings are single or double-quoted. YAML block style isn't
+    /// supported.
+    pub fn add(&mut self, contents: &str, overlay_dir: &Path, working_dir: &Path) -> Result<()> {
+        let file: OverlayFile = serde_json::from_str(&flow_to_json(contents)?)?;
+        let relative_to = match &file.overlay_relative {
+            Some(Value::Bool(true)) => overlay_dir,
+            Some(Value::String(value)) if value == "true" => overlay_dir,
+            _ => working_dir,
+        };
+
+        // Collect every mapping first, so that an invalid overlay adds none.
+        let mut mappings = Vec::new();
+        for root in &file.roots {
+            if !Path::new(root.name()).is_absolute() {
+                return Err(anyhow!("root {} isn't an absolute path", root.name()));
+            }
+            collect(root, Path::new("/"), relative_to, &mut mappings);
+        }
+
+        self.overlays.push(mappings);
+        Ok(())
+    }
+
+    /// The real file of a virtual path, if an overlay maps it. Like in clang,
+    /// the last overlay mapping the path wins, and within an overlay the first
+    /// of its mappings.
+    pub fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let path = normalize_path(path);
+        self.overlays
+            .iter()
+            .rev()
+            .find_map(|mappings| mappings.iter().find_map(|mapping| mapping.resolve(&path)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.overlays.iter().all(Vec::is_empty)
+    }
+}
+
+// Collect the mappings of an entry, whose name is relative to `parent`.
+fn collect(entry: &Entry, parent: &Path, relative_to: &Path, mappings: &mut Vec<Mapping>) {
+    let virtual_path = normalize_path(&parent.join(entry.name()));
+    match entry {
+        Entry::Directory { contents, .. } => {
+            for child in contents {
+                collect(child, &virtual_path, relative_to, mappings);
+            }
+        }
+        Entry::File {
+            external_contents, ..
+        } => mappings.push(Mapping::File(
+            virtual_path,
+            normalize_path(&relative_to.join(external_contents)),
+        )),
+        Entry::DirectoryRemap {
+            external_contents, ..
+        } => mappings.push(Mapping::Dir(
+            virtual_path,
+            normalize_path(&relative_to.join(external_contents)),
+        )),
+    }
+}
+
+// Convert an overlay in YAML flow style to JSON, by rewriting its
+// single-quoted strings, where `''` escapes a quote, as JSON strings. The
+// escapes of double-quoted strings are the ones JSON has.
+fn flow_to_json(contents: &str) -> Result<String> {
+    let mut json = String::with_capacity(contents.len());
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\'') if chars.next_if_eq(&'\'').is_some() => value.push('\''),
+                        Some('\'') => break,
+                        Some(c) => value.push(c),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                json.push_str(&serde_json::to_string(&value)?);
+            }
+            '"' => {
+                json.push(c);
+                loop {
+                    match chars.next() {
+                        Some('\\') => {
+                            json.push('\\');
+                            json.extend(chars.next());
+                        }
+                        Some('"') => break,
+                        Some(c) => json.push(c),
+                        None => bail!("unterminated string"),
+                    }
+                }
+                json.push('"');
+            }
+            _ => json.push(c),
+        }
+    }
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A minimal overlay as clang's `-ivfsoverlay` takes it, mapping a single
+    // header of a virtual include directory.
+    const OVERLAY: &str = "{
+  'version': 0,
+  'case-sensitive': 'false',
+  'overlay-relative': 'true',
+  'roots': [
+    {
+      'type': 'directory',
+      'name': '/virtual/include',
+      'contents': [
+        { 'type': 'file', 'name': 'foo.h', 'external-contents': 'real/foo.h' }
+      ]
+    }
+  ]
+}
+";
+
+    #[test]
+    fn test_parse_overlay() {
+        let mut overlay = VfsOverlay::default();
+        overlay
+            .add(OVERLAY, Path::new("/overlays"), Path::new("/build"))
+            .unwrap();
+        assert_eq!(
+            overlay.resolve(Path::new("/virtual/include/foo.h")),
+            Some(PathBuf::from("/overlays/real/foo.h"))
+        );
+        assert_eq!(
+            overlay.resolve(Path::new("/virtual/include/../include/foo.h")),
+            Some(PathBuf::from("/overlays/real/foo.h"))
+        );
+        assert_eq!(overlay.resolve(Path::new("/virtual/include/bar.h")), None);
+
+        // Double-quoted strings, with a remapped directory resolved against
+        // the working directory, that takes precedence over the file mapped by
+        // the earlier overlay.
+        overlay
+            .add(
+                r#"{"version": 0, "roots": [
+                    {"type": "directory-remap", "name": "/virtual", "external-contents": "sdk"}
+                ]}"#,
+                Path::new("/overlays"),
+                Path::new("/build"),
+            )
+            .unwrap();
+        assert_eq!(
+            overlay.resolve(Path::new("/virtual/include/bar.h")),
+            Some(PathBuf::from("/build/sdk/include/bar.h"))
+        );
+        assert_eq!(
+            overlay.resolve(Path::new("/virtual/include/foo.h")),
+            Some(PathBuf::from("/build/sdk/include/foo.h"))
+        );
+
+        // Which a later file mapping overrides in turn.
+        overlay
+            .add(
+                "{'roots': [{'type': 'file', 'name': '/virtual/include/foo.h', \
+                 'external-contents': '/real/it''s/foo.h'}]}",
+                Path::new("/overlays"),
+                Path::new("/build"),
+            )
+            .unwrap();
+        assert_eq!(
+            overlay.resolve(Path::new("/virtual/include/foo.h")),
+            Some(PathBuf::from("/real/it's/foo.h"))
+        );
+
+        // Invalid overlays add nothing.
+        let mut overlay = VfsOverlay::default();
+        assert!(overlay
+            .add("roots: {", Path::new("/"), Path::new("/"))
+            .is_err());
+        assert!(overlay
+            .add("{'roots': ['unterminated]}", Path::new("/"), Path::new("/"))
+            .is_err());
+        assert!(overlay
+            .add(
+                "roots: [{type: file, name: foo.h, external-contents: foo.h}]",
+                Path::new("/"),
+                Path::new("/")
+            )
+            .is_err());
+        assert!(overlay.is_empty());
+    }
+}
@@ -1,9 +1,13 @@
+use crate::placeholder::{compress_hash, sha256_hash};
+use crate::store_path::StorePath;
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize, Serializer};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
 
 /// A Nix derivation, matching Nix's JSON derivation format
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Derivation {
     /// The name of the derivation
     pub name: String,
@@ -39,7 +43,7 @@ pub struct Derivation {
 }
 
 /// Input derivation specification
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct InputDrv {
     /// Outputs of the input derivation
     pub outputs: Vec<String>,
@@ -50,7 +54,7 @@ pub struct InputDrv {
 }
 
 /// Dynamic output specification for dynamic derivations
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DynamicOutput {
     /// Outputs of the dynamic derivation
     pub outputs: Vec<String>,
@@ -61,8 +65,12 @@ pub struct DynamicOutput {
 }
 
 /// Output specification
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Output {
+    /// Output path for input-addressed and fixed-output derivations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
     /// Hash algorithm for content-addressed derivations
     #[serde(skip_serializing_if = "Option::is_none", rename = "hashAlgo")]
     pub hash_algo: Option<HashAlgorithm>,
@@ -86,7 +94,7 @@ pub enum HashAlgorithm {
 }
 
 /// Output hash mode for derivations
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum OutputHashMode {
     #[serde(rename = "flat")]
     Flat,
@@ -129,7 +137,9 @@ impl Derivation {
         self
     }
 
-    /// Add an input derivation
+    /// Add an input derivation. Its outputs are kept sorted and without
+    /// duplicates, like the set Nix makes of them, when the derivation is
+    /// added several times.
     pub fn add_input_drv(&mut self, path: &str, outputs: Vec<String>) -> &mut Self {
         let input_drv = self
             .input_drvs
@@ -138,7 +148,12 @@ impl Derivation {
                 outputs: vec![],
                 dynamic_outputs: HashMap::new(),
             });
-        input_drv.outputs.extend(outputs);
+        for output in outputs {
+            if !input_drv.outputs.contains(&output) {
+                let index = input_drv.outputs.partition_point(|o| *o < output);
+                input_drv.outputs.insert(index, output);
+            }
+        }
         self
     }
 
@@ -149,16 +164,18 @@ impl Derivation {
         hash_algo: Option<HashAlgorithm>,
         method: Option<OutputHashMode>,
         hash: Option<String>,
-    ) -> &mut Self {
+    ) -> Result<&mut Self> {
+        check_output_name(name)?;
         self.outputs.insert(
             name.to_string(),
             Output {
+                path: None,
                 hash_algo,
                 method,
                 hash,
             },
         );
-        self
+        Ok(self)
     }
 
     /// Add a content-addressed output
@@ -167,16 +184,43 @@ impl Derivation {
         name: &str,
         hash_algo: HashAlgorithm,
         method: OutputHashMode,
-    ) -> &mut Self {
+    ) -> Result<&mut Self> {
+        check_output_name(name)?;
         self.outputs.insert(
             name.to_string(),
             Output {
+                path: None,
                 hash_algo: Some(hash_algo),
                 method: Some(method),
                 hash: None,
             },
         );
-        self
+        Ok(self)
+    }
+
+    /// Add an output of a fixed-output derivation, whose content hash is
+    /// known in advance
+    ///
+    /// The hash must be in base16 or Nix base32 encoding.
+    pub fn add_fixed_output(
+        &mut self,
+        name: &str,
+        hash_algo: HashAlgorithm,
+        method: OutputHashMode,
+        hash: &str,
+    ) -> Result<&mut Self> {
+        check_output_name(name)?;
+        check_output_hash(name, hash_algo, hash)?;
+        self.outputs.insert(
+            name.to_string(),
+            Output {
+                path: None,
+                hash_algo: Some(hash_algo),
+                method: Some(method),
+                hash: Some(hash.to_string()),
+            },
+        );
+        Ok(self)
     }
 
     /// Add a dynamic output to an input derivation
@@ -204,6 +248,174 @@ impl Derivation {
         Ok(self)
     }
 
+    /// Check the invariants Nix requires of derivations, so that violations
+    /// are reported clearly rather than as a failure of `nix derivation add`:
+    /// input derivations and sources must be store paths, and there must be
+    /// outputs, each either input-addressed, content-addressed with a hash
+    /// algorithm and method, or fixed with a hash as well
+    pub fn validate(&self) -> Result<()> {
+        for path in self.input_drvs.keys() {
+            let store_path = StorePath::new(path)
+                .map_err(|err| anyhow!("Invalid input derivation {}: {}", path, err))?;
+            if !store_path.is_derivation() {
+                return Err(anyhow!("Invalid input derivation {}: not a .drv", path));
+            }
+        }
+        for path in &self.input_srcs {
+            StorePath::new(path)
+                .map_err(|err| anyhow!("Invalid input source {}: {}", path, err))?;
+        }
+
+        if self.outputs.is_empty() {
+            return Err(anyhow!("Derivation {} has no outputs", self.name));
+        }
+        let outputs: BTreeMap<&String, &Output> = self.outputs.iter().collect();
+        for (name, output) in outputs {
+            check_output_name(name)?;
+            match (output.hash_algo, output.method, &output.hash) {
+                // Input-addressed, or deferred until the inputs are built.
+                (None, None, None) => {}
+                (Some(_), Some(_), None) if output.path.is_some() => {
+                    return Err(anyhow!(
+                        "Content-addressed output {} can't have a path",
+                        name
+                    ));
+                }
+                (Some(_), Some(_), None) => {}
+                (Some(hash_algo), Some(_), Some(hash)) => {
+                    check_output_hash(name, hash_algo, hash)?;
+                }
+                (_, _, Some(_)) => {
+                    return Err(anyhow!(
+                        "Fixed output {} needs a hash algorithm and method",
+                        name
+                    ));
+                }
+                (_, _, None) => {
+                    return Err(anyhow!(
+                        "Content-addressed output {} needs both a hash algorithm and method",
+                        name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Serialize to the ATerm format of `.drv` files in the Nix store
+    pub fn to_aterm(&self) -> Result<String> {
+        let mut s = String::from("Derive([");
+
+        let outputs: BTreeMap<&String, &Output> = self.outputs.iter().collect();
+        let outputs: Vec<String> = outputs
+            .into_iter()
+            .map(|(name, output)| {
+                let hash_algo = match (output.method, output.hash_algo) {
+                    (Some(method), Some(hash_algo)) => {
+                        format!("{}{}", method.aterm_prefix(), hash_algo.name())
+                    }
+                    (None, Some(hash_algo)) => hash_algo.name().to_string(),
+                    (_, None) => String::new(),
+                };
+                format!(
+                    "({},{},{},{})",
+                    aterm_string(name),
+                    aterm_string(output.path.as_deref().unwrap_or("")),
+                    aterm_string(&hash_algo),
+                    aterm_string(output.hash.as_deref().unwrap_or(""))
+                )
+            })
+            .collect();
+        s.push_str(&outputs.join(","));
+        s.push_str("],[");
+
+        let input_drvs: BTreeMap<&String, &InputDrv> = self.input_drvs.iter().collect();
+        let mut inputs = Vec::new();
+        for (path, input_drv) in input_drvs {
+            if !input_drv.dynamic_outputs.is_empty() {
+                return Err(anyhow!(
+                    "ATerm of input derivations with dynamic outputs is unsupported: {}",
+                    path
+                ));
+            }
+            let outputs: BTreeSet<&String> = input_drv.outputs.iter().collect();
+            inputs.push(format!(
+                "({},{})",
+                aterm_string(path),
+                aterm_list(outputs.into_iter())
+            ));
+        }
+        s.push_str(&inputs.join(","));
+        s.push_str("],");
+
+        let input_srcs: BTreeSet<&String> = self.input_srcs.iter().collect();
+        s.push_str(&aterm_list(input_srcs.into_iter()));
+        s.push(',');
+        s.push_str(&aterm_string(&self.system));
+        s.push(',');
+        s.push_str(&aterm_string(&self.builder));
+        s.push(',');
+        s.push_str(&aterm_list(self.args.iter()));
+        s.push_str(",[");
+
+        let env: BTreeMap<&String, &String> = self.env.iter().collect();
+        let env: Vec<String> = env
+            .into_iter()
+            .map(|(key, value)| format!("({},{})", aterm_string(key), aterm_string(value)))
+            .collect();
+        s.push_str(&env.join(","));
+        s.push_str("])");
+
+        Ok(s)
+    }
+
+    /// Compute the store path of the derivation, as `nix derivation add`
+    /// would, without adding it to the store
+    pub fn store_path(&self, store_dir: &Path) -> Result<StorePath> {
+        let aterm = self.to_aterm()?;
+        let aterm_hash = sha256_hash(aterm.as_bytes());
+
+        // Derivations are text files referencing their inputs.
+        let references: BTreeSet<&String> = self
+            .input_srcs
+            .iter()
+            .chain(self.input_drvs.keys())
+            .collect();
+        let mut path_type = String::from("text");
+        for reference in references {
+            StorePath::new(reference)?;
+            path_type.push(':');
+            path_type.push_str(reference);
+        }
+
+        let name = format!("{}.drv", self.name);
+        let fingerprint = format!(
+            "{}:sha256:{}:{}:{}",
+            path_type,
+            to_hex(&aterm_hash),
+            store_dir.to_string_lossy(),
+            name
+        );
+        let hash = compress_hash(&sha256_hash(fingerprint.as_bytes()), 20);
+
+        StorePath::from_parts(store_dir, &nix_base32::to_nix_base32(&hash), &name)
+    }
+
+    /// A fingerprint of the contents of the derivation, equal for equal
+    /// derivations regardless of the iteration order of their maps and sets.
+    pub fn fingerprint(&self) -> Result<String> {
+        // Objects of JSON values are sorted by key, leaving the input sources
+        // which are serialized as an array.
+        let mut value = serde_json::to_value(self)?;
+        if let Some(input_srcs) = value
+            .get_mut("inputSrcs")
+            .and_then(|input_srcs| input_srcs.as_array_mut())
+        {
+            input_srcs.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+        }
+        Ok(to_hex(&sha256_hash(value.to_string().as_bytes())))
+    }
+
     /// Serialize to JSON
     pub fn to_json(&self) -> Result<String> {
         Ok(serde_json::to_string(self)?)
@@ -218,6 +430,212 @@ impl Derivation {
     pub fn from_json(json: &str) -> Result<Self> {
         Ok(serde_json::from_str(json)?)
     }
+
+    /// Deserialize the output of `nix derivation show`, which maps the store
+    /// paths of derivations to their JSON
+    pub fn from_show_json(json: &str) -> Result<HashMap<String, Self>> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// What changed from this derivation to `other`, field by field and entry
+    /// by entry for the maps and sets, which are compared in sorted order so
+    /// that their iteration order isn't reported as a change
+    pub fn diff(&self, other: &Derivation) -> DerivationDiff {
+        let mut diff = DerivationDiff::default();
+        diff.push("name", Some(&self.name), Some(&other.name));
+        diff.push("system", Some(&self.system), Some(&other.system));
+        diff.push("builder", Some(&self.builder), Some(&other.builder));
+
+        // Arguments are ordered, so they are compared by position.
+        for i in 0..self.args.len().max(other.args.len()) {
+            diff.push(&format!("args[{}]", i), self.args.get(i), other.args.get(i));
+        }
+
+        diff.push_map("env", &self.env, &other.env, |value| value.clone());
+        diff.push_map("inputDrvs", &self.input_drvs, &other.input_drvs, |input| {
+            serde_json::to_string(input).unwrap_or_default()
+        });
+
+        let old_srcs: BTreeSet<&String> = self.input_srcs.iter().collect();
+        let new_srcs: BTreeSet<&String> = other.input_srcs.iter().collect();
+        for src in old_srcs.difference(&new_srcs) {
+            diff.push("inputSrcs", Some(*src), None);
+        }
+        for src in new_srcs.difference(&old_srcs) {
+            diff.push("inputSrcs", None, Some(*src));
+        }
+
+        diff.push_map("outputs", &self.outputs, &other.outputs, |output| {
+            serde_json::to_string(output).unwrap_or_default()
+        });
+        diff
+    }
+}
+
+/// The differences between two derivations, see `Derivation::diff`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DerivationDiff {
+    pub changes: Vec<FieldChange>,
+}
+
+/// A field, or an entry of a map or set field, that differs between two
+/// derivations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    /// Name of the field in the JSON format, followed by the key for entries
+    /// of maps, like `env.CC`
+    pub field: String,
+
+    /// The value in the first derivation, `None` if it was added
+    pub old: Option<String>,
+
+    /// The value in the second derivation, `None` if it was removed
+    pub new: Option<String>,
+}
+
+impl DerivationDiff {
+    /// Whether the derivations are the same
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    fn push(&mut self, field: &str, old: Option<&String>, new: Option<&String>) {
+        if old != new {
+            self.changes.push(FieldChange {
+                field: field.to_string(),
+                old: old.cloned(),
+                new: new.cloned(),
+            });
+        }
+    }
+
+    fn push_map<T>(
+        &mut self,
+        field: &str,
+        old: &HashMap<String, T>,
+        new: &HashMap<String, T>,
+        show: impl Fn(&T) -> String,
+    ) {
+        let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+        for key in keys {
+            self.push(
+                &format!("{}.{}", field, key),
+                old.get(key).map(&show).as_ref(),
+                new.get(key).map(&show).as_ref(),
+            );
+        }
+    }
+}
+
+/// One line per change, `-` for removed values, `+` for added ones, and both
+/// for changed ones
+impl fmt::Display for DerivationDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            if let Some(old) = &change.old {
+                writeln!(f, "- {}: {}", change.field, old)?;
+            }
+            if let Some(new) = &change.new {
+                writeln!(f, "+ {}: {}", change.field, new)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl HashAlgorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    // Size of the digest in bytes.
+    fn size(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+}
+
+impl OutputHashMode {
+    // Prefix of the hash algorithm of outputs in the ATerm format.
+    fn aterm_prefix(&self) -> &'static str {
+        match self {
+            OutputHashMode::Flat => "",
+            OutputHashMode::Nar => "r:",
+            OutputHashMode::Text => "text:",
+        }
+    }
+}
+
+/// Check that an output name only has the characters Nix allows in store path
+/// names, since it becomes part of the store path of the output.
+fn check_output_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(anyhow!("Invalid output name: empty"));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || "+-._?=".contains(*c)))
+    {
+        return Err(anyhow!(
+            "Invalid output name: '{}' is not allowed in {}",
+            c,
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the hash of a fixed output is a `hash_algo` digest, in base16
+/// or Nix base32 encoding.
+fn check_output_hash(name: &str, hash_algo: HashAlgorithm, hash: &str) -> Result<()> {
+    let size = hash_algo.size();
+    let valid = if hash.len() == size * 2 {
+        hash.chars().all(|c| c.is_ascii_hexdigit())
+    } else if hash.len() == (size * 8 - 1) / 5 + 1 {
+        nix_base32::from_nix_base32(hash).is_some()
+    } else {
+        false
+    };
+    if !valid {
+        return Err(anyhow!(
+            "Invalid {} hash for output {}: {}",
+            hash_algo.name(),
+            name,
+            hash
+        ));
+    }
+    Ok(())
+}
+
+fn aterm_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn aterm_list<'a>(strings: impl Iterator<Item = &'a String>) -> String {
+    let strings: Vec<String> = strings.map(|s| aterm_string(s)).collect();
+    format!("[{}]", strings.join(","))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 fn serialize_hashset_as_vec<S, T>(set: &HashSet<T>, serializer: S) -> Result<S::Ok, S::Error>
@@ -249,7 +667,8 @@ mod tests {
                 "PATH",
                 "/nix/store/d1pzgj1pj3nk97vhm5x6n8szy4w3xhx7-coreutils/bin",
             )
-            .add_output("out", None, None, None);
+            .add_output("out", None, None, None)
+            .unwrap();
 
         // Serialize to JSON
         let json = drv.to_json().unwrap();
@@ -275,7 +694,8 @@ mod tests {
         );
 
         // Add a content-addressed output
-        drv.add_ca_output("out", HashAlgorithm::Sha256, OutputHashMode::Nar);
+        drv.add_ca_output("out", HashAlgorithm::Sha256, OutputHashMode::Nar)
+            .unwrap();
 
         // Serialize to JSON
         let json = drv.to_json().unwrap();
@@ -285,6 +705,41 @@ mod tests {
         assert!(json.contains("nar"));
     }
 
+    #[test]
+    fn test_add_input_drv_dedupes_outputs() {
+        let mut drv = Derivation::new("main.o", "x86_64-linux", "/bin/sh");
+        let path = "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-util.drv";
+        drv.add_input_drv(path, vec!["out".to_string()]);
+        drv.add_input_drv(path, vec!["out".to_string()]);
+        assert_eq!(drv.input_drvs[path].outputs, vec!["out"]);
+
+        // Outputs are sorted whatever order they were added in.
+        drv.add_input_drv(path, vec!["lib".to_string(), "dev".to_string()]);
+        drv.add_input_drv(path, vec!["dev".to_string()]);
+        assert_eq!(drv.input_drvs[path].outputs, vec!["dev", "lib", "out"]);
+    }
+
+    #[test]
+    fn test_output_name_validation() {
+        let mut drv = Derivation::new("main.o", "x86_64-linux", "/bin/sh");
+        for name in ["out", "main.o", "sub-main.o", "lib=-foo.so", "c++_1.0"] {
+            drv.add_ca_output(name, HashAlgorithm::Sha256, OutputHashMode::Nar)
+                .unwrap();
+        }
+
+        let err = drv
+            .add_ca_output("sub/main.o", HashAlgorithm::Sha256, OutputHashMode::Nar)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Invalid output name: '/' is not allowed in sub/main.o"
+        );
+        assert!(drv.add_output("", None, None, None).is_err());
+        assert!(drv.add_output("main o", None, None, None).is_err());
+        assert!(!drv.outputs.contains_key("sub/main.o"));
+    }
+
     #[test]
     fn test_dynamic_derivation() {
         // Create a derivation with dynamic outputs
@@ -314,4 +769,429 @@ mod tests {
         // Check that it contains the dynamic outputs
         assert!(json.contains("dynamicOutputs"));
     }
+
+    #[test]
+    fn test_ca_derivation_aterm() {
+        let mut drv = Derivation::new(
+            "ninja-build-main.o",
+            "x86_64-linux",
+            "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-nix-ninja-task/bin/nix-ninja-task",
+        );
+        drv.add_arg("cc -c \"main.c\"\n")
+            .add_env("NIX_NINJA_INPUTS", "b\\c")
+            .add_env("A", "\t")
+            .add_input_src("/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-nix-ninja-task")
+            .add_input_src("/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c")
+            .add_input_drv(
+                "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv",
+                vec!["out".to_string(), "dev".to_string()],
+            )
+            .add_ca_output("main.o", HashAlgorithm::Sha256, OutputHashMode::Nar)
+            .unwrap()
+            .add_ca_output("main.map", HashAlgorithm::Sha256, OutputHashMode::Flat)
+            .unwrap();
+
+        assert_eq!(
+            drv.to_aterm().unwrap(),
+            concat!(
+                r#"Derive([("main.map","","sha256",""),("main.o","","r:sha256","")],"#,
+                r#"[("/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv",["dev","out"])],"#,
+                r#"["/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c","#,
+                r#""/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-nix-ninja-task"],"#,
+                r#""x86_64-linux","#,
+                r#""/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-nix-ninja-task/bin/nix-ninja-task","#,
+                r#"["cc -c \"main.c\"\n"],"#,
+                r#"[("A","\t"),("NIX_NINJA_INPUTS","b\\c")])"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let srcs = [
+            "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c",
+            "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-util.h",
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-config.h",
+        ];
+        let new = |srcs: &[&str]| {
+            let mut drv = Derivation::new("ninja-build-main.o", "x86_64-linux", "/bin/sh");
+            for src in srcs {
+                drv.add_input_src(src).add_env(src, src);
+            }
+            drv.add_ca_output("main.o", HashAlgorithm::Sha256, OutputHashMode::Nar)
+                .unwrap();
+            drv
+        };
+
+        // The order inputs were added in doesn't matter.
+        let drv = new(&srcs);
+        let reversed = new(&[srcs[2], srcs[1], srcs[0]]);
+        assert_eq!(drv, reversed);
+        assert_eq!(drv.fingerprint().unwrap(), reversed.fingerprint().unwrap());
+        assert_eq!(drv.fingerprint().unwrap().len(), 64);
+
+        let other = new(&srcs[..2]);
+        assert_ne!(drv, other);
+        assert_ne!(drv.fingerprint().unwrap(), other.fingerprint().unwrap());
+    }
+
+    #[test]
+    fn test_diff() {
+        let new = |cc: &str, srcs: &[&str]| {
+            let mut drv = Derivation::new("ninja-build-main.o", "x86_64-linux", "/bin/sh");
+            drv.add_arg("cc -c main.c").add_env("CC", cc);
+            for src in srcs {
+                drv.add_input_src(src);
+            }
+            drv.add_ca_output("main.o", HashAlgorithm::Sha256, OutputHashMode::Nar)
+                .unwrap();
+            drv
+        };
+        let main = "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c";
+        let util = "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-util.h";
+        let config = "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-config.h";
+
+        // The order of sets doesn't matter.
+        let drv = new("gcc", &[main, util, config]);
+        assert!(drv.diff(&new("gcc", &[config, util, main])).is_empty());
+
+        let mut other = new("clang", &[main, config]);
+        other.add_arg("-O2");
+        let diff = drv.diff(&other);
+        assert_eq!(
+            diff.changes,
+            vec![
+                FieldChange {
+                    field: "args[1]".to_string(),
+                    old: None,
+                    new: Some("-O2".to_string()),
+                },
+                FieldChange {
+                    field: "env.CC".to_string(),
+                    old: Some("gcc".to_string()),
+                    new: Some("clang".to_string()),
+                },
+                FieldChange {
+                    field: "inputSrcs".to_string(),
+                    old: Some(util.to_string()),
+                    new: None,
+                },
+            ]
+        );
+        assert_eq!(
+            diff.to_string(),
+            format!(
+                "+ args[1]: -O2\n- env.CC: gcc\n+ env.CC: clang\n- inputSrcs: {}\n",
+                util
+            )
+        );
+
+        // Input derivations and outputs are compared entry by entry.
+        let cc = "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-cc.drv";
+        let gen = "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-gen.drv";
+        let mut old = new("gcc", &[main]);
+        old.add_input_drv(cc, vec!["out".to_string()]);
+        let mut other = new("gcc", &[main]);
+        other
+            .add_input_drv(cc, vec!["out".to_string(), "dev".to_string()])
+            .add_input_drv(gen, vec!["config.h".to_string()]);
+        other
+            .add_ca_output("main.d", HashAlgorithm::Sha256, OutputHashMode::Nar)
+            .unwrap();
+        let diff = old.diff(&other);
+        assert_eq!(
+            diff.to_string(),
+            format!(
+                "- inputDrvs.{cc}: {}\n+ inputDrvs.{cc}: {}\n+ inputDrvs.{gen}: {}\n+ outputs.main.d: {}\n",
+                r#"{"outputs":["out"],"dynamicOutputs":{}}"#,
+                r#"{"outputs":["dev","out"],"dynamicOutputs":{}}"#,
+                r#"{"outputs":["config.h"],"dynamicOutputs":{}}"#,
+                r#"{"hashAlgo":"sha256","method":"nar"}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_from_show_json() {
+        let json = r#"{
+  "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-ninja-build-app.drv": {
+    "args": ["gcc main.o -o app"],
+    "builder": "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-nix-ninja-task/bin/nix-ninja-task",
+    "env": {"NIX_NINJA_INPUTS": "main.o"},
+    "inputDrvs": {
+      "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-ninja-build-main.o.drv": {
+        "dynamicOutputs": {},
+        "outputs": ["main.o"]
+      },
+      "/nix/store/0123456789abcd0123456789abcd0123-gen.drv": {
+        "dynamicOutputs": {
+          "out": {"dynamicOutputs": {}, "outputs": ["lib"]}
+        },
+        "outputs": []
+      }
+    },
+    "inputSrcs": ["/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-nix-ninja-task"],
+    "name": "ninja-build-app",
+    "outputs": {"app": {"hashAlgo": "sha256", "method": "nar"}},
+    "system": "x86_64-linux"
+  }
+}"#;
+        let drvs = Derivation::from_show_json(json).unwrap();
+        assert_eq!(drvs.len(), 1);
+        let drv = &drvs["/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-ninja-build-app.drv"];
+        assert_eq!(drv.name, "ninja-build-app");
+        assert_eq!(drv.args, vec!["gcc main.o -o app"]);
+        assert_eq!(
+            drv.input_drvs["/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-ninja-build-main.o.drv"]
+                .outputs,
+            vec!["main.o"]
+        );
+        let dynamic = &drv.input_drvs["/nix/store/0123456789abcd0123456789abcd0123-gen.drv"];
+        assert!(dynamic.outputs.is_empty());
+        assert_eq!(dynamic.dynamic_outputs["out"].outputs, vec!["lib"]);
+        assert_eq!(drv.outputs["app"].method, Some(OutputHashMode::Nar));
+        assert_eq!(drv.outputs["app"].path, None);
+    }
+
+    #[test]
+    fn test_store_path_matches_nix() {
+        // Fixture from the Nix store, for:
+        // derivation { name = "foo"; builder = ":"; system = ":"; bar = <bar>; }
+        let drv = Derivation::from_json(
+            r#"{
+                "name": "foo",
+                "system": ":",
+                "builder": ":",
+                "args": [],
+                "env": {
+                    "bar": "/nix/store/4q0pg5zpfmznxscq3avycvf9xdvx50n3-bar",
+                    "builder": ":",
+                    "name": "foo",
+                    "out": "/nix/store/5vyvcwah9l9kf07d52rcgdk70g2f4y13-foo",
+                    "system": ":"
+                },
+                "inputDrvs": {
+                    "/nix/store/0hm2f1psjpcwg8fijsmr4wwxrx59s092-bar.drv": {
+                        "outputs": ["out"]
+                    }
+                },
+                "inputSrcs": [],
+                "outputs": {
+                    "out": {"path": "/nix/store/5vyvcwah9l9kf07d52rcgdk70g2f4y13-foo"}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            drv.to_aterm().unwrap(),
+            concat!(
+                r#"Derive([("out","/nix/store/5vyvcwah9l9kf07d52rcgdk70g2f4y13-foo","","")],"#,
+                r#"[("/nix/store/0hm2f1psjpcwg8fijsmr4wwxrx59s092-bar.drv",["out"])],[],":",":",[],"#,
+                r#"[("bar","/nix/store/4q0pg5zpfmznxscq3avycvf9xdvx50n3-bar"),("builder",":"),"#,
+                r#"("name","foo"),("out","/nix/store/5vyvcwah9l9kf07d52rcgdk70g2f4y13-foo"),"#,
+                r#"("system",":")])"#,
+            )
+        );
+        assert_eq!(
+            format!("{}", drv.store_path(Path::new("/nix/store")).unwrap()),
+            "/nix/store/4wvvbi4jwn0prsdxb7vs673qa5h9gr7x-foo.drv"
+        );
+
+        // Nix rejects derivations with an invalid name or references that
+        // aren't store paths.
+        let mut invalid = drv.clone();
+        invalid.name = "foo bar".to_string();
+        assert!(invalid.store_path(Path::new("/nix/store")).is_err());
+
+        let mut invalid = drv.clone();
+        invalid.add_input_src("/nix/store/4q0pg5zpfmznxscq3avycvf9xdvx50ne-bar");
+        let err = invalid.store_path(Path::new("/nix/store")).err().unwrap();
+        assert!(err.to_string().contains("not a Nix base32 character"));
+    }
+
+    #[test]
+    fn test_ca_store_path() {
+        // Unlike the fixture above, these paths weren't taken from a Nix store
+        // but computed by a separate implementation of Nix's hashing, which
+        // reproduces that fixture. The first is for:
+        // derivation { name = "foo"; builder = ":"; system = ":";
+        //   __contentAddressed = true; outputHashMode = "recursive";
+        //   outputHashAlgo = "sha256"; }
+        let placeholder = "/1rz4g4znpzjwh1xymhjpm42vipw92pr73vdgl6xs1hycac8kf2n9";
+        let drv = Derivation::from_json(&format!(
+            r#"{{
+                "name": "foo",
+                "system": ":",
+                "builder": ":",
+                "args": [],
+                "env": {{
+                    "__contentAddressed": "1",
+                    "builder": ":",
+                    "name": "foo",
+                    "out": "{}",
+                    "outputHashAlgo": "sha256",
+                    "outputHashMode": "recursive",
+                    "system": ":"
+                }},
+                "inputDrvs": {{}},
+                "inputSrcs": [],
+                "outputs": {{
+                    "out": {{"hashAlgo": "sha256", "method": "nar"}}
+                }}
+            }}"#,
+            placeholder
+        ))
+        .unwrap();
+        assert_eq!(
+            drv.to_aterm().unwrap(),
+            format!(
+                concat!(
+                    r#"Derive([("out","","r:sha256","")],[],[],":",":",[],"#,
+                    r#"[("__contentAddressed","1"),("builder",":"),("name","foo"),"#,
+                    r#"("out","{}"),("outputHashAlgo","sha256"),"#,
+                    r#"("outputHashMode","recursive"),("system",":")])"#,
+                ),
+                placeholder
+            )
+        );
+        assert_eq!(
+            format!("{}", drv.store_path(Path::new("/nix/store")).unwrap()),
+            "/nix/store/cg3b5zv0izg9zv08w546438lfxcmp5xy-foo.drv"
+        );
+
+        // A task like nix-ninja generates, whose inputSrcs are references of
+        // the derivation.
+        let task = "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-nix-ninja-task";
+        let src = "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c";
+        let builder = format!("{}/bin/nix-ninja-task", task);
+        let mut drv = Derivation::new("main.o", "x86_64-linux", &builder);
+        drv.add_arg("cc -c main.c")
+            .add_env("NIX_NINJA_INPUTS", &format!("{}:../main.c", src))
+            .add_env("__contentAddressed", "1")
+            .add_env("builder", &builder)
+            .add_env("name", "main.o")
+            .add_env("out", placeholder)
+            .add_env("outputHashAlgo", "sha256")
+            .add_env("outputHashMode", "recursive")
+            .add_env("system", "x86_64-linux")
+            .add_input_src(task)
+            .add_input_src(src)
+            .add_ca_output("out", HashAlgorithm::Sha256, OutputHashMode::Nar)
+            .unwrap();
+        assert_eq!(
+            format!("{}", drv.store_path(Path::new("/nix/store")).unwrap()),
+            "/nix/store/djycgjpl3bb68aa60m72fvbm4cgwnkrv-main.o.drv"
+        );
+    }
+
+    #[test]
+    fn test_store_path_dynamic_outputs_unsupported() {
+        let mut drv = Derivation::new("dynamic", "x86_64-linux", "/bin/sh");
+        drv.add_dynamic_output(
+            "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-ca-example.drv",
+            "out",
+            vec!["out".to_string()],
+        )
+        .unwrap();
+        assert!(drv.store_path(Path::new("/nix/store")).is_err());
+    }
+
+    #[test]
+    fn test_fixed_output_derivation() {
+        let hash = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let mut drv = Derivation::new("source.tar.gz", "x86_64-linux", "builtin:fetchurl");
+        drv.add_fixed_output("out", HashAlgorithm::Sha256, OutputHashMode::Flat, hash)
+            .unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&drv.to_json().unwrap()).unwrap();
+        let output = &json["outputs"]["out"];
+        assert_eq!(output["hash"], hash);
+        assert_eq!(output["hashAlgo"], "sha256");
+        assert_eq!(output["method"], "flat");
+
+        // Nix base32 encoding of the same hash.
+        drv.add_fixed_output(
+            "out",
+            HashAlgorithm::Sha256,
+            OutputHashMode::Nar,
+            "1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s",
+        )
+        .unwrap();
+
+        assert!(drv
+            .add_fixed_output("out", HashAlgorithm::Sha512, OutputHashMode::Flat, hash)
+            .is_err());
+        assert!(drv
+            .add_fixed_output("out", HashAlgorithm::Sha256, OutputHashMode::Flat, "abc")
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let new_drv = || {
+            let mut drv = Derivation::new("main.o", "x86_64-linux", "/bin/sh");
+            drv.add_ca_output("out", HashAlgorithm::Sha256, OutputHashMode::Nar)
+                .unwrap();
+            drv.add_input_src("/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-bash-5.2")
+                .add_input_drv(
+                    "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-util.o.drv",
+                    vec!["out".to_string()],
+                );
+            drv
+        };
+        let error = |drv: Derivation| drv.validate().err().unwrap().to_string();
+        new_drv().validate().unwrap();
+
+        let mut drv = new_drv();
+        drv.add_input_drv(
+            "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-util.o",
+            vec!["out".to_string()],
+        );
+        assert!(error(drv).contains("not a .drv"));
+
+        let mut drv = new_drv();
+        drv.add_input_drv("util.o.drv", vec!["out".to_string()]);
+        assert!(error(drv).starts_with("Invalid input derivation util.o.drv"));
+
+        let mut drv = new_drv();
+        drv.add_input_src("/build/source/main.c");
+        assert!(error(drv).starts_with("Invalid input source /build/source/main.c"));
+
+        let mut drv = new_drv();
+        drv.outputs.clear();
+        assert_eq!(error(drv), "Derivation main.o has no outputs");
+
+        // Content-addressed outputs need both a hash algorithm and a method,
+        // and no hash or path.
+        let mut drv = new_drv();
+        drv.outputs.get_mut("out").unwrap().method = None;
+        assert!(error(drv).contains("needs both a hash algorithm and method"));
+        let mut drv = new_drv();
+        drv.outputs.get_mut("out").unwrap().path = Some("/nix/store/x".to_string());
+        assert!(error(drv).contains("can't have a path"));
+
+        // Fixed outputs need a valid hash along with them.
+        let mut drv = new_drv();
+        drv.outputs.get_mut("out").unwrap().hash = Some("abc".to_string());
+        assert!(error(drv).starts_with("Invalid sha256 hash for output out"));
+        let mut drv = new_drv();
+        drv.add_output("out", None, None, Some("abc".to_string()))
+            .unwrap();
+        assert!(error(drv).contains("needs a hash algorithm and method"));
+        let mut drv = new_drv();
+        drv.add_fixed_output(
+            "out",
+            HashAlgorithm::Sha256,
+            OutputHashMode::Flat,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        )
+        .unwrap();
+        drv.validate().unwrap();
+
+        let mut drv = new_drv();
+        drv.outputs
+            .insert("a b".to_string(), drv.outputs["out"].clone());
+        assert!(error(drv).starts_with("Invalid output name"));
+    }
 }
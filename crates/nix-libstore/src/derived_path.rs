@@ -1,4 +1,7 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use crate::placeholder::Placeholder;
 use crate::store_path::StorePath;
@@ -17,11 +20,10 @@ impl SingleDerivedPath {
         }
     }
 
+    #[deprecated(note = "use the Display implementation instead")]
+    #[allow(clippy::inherent_to_string_shadow_display)]
     pub fn to_string(&self) -> String {
-        match self {
-            SingleDerivedPath::Opaque(store_path) => store_path.to_string(),
-            SingleDerivedPath::Built(built_path) => built_path.to_string(),
-        }
+        format!("{}", self)
     }
 
     pub fn to_input(&self) -> PathBuf {
@@ -32,6 +34,28 @@ impl SingleDerivedPath {
     }
 }
 
+impl fmt::Display for SingleDerivedPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SingleDerivedPath::Opaque(store_path) => store_path.fmt(f),
+            SingleDerivedPath::Built(built_path) => built_path.fmt(f),
+        }
+    }
+}
+
+/// Parses either a plain store path or a `<drv path>^<output>` built path.
+impl FromStr for SingleDerivedPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.contains('^') {
+            Ok(SingleDerivedPath::Built(s.parse()?))
+        } else {
+            Ok(SingleDerivedPath::Opaque(s.parse()?))
+        }
+    }
+}
+
 /// A single derived path that is built from a derivation.
 /// Built derived paths are a pair of a derivation and an output name.
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -45,7 +69,77 @@ impl SingleDerivedPathBuilt {
         Placeholder::ca_output(&self.drv_path, &self.output).render()
     }
 
+    #[deprecated(note = "use the Display implementation instead")]
+    #[allow(clippy::inherent_to_string_shadow_display)]
     pub fn to_string(&self) -> String {
-        format!("{}^{}", &self.drv_path.to_string(), &self.output)
+        format!("{}", self)
+    }
+}
+
+impl fmt::Display for SingleDerivedPathBuilt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}^{}", self.drv_path, self.output)
+    }
+}
+
+impl FromStr for SingleDerivedPathBuilt {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (drv_path, output) = s
+            .split_once('^')
+            .ok_or_else(|| anyhow!("Invalid built path: missing '^': {}", s))?;
+        let drv_path: StorePath = drv_path.parse()?;
+        if !drv_path.is_derivation() {
+            return Err(anyhow!(
+                "Invalid built path: {} is not a derivation",
+                drv_path
+            ));
+        }
+        if output.is_empty() || output.contains('^') {
+            return Err(anyhow!("Invalid built path: bad output name: {}", s));
+        }
+
+        Ok(SingleDerivedPathBuilt {
+            drv_path,
+            output: output.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_str_round_trip() {
+        for input in [
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-bash-5.2",
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-main.o.drv^out",
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-zlib.drv^dev",
+        ] {
+            let path: SingleDerivedPath = input.parse().unwrap();
+            assert_eq!(format!("{}", path), input);
+        }
+
+        let path: SingleDerivedPath = "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-zlib.drv^dev"
+            .parse()
+            .unwrap();
+        match path {
+            SingleDerivedPath::Built(built) => {
+                assert_eq!(built.drv_path.name(), "zlib.drv");
+                assert_eq!(built.output, "dev");
+            }
+            SingleDerivedPath::Opaque(_) => panic!("expected a built path"),
+        }
+
+        for invalid in [
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-zlib.drv^",
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-zlib^out",
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-zlib.drv^out^dev",
+            "zlib.drv^out",
+        ] {
+            assert!(invalid.parse::<SingleDerivedPath>().is_err(), "{}", invalid);
+        }
     }
 }
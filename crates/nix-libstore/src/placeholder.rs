@@ -22,6 +22,17 @@ impl Placeholder {
         PathBuf::from(format!("/{}", nix_base32::to_nix_base32(&self.hash)))
     }
 
+    /// Parse a placeholder rendered by `render`, or None if `rendered` isn't
+    /// one, like a store path
+    pub fn from_rendered(rendered: &str) -> Option<Self> {
+        let encoded = rendered.strip_prefix('/')?;
+        // The base32 encoding of a SHA-256 hash.
+        if encoded.len() != 52 {
+            return None;
+        }
+        nix_base32::from_nix_base32(encoded).map(Self::new)
+    }
+
     /// Generate a placeholder for a standard output
     pub fn standard_output(output_name: &str) -> Self {
         let clear_text = format!("nix-output:{}", output_name);
@@ -51,6 +62,21 @@ impl Placeholder {
         Self::new(hash)
     }
 
+    /// Generate the placeholder of an output of a content-addressed derivation
+    /// that isn't built yet, so that its store path isn't known, like Nix's
+    /// `DownstreamPlaceholder::unknownCaOutput`. Unlike `ca_output`, this
+    /// requires `drv_path` to be a derivation, whose `.drv` extension Nix
+    /// always strips
+    pub fn unknown_ca_output(drv_path: &StorePath, output_name: &str) -> anyhow::Result<Self> {
+        if !drv_path.is_derivation() {
+            return Err(anyhow!("{} is not a derivation", drv_path));
+        }
+        if output_name.is_empty() {
+            return Err(anyhow!("Empty output name for {}", drv_path));
+        }
+        Ok(Self::ca_output(drv_path, output_name))
+    }
+
     /// Generate a placeholder for a dynamic derivation output
     pub fn dynamic_output(placeholder: &Placeholder, output_name: &str) -> Self {
         // Compress the hash according to Nix's implementation
@@ -89,7 +115,7 @@ pub fn output_path_name(drv_name: &str, output_name: &str) -> String {
 }
 
 /// Compress a hash to a smaller size by XORing bytes
-fn compress_hash(hash: &[u8], new_size: usize) -> Vec<u8> {
+pub(crate) fn compress_hash(hash: &[u8], new_size: usize) -> Vec<u8> {
     if hash.is_empty() {
         return vec![];
     }
@@ -104,7 +130,7 @@ fn compress_hash(hash: &[u8], new_size: usize) -> Vec<u8> {
 }
 
 /// Calculate SHA-256 hash of data
-fn sha256_hash(data: &[u8]) -> Vec<u8> {
+pub(crate) fn sha256_hash(data: &[u8]) -> Vec<u8> {
     use sha2::{Digest, Sha256};
 
     let mut hasher = Sha256::new();
@@ -136,6 +162,56 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_unknown_ca_placeholder() {
+        // From Nix's `DownstreamPlaceholder.unknownCaOutput` test.
+        let drv_path =
+            StorePath::new("/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv").unwrap();
+        let placeholder = Placeholder::unknown_ca_output(&drv_path, "out").unwrap();
+        assert_eq!(
+            placeholder.render(),
+            PathBuf::from("/0c6rn30q4frawknapgwq386zq358m8r6msvywcvc89n6m5p2dgbz")
+        );
+
+        // Chaining into the derivation it outputs, from Nix's
+        // `DownstreamPlaceholder.unknownDerivation` test.
+        let drv_path =
+            StorePath::new("/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv.drv").unwrap();
+        let placeholder = Placeholder::unknown_ca_output(&drv_path, "out").unwrap();
+        assert_eq!(
+            Placeholder::dynamic_output(&placeholder, "out").render(),
+            PathBuf::from("/0gn6agqxjyyalf0dpihgyf49xq5hqxgw100f0wydnj6yqrhqsb3w")
+        );
+
+        let not_drv = StorePath::new("/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo").unwrap();
+        assert!(Placeholder::unknown_ca_output(&not_drv, "out").is_err());
+        assert!(Placeholder::unknown_ca_output(&drv_path, "").is_err());
+    }
+
+    #[test]
+    fn test_from_rendered() {
+        let placeholder = Placeholder::standard_output("out");
+        let rendered = placeholder.render();
+        assert_eq!(
+            Placeholder::from_rendered(&rendered.to_string_lossy()),
+            Some(placeholder)
+        );
+
+        assert_eq!(
+            Placeholder::from_rendered("/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"),
+            None
+        );
+        assert_eq!(
+            Placeholder::from_rendered("1rz4g4znpzjwh1xymhjpm42vipw92pr73vdgl6xs1hycac8kf2n9"),
+            None
+        );
+        // 'e' isn't a Nix base32 character.
+        assert_eq!(
+            Placeholder::from_rendered("/erz4g4znpzjwh1xymhjpm42vipw92pr73vdgl6xs1hycac8kf2n9"),
+            None
+        );
+    }
+
     #[test]
     fn test_dynamic_placeholder() {
         let store_path =
@@ -1,5 +1,11 @@
 use anyhow::{anyhow, Result};
-use std::path::PathBuf;
+use std::cmp::Ordering;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Characters of the Nix base32 encoding used for store path hashes
+const NIX_BASE32_CHARS: &str = "0123456789abcdfghijklmnpqrsvwxyz";
 
 /// A Nix store path
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -27,9 +33,53 @@ impl StorePath {
             ));
         }
 
+        if let Some(c) = filename
+            .chars()
+            .take(32)
+            .find(|c| !NIX_BASE32_CHARS.contains(*c))
+        {
+            return Err(anyhow!(
+                "Invalid store path: hash contains '{}' which is not a Nix base32 character: {}",
+                c,
+                filename
+            ));
+        }
+
         Ok(Self { path: path_buf })
     }
 
+    /// Create a store path from its store directory, hash part and name,
+    /// validating each part instead of the assembled path
+    pub fn from_parts(store_dir: &Path, hash: &str, name: &str) -> Result<Self> {
+        if hash.len() != 32 {
+            return Err(anyhow!(
+                "Invalid store path hash: expected 32 characters, got {}: {}",
+                hash.len(),
+                hash
+            ));
+        }
+        if let Some(c) = hash.chars().find(|c| !NIX_BASE32_CHARS.contains(*c)) {
+            return Err(anyhow!(
+                "Invalid store path hash: '{}' is not a Nix base32 character: {}",
+                c,
+                hash
+            ));
+        }
+        // Like Nix, which also rejects the names of the current and parent
+        // directories.
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-._?=".contains(c))
+        {
+            return Err(anyhow!("Invalid store path name: {:?}", name));
+        }
+
+        Self::new(store_dir.join(format!("{}-{}", hash, name)))
+    }
+
     /// Get the hash part of the store path (always 32 characters)
     pub fn hash_part(&self) -> &str {
         let filename = self
@@ -57,12 +107,157 @@ impl StorePath {
         &self.path
     }
 
+    #[deprecated(note = "use the Display implementation instead")]
+    #[allow(clippy::inherent_to_string_shadow_display)]
     pub fn to_string(&self) -> String {
-        self.path.to_string_lossy().into_owned()
+        format!("{}", self)
     }
 
     /// Check if this is a derivation path
     pub fn is_derivation(&self) -> bool {
         self.name().ends_with(".drv")
     }
+
+    /// Compare by name and then by hash, unlike the derived ordering which
+    /// compares the full path and therefore mostly orders by hash
+    pub fn cmp_by_name(&self, other: &Self) -> Ordering {
+        self.name()
+            .cmp(other.name())
+            .then_with(|| self.hash_part().cmp(other.hash_part()))
+    }
+}
+
+impl fmt::Display for StorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+impl FromStr for StorePath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::new(s)
+    }
+}
+
+/// Sort store paths by name then hash, for human-facing output like manifests
+/// and logs
+pub fn sort_by_name(store_paths: &mut [StorePath]) {
+    store_paths.sort_by(StorePath::cmp_by_name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_validates_hash() {
+        let path = StorePath::new("/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-bash-5.2").unwrap();
+        assert_eq!(path.hash_part(), "0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm");
+        assert_eq!(path.name(), "bash-5.2");
+
+        for invalid in [
+            // Uppercase characters.
+            "/nix/store/0C7FQPFSB1KHRXS5RA7GX4WXRK2XQPQM-bash-5.2",
+            // 'e', 'o', 'u' and 't' aren't part of Nix base32.
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqe-bash-5.2",
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqo-bash-5.2",
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqu-bash-5.2",
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqt-bash-5.2",
+            // Multi-byte characters.
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqé-bash-5.2",
+            // Too short or missing the dash.
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm",
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm_bash-5.2",
+        ] {
+            assert!(StorePath::new(invalid).is_err(), "{}", invalid);
+        }
+
+        let err = StorePath::new("/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqe-bash-5.2")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("'e'"));
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let store_dir = Path::new("/nix/store");
+        let path = StorePath::from_parts(store_dir, "0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm", "bash-5.2")
+            .unwrap();
+        assert_eq!(
+            path.path(),
+            Path::new("/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-bash-5.2")
+        );
+        assert_eq!(path.hash_part(), "0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm");
+        assert_eq!(path.name(), "bash-5.2");
+
+        // A hash of the wrong length can't be mistaken for part of the name.
+        let err = StorePath::from_parts(store_dir, "0c7fqpfsb1khrxs5ra7gx4wxrk2xqpq", "bash-5.2")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("expected 32 characters, got 31"));
+        assert!(
+            StorePath::from_parts(store_dir, "0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqmm", "bash").is_err()
+        );
+
+        assert!(
+            StorePath::from_parts(store_dir, "0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqe", "bash").is_err()
+        );
+        assert!(StorePath::from_parts(store_dir, "0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm", "").is_err());
+        assert!(
+            StorePath::from_parts(store_dir, "0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm", "bin/bash")
+                .is_err()
+        );
+        for name in ["..", "my bash", "bash@5"] {
+            assert!(
+                StorePath::from_parts(store_dir, "0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm", name).is_err(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_str_round_trip() {
+        let input = "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-bash-5.2";
+        let path: StorePath = input.parse().unwrap();
+        assert_eq!(format!("{}", path), input);
+        #[allow(deprecated)]
+        let string = path.to_string();
+        assert_eq!(string, input);
+        assert_eq!(format!("{}/bin/bash", path), format!("{}/bin/bash", input));
+
+        assert!("/nix/store/bash-5.2".parse::<StorePath>().is_err());
+    }
+
+    #[test]
+    fn test_sort_by_name() {
+        let mut store_paths: Vec<StorePath> = [
+            "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-zlib-1.3.1",
+            "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-gcc-14.2.1",
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-gcc-14.2.1",
+            "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-bash-5.2",
+        ]
+        .iter()
+        .map(|path| StorePath::new(path).unwrap())
+        .collect();
+
+        sort_by_name(&mut store_paths);
+        let sorted: Vec<String> = store_paths.iter().map(|p| format!("{}", p)).collect();
+        assert_eq!(
+            sorted,
+            vec![
+                "/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-bash-5.2",
+                "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-gcc-14.2.1",
+                "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-gcc-14.2.1",
+                "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-zlib-1.3.1",
+            ]
+        );
+
+        // The derived ordering is by hash instead.
+        store_paths.sort();
+        assert_eq!(store_paths[0].name(), "bash-5.2");
+        assert_eq!(store_paths[1].name(), "zlib-1.3.1");
+    }
 }
@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Result};
+use nix_libstore::derived_path::{SingleDerivedPath, SingleDerivedPathBuilt};
+use nix_libstore::prelude::Placeholder;
 use nix_libstore::store_path::StorePath;
-use nix_libstore::{derived_path::SingleDerivedPath, prelude::Placeholder};
 use std::path::PathBuf;
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -11,46 +12,242 @@ pub struct DerivedFile {
 
 impl DerivedFile {
     pub fn to_string(&self) -> String {
-        self.path.to_string()
+        format!("{}", self.path)
     }
 
     pub fn to_encoded(&self) -> String {
-        format!(
-            "{}:{}",
-            self.path.to_input().display(),
-            &self.source.to_string_lossy()
+        encode_pair(
+            &self.path.to_input().to_string_lossy(),
+            &self.source.to_string_lossy(),
         )
     }
 
+    /// Decode a derived file encoded by `to_encoded` after Nix substituted
+    /// the placeholders of built paths with their store paths, which is what
+    /// tasks get in their environment.
     pub fn from_encoded(encoded: &str) -> Result<Self> {
-        // Split by colon to separate path from source
-        let parts: Vec<&str> = encoded.split(':').collect();
-        if parts.len() != 2 {
+        Self::from_encoded_with(encoded, &[])
+    }
+
+    /// Decode a derived file encoded by `to_encoded`. A built path is encoded
+    /// as the placeholder of its output, which can't be reversed, so it's
+    /// matched against the placeholders of `built`.
+    pub fn from_encoded_with(encoded: &str, built: &[SingleDerivedPathBuilt]) -> Result<Self> {
+        let (path, source) =
+            decode_pair(encoded).map_err(|err| anyhow!("Invalid encoded derived file: {}", err))?;
+        if path.is_empty() || source.is_empty() {
             return Err(anyhow!(
-                "Expected one ':' in encoded derived file but got {}",
+                "Invalid encoded derived file: empty path or source in {}",
                 encoded
             ));
         }
 
-        let store_path = StorePath::new(parts[0])?;
-        let path = SingleDerivedPath::Opaque(store_path);
-        let source = PathBuf::from(parts[1]);
+        // Sources are relative to the build directory, otherwise linking them
+        // would write outside of it.
+        let source = PathBuf::from(source);
+        if source.is_absolute() {
+            return Err(anyhow!(
+                "Invalid encoded derived file: source {} is absolute",
+                source.display()
+            ));
+        }
+
+        let path = match Placeholder::from_rendered(&path) {
+            Some(placeholder) => {
+                let built = built
+                    .iter()
+                    .find(|built| Placeholder::ca_output(&built.drv_path, &built.output) == placeholder)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Invalid encoded derived file: {} is the placeholder of an unknown derivation output",
+                            path
+                        )
+                    })?;
+                SingleDerivedPath::Built(built.clone())
+            }
+            None => SingleDerivedPath::Opaque(StorePath::new(path)?),
+        };
 
         Ok(DerivedFile { path, source })
     }
 }
 
+/// An output of a derivation that nix-ninja-task copies `source` to, found at
+/// the placeholder Nix replaces with the path of the output named
+/// `output_name`.
 pub struct DerivedOutput {
+    pub output_name: String,
     pub placeholder: Placeholder,
     pub source: PathBuf,
 }
 
 impl DerivedOutput {
+    pub fn new(output_name: &str, source: PathBuf) -> Self {
+        DerivedOutput {
+            output_name: output_name.to_string(),
+            placeholder: Placeholder::standard_output(output_name),
+            source,
+        }
+    }
+
+    /// Whether the placeholder is the one of `output_name`, so that the
+    /// output is copied to where Nix expects it.
+    pub fn is_consistent(&self) -> bool {
+        self.placeholder == Placeholder::standard_output(&self.output_name)
+    }
+
     pub fn to_encoded(&self) -> String {
-        format!(
-            "{}:{}",
-            &self.placeholder.render().display(),
-            &self.source.display()
+        encode_pair(
+            &self.placeholder.render().to_string_lossy(),
+            &self.source.to_string_lossy(),
         )
     }
 }
+
+/// Encode a pair of strings as `first:second`, escaping `\` and `:` in both
+/// with a backslash so that they may contain colons.
+pub fn encode_pair(first: &str, second: &str) -> String {
+    format!("{}:{}", escape(first), escape(second))
+}
+
+/// Decode a pair of strings encoded by `encode_pair`.
+pub fn decode_pair(encoded: &str) -> Result<(String, String)> {
+    let mut parts = vec![String::new()];
+    let mut chars = encoded.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) => parts.last_mut().unwrap().push(c),
+                None => return Err(anyhow!("Trailing '\\' in {}", encoded)),
+            },
+            ':' => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+
+    let [first, second]: [String; 2] = parts
+        .try_into()
+        .map_err(|_| anyhow!("Expected one unescaped ':' but got {}", encoded))?;
+    Ok((first, second))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(':', "\\:")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoded_with_colons() {
+        let derived_file = DerivedFile {
+            path: SingleDerivedPath::Opaque(
+                StorePath::new("/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-a_b.h").unwrap(),
+            ),
+            source: PathBuf::from("include/c:\\d/a:b.h"),
+        };
+        let encoded = derived_file.to_encoded();
+        assert_eq!(
+            encoded,
+            "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-a_b.h:include/c\\:\\\\d/a\\:b.h"
+        );
+        assert!(DerivedFile::from_encoded(&encoded).unwrap() == derived_file);
+
+        // Encodings without colons or backslashes are unchanged.
+        let decoded = decode_pair("/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-a.h:a.h").unwrap();
+        assert_eq!(
+            decoded,
+            (
+                "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-a.h".to_string(),
+                "a.h".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_encoded_round_trip() {
+        let opaque = DerivedFile {
+            path: SingleDerivedPath::Opaque(
+                StorePath::new("/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c").unwrap(),
+            ),
+            source: PathBuf::from("../src/main.c"),
+        };
+        assert!(DerivedFile::from_encoded(&opaque.to_encoded()).unwrap() == opaque);
+
+        let built_path = SingleDerivedPathBuilt {
+            drv_path: StorePath::new("/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-main.o.drv")
+                .unwrap(),
+            output: "main.o".to_string(),
+        };
+        let built = DerivedFile {
+            path: SingleDerivedPath::Built(built_path.clone()),
+            source: PathBuf::from("main.o"),
+        };
+        let encoded = built.to_encoded();
+        let other = SingleDerivedPathBuilt {
+            output: "util.o".to_string(),
+            ..built_path.clone()
+        };
+        let decoded = DerivedFile::from_encoded_with(&encoded, &[other.clone(), built_path]);
+        assert!(decoded.unwrap() == built);
+
+        // The placeholder isn't mistaken for a store path.
+        assert!(DerivedFile::from_encoded(&encoded).is_err());
+        assert!(DerivedFile::from_encoded_with(&encoded, &[other]).is_err());
+
+        // Once Nix substituted the placeholder, the output is an opaque path.
+        let output = StorePath::new("/nix/store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-main.o").unwrap();
+        let encoded = built.to_encoded().replace(
+            &built.path.to_input().to_string_lossy().into_owned(),
+            &format!("{}", output),
+        );
+        let decoded = DerivedFile::from_encoded(&encoded).unwrap();
+        assert!(decoded.path == SingleDerivedPath::Opaque(output));
+        assert_eq!(decoded.source, PathBuf::from("main.o"));
+    }
+
+    #[test]
+    fn test_from_encoded_invalid() {
+        for encoded in [
+            ":main.c",
+            "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c:",
+            "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c:/src/main.c",
+            "main.c:main.c",
+        ] {
+            assert!(DerivedFile::from_encoded(encoded).is_err(), "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn test_decode_pair_invalid() {
+        assert!(decode_pair("a.h").is_err());
+        assert!(decode_pair("a:b:c").is_err());
+        assert!(decode_pair("a:b\\").is_err());
+        assert_eq!(
+            decode_pair("a\\:b:c").unwrap(),
+            ("a:b".to_string(), "c".to_string())
+        );
+    }
+
+    #[test]
+    fn test_derived_output_placeholder() {
+        let output = DerivedOutput::new("obj-main.o", PathBuf::from("obj/main.o"));
+        assert!(output.is_consistent());
+        assert_eq!(
+            output.to_encoded(),
+            format!(
+                "{}:obj/main.o",
+                Placeholder::standard_output("obj-main.o")
+                    .render()
+                    .display()
+            )
+        );
+
+        let mismatched = DerivedOutput {
+            placeholder: Placeholder::standard_output("out"),
+            ..output
+        };
+        assert!(!mismatched.is_consistent());
+    }
+}
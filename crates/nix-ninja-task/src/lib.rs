@@ -1 +1,6 @@
 pub mod derived_file;
+
+/// Directory failed tasks copy their source tree to when it exists in the
+/// sandbox, where `nix-ninja --keep-failed-dir` mounts a directory of the host
+/// with the `extra-sandbox-paths` setting so that derivations stay the same.
+pub const KEEP_FAILED_DIR: &str = "/nix-ninja-keep-failed";
@@ -2,11 +2,17 @@ use anyhow::{anyhow, Result};
 use clap::command;
 use clap::Parser;
 use nix_ninja_task::derived_file::DerivedFile;
+use nix_ninja_task::KEEP_FAILED_DIR;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::os::unix::fs::symlink;
-use std::path::PathBuf;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Component, Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Parser)]
 #[command(author, disable_version_flag = true)]
@@ -15,38 +21,108 @@ pub struct Cli {
     #[arg(long = "store-dir", env = "NIX_STORE", default_value = "/nix/store")]
     pub store_dir: PathBuf,
 
-    /// Directory prefix to recreate sources via symlinks.
-    #[arg(long = "build-dir", default_value = "/build/source/build")]
-    pub build_dir: PathBuf,
+    /// Directory prefix to recreate sources via symlinks, `source/build` under
+    /// $NIX_BUILD_TOP or $TMPDIR by default, or /build/source/build. Outside
+    /// the sandbox these vary between runs and end up in outputs that embed
+    /// the build directory, so pass it explicitly for reproducible outputs.
+    #[arg(long = "build-dir")]
+    pub build_dir: Option<PathBuf>,
 
     /// Optional build target description.
     #[arg(long)]
     pub description: Option<String>,
 
     // Encoded derived files to prepare the source directory.
-    #[arg(long, env = "NIX_NINJA_INPUTS")]
+    #[arg(long, env = "NIX_NINJA_INPUTS", default_value = "")]
     pub inputs: String,
 
+    // File containing the encoded inputs, used instead of `inputs` when they
+    // are too long to be passed inline and Nix passes them as a file.
+    #[arg(long = "inputs-file", env = "NIX_NINJA_INPUTSPath")]
+    pub inputs_file: Option<PathBuf>,
+
     // Encoded derived files that build outputs should be copied to.
     #[arg(long, env = "NIX_NINJA_OUTPUTS")]
     pub outputs: String,
 
+    // Response file to write before running the command.
+    #[arg(long, env = "NIX_NINJA_RSPFILE")]
+    pub rspfile: Option<PathBuf>,
+
+    // Contents of the response file.
+    #[arg(long, env = "NIX_NINJA_RSPFILE_CONTENT", default_value = "")]
+    pub rspfile_content: String,
+
+    /// Keep the response file after the command succeeds.
+    #[arg(long = "keep-rspfile", default_value = "false")]
+    pub keep_rspfile: bool,
+
+    /// Verify that every input resolves to a readable file before running the
+    /// command.
+    #[arg(long = "check-inputs", default_value = "false")]
+    pub check_inputs: bool,
+
+    /// Normalize the environment of the command to make its outputs
+    /// reproducible across machines.
+    #[arg(long = "deterministic", default_value = "false")]
+    pub deterministic: bool,
+
+    /// Kill the command if it runs for longer than this many seconds.
+    #[arg(long = "timeout", env = "NIX_NINJA_TASK_TIMEOUT")]
+    pub timeout: Option<u64>,
+
+    /// Shell to run the command with, looked up on $PATH unless it is a path.
+    #[arg(long = "shell", env = "NIX_NINJA_SHELL", default_value = "sh")]
+    pub shell: String,
+
+    /// Prefix each line the command outputs with the description, or the
+    /// first output, so that the logs of concurrent builds stay readable.
+    #[arg(long = "prefix-output", default_value = "false")]
+    pub prefix_output: bool,
+
+    /// Only print the output of the command and failures, not what the task
+    /// does.
+    #[arg(
+        short = 'q',
+        long = "quiet",
+        env = "NIX_NINJA_QUIET",
+        default_value = "false"
+    )]
+    pub quiet: bool,
+
+    /// When the command fails, copy the source tree along with what the
+    /// command produced to a directory named after the derivation under this
+    /// one, by default /nix-ninja-keep-failed if it is mounted in the sandbox.
+    #[arg(long = "keep-failed-dir")]
+    pub keep_failed_dir: Option<PathBuf>,
+
     // Command to run.
     pub cmdline: String,
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    let info = |line: String| print_info(&mut io::stdout(), cli.quiet, &line);
 
-    println!("NIX_BUILD_TOP {}", env::var("NIX_BUILD_TOP")?);
+    if let Ok(build_top) = env::var("NIX_BUILD_TOP") {
+        info(format!("NIX_BUILD_TOP {}", build_top));
+    }
 
     // Create the build directory
-    fs::create_dir_all(&cli.build_dir)?;
-    std::env::set_current_dir(&cli.build_dir)?;
+    let build_dir = match &cli.build_dir {
+        Some(build_dir) => build_dir.clone(),
+        None => default_build_dir(|name| env::var_os(name)),
+    };
+    prepare_build_dir(&build_dir)?;
+    std::env::set_current_dir(&build_dir)?;
 
     // Parse the inputs into derived files.
+    let encoded_inputs = match &cli.inputs_file {
+        Some(inputs_file) => fs::read_to_string(inputs_file)?,
+        None => cli.inputs.clone(),
+    };
     let mut inputs = Vec::new();
-    for encoded in cli.inputs.split_whitespace() {
+    for encoded in encoded_inputs.split_whitespace() {
         // println!("Processing input {}", encoded);
         let input = DerivedFile::from_encoded(encoded)?;
         inputs.push(input);
@@ -64,48 +140,240 @@ fn main() -> Result<()> {
     // symlinked while preserving the original directory hierarchy of the
     // sources. This ensures relative includes and other path-dependent
     // references remain valid.
-    create_symlinks(&cli.build_dir, inputs)?;
-    println!(
-        "nix-ninja-task: Setup source directory in {}",
-        cli.build_dir.display()
-    );
+    create_symlinks(&build_dir, &inputs)?;
+    if cli.check_inputs {
+        check_inputs(&build_dir, &inputs)?;
+    }
+    info(setup_line(&build_dir));
 
     // Ensure all output sources have parent directories created.
     create_parent_dirs(&outputs)?;
 
     // Print out ninja build rule description if available.
-    if let Some(desc) = cli.description {
-        println!("nix-ninja-task: {}", &desc);
+    if let Some(desc) = &cli.description {
+        info(format!("nix-ninja-task: {}", desc));
+    }
+
+    // Like ninja, the response file is written right before running the
+    // command.
+    if let Some(rspfile) = &cli.rspfile {
+        write_rspfile(rspfile, &cli.rspfile_content)?;
+    }
+
+    let mut envs: HashMap<String, String> = env::vars().collect();
+    if cli.deterministic {
+        envs.extend(deterministic_env(&build_dir, &envs));
     }
 
     // Spawn cmdline process via sh like ninja upstream does.
-    println!("nix-ninja-task: Running: /bin/sh -c \"{}\"", &cli.cmdline);
-    let exit_code = spawn_process(cli.cmdline)?;
+    info(format!(
+        "nix-ninja-task: Running: {} -c \"{}\"",
+        &cli.shell, &cli.cmdline
+    ));
+    let timeout = cli.timeout.map(Duration::from_secs);
+    let prefix = cli
+        .prefix_output
+        .then(|| output_prefix(cli.description.as_deref(), &outputs));
+    let exit_code = spawn_process(
+        &cli.shell,
+        cli.cmdline.clone(),
+        &envs,
+        timeout,
+        prefix.as_deref(),
+    )?;
     if exit_code != 0 {
         println!("nix-ninja-task: Failed with exit code {}", exit_code);
+        println!("nix-ninja-task: Build directory {}", build_dir.display());
+        if let Some(keep_failed_dir) = keep_failed_dir(&cli) {
+            let kept = source_tree(&build_dir, &inputs)
+                .and_then(|tree| keep_failed(&keep_failed_dir, &tree, &derivation_name()));
+            // Failing to keep the tree is only worth a warning, the task
+            // failed anyway.
+            match kept {
+                Ok(dest) => println!("nix-ninja-task: Kept build directory in {}", dest.display()),
+                Err(err) => println!(
+                    "nix-ninja-task: warning: Failed to keep build directory: {}",
+                    err
+                ),
+            }
+        }
         std::process::exit(exit_code);
     }
 
+    if let Some(rspfile) = &cli.rspfile {
+        let keep_dir = keep_failed_dir(&cli).map(|dir| dir.join(derivation_name()));
+        match cleanup_rspfile(rspfile, cli.keep_rspfile, keep_dir.as_deref())? {
+            Some(kept) => info(format!(
+                "nix-ninja-task: Kept rspfile in {}",
+                kept.display()
+            )),
+            None if cli.keep_rspfile => println!(
+                "nix-ninja-task: warning: rspfile {} is only kept in the sandbox, \
+                 use nix-ninja --keep-failed-dir to keep it",
+                rspfile.display()
+            ),
+            None => {}
+        }
+    }
+
     // Outputs must be created in build directory and then copied out because
     // ninja build rules can have implicit outputs that we have no way of
     // knowing. For example, a custom command that doesn't leverage the `$out`
     // implicit variable in the ninja evaluation context.
-    println!(
+    check_outputs(&cli.cmdline, &build_dir, &outputs)?;
+    info(format!(
         "nix-ninja-task: Finished! Copying {} build outputs to derivation output paths",
         outputs.len(),
-    );
+    ));
     for output in &outputs {
-        fs::copy(&output.source, &output.to_string())?;
+        copy_output(&output.source, Path::new(&output.to_string()))?;
     }
 
     Ok(())
 }
 
+/// The directory the sources of the inputs are under, which is above the build
+/// directory when they are relative to it with `..`.
+fn source_tree(build_dir: &Path, inputs: &[DerivedFile]) -> Result<PathBuf> {
+    let depth = inputs
+        .iter()
+        .map(|input| {
+            input
+                .source
+                .components()
+                .take_while(|component| *component == Component::ParentDir)
+                .count()
+        })
+        .max()
+        .unwrap_or(0);
+    // Never copy the whole filesystem, which has the store and /proc.
+    match build_dir.ancestors().nth(depth) {
+        Some(tree) if tree.parent().is_some() => Ok(tree.to_path_buf()),
+        _ => Err(anyhow!(
+            "source tree of {} is the root directory",
+            build_dir.display()
+        )),
+    }
+}
+
+// Nix names the derivation in $name, which is unique enough for a build of a
+// target.
+fn derivation_name() -> String {
+    env::var("name").unwrap_or_else(|_| "nix-ninja-task".to_string())
+}
+
+// The directory to keep the source tree of a failed task in: the one given,
+// or the one nix-ninja mounts into the sandbox if it is there.
+fn keep_failed_dir(cli: &Cli) -> Option<PathBuf> {
+    cli.keep_failed_dir.clone().or_else(|| {
+        let dir = Path::new(KEEP_FAILED_DIR);
+        dir.is_dir().then(|| dir.to_path_buf())
+    })
+}
+
+/// Copies the source tree of a failed task to `name` under `keep_failed_dir`,
+/// replacing what a previous failure of the same derivation left there, so
+/// that the failure can be reproduced once the sandbox is gone. Unlike Nix's
+/// `--keep-failed`, which keeps the whole sandbox of local builds, this works
+/// on remote builders and only keeps the tree. Returns where it was kept.
+fn keep_failed(keep_failed_dir: &Path, tree: &Path, name: &str) -> Result<PathBuf> {
+    let dest = keep_failed_dir.join(name);
+    match fs::remove_dir_all(&dest) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(anyhow!("Failed to remove {}: {}", dest.display(), err));
+        }
+        _ => {}
+    }
+    copy_tree(tree, &dest).map_err(|err| {
+        anyhow!(
+            "Failed to copy {} to {}: {}",
+            tree.display(),
+            dest.display(),
+            err
+        )
+    })?;
+    Ok(dest)
+}
+
+// Copies `source` to `dest` recursively. Symlinks are copied as is, since the
+// inputs they point to in the store outlive the sandbox.
+fn copy_tree(source: &Path, dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let path = entry.path();
+        let dest_path = dest.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            symlink(fs::read_link(&path)?, &dest_path)?;
+        } else if file_type.is_dir() {
+            copy_tree(&path, &dest_path)?;
+        } else {
+            fs::copy(&path, &dest_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Print a line about what the task does to `out`, unless `quiet`. Failing to
+/// print it doesn't fail the task.
+fn print_info(out: &mut impl Write, quiet: bool, line: &str) {
+    if !quiet {
+        let _ = writeln!(out, "{}", line);
+    }
+}
+
+fn setup_line(build_dir: &Path) -> String {
+    format!(
+        "nix-ninja-task: Setup source directory in {}",
+        build_dir.display()
+    )
+}
+
+/// The build directory when none is given: under the top of the sandbox, or
+/// the temporary directory outside of one, so that nothing is written
+/// elsewhere. `var` looks up environment variables.
+///
+/// Only the sandbox gives a fixed $NIX_BUILD_TOP. Elsewhere it, or $TMPDIR,
+/// differs between machines and runs, so the default isn't deterministic and
+/// callers running tasks by hand should pass `--build-dir`.
+fn default_build_dir(var: impl Fn(&str) -> Option<std::ffi::OsString>) -> PathBuf {
+    let top = ["NIX_BUILD_TOP", "TMPDIR"]
+        .into_iter()
+        .filter_map(var)
+        .find(|top| !top.is_empty())
+        .map_or(PathBuf::from("/build"), PathBuf::from);
+    top.join("source/build")
+}
+
+/// Creates the build directory and checks that it is writable, which fails
+/// with a clear error rather than on the first symlink.
+fn prepare_build_dir(build_dir: &Path) -> Result<()> {
+    fs::create_dir_all(build_dir).map_err(|e| {
+        anyhow!(
+            "Failed to create build directory {}: {}",
+            build_dir.display(),
+            e
+        )
+    })?;
+
+    let probe = build_dir.join(format!(".nix-ninja-task-{}", std::process::id()));
+    fs::write(&probe, "")
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| {
+            anyhow!(
+                "Build directory {} is not writable: {}",
+                build_dir.display(),
+                e
+            )
+        })
+}
+
 /// Creates symlinks for derived files under the specified prefix.
 ///
 /// For each derived file, creates a symlink at `prefix/${derived_file.source}`
 /// pointing to the actual file at `derived_file.path`.
-fn create_symlinks(prefix: &PathBuf, inputs: Vec<DerivedFile>) -> Result<()> {
+fn create_symlinks(prefix: &Path, inputs: &[DerivedFile]) -> Result<()> {
     for input in inputs {
         // Get the source path (where the symlink points to)
         let source_path = input.to_string();
@@ -118,6 +386,20 @@ fn create_symlinks(prefix: &PathBuf, inputs: Vec<DerivedFile>) -> Result<()> {
             fs::create_dir_all(parent)?;
         }
 
+        // Several inputs may map to the same source, which is fine as long as
+        // they are the same file.
+        if let Ok(existing) = fs::read_link(&dest_path) {
+            if existing == Path::new(&source_path) {
+                continue;
+            }
+            return Err(anyhow!(
+                "Conflicting inputs for {}: both {} and {}",
+                dest_path.display(),
+                existing.display(),
+                source_path
+            ));
+        }
+
         // Create the symlink directly
         symlink(&source_path, &dest_path).map_err(|e| {
             anyhow!(
@@ -132,6 +414,28 @@ fn create_symlinks(prefix: &PathBuf, inputs: Vec<DerivedFile>) -> Result<()> {
     Ok(())
 }
 
+/// Checks that the symlinks created for inputs under the specified prefix
+/// resolve to readable files, so that missing inputs are reported before the
+/// command fails on them in confusing ways.
+fn check_inputs(prefix: &Path, inputs: &[DerivedFile]) -> Result<()> {
+    for input in inputs {
+        let dest_path = prefix.join(&input.source);
+        if let Err(err) = fs::File::open(&dest_path) {
+            let problem = match err.kind() {
+                std::io::ErrorKind::NotFound => "missing".to_string(),
+                _ => format!("not readable: {}", err),
+            };
+            return Err(anyhow!(
+                "input {} -> store path {} is {}",
+                input.source.display(),
+                input.to_string(),
+                problem
+            ));
+        }
+    }
+    Ok(())
+}
+
 fn create_parent_dirs(outputs: &Vec<DerivedFile>) -> Result<()> {
     let mut dirs: Vec<&std::path::Path> = Vec::new();
     for output in outputs {
@@ -146,16 +450,716 @@ fn create_parent_dirs(outputs: &Vec<DerivedFile>) -> Result<()> {
     Ok(())
 }
 
-fn spawn_process(cmdline: String) -> Result<i32> {
-    let mut cmd = Command::new("/bin/sh");
+// Maximum number of files of the build directory listed when outputs are
+// missing.
+const MAX_LISTED_FILES: usize = 100;
+
+/// Checks that the command produced all the outputs, describing the command,
+/// the missing outputs and what the build directory contains otherwise.
+fn check_outputs(cmdline: &str, build_dir: &Path, outputs: &[DerivedFile]) -> Result<()> {
+    let missing: Vec<&DerivedFile> = outputs
+        .iter()
+        .filter(|output| !build_dir.join(&output.source).exists())
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = format!("command did not produce all its outputs: {}\n", cmdline);
+    message.push_str("missing outputs:\n");
+    for output in &missing {
+        message.push_str(&format!("  {}\n", output.source.display()));
+    }
+
+    let mut files = Vec::new();
+    list_files(build_dir, build_dir, &mut files)?;
+    files.sort();
+    message.push_str(&format!(
+        "build directory {} contains:\n",
+        build_dir.display()
+    ));
+    for file in files.iter().take(MAX_LISTED_FILES) {
+        message.push_str(&format!("  {}\n", file.display()));
+    }
+    if files.len() > MAX_LISTED_FILES {
+        message.push_str(&format!(
+            "  ... and {} more\n",
+            files.len() - MAX_LISTED_FILES
+        ));
+    }
+
+    Err(anyhow!(message.trim_end().to_string()))
+}
+
+// Lists files under `dir` relative to `root`, without following symlinks.
+fn list_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            list_files(root, &path, files)?;
+        } else {
+            files.push(path.strip_prefix(root)?.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Copies a build output to its derivation output path, carrying over the
+/// permissions of the source so that executables stay executable regardless
+/// of the umask. Symlinks are followed, as their target may not exist outside
+/// of the build directory.
+fn copy_output(source: &Path, dest: &Path) -> Result<()> {
+    fs::copy(source, dest).map_err(|e| {
+        anyhow!(
+            "Failed to copy output {} to {}: {}",
+            source.display(),
+            dest.display(),
+            e
+        )
+    })?;
+    let permissions = fs::metadata(source)?.permissions();
+    fs::set_permissions(dest, permissions)?;
+    Ok(())
+}
+
+fn write_rspfile(rspfile: &Path, content: &str) -> Result<()> {
+    if let Some(parent) = rspfile.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(rspfile, content)
+        .map_err(|e| anyhow!("Failed to write rspfile {}: {}", rspfile.display(), e))
+}
+
+/// Removes the response file after a successful command unless it should be
+/// kept for debugging (`nix-ninja -d keeprsp`). The build directory doesn't
+/// outlive the sandbox, so a kept response file is also copied to `keep_dir`,
+/// returning where it was copied.
+fn cleanup_rspfile(rspfile: &Path, keep: bool, keep_dir: Option<&Path>) -> Result<Option<PathBuf>> {
+    if !keep {
+        fs::remove_file(rspfile)?;
+        return Ok(None);
+    }
+    let Some(keep_dir) = keep_dir else {
+        return Ok(None);
+    };
+
+    let name = rspfile
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid rspfile {}", rspfile.display()))?;
+    let dest = keep_dir.join(name);
+    fs::create_dir_all(keep_dir)
+        .and_then(|_| fs::copy(rspfile, &dest))
+        .map_err(|e| anyhow!("Failed to keep rspfile in {}: {}", dest.display(), e))?;
+    Ok(Some(dest))
+}
+
+/// SOURCE_DATE_EPOCH used by nixpkgs for files without a meaningful
+/// timestamp, 1980-01-01.
+const SOURCE_DATE_EPOCH: &str = "315532800";
+
+/// Environment variables normalizing timestamps, locale, timezone and working
+/// directory so that the command's outputs don't depend on the machine.
+///
+/// Variables that are already set in `envs`, for example forwarded from the
+/// build environment, are left untouched, except for PWD which must match
+/// the build directory the command runs in.
+fn deterministic_env(build_dir: &Path, envs: &HashMap<String, String>) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    for (key, value) in [
+        ("SOURCE_DATE_EPOCH", SOURCE_DATE_EPOCH),
+        ("LC_ALL", "C"),
+        ("TZ", "UTC"),
+    ] {
+        if !envs.contains_key(key) {
+            vars.push((key.to_string(), value.to_string()));
+        }
+    }
+    vars.push(("PWD".to_string(), build_dir.to_string_lossy().into_owned()));
+    vars
+}
+
+// How often a command with a timeout is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Prefix of the output lines of the command, the description of the build or
+/// else its first output.
+fn output_prefix(description: Option<&str>, outputs: &[DerivedFile]) -> String {
+    match (description, outputs.first()) {
+        (Some(desc), _) => desc.to_string(),
+        (None, Some(output)) => output.source.display().to_string(),
+        (None, None) => "nix-ninja-task".to_string(),
+    }
+}
+
+/// Copies `reader` to `writer` line by line, prefixing each line. Lines are
+/// written at once so that they don't interleave with the lines of others.
+fn prefix_lines(reader: impl Read, mut writer: impl Write, prefix: &str) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        if line.last() != Some(&b'\n') {
+            line.push(b'\n');
+        }
+        let mut prefixed = format!("{}: ", prefix).into_bytes();
+        prefixed.extend_from_slice(&line);
+        writer.write_all(&prefixed)?;
+        writer.flush()?;
+    }
+}
+
+/// Runs `cmdline` via `shell`, streaming its output unless a `prefix` is
+/// given, in which case its output is captured and every line prefixed.
+///
+/// A `shell` without a slash is looked up on the `PATH` of `envs`.
+fn spawn_process(
+    shell: &str,
+    cmdline: String,
+    envs: &HashMap<String, String>,
+    timeout: Option<Duration>,
+    prefix: Option<&str>,
+) -> Result<i32> {
+    let stdio = || match prefix {
+        Some(_) => Stdio::piped(),
+        None => Stdio::inherit(),
+    };
+    let mut cmd = Command::new(shell);
     cmd.args(["-c", &cmdline])
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .envs(env::vars());
+        .stdout(stdio())
+        .stderr(stdio())
+        .env_clear()
+        .envs(envs);
+    // Run a command with a timeout in its own process group, so that the
+    // processes it started are killed along with it.
+    if timeout.is_some() {
+        cmd.process_group(0);
+    }
 
-    // Spawn and wait for the process
-    let output = cmd.status()?;
+    // Spawn and wait for the process, killing it past the timeout.
+    let mut child = cmd.spawn()?;
+    let mut forwarders = Vec::new();
+    if let Some(prefix) = prefix {
+        if let Some(stdout) = child.stdout.take() {
+            let prefix = prefix.to_string();
+            forwarders.push(thread::spawn(move || {
+                prefix_lines(stdout, io::stdout(), &prefix)
+            }));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            let prefix = prefix.to_string();
+            forwarders.push(thread::spawn(move || {
+                prefix_lines(stderr, io::stderr(), &prefix)
+            }));
+        }
+    }
+    let output = match timeout {
+        None => child.wait()?,
+        Some(timeout) => {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+                if Instant::now() >= deadline {
+                    // The process group has the id of its leader, the shell.
+                    let pgid = child.id() as libc::pid_t;
+                    if unsafe { libc::kill(-pgid, libc::SIGKILL) } != 0 {
+                        return Err(io::Error::last_os_error().into());
+                    }
+                    child.wait()?;
+                    return Err(anyhow!(
+                        "nix-ninja-task: Command timed out after {} s: {}",
+                        timeout.as_secs_f64(),
+                        cmdline
+                    ));
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    };
+    for forwarder in forwarders {
+        forwarder
+            .join()
+            .map_err(|_| anyhow!("nix-ninja-task: Failed to forward command output"))??;
+    }
+
+    // Like a shell, report a command terminated by a signal with exit code
+    // 128 + signal, e.g. 137 for SIGKILL from the OOM killer.
+    if let Some(signal) = output.signal() {
+        println!(
+            "nix-ninja-task: Command terminated by signal {}{}",
+            signal,
+            signal_name(signal)
+                .map(|name| format!(" ({})", name))
+                .unwrap_or_default()
+        );
+        return Ok(128 + signal);
+    }
 
     // Return the exit code
     Ok(output.code().unwrap_or(1))
 }
+
+fn signal_name(signal: i32) -> Option<&'static str> {
+    let name = match signal {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        5 => "SIGTRAP",
+        6 => "SIGABRT",
+        7 => "SIGBUS",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        11 => "SIGSEGV",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        _ => return None,
+    };
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("nix-ninja-task-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_rspfile_removed_on_success() {
+        let rspfile = temp_path("removed.rsp");
+        write_rspfile(&rspfile, "a.o b.o").unwrap();
+        assert_eq!(cleanup_rspfile(&rspfile, false, None).unwrap(), None);
+        assert!(!rspfile.exists());
+    }
+
+    #[test]
+    fn test_quiet_suppresses_setup_line() {
+        let line = setup_line(Path::new("/build/source/build"));
+        let mut out = Vec::new();
+        print_info(&mut out, false, &line);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "nix-ninja-task: Setup source directory in /build/source/build\n"
+        );
+
+        let mut out = Vec::new();
+        print_info(&mut out, true, &line);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_default_build_dir() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.into())
+            }
+        };
+        assert_eq!(
+            default_build_dir(env(&[("NIX_BUILD_TOP", "/sandbox"), ("TMPDIR", "/tmp")])),
+            Path::new("/sandbox/source/build")
+        );
+        assert_eq!(
+            default_build_dir(env(&[("NIX_BUILD_TOP", ""), ("TMPDIR", "/tmp")])),
+            Path::new("/tmp/source/build")
+        );
+        assert_eq!(
+            default_build_dir(env(&[])),
+            Path::new("/build/source/build")
+        );
+    }
+
+    #[test]
+    fn test_prepare_build_dir() {
+        let dir = temp_path("prepare-build-dir");
+        let build_dir = dir.join("source/build");
+        prepare_build_dir(&build_dir).unwrap();
+        assert!(build_dir.is_dir());
+        assert_eq!(fs::read_dir(&build_dir).unwrap().count(), 0);
+
+        // A file in the way of the build directory.
+        fs::write(dir.join("file"), "").unwrap();
+        let err = prepare_build_dir(&dir.join("file/build")).err().unwrap();
+        assert!(err.to_string().contains("Failed to create build directory"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_deterministic_env() {
+        let build_dir = Path::new("/build/source/build");
+        let mut envs: HashMap<String, String> =
+            [("TZ".to_string(), "Europe/Paris".to_string())].into();
+        envs.extend(deterministic_env(build_dir, &envs));
+
+        assert_eq!(envs["SOURCE_DATE_EPOCH"], SOURCE_DATE_EPOCH);
+        assert_eq!(envs["LC_ALL"], "C");
+        assert_eq!(envs["PWD"], "/build/source/build");
+        // Explicitly set variables are kept.
+        assert_eq!(envs["TZ"], "Europe/Paris");
+
+        let exit_code = spawn_process(
+            "/bin/sh",
+            "test \"$LC_ALL\" = C && test \"$SOURCE_DATE_EPOCH\" = 315532800".to_string(),
+            &envs,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(exit_code, 0);
+    }
+
+    #[test]
+    fn test_check_inputs_missing() {
+        let dir = temp_path("check-inputs");
+        let _ = fs::remove_dir_all(&dir);
+        let store_dir = dir.join("store");
+        fs::create_dir_all(&store_dir).unwrap();
+        fs::write(
+            store_dir.join("ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c"),
+            "",
+        )
+        .unwrap();
+
+        let input = |store_path: &str, source: &str| {
+            let encoded = format!("{}:{}", store_dir.join(store_path).display(), source);
+            DerivedFile::from_encoded(&encoded).unwrap()
+        };
+        let mut inputs = vec![input(
+            "ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c",
+            "src/main.c",
+        )];
+        let build_dir = dir.join("build");
+        create_symlinks(&build_dir, &inputs).unwrap();
+        check_inputs(&build_dir, &inputs).unwrap();
+
+        inputs.push(input(
+            "q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-util.h",
+            "src/util.h",
+        ));
+        create_symlinks(&build_dir, &inputs[1..]).unwrap();
+        let err = check_inputs(&build_dir, &inputs).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "input src/util.h -> store path {} is missing",
+                store_dir
+                    .join("q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-util.h")
+                    .display()
+            )
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_copy_output_keeps_executable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_path("copy-output");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("gen.sh");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        symlink("gen.sh", dir.join("gen-link.sh")).unwrap();
+
+        for source in ["gen.sh", "gen-link.sh"] {
+            let dest = dir.join(format!("{}.out", source));
+            copy_output(&dir.join(source), &dest).unwrap();
+            let metadata = fs::symlink_metadata(&dest).unwrap();
+            assert!(metadata.is_file());
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_outputs_missing() {
+        let dir = temp_path("check-outputs");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/main.o"), "").unwrap();
+
+        let output = |source: &str| {
+            let encoded = format!("/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-out:{}", source);
+            DerivedFile::from_encoded(&encoded).unwrap()
+        };
+        let mut outputs = vec![output("sub/main.o")];
+        check_outputs("cc -c main.c", &dir, &outputs).unwrap();
+
+        outputs.push(output("main.map"));
+        let err = check_outputs("cc -c main.c", &dir, &outputs).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "command did not produce all its outputs: cc -c main.c\n\
+                 missing outputs:\n  main.map\n\
+                 build directory {} contains:\n  sub/main.o",
+                dir.display()
+            )
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keep_failed_source_tree() {
+        let dir = temp_path("keep-failed");
+        let _ = fs::remove_dir_all(&dir);
+        let input = |encoded: &str| DerivedFile::from_encoded(encoded).unwrap();
+        let inputs = vec![
+            input("/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c:../src/main.c"),
+            input("/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-config.h:config.h"),
+        ];
+        let build_dir = dir.join("source/build");
+        assert_eq!(
+            source_tree(&build_dir, &inputs).unwrap(),
+            dir.join("source")
+        );
+        assert_eq!(source_tree(&build_dir, &inputs[1..]).unwrap(), build_dir);
+        assert!(source_tree(Path::new("/build"), &inputs).is_err());
+
+        // Inputs stay links to the store, while produced files are copied.
+        create_symlinks(&build_dir, &inputs).unwrap();
+        fs::create_dir_all(build_dir.join("obj")).unwrap();
+        fs::write(build_dir.join("obj/main.o.tmp"), "partial").unwrap();
+        let kept = dir.join("kept");
+        copy_tree(&dir.join("source"), &kept).unwrap();
+        assert_eq!(
+            fs::read_link(kept.join("src/main.c")).unwrap(),
+            PathBuf::from("/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c")
+        );
+        assert_eq!(
+            fs::read_to_string(kept.join("build/obj/main.o.tmp")).unwrap(),
+            "partial"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keep_failed_replaces_previous() {
+        let dir = temp_path("keep-failed-replace");
+        let _ = fs::remove_dir_all(&dir);
+        let tree = dir.join("source");
+        fs::create_dir_all(tree.join("build")).unwrap();
+        fs::write(tree.join("build/main.o.tmp"), "partial").unwrap();
+
+        // What a previous failure kept is replaced.
+        let keep_failed_dir = dir.join("kept");
+        fs::create_dir_all(keep_failed_dir.join("ninja-build-main.o")).unwrap();
+        fs::write(keep_failed_dir.join("ninja-build-main.o/stale"), "").unwrap();
+
+        let dest = keep_failed(&keep_failed_dir, &tree, "ninja-build-main.o").unwrap();
+        assert_eq!(dest, keep_failed_dir.join("ninja-build-main.o"));
+        assert!(!dest.join("stale").exists());
+        assert_eq!(
+            fs::read_to_string(dest.join("build/main.o.tmp")).unwrap(),
+            "partial"
+        );
+
+        // Other derivations are left alone.
+        fs::create_dir_all(keep_failed_dir.join("ninja-build-util.o")).unwrap();
+        keep_failed(&keep_failed_dir, &tree, "ninja-build-main.o").unwrap();
+        assert!(keep_failed_dir.join("ninja-build-util.o").exists());
+
+        // A tree that can't be copied fails.
+        assert!(keep_failed(&keep_failed_dir, &dir.join("missing"), "ninja-build-main.o").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_spawn_process_exit_codes() {
+        let envs: HashMap<String, String> = env::vars().collect();
+        assert_eq!(
+            spawn_process("/bin/sh", "exit 3".to_string(), &envs, None, None).unwrap(),
+            3
+        );
+        assert_eq!(
+            spawn_process("/bin/sh", "kill -TERM $$".to_string(), &envs, None, None).unwrap(),
+            128 + 15
+        );
+        assert_eq!(signal_name(9), Some("SIGKILL"));
+    }
+
+    #[test]
+    fn test_spawn_process_shell() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_path("shell");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        // A shell that records how it was run before running the command.
+        let shell = dir.join("bin/fake-sh");
+        fs::write(
+            &shell,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {}\nexec /bin/sh \"$@\"\n",
+                dir.join("shell.log").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&shell, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let envs: HashMap<String, String> = env::vars().collect();
+        let exit_code = spawn_process(
+            &shell.to_string_lossy(),
+            "exit 4".to_string(),
+            &envs,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(exit_code, 4);
+        assert_eq!(
+            fs::read_to_string(dir.join("shell.log")).unwrap(),
+            "-c exit 4\n"
+        );
+
+        // Shells without a path are looked up on the PATH of the command.
+        fs::remove_file(dir.join("shell.log")).unwrap();
+        let mut envs = envs;
+        envs.insert("PATH".to_string(), dir.join("bin").display().to_string());
+        let exit_code = spawn_process("fake-sh", "exit 0".to_string(), &envs, None, None).unwrap();
+        assert_eq!(exit_code, 0);
+        assert!(dir.join("shell.log").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_spawn_process_timeout() {
+        let envs: HashMap<String, String> = env::vars().collect();
+        let timeout = Some(Duration::from_millis(100));
+        assert_eq!(
+            spawn_process("/bin/sh", "exit 0".to_string(), &envs, timeout, None).unwrap(),
+            0
+        );
+
+        let start = Instant::now();
+        let err = spawn_process("/bin/sh", "sleep 10".to_string(), &envs, timeout, None)
+            .err()
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            err.to_string(),
+            "nix-ninja-task: Command timed out after 0.1 s: sleep 10"
+        );
+
+        // Processes started by the command are killed too.
+        let dir = env::temp_dir().join(format!("nix-ninja-task-timeout-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pid_file = dir.join("pid");
+        let cmdline = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        spawn_process("/bin/sh", cmdline, &envs, timeout, None)
+            .err()
+            .unwrap();
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        let stat = Path::new("/proc").join(pid.trim()).join("stat");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        // Until it's gone, or a zombie left for init to reap.
+        while fs::read_to_string(&stat).is_ok_and(|stat| !stat.contains(") Z ")) {
+            assert!(
+                Instant::now() < deadline,
+                "sleep {} wasn't killed",
+                pid.trim()
+            );
+            thread::sleep(POLL_INTERVAL);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_prefix_lines() {
+        let mut output = Vec::new();
+        prefix_lines(&b"first\nsecond"[..], &mut output, "CC main.o").unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "CC main.o: first\nCC main.o: second\n"
+        );
+
+        let envs: HashMap<String, String> = env::vars().collect();
+        let exit_code = spawn_process(
+            "/bin/sh",
+            "echo out; echo err >&2; exit 2".to_string(),
+            &envs,
+            None,
+            Some("CC main.o"),
+        )
+        .unwrap();
+        assert_eq!(exit_code, 2);
+
+        let output = |source: &str| {
+            let encoded = format!("/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-out:{}", source);
+            DerivedFile::from_encoded(&encoded).unwrap()
+        };
+        assert_eq!(output_prefix(Some("CC main.o"), &[]), "CC main.o");
+        assert_eq!(
+            output_prefix(None, &[output("main.o"), output("main.d")]),
+            "main.o"
+        );
+    }
+
+    #[test]
+    fn test_create_symlinks_collisions() {
+        let dir = temp_path("symlink-collisions");
+        let _ = fs::remove_dir_all(&dir);
+        let input = |store_path: &str| {
+            let encoded = format!("/nix/store/{}:src/util.h", store_path);
+            DerivedFile::from_encoded(&encoded).unwrap()
+        };
+        let util = input("ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-util.h");
+        let other_util = input("q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-util.h");
+
+        // The same input twice is only linked once.
+        create_symlinks(&dir, &[util.clone(), util.clone()]).unwrap();
+        assert_eq!(
+            fs::read_link(dir.join("src/util.h")).unwrap(),
+            PathBuf::from("/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-util.h")
+        );
+
+        let err = create_symlinks(&dir, &[other_util]).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Conflicting inputs for {}: both \
+                 /nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-util.h and \
+                 /nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-util.h",
+                dir.join("src/util.h").display()
+            )
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rspfile_kept() {
+        let rspfile = temp_path("kept.rsp");
+        write_rspfile(&rspfile, "a.o b.o").unwrap();
+        assert_eq!(cleanup_rspfile(&rspfile, true, None).unwrap(), None);
+        assert_eq!(fs::read_to_string(&rspfile).unwrap(), "a.o b.o");
+
+        // The kept response file is copied out of the build directory.
+        let keep_dir = temp_path("kept-rsp/ninja-build-app");
+        let kept = cleanup_rspfile(&rspfile, true, Some(&keep_dir))
+            .unwrap()
+            .unwrap();
+        assert_eq!(kept, keep_dir.join(rspfile.file_name().unwrap()));
+        assert_eq!(fs::read_to_string(&kept).unwrap(), "a.o b.o");
+
+        fs::remove_file(&rspfile).unwrap();
+        fs::remove_dir_all(temp_path("kept-rsp")).unwrap();
+    }
+}
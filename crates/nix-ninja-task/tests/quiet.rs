@@ -0,0 +1,32 @@
+use std::fs;
+use std::process::Command;
+
+// Run a task echoing a line in `build_dir`, with NIX_NINJA_QUIET set to
+// `quiet`, returning its stdout.
+fn run_task(build_dir: &std::path::Path, quiet: &str) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_nix-ninja-task"))
+        .arg("--build-dir")
+        .arg(build_dir)
+        .args(["--outputs", "", "echo hello"])
+        .env("NIX_NINJA_QUIET", quiet)
+        .env_remove("NIX_BUILD_TOP")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_quiet_task_only_prints_command_output() {
+    let dir = std::env::temp_dir().join(format!("nix-ninja-task-quiet-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let stdout = run_task(&dir, "false");
+    assert!(stdout.contains("nix-ninja-task: Setup source directory in"));
+    assert!(stdout.contains("nix-ninja-task: Running: sh -c \"echo hello\""));
+    assert!(stdout.contains("\nhello\nnix-ninja-task: Finished!"));
+
+    assert_eq!(run_task(&dir, "true"), "hello\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
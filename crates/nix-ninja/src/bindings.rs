@@ -0,0 +1,262 @@
+use anyhow::{bail, Result};
+use n2::graph::BuildId;
+use n2::{canon, load, scanner};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Variables bound on build statements, like `dyndep` or `msvc_deps_prefix`.
+///
+/// n2 folds the build-level bindings it knows about into its builds and drops
+/// the others, so the build files are scanned for them again. Values are
+/// evaluated against the file-level variables only, as the build-level ones
+/// like `$in` and `$out` aren't kept either.
+#[derive(Debug, Default)]
+pub struct BuildBindings {
+    by_build: HashMap<BuildId, HashMap<String, String>>,
+}
+
+impl BuildBindings {
+    /// Scan the build file, and the files it `include`s and `subninja`s, for
+    /// the bindings of the builds in the loaded graph.
+    pub fn read(loader: &load::Loader, build_filename: &str) -> Result<Self> {
+        let mut bindings = BuildBindings::default();
+        let mut vars = HashMap::new();
+        bindings.scan_file(loader, Path::new(build_filename), &mut vars)?;
+        Ok(bindings)
+    }
+
+    /// The value `name` is bound to on the build, if any.
+    pub fn get(&self, bid: BuildId, name: &str) -> Option<&str> {
+        self.by_build.get(&bid)?.get(name).map(String::as_str)
+    }
+
+    fn scan_file(
+        &mut self,
+        loader: &load::Loader,
+        path: &Path,
+        vars: &mut HashMap<String, String>,
+    ) -> Result<()> {
+        let bytes = match scanner::read_file_with_nul(path) {
+            Ok(b) => b,
+            Err(e) => bail!("read {}: {}", path.display(), e),
+        };
+        let text = String::from_utf8_lossy(&bytes);
+        let text = text.trim_end_matches('\0');
+
+        // The build whose indented bindings follow.
+        let mut build: Option<BuildId> = None;
+        for line in join_continuations(text) {
+            if line.trim().is_empty() {
+                build = None;
+                continue;
+            }
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with(' ') {
+                if let (Some(bid), Some((name, value))) = (build, split_binding(&line)) {
+                    let value = eval(value, vars);
+                    self.by_build
+                        .entry(bid)
+                        .or_default()
+                        .insert(name.to_string(), value);
+                }
+                continue;
+            }
+
+            build = None;
+            if let Some(rest) = line.strip_prefix("build ") {
+                build = outputs(rest, vars)
+                    .first()
+                    .and_then(|out| loader.graph.files.lookup(&canon::to_owned_canon_path(out)))
+                    .and_then(|fid| loader.graph.file(fid).input);
+                // Only the last of duplicate build statements is kept, with
+                // `-w dupbuild=warn`.
+                if let Some(bid) = build {
+                    self.by_build.remove(&bid);
+                }
+            } else if let Some(file) = line.strip_prefix("include ") {
+                let file = eval(file.trim(), vars);
+                self.scan_file(loader, Path::new(&file), vars)?;
+            } else if let Some(file) = line.strip_prefix("subninja ") {
+                // Variables set by a subninja don't leak into its parent.
+                let file = eval(file.trim(), vars);
+                self.scan_file(loader, Path::new(&file), &mut vars.clone())?;
+            } else if let Some((name, value)) = split_binding(&line) {
+                let value = eval(value, vars);
+                vars.insert(name.to_string(), value);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Lines of the file with `$`-escaped newlines joined.
+pub(crate) fn join_continuations(text: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut pending = String::new();
+    for line in text.lines() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if pending.is_empty() {
+            pending.push_str(line);
+        } else {
+            pending.push_str(line.trim_start());
+        }
+        // An odd number of trailing `$` escapes the newline.
+        let dollars = pending.chars().rev().take_while(|&c| c == '$').count();
+        if dollars % 2 == 1 {
+            pending.pop();
+            continue;
+        }
+        lines.push(std::mem::take(&mut pending));
+    }
+    if !pending.is_empty() {
+        lines.push(pending);
+    }
+    lines
+}
+
+// Split `name = value` into its name and unevaluated value.
+pub(crate) fn split_binding(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = line.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(' ') {
+        return None;
+    }
+    Some((name, value.trim_start()))
+}
+
+// The outputs of a build statement, from the text after `build `, explicit
+// ones first.
+pub(crate) fn outputs(rest: &str, vars: &HashMap<String, String>) -> Vec<String> {
+    let mut outs = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ':' => break,
+            '$' => {
+                outs.push(c);
+                outs.extend(chars.next());
+            }
+            _ => outs.push(c),
+        }
+    }
+
+    // Implicit outputs follow a `|`.
+    split_unescaped(&outs)
+        .into_iter()
+        .filter(|word| word != "|")
+        .map(|word| eval(&word, vars))
+        .collect()
+}
+
+// Split on spaces that aren't escaped by `$`.
+fn split_unescaped(s: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '$' => {
+                word.push(c);
+                word.extend(chars.next());
+            }
+            ' ' => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            _ => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+// Expand the `$` escapes and variable references of a value.
+pub(crate) fn eval(value: &str, vars: &HashMap<String, String>) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('{') => {
+                let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                out.push_str(vars.get(&name).map_or("", String::as_str));
+            }
+            Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-' => {
+                let mut name = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                out.push_str(vars.get(&name).map_or("", String::as_str));
+            }
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::load_file;
+    use crate::testutil::test_dir;
+    use std::fs;
+
+    #[test]
+    fn test_read_bindings() {
+        let dir = test_dir("bindings");
+        let ninja = dir.join("build.ninja");
+        fs::write(
+            &ninja,
+            format!(
+                "root = {}\n\
+                 out = obj\n\
+                 include $root/rules.ninja\n\
+                 build $out/a.o: cc a.c\n  \
+                   dyndep = a.dd\n  \
+                   msvc_deps_prefix = Note: including $\n    file:\n\
+                 \n\
+                 build b.o | b.d: cc b.c\n  \
+                   # A comment between bindings.\n  \
+                   pool = console\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("rules.ninja"),
+            "rule cc\n  command = cc $in -o $out\n  dyndep = ignored\n",
+        )
+        .unwrap();
+
+        let loader = load_file(&ninja.to_string_lossy(), false).unwrap();
+        let bindings = BuildBindings::read(&loader, &ninja.to_string_lossy()).unwrap();
+        let build = |out: &str| {
+            let fid = loader.graph.files.lookup(out).unwrap();
+            loader.graph.file(fid).input.unwrap()
+        };
+        assert_eq!(bindings.get(build("obj/a.o"), "dyndep"), Some("a.dd"));
+        assert_eq!(
+            bindings.get(build("obj/a.o"), "msvc_deps_prefix"),
+            Some("Note: including file:")
+        );
+        assert_eq!(bindings.get(build("b.o"), "pool"), Some("console"));
+        // Rule-level bindings aren't build-level ones.
+        assert_eq!(bindings.get(build("b.o"), "dyndep"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,76 +1,745 @@
+use crate::bindings::BuildBindings;
+use crate::cache::BuildCache;
+use crate::dupbuild;
+use crate::error::{BuildFailure, NixNinjaError};
+use crate::features;
+use crate::graph_json;
+use crate::progress::{ConsoleProgress, Counts, NoProgress, Progress};
+use crate::relative_from::relative_from;
+use crate::report::BuildReport;
 use crate::task;
+use crate::toolchain::Toolchain;
 use anyhow::bail;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Error, Result};
 use n2::densemap::DenseMap;
 use n2::graph::{Build, BuildId, FileId, Graph};
 use n2::{canon, load, scanner};
+use nix_libstore::derivation::Derivation;
 use nix_ninja_task::derived_file::DerivedFile;
 use nix_tool::{NixTool, StoreConfig};
-use std::collections::HashSet;
 use std::collections::VecDeque;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 
+// Like ninja, give up on a build file that is still stale after being
+// regenerated this many times.
+const MAX_REGENERATIONS: usize = 100;
+
+#[derive(Clone)]
 pub struct BuildConfig {
     pub build_dir: PathBuf,
+    /// Directory the relative paths of the build file are resolved against,
+    /// usually `build_dir`.
+    pub source_root: PathBuf,
     pub store_dir: PathBuf,
+    /// System of the derivations, where their tasks run.
+    pub build_system: String,
+    /// System the toolchain of the build produces artifacts for.
+    pub host_system: String,
     pub nix_tool: String,
     pub extra_inputs: Vec<String>,
+    pub toolchain_manifest: Option<PathBuf>,
+    pub keep_rspfile: bool,
+    pub check_inputs: bool,
+    pub deterministic: bool,
+    /// Whether tasks prefix the lines their command outputs.
+    pub prefix_task_output: bool,
+    /// Add the closure of the store paths referenced by forwarded environment
+    /// variables as inputs, instead of only the paths themselves.
+    pub env_closure: bool,
+    /// Seconds after which the command of a task is killed.
+    pub task_timeout: Option<u64>,
+    /// Shell running the commands of tasks, a name on `$PATH` or a path.
+    pub shell: String,
+    /// Run nix commands with `--offline`.
+    pub offline: bool,
+    pub jobs: usize,
+    /// Number of failed tasks tolerated before stopping, 0 for no limit.
+    pub keep_going: usize,
+    pub jobs_per_rule: HashMap<String, usize>,
+    pub max_inline_input_chars: usize,
+    /// Number of inputSrcs of a derivation above which nix-ninja warns, 0 for
+    /// no limit.
+    pub max_input_srcs: usize,
+    /// Fail instead of warning when a derivation exceeds `max_input_srcs`.
+    pub strict_input_srcs: bool,
+    /// Keep the last of the build statements of an output and warn, instead
+    /// of failing, like ninja's `-w dupbuild=warn`.
+    pub warn_dupbuild: bool,
+    pub forward_env: Vec<String>,
+    pub build_dir_exclude: Vec<String>,
+    /// File caching the generated derivations across sessions, if any.
+    pub cache_file: Option<PathBuf>,
+    pub dump_graph_json: Option<PathBuf>,
+    /// Write a JSON summary of every build to this file.
+    pub report: Option<PathBuf>,
+    pub verbose: bool,
+    /// Only print warnings and failures, in nix-ninja and its tasks.
+    pub quiet: bool,
 }
 
-pub fn build(
-    build_filename: &str,
-    targets: Vec<String>,
+/// Generate the derivations to build `targets` of `build_file`, returning the
+/// derived file of each target for the caller to build or inspect. Progress is
+/// logged unless `config.quiet`, use a `Session` to report it elsewhere and to
+/// get the validations that have to be built along with the targets.
+///
+/// Like in ninja, paths in the build file are relative to the working
+/// directory, which is expected to be `config.build_dir`, unless
+/// `config.source_root` is elsewhere.
+pub fn build_targets(
+    build_file: &Path,
+    targets: &[String],
     config: BuildConfig,
-) -> Result<DerivedFile> {
-    let mut loader = load_file(build_filename)?;
-
-    let nix = NixTool::new(StoreConfig {
-        nix_tool: config.nix_tool,
-        extra_args: Vec::new(),
-    });
-
-    let tools = task::Tools {
-        nix,
-        coreutils: task::which_store_path("coreutils")?,
-        nix_ninja_task: task::which_store_path("nix-ninja-task")?,
+) -> Result<Vec<DerivedFile>, NixNinjaError> {
+    let session = Session::new(&build_file.to_string_lossy(), config)?;
+    build_session_targets(session, targets)
+}
+
+// Build the targets in a new session, which isn't kept for later builds.
+fn build_session_targets(
+    mut session: Session,
+    targets: &[String],
+) -> Result<Vec<DerivedFile>, NixNinjaError> {
+    session.build(targets.to_vec())
+}
+
+/// The Nix system of the machine nix-ninja runs on, like `x86_64-linux`.
+pub fn current_system() -> String {
+    let arch = match std::env::consts::ARCH {
+        "x86" => "i686",
+        arch => arch,
+    };
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
     };
+    format!("{}-{}", arch, os)
+}
 
-    let mut runner = task::Runner::new(
-        tools,
-        task::RunnerConfig {
-            system: "x86_64-linux".to_string(),
-            build_dir: config.build_dir,
-            store_dir: config.store_dir,
-        },
-    )?;
-    runner.read_build_dir(&mut loader.graph.files)?;
-    runner.add_extra_inputs(&mut loader.graph.files, config.extra_inputs)?;
+/// Session keeps the parsed build graph and the generated derivations across
+/// builds, so that subsequent builds only generate derivations again for
+/// builds whose inputs changed.
+pub struct Session {
+    build_filename: String,
+    config: BuildConfig,
+    tools: task::Tools,
+    progress: Box<dyn Progress>,
+    printer: Option<task::DerivationPrinter>,
+    state: Option<SessionState>,
+}
 
-    let mut scheduler = Scheduler::new(&mut loader.graph, &mut runner);
+struct SessionState {
+    loader: load::Loader,
+    runner: task::Runner,
+    build_file_mtime: SystemTime,
 
-    // TODO: Support multiple targets, probably treat it like a dynamically
-    // generated phony target.
-    let Some(name) = targets.iter().next() else {
-        return Err(anyhow!("unimplemented"));
-    };
-    let fid = scheduler
-        .lookup(name)
-        .ok_or_else(|| anyhow!("unknown path requested: {}", name))?;
-    let _ = scheduler.want_file(fid);
-    scheduler.run()?;
+    /// The build regenerating the build file, which runs locally before
+    /// scheduling instead of getting a derivation.
+    regeneration: Option<BuildId>,
+
+    /// Builds whose derivation is still up to date.
+    done: HashSet<BuildId>,
+
+    /// Outputs of the validations of the last build.
+    validations: Vec<DerivedFile>,
+}
+
+impl Session {
+    pub fn new(build_filename: &str, config: BuildConfig) -> Result<Self> {
+        let nix = NixTool::new(StoreConfig {
+            nix_tool: config.nix_tool.clone(),
+            extra_args: Vec::new(),
+            offline: config.offline,
+            realise: false,
+        });
+
+        let toolchain = match &config.toolchain_manifest {
+            Some(path) => Toolchain::from_manifest(path, &config.store_dir)?,
+            None => Toolchain::new(&config.store_dir),
+        };
+
+        let tools = task::Tools {
+            nix,
+            coreutils: toolchain.resolve("coreutils")?,
+            nix_ninja_task: toolchain.resolve("nix-ninja-task")?,
+            shell: task::Shell::resolve(&toolchain, &config.shell)?,
+            toolchain,
+        };
+
+        Ok(Self::with_tools(build_filename, config, tools))
+    }
+
+    /// Report the progress of builds to `progress` instead of the console.
+    pub fn with_progress(mut self, progress: Box<dyn Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Print the derivations of the targets to `out` as JSON as they are
+    /// generated, before they are added to the store, so that the ones the
+    /// store rejects can be inspected too.
+    pub fn print_derivations(mut self, out: impl Write + Send + 'static) -> Self {
+        self.printer = Some(Arc::new(Mutex::new(Box::new(out))));
+        self
+    }
+
+    pub(crate) fn with_tools(
+        build_filename: &str,
+        config: BuildConfig,
+        tools: task::Tools,
+    ) -> Self {
+        // Like paths in the build file, its own path is relative to the build
+        // directory.
+        let build_filename = config.build_dir.join(build_filename);
+        Session {
+            build_filename: build_filename.to_string_lossy().into_owned(),
+            progress: if config.quiet {
+                Box::new(NoProgress)
+            } else {
+                Box::new(ConsoleProgress::new(config.verbose))
+            },
+            config,
+            tools,
+            printer: None,
+            state: None,
+        }
+    }
+
+    /// Generate the derivation for the targets, reusing the derivations of
+    /// previous builds that are still up to date.
+    pub fn build(&mut self, targets: Vec<String>) -> Result<Vec<DerivedFile>, NixNinjaError> {
+        let started = Instant::now();
+        let mut regenerations = 0;
+        let mut state = loop {
+            // The build file is only parsed again when it changed, otherwise
+            // FileIds and BuildIds of the previous build are still valid.
+            let build_file_mtime = fs::metadata(&self.build_filename)?.modified()?;
+            let state = match self.state.take() {
+                Some(mut state) if state.build_file_mtime == build_file_mtime => {
+                    if state.invalidate_changed() {
+                        state
+                    } else {
+                        self.load(build_file_mtime)?
+                    }
+                }
+                _ => self.load(build_file_mtime)?,
+            };
+
+            // Regenerate a stale build file and load it again, like ninja
+            // does before building anything.
+            let Some(cmdline) = state.stale_regeneration(&self.config.source_root)? else {
+                break state;
+            };
+            if regenerations == MAX_REGENERATIONS {
+                return Err(anyhow!(
+                    "{} is still stale after regenerating it {} times",
+                    self.build_filename,
+                    MAX_REGENERATIONS
+                )
+                .into());
+            }
+            regenerations += 1;
+            self.regenerate(&cmdline)?;
+        };
+
+        // A failed build may leave tasks running, so its state is discarded.
+        // The derivations generated until then are still cached.
+        let result = state.build(
+            targets,
+            self.config.keep_going,
+            self.progress.as_mut(),
+            self.printer.as_ref(),
+        );
+        state.runner.save_cache()?;
+        let (stats, derivations_added) = state.runner.take_stats();
+        if let Some(path) = &self.config.report {
+            let derived_files = result.as_deref().ok();
+            BuildReport::new(
+                &state.loader.graph,
+                stats,
+                derivations_added,
+                derived_files,
+                started.elapsed(),
+            )
+            .write(path)?;
+        }
+        let derived_files = result?;
+        if let Some(path) = &self.config.dump_graph_json {
+            graph_json::write(path, &state.loader.graph, &state.runner.resolved)?;
+        }
+        self.state = Some(state);
+
+        if let Some(path) = &self.config.toolchain_manifest {
+            self.tools.toolchain.write_manifest(path)?;
+        }
+
+        Ok(derived_files)
+    }
 
-    // println!("Successfully generated all derivations");
+    /// Outputs of the validations of the builds needed by the last build,
+    /// which must be built along with its targets for the build to succeed.
+    pub fn validations(&self) -> &[DerivedFile] {
+        self.state
+            .as_ref()
+            .map_or(&[], |state| state.validations.as_slice())
+    }
+
+    /// The derivation generated for `target` by the last build, as it was
+    /// added to the store, or None if there is none.
+    pub fn derivation(&self, target: &str) -> Result<Option<Derivation>> {
+        let Some(drv_path) = self
+            .resolved_build(target)
+            .and_then(|r| r.drv_path.as_ref())
+        else {
+            return Ok(None);
+        };
+        // Derivations aren't kept in memory, as there is one per build.
+        let drv = self
+            .tools
+            .nix
+            .derivation_show_parsed(drv_path)?
+            .into_values()
+            .next()
+            .ok_or_else(|| anyhow!("nix derivation show returned nothing for {}", drv_path))?;
+        Ok(Some(drv))
+    }
+
+    /// The inputs of the derivation generated for `target` by the last build,
+    /// with where each of them was found.
+    pub fn derivation_inputs(&self, target: &str) -> Option<&[task::TaskInput]> {
+        let resolved = self.resolved_build(target)?;
+        resolved.drv_path.as_ref()?;
+        Some(&resolved.derivation_inputs)
+    }
+
+    // The build of `target` in the last build.
+    fn resolved_build(&self, target: &str) -> Option<&task::ResolvedBuild> {
+        let state = self.state.as_ref()?;
+        let files = &state.loader.graph.files;
+        let fid = files.lookup(&canon::to_owned_canon_path(target))?;
+        let bid = files.by_id[fid].input?;
+        state.runner.resolved.get(&bid)
+    }
+
+    // Run the command regenerating the build file in the build directory.
+    fn regenerate(&self, cmdline: &str) -> Result<()> {
+        tracing::info!("nix-ninja: regenerating {}", self.build_filename);
+        let status = Command::new(&self.tools.shell.path)
+            .arg("-c")
+            .arg(cmdline)
+            .current_dir(&self.config.source_root)
+            .status()
+            .map_err(|err| anyhow!("Failed to run {}: {}", cmdline, err))?;
+        if !status.success() {
+            bail!(
+                "Failed to regenerate {} with {}: {}",
+                self.build_filename,
+                cmdline,
+                status
+            );
+        }
+        Ok(())
+    }
+
+    fn load(&self, build_file_mtime: SystemTime) -> Result<SessionState> {
+        let mut loader = load_file(&self.build_filename, self.config.warn_dupbuild)?;
+        let bindings = BuildBindings::read(&loader, &self.build_filename)?;
+        features::check(&loader, &bindings)?;
+
+        // Like paths in the build file, its name in the graph is relative to
+        // the source root.
+        let build_file = Path::new(&self.build_filename);
+        let build_file =
+            relative_from(build_file, &self.config.source_root).unwrap_or(build_file.to_path_buf());
+        let regeneration = detach_regeneration(&mut loader.graph, &build_file.to_string_lossy());
 
-    let derived_file = runner.derived_files.get(&fid).ok_or(anyhow!(
-        "Missing derived file {:?} for target {}",
-        fid,
-        name
-    ))?;
+        // Bound the number of derivations being generated concurrently, each of
+        // which shells out to nix.
+        let jobs = match self.config.jobs {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            jobs => jobs,
+        };
 
-    Ok(derived_file.clone())
+        let mut runner = task::Runner::new(
+            self.tools.clone(),
+            task::RunnerConfig {
+                build_system: self.config.build_system.clone(),
+                host_system: self.config.host_system.clone(),
+                source_root: self.config.source_root.clone(),
+                store_dir: self.config.store_dir.clone(),
+                keep_rspfile: self.config.keep_rspfile,
+                check_inputs: self.config.check_inputs,
+                deterministic: self.config.deterministic,
+                prefix_task_output: self.config.prefix_task_output,
+                quiet: self.config.quiet,
+                env_closure: self.config.env_closure,
+                task_timeout: self.config.task_timeout,
+                jobs,
+                jobs_per_rule: self.config.jobs_per_rule.clone(),
+                pool_depths: pool_depths(&loader),
+                max_inline_input_chars: self.config.max_inline_input_chars,
+                max_input_srcs: self.config.max_input_srcs,
+                strict_input_srcs: self.config.strict_input_srcs,
+                forward_env: self.config.forward_env.clone(),
+                build_dir_exclude: self.config.build_dir_exclude.clone(),
+                msvc_deps_prefix: msvc_deps_prefix(&loader, &bindings),
+                cache_file: self.config.cache_file.clone(),
+            },
+        )?;
+        runner.read_build_dir(&mut loader.graph.files)?;
+        runner.add_extra_inputs(&mut loader.graph, self.config.extra_inputs.clone())?;
+
+        Ok(SessionState {
+            loader,
+            runner,
+            build_file_mtime,
+            regeneration,
+            done: HashSet::new(),
+            validations: Vec::new(),
+        })
+    }
 }
 
-fn load_file(build_filename: &str) -> Result<load::Loader> {
+impl SessionState {
+    fn build(
+        &mut self,
+        targets: Vec<String>,
+        keep_going: usize,
+        progress: &mut dyn Progress,
+        printer: Option<&task::DerivationPrinter>,
+    ) -> Result<Vec<DerivedFile>, NixNinjaError> {
+        let roots = roots(&self.loader, &targets)?;
+        let validations = validations(&self.loader.graph, &roots);
+        if let Some(printer) = printer {
+            let files = &self.loader.graph.files;
+            let bids = roots.iter().filter_map(|&fid| files.by_id[fid].input);
+            self.runner.print_derivations(bids, printer.clone());
+        }
+
+        let mut scheduler = Scheduler::new(
+            &mut self.loader.graph,
+            &mut self.runner,
+            progress,
+            keep_going,
+            &self.done,
+        );
+        for &fid in roots.iter().chain(&validations) {
+            scheduler.want_file(fid)?;
+        }
+        scheduler.run()?;
+
+        let done = scheduler.done_builds();
+        self.done.extend(done);
+
+        self.validations = self.derived_files(&validations)?;
+        Ok(self.derived_files(&roots)?)
+    }
+
+    /// The command regenerating the build file if any of its sources is
+    /// newer than it. Paths in the graph are relative to `source_root`.
+    fn stale_regeneration(&self, source_root: &Path) -> Result<Option<String>> {
+        let Some(bid) = self.regeneration else {
+            return Ok(None);
+        };
+        let graph = &self.loader.graph;
+        let build = &graph.builds[bid];
+
+        for &fid in build.dirtying_ins() {
+            let file = &graph.files.by_id[fid];
+            // Generated inputs only exist in the store.
+            if file.input.is_some() {
+                continue;
+            }
+            let mtime = fs::metadata(source_root.join(&file.name))
+                .and_then(|m| m.modified())
+                .map_err(|_| {
+                    anyhow!(
+                        "'{}', needed to regenerate the build file, missing and no known rule to make it",
+                        file.name
+                    )
+                })?;
+            if mtime > self.build_file_mtime {
+                return Ok(build.cmdline.clone());
+            }
+        }
+        Ok(None)
+    }
+
+    fn derived_files(&self, fids: &[FileId]) -> Result<Vec<DerivedFile>> {
+        let mut derived_files = Vec::new();
+        for &fid in fids {
+            let derived_file = self.runner.derived_files.get(&fid).ok_or(anyhow!(
+                "Missing derived file {:?} for target {}",
+                fid,
+                self.loader.graph.files.by_id[fid].name
+            ))?;
+            derived_files.push(derived_file.clone());
+        }
+        Ok(derived_files)
+    }
+
+    /// Forget the derivations of builds affected by sources that changed since
+    /// the previous build. Returns false if the changes affect every build, in
+    /// which case the state isn't worth reusing.
+    fn invalidate_changed(&mut self) -> bool {
+        let changed = self.runner.take_changed_sources();
+        if changed
+            .iter()
+            .any(|&fid| self.runner.is_build_dir_input(fid))
+        {
+            return false;
+        }
+
+        let files = &self.loader.graph.files;
+        let mut stack: Vec<BuildId> = Vec::new();
+        for fid in changed {
+            stack.extend(files.by_id[fid].dependents.iter().copied());
+            stack.extend(self.runner.undeclared_consumers(fid));
+        }
+
+        while let Some(bid) = stack.pop() {
+            if !self.done.remove(&bid) {
+                continue;
+            }
+            for &fid in self.loader.graph.builds[bid].outs() {
+                self.runner.forget(fid);
+                stack.extend(files.by_id[fid].dependents.iter().copied());
+            }
+        }
+        true
+    }
+}
+
+/// Whether the output symlinks of `targets` left by a previous build are newer
+/// than the build file and all the source inputs needed to build them, like
+/// ninja's staleness check, in which case there is nothing to do.
+///
+/// Sources include the inputs discovered by the previous build, like headers,
+/// as recorded in `cache`. Outputs are linked in `build_dir` while the other
+/// paths of the graph are relative to `source_root`. Missing files count as
+/// changed.
+pub(crate) fn output_up_to_date(
+    graph: &Graph,
+    cache: &BuildCache,
+    build_dir: &Path,
+    source_root: &Path,
+    build_filename: &Path,
+    targets: &[String],
+) -> Result<bool> {
+    if targets.is_empty() {
+        return Ok(false);
+    }
+
+    for target in targets {
+        let fid = graph
+            .files
+            .lookup(&canon::to_owned_canon_path(target))
+            .ok_or_else(|| anyhow!("unknown path requested: {}", target))?;
+
+        // The symlink must still point to the output in the store.
+        let output = build_dir.join(&graph.files.by_id[fid].name);
+        if !fs::symlink_metadata(&output).is_ok_and(|m| m.file_type().is_symlink())
+            || !output.exists()
+        {
+            return Ok(false);
+        }
+        let output_mtime = fs::symlink_metadata(&output)?.modified()?;
+
+        let mut sources = vec![build_dir.join(build_filename)];
+        for bid in reachable_builds(graph, &[fid]) {
+            let build = &graph.builds[bid];
+            for &input in build.ordering_ins() {
+                let file = &graph.files.by_id[input];
+                if file.input.is_none() {
+                    sources.push(source_root.join(&file.name));
+                }
+            }
+
+            // Cache entries are named by the first output of their build.
+            let Some(&out) = build.outs().first() else {
+                continue;
+            };
+            if let Some(entry) = cache.get(&graph.files.by_id[out].name) {
+                for discovered in &entry.discovered {
+                    sources.push(source_root.join(&discovered.source));
+                }
+            }
+        }
+
+        for source in sources {
+            match fs::metadata(&source).and_then(|m| m.modified()) {
+                Ok(mtime) if mtime < output_mtime => {}
+                _ => return Ok(false),
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Find the build producing the build file named `build_filename` and make
+/// its outputs sources of the graph, as the build file is regenerated locally
+/// rather than by a derivation.
+fn detach_regeneration(graph: &mut Graph, build_filename: &str) -> Option<BuildId> {
+    let fid = graph
+        .files
+        .lookup(&canon::to_owned_canon_path(build_filename))?;
+    let bid = graph.files.by_id[fid].input?;
+    // A phony build can't regenerate anything.
+    graph.builds[bid].cmdline.as_ref()?;
+
+    let outs = graph.builds[bid].outs().to_vec();
+    for fid in outs {
+        graph.files.by_id[fid].input = None;
+    }
+    Some(bid)
+}
+
+// The `msvc_deps_prefix` of builds setting it, either on the build statement
+// or on its rule.
+fn msvc_deps_prefix(loader: &load::Loader, bindings: &BuildBindings) -> HashMap<BuildId, String> {
+    loader
+        .graph
+        .builds
+        .all_ids()
+        .filter_map(|bid| {
+            let prefix = bindings.get(bid, "msvc_deps_prefix").or_else(|| {
+                let vars = loader.rules.get(&loader.graph.builds[bid].rule)?;
+                vars.get("msvc_deps_prefix").map(|prefix| prefix.as_str())
+            })?;
+            Some((bid, prefix.to_string()))
+        })
+        .collect()
+}
+
+// The depth of the pools declared with one, and of the console pool.
+fn pool_depths(loader: &load::Loader) -> HashMap<String, usize> {
+    let mut depths: HashMap<String, usize> = loader
+        .pools
+        .iter()
+        .filter(|(_, depth)| *depth > 0)
+        .map(|(name, depth)| (name.clone(), *depth))
+        .collect();
+    depths.insert(task::CONSOLE_POOL.to_string(), 1);
+    depths
+}
+
+/// Files to build for `targets`. Like ninja, the `default` targets of the
+/// build file are built when no targets are given, or else every output that
+/// isn't an input of another build.
+///
+/// Phony targets are replaced by their inputs, as they have no derivation of
+/// their own.
+fn roots(loader: &load::Loader, targets: &[String]) -> Result<Vec<FileId>, NixNinjaError> {
+    let graph = &loader.graph;
+    let mut stack = Vec::new();
+    let mut unknown = Vec::new();
+    for name in targets {
+        match graph.files.lookup(&canon::to_owned_canon_path(name)) {
+            Some(fid) => stack.push(fid),
+            None => unknown.push(name.clone()),
+        }
+    }
+    // Report all the unknown targets at once, as there can be many when they
+    // are read from a file.
+    if !unknown.is_empty() {
+        return Err(NixNinjaError::UnknownTargets(unknown));
+    }
+    if targets.is_empty() {
+        stack = loader.default.clone();
+    }
+    if stack.is_empty() {
+        stack = graph
+            .files
+            .all_ids()
+            .filter(|&fid| {
+                let file = &graph.files.by_id[fid];
+                file.input.is_some() && file.dependents.is_empty()
+            })
+            .collect();
+    }
+    Ok(expand_phony(graph, stack))
+}
+
+// Replace the outputs of phony builds by their inputs, recursively.
+fn expand_phony(graph: &Graph, mut files: Vec<FileId>) -> Vec<FileId> {
+    files.reverse();
+
+    let mut expanded = Vec::new();
+    let mut visited = HashSet::new();
+    while let Some(fid) = files.pop() {
+        if !visited.insert(fid) {
+            continue;
+        }
+        match graph.files.by_id[fid].input {
+            Some(bid) if graph.builds[bid].cmdline.is_none() => {
+                files.extend(graph.builds[bid].ordering_ins().iter().rev());
+            }
+            _ => expanded.push(fid),
+        }
+    }
+    expanded
+}
+
+/// Outputs of the validations of the builds needed for `roots`. Like in
+/// ninja, validations are built along with the roots, without being inputs
+/// of the builds declaring them, and may have validations of their own.
+fn validations(graph: &Graph, roots: &[FileId]) -> Vec<FileId> {
+    let mut validations = Vec::new();
+    let mut seen: HashSet<FileId> = roots.iter().copied().collect();
+    let mut pending = roots.to_vec();
+    while !pending.is_empty() {
+        let found: Vec<FileId> = reachable_builds(graph, &pending)
+            .into_iter()
+            .flat_map(|bid| graph.builds[bid].validation_ins().iter().copied())
+            .collect();
+        pending = expand_phony(graph, found)
+            .into_iter()
+            .filter(|&fid| seen.insert(fid))
+            .collect();
+        validations.extend(&pending);
+    }
+    validations
+}
+
+/// Builds needed to build `roots`, ordered such that builds come after the
+/// builds of their inputs.
+pub(crate) fn reachable_builds(graph: &Graph, roots: &[FileId]) -> Vec<BuildId> {
+    let mut builds = Vec::new();
+    let mut visited = HashSet::new();
+    // Builds are pushed once all their inputs have been visited.
+    let mut stack: Vec<(FileId, bool)> = roots.iter().rev().map(|&fid| (fid, false)).collect();
+    while let Some((fid, inputs_visited)) = stack.pop() {
+        let Some(bid) = graph.files.by_id[fid].input else {
+            continue;
+        };
+        if inputs_visited {
+            builds.push(bid);
+            continue;
+        }
+        if !visited.insert(bid) {
+            continue;
+        }
+        stack.push((fid, true));
+        for &fid in graph.builds[bid].ordering_ins().iter().rev() {
+            stack.push((fid, false));
+        }
+    }
+    builds
+}
+
+/// Load the build file along with the files it `include`s and `subninja`s,
+/// which like in ninja are resolved relative to the working directory (the
+/// `-C` directory) rather than to the including file.
+///
+/// Duplicate outputs are errors, unless `warn_dupbuild`, in which case the
+/// last build statement of an output is kept like with ninja's
+/// `-w dupbuild=warn`.
+pub(crate) fn load_file(build_filename: &str, warn_dupbuild: bool) -> Result<load::Loader> {
     let mut loader = load::Loader::new();
 
     let id = loader
@@ -79,16 +748,62 @@ fn load_file(build_filename: &str) -> Result<load::Loader> {
         .id_from_canonical(canon::to_owned_canon_path(build_filename));
 
     let path = loader.graph.file(id).path().to_path_buf();
-    let bytes = match scanner::read_file_with_nul(&path) {
-        Ok(b) => b,
-        Err(e) => bail!("read {}: {}", path.display(), e),
+    let temp_dir = std::env::temp_dir().join(format!(
+        "nix-ninja-dupbuild-subninjas-{}",
+        std::process::id()
+    ));
+    let rewritten = if warn_dupbuild {
+        dupbuild::keep_last(&path, &temp_dir)?
+    } else {
+        None
+    };
+    let bytes = match rewritten {
+        Some(text) => {
+            let mut bytes = text.into_bytes();
+            bytes.push(0);
+            bytes
+        }
+        None => match scanner::read_file_with_nul(&path) {
+            Ok(b) => b,
+            Err(e) => bail!("read {}: {}", path.display(), e),
+        },
     };
 
-    loader.parse(path, &bytes)?;
+    let result = loader.parse(path, &bytes);
+    if temp_dir.exists() {
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+    result?;
 
     Ok(loader)
 }
 
+/// Error for a dependency cycle between files in the build graph.
+#[derive(Debug)]
+pub struct DependencyCycle {
+    /// Files forming the cycle, starting and ending with the same file.
+    pub files: Vec<FileId>,
+    names: Vec<String>,
+}
+
+impl DependencyCycle {
+    fn new(graph: &Graph, files: Vec<FileId>) -> Self {
+        let names = files
+            .iter()
+            .map(|&fid| graph.files.by_id[fid].name.clone())
+            .collect();
+        DependencyCycle { files, names }
+    }
+}
+
+impl std::fmt::Display for DependencyCycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "dependency cycle: {}", self.names.join(" -> "))
+    }
+}
+
+impl std::error::Error for DependencyCycle {}
+
 /// Build steps go through this sequence of states.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BuildState {
@@ -102,27 +817,44 @@ pub enum BuildState {
     Running,
     /// Derivation has been written to the Nix store.
     Done,
+    /// Generating the derivation failed.
+    Failed,
 }
 
 /// BuildStates is a state machine for build targets.
 ///
 /// It tracks the progress of each build and lets the Scheduler know when a
 /// build is ready to be started.
-struct BuildStates {
+pub(crate) struct BuildStates {
     states: DenseMap<BuildId, BuildState>,
 
     /// Total number of builds that haven't had a derivation generated yet.
     total_pending: usize,
 
+    /// Builds that had a derivation generated in this run.
+    total_done: usize,
+
+    /// Builds whose derivation is being generated.
+    total_running: usize,
+
     /// Builds in the ready state, stored redundantly for quick access.
     ready: VecDeque<BuildId>,
 }
 
 impl BuildStates {
-    fn new(size: BuildId) -> Self {
+    pub(crate) fn new(size: BuildId, done: &HashSet<BuildId>) -> Self {
+        // Builds done by a previous run are treated as if they were already
+        // generated in this run.
+        let mut states = DenseMap::new_sized(size, BuildState::Unneeded);
+        for &bid in done {
+            states[bid] = BuildState::Done;
+        }
+
         BuildStates {
-            states: DenseMap::new_sized(size, BuildState::Unneeded),
+            states,
             total_pending: 0,
+            total_done: 0,
+            total_running: 0,
             ready: VecDeque::new(),
         }
     }
@@ -134,36 +866,52 @@ impl BuildStates {
     fn set(&mut self, bid: BuildId, state: BuildState) {
         let prev = std::mem::replace(&mut self.states[bid], state);
 
-        if prev == BuildState::Unneeded {
-            self.total_pending += 1;
+        match prev {
+            BuildState::Unneeded => self.total_pending += 1,
+            BuildState::Running => self.total_running -= 1,
+            _ => {}
         }
 
         match state {
             BuildState::Ready => {
                 self.ready.push_back(bid);
             }
+            BuildState::Running => {
+                self.total_running += 1;
+            }
             BuildState::Done => {
                 self.total_pending -= 1;
+                self.total_done += 1;
             }
             _ => {}
         }
     }
 
+    fn counts(&self) -> Counts {
+        Counts {
+            done: self.total_done,
+            running: self.total_running,
+            pending: self.total_pending,
+        }
+    }
+
     fn unfinished(&self) -> bool {
         self.total_pending > 0
     }
 
-    fn want_file(&mut self, graph: &Graph, stack: &mut Vec<FileId>, fid: FileId) -> Result<bool> {
+    pub(crate) fn want_file(
+        &mut self,
+        graph: &Graph,
+        stack: &mut Vec<FileId>,
+        fid: FileId,
+    ) -> Result<bool> {
         let file = &graph.files.by_id[fid];
 
         // Check for a dependency cycle.
         if let Some(cycle) = stack.iter().position(|&sid| sid == fid) {
-            let mut err = "dependency cycle: ".to_string();
-            for &fid in stack[cycle..].iter() {
-                err.push_str(&format!("{} -> ", graph.files.by_id[fid].name));
-            }
-            err.push_str(&file.name);
-            anyhow::bail!(err);
+            let mut files = stack[cycle..].to_vec();
+            files.push(fid);
+            return Err(DependencyCycle::new(graph, files).into());
         }
 
         let mut ready = true;
@@ -205,16 +953,22 @@ impl BuildStates {
 
         self.set(bid, state);
 
-        for &fid in build.validation_ins() {
-            let _ = self.want_file(graph, stack, fid)?;
-        }
-
+        // Validations aren't dependencies of the build, they are wanted
+        // along with the roots of the build instead, see `validations`.
         Ok(state)
     }
 
     pub fn pop_ready(&mut self) -> Option<BuildId> {
         self.ready.pop_front()
     }
+
+    // Put builds that were popped but couldn't be started back in front of
+    // the ready queue, in their original order.
+    fn unpop_ready(&mut self, bids: Vec<BuildId>) {
+        for bid in bids.into_iter().rev() {
+            self.ready.push_front(bid);
+        }
+    }
 }
 
 /// Topological scheduler of a Ninja build graph.
@@ -224,22 +978,40 @@ impl BuildStates {
 struct Scheduler<'a> {
     graph: &'a mut Graph,
     runner: &'a mut task::Runner,
+    progress: &'a mut dyn Progress,
     build_states: BuildStates,
+
+    /// Number of failed tasks tolerated before stopping, 0 for no limit.
+    keep_going: usize,
+    failures: Vec<(BuildId, Error)>,
 }
 
 impl<'a> Scheduler<'a> {
-    fn new(graph: &'a mut Graph, runner: &'a mut task::Runner) -> Self {
+    fn new(
+        graph: &'a mut Graph,
+        runner: &'a mut task::Runner,
+        progress: &'a mut dyn Progress,
+        keep_going: usize,
+        done: &HashSet<BuildId>,
+    ) -> Self {
         let build_count = graph.builds.next_id();
 
         Scheduler {
             graph,
             runner,
-            build_states: BuildStates::new(build_count),
+            progress,
+            build_states: BuildStates::new(build_count, done),
+            keep_going,
+            failures: Vec::new(),
         }
     }
 
-    pub fn lookup(&self, name: &str) -> Option<FileId> {
-        self.graph.files.lookup(&canon::to_owned_canon_path(name))
+    fn done_builds(&self) -> Vec<BuildId> {
+        self.graph
+            .builds
+            .all_ids()
+            .filter(|&bid| self.build_states.get(bid) == BuildState::Done)
+            .collect()
     }
 
     pub fn want_file(&mut self, fid: FileId) -> Result<()> {
@@ -293,26 +1065,930 @@ impl<'a> Scheduler<'a> {
         }
     }
 
-    fn run(&mut self) -> Result<()> {
+    fn run(&mut self) -> Result<(), NixNinjaError> {
         while self.build_states.unfinished() {
             let mut made_progress = false;
-            while let Some(bid) = self.build_states.pop_ready() {
+            // Ready builds whose rule or pool is at its job limit.
+            let mut deferred = Vec::new();
+            while self.runner.can_start() {
+                let Some(bid) = self.build_states.pop_ready() else {
+                    break;
+                };
                 let build = &self.graph.builds[bid];
+                if !self.runner.can_start_rule(&build.rule)
+                    || !self.runner.can_start_pool(build.pool.as_deref())
+                {
+                    deferred.push(bid);
+                    continue;
+                }
                 self.build_states.set(bid, BuildState::Running);
-                // println!("Writing derivation for {:?} at {:?}", &bid, &build.location);
+                let command = build.cmdline.as_deref().unwrap_or(&build.rule);
+                self.progress
+                    .task_started(&self.build_states.counts(), command);
                 self.runner.start(&mut self.graph.files, bid, build)?;
                 made_progress = true;
             }
+            self.build_states.unpop_ready(deferred);
 
             if made_progress {
+                self.progress.update(&self.build_states.counts());
                 continue;
             }
 
-            let bid = self.runner.wait(&mut self.graph.files)?;
-            // println!("Derivation for build {:?} has been written", &bid);
-            self.ready_dependents(bid);
+            // Only builds depending on failed builds are left.
+            if !self.runner.is_running() {
+                break;
+            }
+
+            let (bid, result) = self.runner.wait(&mut self.graph.files);
+            match result {
+                Ok(()) => self.ready_dependents(bid),
+                Err(err) => {
+                    self.build_states.set(bid, BuildState::Failed);
+                    self.failures.push((bid, err));
+                    if self.failures.len() == self.keep_going {
+                        return Err(self.failures_error());
+                    }
+                }
+            }
+            self.progress.update(&self.build_states.counts());
         }
 
+        self.progress.finish(&self.build_states.counts());
+        if !self.failures.is_empty() {
+            return Err(self.failures_error());
+        }
         Ok(())
     }
+
+    // Take the failed tasks, naming builds by their first output.
+    fn failures_error(&mut self) -> NixNinjaError {
+        let failures = std::mem::take(&mut self.failures)
+            .into_iter()
+            .map(|(bid, error)| BuildFailure {
+                build: bid,
+                target: match self.graph.builds[bid].outs().first() {
+                    Some(&fid) => self.graph.files.by_id[fid].name.clone(),
+                    None => format!("{:?}", bid),
+                },
+                error,
+            })
+            .collect();
+        NixNinjaError::BuildFailed(failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheEntry, CachedFile, CACHE_FILE};
+    use crate::testutil::{
+        logged_warnings, nix_args, nix_calls, test_dir, test_session, write_executable,
+    };
+    use std::time::Duration;
+
+    // Writes to a buffer shared with the test.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_print_derivations() {
+        let dir = test_dir("print-derivations");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        let ninja = "rule cc\n  command = @CC@ $in -o $out\n\
+                     build main.o: cc ../src/main.c\n\
+                     build app: cc main.o\n";
+
+        // Only the derivations of the targets are printed.
+        let out = SharedBuffer::default();
+        let mut session = test_session(&dir, ninja, |_| {}).print_derivations(out.clone());
+        session.build(vec!["app".to_string()]).unwrap();
+        let printed = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let drv = Derivation::from_json(&printed).unwrap();
+        assert_eq!(drv.name, "ninja-build-app");
+        assert_eq!(Some(drv), session.derivation("app").unwrap());
+
+        // A derivation is printed before nix gets to reject it.
+        let nix = dir.join("rejecting-nix");
+        write_executable(
+            &nix,
+            &format!(
+                "#!/bin/sh
+                 if [ \"$1 $2\" = \"derivation add\" ]; then echo rejected >&2; exit 1; fi
+                 exec {} \"$@\"
+",
+                dir.join("nix").display()
+            ),
+        );
+        let out = SharedBuffer::default();
+        let mut session = test_session(&dir, ninja, |config| {
+            config.nix_tool = nix.to_string_lossy().into_owned();
+        })
+        .print_derivations(out.clone());
+        assert!(session.build(vec!["main.o".to_string()]).is_err());
+        let printed = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            Derivation::from_json(&printed).unwrap().name,
+            "ninja-build-main.o"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_targets() {
+        let dir = test_dir("build-targets");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        let ninja = "rule cc\n  command = @CC@ $in -o $out\n\
+                     build main.o: cc ../src/main.c\n\
+                     build app: cc main.o\n";
+
+        let session = test_session(&dir, ninja, |_| {});
+        let targets = vec!["main.o".to_string(), "app".to_string()];
+        let derived_files = build_session_targets(session, &targets).unwrap();
+        assert_eq!(derived_files.len(), 2);
+        assert_eq!(derived_files[0].source, Path::new("main.o"));
+        assert_eq!(derived_files[1].source, Path::new("app"));
+
+        let session = test_session(&dir, ninja, |_| {});
+        let err = build_session_targets(session, &["missing".to_string()])
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("missing"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_session_reuses_derivations() {
+        let dir = test_dir("session");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        fs::write(dir.join("src/util.c"), "int util() {}").unwrap();
+
+        let mut session = test_session(
+            &dir,
+            "rule cc\n  command = @CC@ $in -o $out\n\
+             build main.o: cc ../src/main.c\n\
+             build util.o: cc ../src/util.c\n\
+             build app: cc main.o util.o\n",
+            |_| {},
+        );
+
+        let first = session.build(vec!["app".to_string()]).unwrap();
+        assert_eq!(nix_calls(&dir, "derivation add"), 3);
+
+        // Nothing changed, so every derivation is reused.
+        let second = session.build(vec!["app".to_string()]).unwrap();
+        assert_eq!(nix_calls(&dir, "derivation add"), 3);
+        assert!(first == second);
+
+        // The generated derivations are kept, the link depending on the
+        // outputs of the compiles.
+        let drv = session.derivation("app").unwrap().unwrap();
+        assert_eq!(drv.name, "ninja-build-app");
+        assert_eq!(drv.input_drvs.len(), 2);
+        assert!(session.derivation("../src/main.c").unwrap().is_none());
+
+        // Only builds depending on the changed source are generated again.
+        let util = fs::File::options()
+            .write(true)
+            .open(dir.join("src/util.c"))
+            .unwrap();
+        util.set_len(0).unwrap();
+        util.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        session.build(vec!["app".to_string()]).unwrap();
+        assert_eq!(nix_calls(&dir, "derivation add"), 5);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_reuses_derivations() {
+        let dir = test_dir("cache-session");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        fs::write(dir.join("src/util.c"), "int util() {}").unwrap();
+
+        let ninja = "rule cc\n  command = @CC@ $in -o $out\n\
+                     build main.o: cc ../src/main.c\n\
+                     build util.o: cc ../src/util.c\n\
+                     build app: cc main.o util.o\n";
+        let cache_file = dir.join("build").join(crate::cache::CACHE_FILE);
+        let new_session = || {
+            test_session(&dir, ninja, |config| {
+                config.cache_file = Some(cache_file.clone());
+            })
+        };
+
+        let first = new_session().build(vec!["app".to_string()]).unwrap();
+        assert_eq!(nix_calls(&dir, "derivation add"), 3);
+        assert!(cache_file.exists());
+
+        // Another session reuses every cached derivation.
+        let mut session = new_session();
+        let second = session.build(vec!["app".to_string()]).unwrap();
+        assert_eq!(nix_calls(&dir, "derivation add"), 3);
+        assert!(first == second);
+        assert_eq!(
+            session.derivation("app").unwrap().unwrap().input_drvs.len(),
+            2
+        );
+
+        // A changed input invalidates the builds depending on it.
+        fs::write(dir.join("src/util.c"), "int util() { return 1; }").unwrap();
+        new_session().build(vec!["app".to_string()]).unwrap();
+        assert_eq!(nix_calls(&dir, "derivation add"), 5);
+
+        // So does a changed command line.
+        let ninja = ninja.replace("-o $out", "-O2 -o $out");
+        test_session(&dir, &ninja, |config| {
+            config.cache_file = Some(cache_file.clone());
+        })
+        .build(vec!["app".to_string()])
+        .unwrap();
+        assert_eq!(nix_calls(&dir, "derivation add"), 8);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_default_targets() {
+        let dir = test_dir("default-targets");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        fs::write(dir.join("src/util.c"), "int util() {}").unwrap();
+        let rules = "rule cc\n  command = @CC@ $in -o $out\n\
+                     build main.o: cc ../src/main.c\n\
+                     build util.o: cc ../src/util.c\n\
+                     build app: cc main.o\n\
+                     build all: phony app util.o\n";
+        let sources = |derived_files: Vec<DerivedFile>| -> Vec<PathBuf> {
+            derived_files.into_iter().map(|f| f.source).collect()
+        };
+
+        // The default phony target is replaced by its inputs.
+        let mut session = test_session(&dir, &format!("{}default all\n", rules), |_| {});
+        let derived_files = session.build(Vec::new()).unwrap();
+        assert_eq!(
+            sources(derived_files),
+            vec![PathBuf::from("app"), PathBuf::from("util.o")]
+        );
+        assert_eq!(nix_calls(&dir, "derivation add"), 3);
+
+        // Explicit targets take precedence over the defaults.
+        let derived_files = session.build(vec!["main.o".to_string()]).unwrap();
+        assert_eq!(sources(derived_files), vec![PathBuf::from("main.o")]);
+
+        // Unknown targets are all reported at once.
+        let targets = ["nope.o", "main.o", "missing.o"].map(String::from).to_vec();
+        let err = session.build(targets).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "unknown paths requested: nope.o, missing.o"
+        );
+        assert!(matches!(
+            err,
+            NixNinjaError::UnknownTargets(targets) if targets == ["nope.o", "missing.o"]
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Without defaults, every output that isn't an input is built.
+        let dir = test_dir("no-default-targets");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        fs::write(dir.join("src/util.c"), "int util() {}").unwrap();
+        let mut session = test_session(&dir, rules, |_| {});
+        let derived_files = session.build(Vec::new()).unwrap();
+        assert_eq!(
+            sources(derived_files),
+            vec![PathBuf::from("app"), PathBuf::from("util.o")]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dependency_cycle() {
+        let dir = test_dir("dependency-cycle");
+        let mut session = test_session(
+            &dir,
+            "rule cc\n  command = @CC@ $in -o $out\n\
+             build a.o: cc b.o\n\
+             build b.o: cc a.o\n\
+             build app: cc a.o\n",
+            |_| {},
+        );
+
+        let err = session.build(vec!["app".to_string()]).err().unwrap();
+        assert_eq!(err.to_string(), "dependency cycle: a.o -> b.o -> a.o");
+        match err {
+            NixNinjaError::DependencyCycle(cycle) => assert_eq!(cycle.files.len(), 3),
+            err => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(nix_calls(&dir, "derivation add"), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keep_going() {
+        let sources = |name: &str| {
+            let dir = test_dir(name);
+            fs::create_dir_all(dir.join("src")).unwrap();
+            for name in ["a", "b", "c"] {
+                fs::write(dir.join(format!("src/{}.c", name)), "").unwrap();
+            }
+            dir
+        };
+        let ninja = "rule cc\n  command = @CC@ $in -o $out\n\
+                     rule missing\n  command = nix-ninja-missing-tool $in -o $out\n\
+                     build a.o: missing ../src/a.c\n\
+                     build b.o: missing ../src/b.c\n\
+                     build c.o: cc ../src/c.c\n\
+                     build app: cc a.o b.o c.o\n";
+
+        // By default the build stops at the first failure.
+        let dir = sources("fail-fast");
+        let mut session = test_session(&dir, ninja, |_| {});
+        let err = session.build(vec!["app".to_string()]).err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("Failed to build task derivation for "));
+        match err {
+            NixNinjaError::BuildFailed(failures) => {
+                assert_eq!(failures.len(), 1);
+                assert!(["a.o", "b.o"].contains(&failures[0].target.as_str()));
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Independent builds keep going and all failures are reported.
+        let dir = sources("keep-going");
+        let mut session = test_session(&dir, ninja, |config| config.keep_going = 0);
+        let err = session.build(vec!["app".to_string()]).err().unwrap();
+        let message = err.to_string();
+        assert!(message.starts_with("2 tasks failed:"), "{}", message);
+        assert!(message.contains("\n  a.o: "));
+        assert!(message.contains("\n  b.o: "));
+        assert_eq!(nix_calls(&dir, "derivation add"), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_validations() {
+        let dir = test_dir("validations");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        let ninja = "rule cc\n  command = @CC@ $in -o $out\n\
+                     build main.o: cc ../src/main.c |@ main.lint\n\
+                     build main.lint: cc ../src/main.c\n\
+                     build app: cc main.o |@ checks\n\
+                     build app.check: cc app |@ app.check.lint\n\
+                     build app.check.lint: cc ../src/main.c\n\
+                     build checks: phony app.check\n\
+                     build unrelated.lint: cc ../src/main.c\n";
+        let sources = |derived_files: &[DerivedFile]| -> Vec<PathBuf> {
+            derived_files.iter().map(|f| f.source.clone()).collect()
+        };
+
+        // Validations may depend on the target, and have validations of
+        // their own.
+        let mut session = test_session(&dir, ninja, |_| {});
+        let derived_files = session.build(vec!["app".to_string()]).unwrap();
+        assert_eq!(sources(&derived_files), vec![PathBuf::from("app")]);
+        assert_eq!(
+            sources(session.validations()),
+            vec![
+                PathBuf::from("main.lint"),
+                PathBuf::from("app.check"),
+                PathBuf::from("app.check.lint")
+            ]
+        );
+        assert_eq!(nix_calls(&dir, "derivation add"), 5);
+
+        // Validations of builds that are already done are still returned.
+        session.build(vec!["main.o".to_string()]).unwrap();
+        assert_eq!(
+            sources(session.validations()),
+            vec![PathBuf::from("main.lint")]
+        );
+        assert_eq!(nix_calls(&dir, "derivation add"), 5);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_pools() {
+        let dir = test_dir("pools");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        fs::write(dir.join("src/util.c"), "int util() {}").unwrap();
+        let ninja = "pool link_pool\n  depth = 1\n\
+                     pool unbounded\n\
+                     rule cc\n  command = @CC@ $in -o $out\n  pool = unbounded\n\
+                     rule link\n  command = @CC@ $in -o $out\n  pool = link_pool\n\
+                     rule gen\n  command = @CC@ $in -o $out\n  pool = console\n\
+                     build main.o: cc ../src/main.c\n\
+                     build util.o: cc ../src/util.c\n\
+                     build app: link main.o util.o\n\
+                     build gen.h: gen ../src/main.c\n";
+        let mut session = test_session(&dir, ninja, |_| {});
+
+        let loader = load_file(&session.build_filename, false).unwrap();
+        let depths = pool_depths(&loader);
+        assert_eq!(depths.get("link_pool"), Some(&1));
+        assert_eq!(depths.get("console"), Some(&1));
+        assert_eq!(depths.get("unbounded"), None);
+
+        session
+            .build(vec!["app".to_string(), "gen.h".to_string()])
+            .unwrap();
+        assert_eq!(nix_calls(&dir, "derivation add"), 4);
+
+        // Console builds stay on the local machine.
+        let prefer_local = |target: &str| {
+            let drv = session.derivation(target).unwrap().unwrap();
+            drv.env.get("preferLocalBuild").cloned()
+        };
+        assert_eq!(prefer_local("gen.h"), Some("1".to_string()));
+        assert_eq!(prefer_local("app"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_regenerate_build_file() {
+        let dir = test_dir("regenerate");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        fs::write(dir.join("src/util.c"), "int util() {}").unwrap();
+        let mut session = test_session(
+            &dir,
+            "rule regen\n  command = cp ../build.ninja.in build.ninja\n  generator = 1\n\
+             rule cc\n  command = @CC@ $in -o $out\n\
+             build build.ninja: regen ../build.ninja.in\n\
+             build main.o: cc ../src/main.c | build.ninja\n",
+            |_| {},
+        );
+        // The shell running the regeneration locally.
+        let shell = dir.join("store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-bash/bin/sh");
+        fs::create_dir_all(shell.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink("/bin/sh", &shell).unwrap();
+
+        // The build file is older than the source it is generated from, which
+        // adds a build.
+        let ninja = dir.join("build/build.ninja");
+        let past = SystemTime::now() - Duration::from_secs(100);
+        fs::File::options()
+            .write(true)
+            .open(&ninja)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+        let regenerated = fs::read_to_string(&ninja).unwrap() + "build util.o: cc ../src/util.c\n";
+        fs::write(dir.join("build.ninja.in"), regenerated).unwrap();
+
+        // Targets of the regenerated build file can be built, and the build
+        // file is a source of the builds depending on it.
+        session
+            .build(vec!["main.o".to_string(), "util.o".to_string()])
+            .unwrap();
+        assert!(session.derivation("build.ninja").unwrap().is_none());
+        assert!(session.derivation("util.o").unwrap().is_some());
+        assert_eq!(nix_calls(&dir, "derivation add"), 2);
+
+        // It isn't regenerated again while up to date.
+        let mtime = fs::metadata(&ninja).unwrap().modified().unwrap();
+        session.build(vec!["main.o".to_string()]).unwrap();
+        assert_eq!(fs::metadata(&ninja).unwrap().modified().unwrap(), mtime);
+
+        // A regeneration that doesn't update the build file gives up.
+        let future = SystemTime::now() + Duration::from_secs(10);
+        let contents = fs::read_to_string(&ninja)
+            .unwrap()
+            .replace("cp ../build.ninja.in build.ninja", "true");
+        fs::write(&ninja, contents).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(dir.join("build.ninja.in"))
+            .unwrap()
+            .set_modified(future)
+            .unwrap();
+        let err = session.build(vec!["main.o".to_string()]).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("still stale after regenerating it"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_input_srcs() {
+        let dir = test_dir("max-input-srcs");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        let ninja = "rule cc\n  command = @CC@ $in -o $out\nbuild main.o: cc ../src/main.c\n";
+
+        // Exceeding the limit only warns by default.
+        logged_warnings();
+        let mut session = test_session(&dir, ninja, |config| config.max_input_srcs = 1);
+        session.build(vec!["main.o".to_string()]).unwrap();
+        assert!(
+            session
+                .derivation("main.o")
+                .unwrap()
+                .unwrap()
+                .input_srcs
+                .len()
+                > 1
+        );
+        assert!(logged_warnings()
+            .contains("nix-ninja: warning: derivation of main.o has 6 inputSrcs, more than 1"));
+
+        let mut session = test_session(&dir, ninja, |config| {
+            config.max_input_srcs = 1;
+            config.strict_input_srcs = true;
+        });
+        let err = session.build(vec!["main.o".to_string()]).err().unwrap();
+        let message = err.to_string();
+        assert!(message.contains("derivation of main.o has 6 inputSrcs, more than 1"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_source_root() {
+        let dir = test_dir("source-root");
+        let source_root = dir.join("source");
+        fs::create_dir_all(&source_root).unwrap();
+        fs::write(source_root.join("main.c"), "#include \"config.h\"").unwrap();
+        fs::write(source_root.join("config.h"), "").unwrap();
+
+        // The build file lives in the build directory, but its paths are
+        // relative to the source root.
+        let mut session = test_session(
+            &dir,
+            "rule cc\n  command = @CC@ -c $in -o $out\nbuild main.o: cc main.c\n",
+            |config| config.source_root = source_root.clone(),
+        );
+        fs::write(dir.join("build/notes.txt"), "").unwrap();
+        session.build(vec!["main.o".to_string()]).unwrap();
+
+        let drv = session.derivation("main.o").unwrap().unwrap();
+        let inputs = &drv.env["NIX_NINJA_INPUTS"];
+        assert!(inputs.contains(":main.c"));
+        assert!(inputs.contains(":config.h"));
+        assert!(!inputs.contains("build.ninja"));
+        assert!(!inputs.contains("notes.txt"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_offline() {
+        let dir = test_dir("offline");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        let mut session = test_session(
+            &dir,
+            "rule cc\n  command = @CC@ $in -o $out\n\
+             build main.o: cc ../src/main.c\n",
+            |config| config.offline = true,
+        );
+
+        session.build(vec!["main.o".to_string()]).unwrap();
+        assert_eq!(nix_calls(&dir, "derivation add"), 1);
+        let args = nix_args(&dir);
+        assert!(args.iter().any(|args| args.contains("store add")));
+        for args in args {
+            assert!(args.starts_with("--offline "), "{}", args);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cross_systems() {
+        let dir = test_dir("cross");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        // A cross compiler runs on the build system.
+        let cross_cc =
+            dir.join("store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-aarch64-cc/bin/aarch64-linux-gcc");
+        write_executable(&cross_cc, "#!/bin/sh\n");
+        let ninja = format!(
+            "rule cc\n  command = {} $in -o $out\n\
+             build main.o: cc ../src/main.c\n",
+            cross_cc.display()
+        );
+        let mut session = test_session(&dir, &ninja, |config| {
+            config.host_system = "aarch64-linux".to_string();
+        });
+
+        session.build(vec!["main.o".to_string()]).unwrap();
+        let drv = session.derivation("main.o").unwrap().unwrap();
+        assert_eq!(drv.system, "x86_64-linux");
+        assert_eq!(
+            drv.env.get("NIX_NINJA_HOST_SYSTEM").map(String::as_str),
+            Some("aarch64-linux")
+        );
+        let cross_cc_store_path = dir
+            .join("store/0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-aarch64-cc")
+            .to_string_lossy()
+            .into_owned();
+        assert!(drv.input_srcs.contains(&cross_cc_store_path));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_derivation_inputs() {
+        use crate::task::InputOrigin;
+
+        let dir = test_dir("derivation-inputs");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "#include \"util.h\"").unwrap();
+        fs::write(dir.join("src/util.h"), "").unwrap();
+        let sdk = dir.join("store/0123456789abcd0123456789abcd0123-sdk");
+        fs::create_dir_all(&sdk).unwrap();
+        // Stands in for cl, printing the headers included by the source.
+        let cl = dir.join("store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-cl/bin/cl");
+        write_executable(
+            &cl,
+            "#!/bin/sh\necho 'Note: including file: ../src/util.h'\n",
+        );
+        let ninja = format!(
+            "rule cl\n  command = {} /LIBPATH:{}/lib $in /Fo$out\n  deps = msvc\n\
+             rule link\n  command = @CC@ $in -o $out\n\
+             build main.obj: cl ../src/main.c\n\
+             build app: link main.obj\n",
+            cl.display(),
+            sdk.display()
+        );
+        let mut session = test_session(&dir, &ninja, |_| {});
+        session.build(vec!["app".to_string()]).unwrap();
+
+        let origins = |target: &str| -> Vec<(InputOrigin, Option<String>)> {
+            session
+                .derivation_inputs(target)
+                .unwrap()
+                .iter()
+                .map(|input| (input.origin, input.source.clone()))
+                .collect()
+        };
+        let main_origins = origins("main.obj");
+        for expected in [
+            (InputOrigin::Explicit, Some("../src/main.c".to_string())),
+            (InputOrigin::Discovered, Some("../src/util.h".to_string())),
+            (InputOrigin::Cmdline, None),
+            (InputOrigin::Tool, Some("coreutils".to_string())),
+            (InputOrigin::Tool, Some("shell".to_string())),
+            (InputOrigin::Tool, Some(cl.to_string_lossy().into_owned())),
+        ] {
+            assert!(main_origins.contains(&expected), "{:?}", expected);
+        }
+
+        // Outputs of other builds are inputs as derivation outputs.
+        let app_inputs = session.derivation_inputs("app").unwrap();
+        let main_obj = app_inputs
+            .iter()
+            .find(|input| input.source.as_deref() == Some("main.obj"))
+            .unwrap();
+        assert_eq!(main_obj.origin, InputOrigin::Explicit);
+        assert!(main_obj.path.ends_with(".drv^main.obj"));
+        assert!(main_obj.to_string().starts_with("explicit   /"));
+        assert!(session.derivation_inputs("../src/main.c").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_states_counts() {
+        let mut loader = load::Loader::new();
+        let ninja = "rule cc\n  command = cc $in -o $out\n\
+                     build main.o: cc main.c\n\
+                     build app: cc main.o\n\0";
+        loader
+            .parse(PathBuf::from("build.ninja"), ninja.as_bytes())
+            .unwrap();
+        let graph = &loader.graph;
+
+        let mut build_states = BuildStates::new(graph.builds.next_id(), &HashSet::new());
+        let app = graph.files.lookup("app").unwrap();
+        build_states.want_file(graph, &mut Vec::new(), app).unwrap();
+        let counts = |done, running, pending| Counts {
+            done,
+            running,
+            pending,
+        };
+        assert_eq!(build_states.counts(), counts(0, 0, 2));
+
+        let bid = build_states.pop_ready().unwrap();
+        build_states.set(bid, BuildState::Running);
+        assert_eq!(build_states.counts(), counts(0, 1, 2));
+        build_states.set(bid, BuildState::Done);
+        assert_eq!(build_states.counts(), counts(1, 0, 1));
+        assert_eq!(build_states.counts().total(), 2);
+    }
+
+    #[test]
+    fn test_load_file_subninja() {
+        let dir = test_dir("subninja");
+        // Like ninja, included paths are relative to the working directory
+        // rather than to the including file, so the fixture refers to the
+        // test directory relative to the working directory of the tests.
+        let root = relative_from(&dir, &std::env::current_dir().unwrap()).unwrap();
+        fs::write(
+            dir.join("build.ninja"),
+            format!(
+                "root = {}\n\
+                 include $root/rules.ninja\n\
+                 build main.o: cc main.c\n\
+                 subninja $root/lib/build.ninja\n",
+                root.display()
+            ),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("rules.ninja"),
+            "rule cc\n  command = cc $in -o $out\n",
+        )
+        .unwrap();
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("lib/build.ninja"),
+            "build lib/util.o: cc lib/util.c\nbuild app: cc main.o lib/util.o\n",
+        )
+        .unwrap();
+
+        let loader = load_file(&root.join("build.ninja").to_string_lossy(), false).unwrap();
+        let graph = &loader.graph;
+        let fid = graph.files.lookup("app").unwrap();
+        let bid = graph.files.by_id[fid].input.unwrap();
+        assert_eq!(graph.builds[bid].rule, "cc");
+        assert_eq!(reachable_builds(graph, &[fid]).len(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_msvc_deps_prefix() {
+        let dir = test_dir("msvc-deps-prefix");
+        let ninja = dir.join("build.ninja");
+        fs::write(
+            &ninja,
+            "rule cl\n  command = cl /c $in /Fo$out\n  deps = msvc\n  \
+               msvc_deps_prefix = Note: including file:\n\
+             build a.obj: cl a.c\n\
+             build b.obj: cl b.c\n  msvc_deps_prefix = Remarque : inclusion du fichier :\n",
+        )
+        .unwrap();
+
+        let loader = load_file(&ninja.to_string_lossy(), false).unwrap();
+        let bindings = BuildBindings::read(&loader, &ninja.to_string_lossy()).unwrap();
+        let prefixes = msvc_deps_prefix(&loader, &bindings);
+        let prefix = |out: &str| {
+            let fid = loader.graph.files.lookup(out).unwrap();
+            prefixes[&loader.graph.file(fid).input.unwrap()].as_str()
+        };
+        // The build-level binding overrides the rule's.
+        assert_eq!(prefix("a.obj"), "Note: including file:");
+        assert_eq!(prefix("b.obj"), "Remarque : inclusion du fichier :");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_file_duplicate_output() {
+        let dir = test_dir("dupbuild");
+        let ninja = dir.join("build.ninja");
+        fs::write(
+            &ninja,
+            "rule cc\n  command = cc $in -o $out\n\
+             build main.o: cc main.c\n\
+             build main.o: cc other.c\n",
+        )
+        .unwrap();
+
+        // Like ninja's dupbuild=err, both build statements are named.
+        let err = load_file(&ninja.to_string_lossy(), false).err().unwrap();
+        let message = err.to_string();
+        assert!(message.contains("\"main.o\" is already an output at"));
+        assert!(message.contains(":3") && message.contains(":4"));
+
+        // Like ninja's dupbuild=warn, the last build statement is kept.
+        let loader = load_file(&ninja.to_string_lossy(), true).unwrap();
+        let fid = loader.graph.files.lookup("main.o").unwrap();
+        let build = &loader.graph.builds[loader.graph.file(fid).input.unwrap()];
+        let inputs: Vec<&str> = build
+            .explicit_ins()
+            .iter()
+            .map(|&fid| loader.graph.file(fid).name.as_str())
+            .collect();
+        assert_eq!(inputs, vec!["other.c"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_output_up_to_date() {
+        let dir = test_dir("up-to-date");
+        let build_dir = dir.join("build");
+        let past = SystemTime::now() - Duration::from_secs(100);
+        let write = |path: &Path, contents: &str, mtime: SystemTime| {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+            let file = fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(mtime).unwrap();
+        };
+
+        let ninja = build_dir.join("build.ninja");
+        write(
+            &ninja,
+            "rule cc\n  command = cc $in -o $out\n\
+             build main.o: cc ../src/main.c\n\
+             build util.o: cc ../src/util.c\n\
+             build app: cc main.o\n",
+            past,
+        );
+        write(&dir.join("src/main.c"), "int main() {}", past);
+        write(&dir.join("src/main.h"), "", past);
+        write(&dir.join("src/util.c"), "int util() {}", past);
+        let loader = load_file(&ninja.to_string_lossy(), false).unwrap();
+
+        // The previous build discovered that main.c includes main.h.
+        let mut cache = BuildCache::load(&build_dir.join(CACHE_FILE));
+        cache.insert(
+            "main.o".to_string(),
+            CacheEntry {
+                key: String::new(),
+                derivation: Derivation::new("ninja-build-main.o", "x86_64-linux", "/bin/sh"),
+                discovered: vec![CachedFile {
+                    path: dir.join("store/main.h").to_string_lossy().into_owned(),
+                    source: PathBuf::from("../src/main.h"),
+                }],
+                outputs: Vec::new(),
+                inputs: Vec::new(),
+            },
+        );
+        let up_to_date = |targets: &[&str]| {
+            let targets: Vec<String> = targets.iter().map(|t| t.to_string()).collect();
+            output_up_to_date(
+                &loader.graph,
+                &cache,
+                &build_dir,
+                &build_dir,
+                Path::new("build.ninja"),
+                &targets,
+            )
+            .unwrap()
+        };
+
+        // No output from a previous build.
+        assert!(!up_to_date(&["app"]));
+
+        write(&dir.join("store/app"), "", past);
+        std::os::unix::fs::symlink(dir.join("store/app"), build_dir.join("app")).unwrap();
+        assert!(up_to_date(&["app"]));
+
+        // Every target needs an output.
+        assert!(!up_to_date(&["app", "util.o"]));
+        write(&dir.join("store/util.o"), "", past);
+        std::os::unix::fs::symlink(dir.join("store/util.o"), build_dir.join("util.o")).unwrap();
+        assert!(up_to_date(&["app", "util.o"]));
+
+        // A discovered header changed after the output was built.
+        let future = SystemTime::now() + Duration::from_secs(10);
+        write(&dir.join("src/main.h"), "#define MAIN", future);
+        assert!(!up_to_date(&["app"]));
+        write(&dir.join("src/main.h"), "", past);
+
+        // A source of any of the targets changed.
+        write(&dir.join("src/util.c"), "int util() { return 1; }", future);
+        assert!(up_to_date(&["app"]));
+        assert!(!up_to_date(&["app", "util.o"]));
+
+        // A source changed after the output was built.
+        write(&dir.join("src/main.c"), "int main() { return 1; }", future);
+        assert!(!up_to_date(&["app"]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
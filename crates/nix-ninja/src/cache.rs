@@ -0,0 +1,185 @@
+use crate::task::TaskInput;
+use anyhow::{anyhow, Context, Result};
+use nix_libstore::derivation::Derivation;
+use nix_libstore::derived_path::SingleDerivedPath;
+use nix_ninja_task::derived_file::DerivedFile;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the cache file in the build directory.
+pub const CACHE_FILE: &str = ".nix-ninja_cache";
+
+// Bumped whenever entries change shape, which discards older caches.
+const CACHE_VERSION: u32 = 1;
+
+/// BuildCache keeps the derivations generated for builds across runs of
+/// nix-ninja, so that builds whose inputs didn't change reuse them instead of
+/// generating them again.
+///
+/// There is one entry per build, named by its first output, which is replaced
+/// when the build is generated again.
+#[derive(Default)]
+pub struct BuildCache {
+    path: Option<PathBuf>,
+    entries: BTreeMap<String, CacheEntry>,
+    changed: bool,
+}
+
+#[derive(Deserialize)]
+struct CacheFile {
+    version: u32,
+    entries: BTreeMap<String, CacheEntry>,
+}
+
+/// The derivation generated for a build, along with what it was generated
+/// from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// Hash of everything the derivation was generated from, except the
+    /// discovered inputs.
+    pub key: String,
+    pub derivation: Derivation,
+    /// Inputs found while generating the derivation, like headers, which must
+    /// still be the same for the entry to be reused.
+    pub discovered: Vec<CachedFile>,
+    pub outputs: Vec<CachedFile>,
+    pub inputs: Vec<TaskInput>,
+}
+
+/// A derived file, as its derived path and source.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CachedFile {
+    pub path: String,
+    pub source: PathBuf,
+}
+
+impl From<&DerivedFile> for CachedFile {
+    fn from(derived_file: &DerivedFile) -> Self {
+        CachedFile {
+            path: format!("{}", derived_file.path),
+            source: derived_file.source.clone(),
+        }
+    }
+}
+
+impl TryFrom<&CachedFile> for DerivedFile {
+    type Error = anyhow::Error;
+
+    fn try_from(cached: &CachedFile) -> Result<Self> {
+        Ok(DerivedFile {
+            path: cached.path.parse::<SingleDerivedPath>()?,
+            source: cached.source.clone(),
+        })
+    }
+}
+
+impl BuildCache {
+    /// Load the cache from `path`. A missing cache is empty, and so is one
+    /// that can't be read, which is only worth a warning since everything is
+    /// generated again.
+    pub fn load(path: &Path) -> Self {
+        let mut cache = BuildCache {
+            path: Some(path.to_path_buf()),
+            ..Default::default()
+        };
+        if !path.exists() {
+            return cache;
+        }
+
+        let file = fs::read_to_string(path)
+            .map_err(|err| anyhow!(err))
+            .and_then(|contents| Ok(serde_json::from_str::<CacheFile>(&contents)?));
+        match file {
+            Ok(file) if file.version == CACHE_VERSION => cache.entries = file.entries,
+            Ok(_) => tracing::debug!(path = %path.display(), "ignoring cache of another version"),
+            Err(err) => tracing::warn!(
+                "nix-ninja: warning: ignoring unreadable cache {}: {}",
+                path.display(),
+                err
+            ),
+        }
+        cache
+    }
+
+    /// The entry of the build named `target`, to be reused if it has the key
+    /// of the build.
+    pub fn get(&self, target: &str) -> Option<&CacheEntry> {
+        self.entries.get(target)
+    }
+
+    pub fn insert(&mut self, target: String, entry: CacheEntry) {
+        if self.entries.get(&target) != Some(&entry) {
+            self.entries.insert(target, entry);
+            self.changed = true;
+        }
+    }
+
+    /// Write the cache if it changed since it was loaded, replacing the
+    /// previous one at once so that an interrupted write doesn't lose it.
+    pub fn save(&mut self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.changed {
+            return Ok(());
+        }
+
+        let json = serde_json::to_string(&serde_json::json!({
+            "version": CACHE_VERSION,
+            "entries": &self.entries,
+        }))?;
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, json)
+            .and_then(|_| fs::rename(&temp, path))
+            .with_context(|| format!("Failed to write cache {}", path.display()))?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::test_dir;
+
+    fn entry(key: &str) -> CacheEntry {
+        CacheEntry {
+            key: key.to_string(),
+            derivation: Derivation::new("ninja-build-main.o", "x86_64-linux", "/bin/sh"),
+            discovered: vec![CachedFile {
+                path: "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-util.h".to_string(),
+                source: PathBuf::from("util.h"),
+            }],
+            outputs: Vec::new(),
+            inputs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_cache_round_trip() {
+        let dir = test_dir("cache");
+        let path = dir.join(CACHE_FILE);
+
+        let mut cache = BuildCache::load(&path);
+        assert!(cache.get("main.o").is_none());
+        cache.insert("main.o".to_string(), entry("a"));
+        cache.save().unwrap();
+
+        // Entries are replaced per build.
+        let mut cache = BuildCache::load(&path);
+        assert_eq!(cache.get("main.o"), Some(&entry("a")));
+        cache.insert("main.o".to_string(), entry("b"));
+        cache.save().unwrap();
+        let cache = BuildCache::load(&path);
+        assert_eq!(cache.get("main.o"), Some(&entry("b")));
+
+        // A corrupt cache is ignored.
+        fs::write(&path, "{").unwrap();
+        assert!(BuildCache::load(&path).get("main.o").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
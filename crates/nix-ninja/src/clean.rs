@@ -0,0 +1,91 @@
+use crate::build::load_file;
+use anyhow::Result;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Removes the symlinks into the Nix store that nix-ninja creates for built
+/// outputs, returning the removed paths.
+///
+/// Real files are never removed, only symlinks whose target resolves under the
+/// store directory.
+pub fn clean(build_filename: &str, warn_dupbuild: bool, store_dir: &Path) -> Result<Vec<PathBuf>> {
+    let loader = load_file(build_filename, warn_dupbuild)?;
+    let graph = &loader.graph;
+
+    let mut outputs: Vec<PathBuf> = Vec::new();
+    for bid in graph.builds.all_ids() {
+        for fid in graph.builds[bid].outs() {
+            outputs.push(PathBuf::from(&graph.files.by_id[*fid].name));
+        }
+    }
+
+    remove_store_symlinks(outputs, store_dir)
+}
+
+fn remove_store_symlinks(paths: Vec<PathBuf>, store_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for path in paths {
+        if !is_store_symlink(&path, store_dir) {
+            continue;
+        }
+        fs::remove_file(&path)?;
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+fn is_store_symlink(path: &Path, store_dir: &Path) -> bool {
+    let Ok(target) = fs::read_link(path) else {
+        return false;
+    };
+
+    let target = match path.parent() {
+        Some(parent) if target.is_relative() => parent.join(target),
+        _ => target,
+    };
+    target.starts_with(store_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_remove_store_symlinks() {
+        let dir = std::env::temp_dir().join(format!("nix-ninja-clean-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let store_link = dir.join("hello");
+        let other_link = dir.join("other");
+        let source = dir.join("main.c");
+        let missing = dir.join("missing");
+        symlink(
+            "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-hello",
+            &store_link,
+        )
+        .unwrap();
+        symlink(&source, &other_link).unwrap();
+        fs::write(&source, "int main() {}").unwrap();
+
+        let removed = remove_store_symlinks(
+            vec![
+                store_link.clone(),
+                other_link.clone(),
+                source.clone(),
+                missing,
+            ],
+            Path::new("/nix/store"),
+        )
+        .unwrap();
+
+        assert_eq!(removed, vec![store_link.clone()]);
+        assert!(fs::symlink_metadata(&store_link).is_err());
+        assert!(fs::symlink_metadata(&other_link).is_ok());
+        assert!(source.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
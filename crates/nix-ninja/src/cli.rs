@@ -1,10 +1,27 @@
-use crate::build::{self, BuildConfig};
+use crate::build::{self, build_targets, BuildConfig, Session};
+use crate::cache::{BuildCache, CACHE_FILE};
+use crate::clean;
+use crate::compdb::{self, CommandStyle};
+use crate::graphviz;
+use crate::targets::{self, TargetsMode};
 use anyhow::{anyhow, Result};
-use clap::Parser;
-use nix_libstore::store_path::StorePath;
+use clap::{ArgAction, Parser, ValueEnum};
+use n2::canon;
+use n2::graph::Graph;
+use n2::load;
+use nix_libstore::derivation::Derivation;
+use nix_libstore::store_path::{sort_by_name, StorePath};
 use nix_ninja_task::derived_file::DerivedFile;
+use nix_ninja_task::KEEP_FAILED_DIR;
 use nix_tool::{NixTool, StoreConfig};
-use std::{env, fs, os::unix::fs::symlink, path::PathBuf, str};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+    str,
+};
 
 #[derive(Parser)]
 #[command(
@@ -21,26 +38,64 @@ pub struct Cli {
     #[arg(short = 'f', default_value = "build.ninja")]
     pub build_filename: PathBuf,
 
+    /// Directory the build runs in, set by `resolve_paths`
+    #[arg(skip)]
+    pub build_dir: PathBuf,
+
+    /// Resolve the relative paths of the build file, like sources, against
+    /// DIR instead of the build directory, which keeps the outputs
+    #[arg(long = "source-root", value_name = "DIR")]
+    pub source_root: Option<PathBuf>,
+
     /// Run a subtool (use '-t list' to list subtools)
     #[arg(short = 't')]
     pub tool: Option<String>,
 
-    /// Run N jobs in parallel (0 means infinity)
-    #[arg(short = 'j', default_value = "0", hide = true)]
+    /// Run N jobs in parallel (0 means the number of CPUs)
+    #[arg(short = 'j', default_value = "0")]
     pub jobs: usize,
 
+    /// Keep going until N jobs fail (0 means infinity)
+    #[arg(short = 'k', long = "keep-going", default_value = "1")]
+    pub keep_going: usize,
+
+    /// Run at most N jobs in parallel for builds of RULE (can be repeated)
+    #[arg(long = "jobs-per-rule", value_name = "RULE=N", value_parser = parse_rule_jobs)]
+    pub jobs_per_rule: Vec<(String, usize)>,
+
     /// Do not start new jobs if the load average is greater than N
     #[arg(short = 'l', default_value = "0.0", hide = true)]
     pub load_average: f64,
 
-    /// Show all command lines while building
-    #[arg(short = 'v', long = "verbose", default_value = "false")]
-    pub verbose: bool,
+    /// Enable debugging (use '-d list' to list modes)
+    #[arg(short = 'd')]
+    pub debug: Vec<String>,
+
+    /// Adjust warnings (use '-w list' to list warnings)
+    #[arg(short = 'w')]
+    pub warnings: Vec<String>,
+
+    /// Generate and add the derivations to the store, but print the derived
+    /// paths of the targets instead of building them
+    #[arg(short = 'n', long = "dry-run")]
+    pub dry_run: bool,
+
+    /// Show all command lines while building, repeat to log in more detail
+    #[arg(short = 'v', long = "verbose", action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Only print warnings and failures, here and in the tasks
+    #[arg(short = 'q', long = "quiet", conflicts_with = "verbose")]
+    pub quiet: bool,
 
     /// Print ninja version
     #[arg(long = "version", default_value = "false")]
     pub print_version: bool,
 
+    /// Print the version of nix-ninja itself
+    #[arg(long = "nix-ninja-version", default_value = "false")]
+    pub print_nix_ninja_version: bool,
+
     /// Specify the Nix store directory
     #[arg(long = "store-dir", default_value = "/nix/store", env = "NIX_STORE")]
     pub store_dir: PathBuf,
@@ -61,6 +116,10 @@ pub struct Cli {
     /// .parser.y. The parser-tab.cc depends on finally.hh but we cannot
     /// determine it during nix-ninja build-time, only at nix-ninja-task
     /// build-time.
+    ///
+    /// Each extra input is given as SELECTOR:PATH, where colons in the
+    /// selector or path are escaped as `\:`. The selector is a target, a
+    /// rule name like `cxx_COMPILER`, or a glob of outputs like `*.tab.cc`.
     #[arg(
         long = "extra-inputs",
         env = "NIX_NINJA_EXTRA_INPUTS",
@@ -68,102 +127,709 @@ pub struct Cli {
     )]
     pub extra_inputs: Vec<String>,
 
-    /// Target to build (only used with certain subtools)
-    #[arg(trailing_var_arg = true)]
+    /// Record the store paths of the toolchain (coreutils, nix-ninja-task and
+    /// compilers) to FILE, or pin them if FILE already exists
+    #[arg(long = "toolchain-manifest", env = "NIX_NINJA_TOOLCHAIN_MANIFEST")]
+    pub toolchain_manifest: Option<PathBuf>,
+
+    /// Pass a task's inputs to nix-ninja-task via a file instead of an
+    /// environment variable when they are longer than N characters
+    #[arg(long = "max-inline-input-chars", default_value = "32768")]
+    pub max_inline_input_chars: usize,
+
+    /// Warn about derivations with more than N inputSrcs, 0 for no limit (use
+    /// '-w inputsrcs=err' to fail instead)
+    #[arg(long = "max-input-srcs", value_name = "N", default_value = "1000")]
+    pub max_input_srcs: usize,
+
+    /// Propagate environment variables matching PATTERN to tasks, where `*`
+    /// matches any characters (can be repeated, NIX_LDFLAGS, NIX_CFLAGS_COMPILE
+    /// and NIX_CC_WRAPPER* are always propagated)
+    #[arg(
+        long = "forward-env",
+        value_name = "PATTERN",
+        env = "NIX_NINJA_FORWARD_ENV",
+        value_delimiter = ','
+    )]
+    pub forward_env: Vec<String>,
+
+    /// Add the closure of the store paths referenced by forwarded environment
+    /// variables as inputs of tasks, not only the paths themselves. This
+    /// queries the store for every path, so it is off by default
+    #[arg(long = "env-closure")]
+    pub env_closure: bool,
+
+    /// Don't pass files in the build directory matching GLOB to tasks, either
+    /// by their relative path or, without a `/`, by their name, where `*`
+    /// matches any characters (can be repeated, `.git` is always excluded)
+    #[arg(long = "build-dir-exclude", value_name = "GLOB")]
+    pub build_dir_exclude: Vec<String>,
+
+    /// Run tasks with a normalized environment (SOURCE_DATE_EPOCH, LC_ALL=C,
+    /// TZ=UTC and PWD) so their outputs don't depend on the machine
+    #[arg(long = "deterministic")]
+    pub deterministic: bool,
+
+    /// Prefix each line output by the command of a task with its description,
+    /// so that the logs of builds running concurrently stay readable.
+    /// Otherwise the output is streamed as is
+    #[arg(long = "prefix-task-output")]
+    pub prefix_task_output: bool,
+
+    /// When the command of a task fails, copy its source tree and what the
+    /// command produced to DIR/<derivation name>. DIR is mounted into the
+    /// sandbox with the `extra-sandbox-paths` setting, which needs a trusted
+    /// user, so derivations don't change. Unlike `nix build --keep-failed`,
+    /// which keeps the whole sandbox of local builds, this also works for
+    /// remote builders
+    #[arg(long = "keep-failed-dir", value_name = "DIR")]
+    pub keep_failed_dir: Option<PathBuf>,
+
+    /// Kill the command of a task that runs for longer than SECONDS, failing
+    /// its build
+    #[arg(long = "task-timeout", value_name = "SECONDS")]
+    pub task_timeout: Option<u64>,
+
+    /// Shell running the commands of tasks instead of the `sh` on $PATH, as
+    /// a name to look up on $PATH or a path
+    #[arg(long = "shell", env = "NIX_NINJA_SHELL", default_value = "sh")]
+    pub shell: String,
+
+    /// Nix system of the generated derivations, where their tasks run.
+    /// Defaults to the system nix-ninja runs on
+    #[arg(long = "build-system", value_name = "SYSTEM")]
+    pub build_system: Option<String>,
+
+    /// Nix system the toolchain produces artifacts for when cross-compiling.
+    /// Defaults to the build system
+    #[arg(long = "host-system", value_name = "SYSTEM")]
+    pub host_system: Option<String>,
+
+    /// Run nix commands with --offline, so that builds fail instead of
+    /// fetching from substituters
+    #[arg(long = "offline")]
+    pub offline: bool,
+
+    /// Build the targets with nix-store --realise instead of nix build, for
+    /// setups where the nix command is restricted
+    #[arg(long = "realise")]
+    pub realise: bool,
+
+    /// Skip generating derivations when the output symlink of the target is
+    /// newer than the build file and all its source inputs
+    #[arg(long = "rebuild-if-newer")]
+    pub rebuild_if_newer: bool,
+
+    /// Copy the closure of the built outputs to STORE, such as a binary cache
+    #[arg(long = "push-to", value_name = "STORE")]
+    pub push_to: Option<String>,
+
+    /// Write the builds resolved to derivations, with their inputs and
+    /// outputs, as JSON to FILE
+    #[arg(long = "dump-graph-json", value_name = "FILE")]
+    pub dump_graph_json: Option<PathBuf>,
+
+    /// Write a JSON summary of the build to FILE once it finished, with the
+    /// number of builds, cache hits and derivations added, and how long the
+    /// derivation of each build took
+    #[arg(long = "report", value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Print the command of the build of TARGET instead of building it
+    #[arg(long = "print-cmdline", value_name = "TARGET")]
+    pub print_cmdline: Option<String>,
+
+    /// With --print-cmdline, print the commands of all builds needed for TARGET
+    #[arg(long = "all", requires = "print_cmdline")]
+    pub all: bool,
+
+    /// Also build the targets listed in FILE, one per line, or read them from
+    /// stdin with `-`
+    #[arg(long = "targets-from", value_name = "FILE")]
+    pub targets_from: Option<PathBuf>,
+
+    /// Targets to build, or the default targets of the build file if none
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     pub targets: Vec<String>,
 }
 
-pub fn run() -> Result<i32> {
-    let cli = Cli::parse();
+impl Cli {
+    /// Resolve the `-C` directory against `cwd` and the build file against
+    /// it, so that the build file, the build directory walked for inputs and
+    /// the paths relative to it all share the same base. `--source-root` is
+    /// resolved against `cwd` too, and defaults to the build directory.
+    fn resolve_paths(&mut self, cwd: &Path) -> Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => cwd.join(dir),
+            None => cwd.to_path_buf(),
+        };
+        self.build_dir =
+            fs::canonicalize(&dir).map_err(|err| anyhow!("chdir to {}: {}", dir.display(), err))?;
+        self.build_filename = self.build_dir.join(&self.build_filename);
+
+        let source_root = match &self.source_root {
+            Some(source_root) => {
+                let source_root = cwd.join(source_root);
+                fs::canonicalize(&source_root)
+                    .map_err(|err| anyhow!("source root {}: {}", source_root.display(), err))?
+            }
+            None => self.build_dir.clone(),
+        };
+        self.source_root = Some(source_root);
+
+        // Tasks run elsewhere, so the directory has to be absolute.
+        if let Some(keep_failed_dir) = &self.keep_failed_dir {
+            self.keep_failed_dir = Some(cwd.join(keep_failed_dir));
+        }
+        Ok(())
+    }
+
+    fn source_root(&self) -> &Path {
+        self.source_root.as_deref().unwrap_or(&self.build_dir)
+    }
+
+    /// Append the targets listed by `--targets-from` to the targets of the
+    /// command line, reading them before changing directory so that FILE is
+    /// relative to where nix-ninja was started.
+    fn read_targets_from(&mut self) -> Result<()> {
+        let Some(path) = &self.targets_from else {
+            return Ok(());
+        };
+        let contents = if path == Path::new("-") {
+            io::read_to_string(io::stdin())?
+        } else {
+            fs::read_to_string(path)
+                .map_err(|err| anyhow!("read targets from {}: {}", path.display(), err))?
+        };
+        self.targets.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string),
+        );
+        Ok(())
+    }
+}
+
+// The crate version, with the git revision it was built from when the build
+// provides one through NIX_NINJA_GIT_REV.
+fn nix_ninja_version() -> String {
+    match option_env!("NIX_NINJA_GIT_REV").filter(|rev| !rev.is_empty()) {
+        Some(rev) => format!("{} ({})", env!("CARGO_PKG_VERSION"), rev),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+impl Cli {
+    /// The tracing filter for the verbosity of the command line, when
+    /// `RUST_LOG` doesn't set one.
+    pub fn log_filter(&self) -> &'static str {
+        match (self.quiet, self.verbose) {
+            (true, _) => "warn",
+            (false, 0 | 1) => "info",
+            (false, 2) => "debug",
+            (false, _) => "trace",
+        }
+    }
+}
 
+pub fn run(mut cli: Cli) -> Result<i32> {
     if cli.print_version {
         // For compatibility with meson, it expects >= 1.8.2.
         println!("1.8.2");
         return Ok(0);
     }
+    if cli.print_nix_ninja_version {
+        println!("nix-ninja {}", nix_ninja_version());
+        return Ok(0);
+    }
 
-    // Change directory if specified
-    if let Some(dir) = &cli.dir {
-        std::env::set_current_dir(dir)?;
+    for mode in &cli.debug {
+        match mode.as_str() {
+            "list" => {
+                println!("debugging modes:");
+                println!("  keeprsp      don't delete @response files on success, and copy them");
+                println!("               to --keep-failed-dir");
+                println!("  checkinputs  verify task inputs exist before running commands");
+                return Ok(0);
+            }
+            "keeprsp" | "checkinputs" => {}
+            _ => return Err(anyhow!("unknown debug setting '{}'", mode)),
+        }
     }
 
+    for warning in &cli.warnings {
+        match warning.as_str() {
+            "list" => {
+                println!("warning flags:");
+                println!("  dupbuild={{err,warn}}  multiple build lines for one target");
+                println!("  inputsrcs={{err,warn}}  derivations with more than --max-input-srcs inputSrcs");
+                return Ok(0);
+            }
+            "dupbuild=err" | "dupbuild=warn" | "inputsrcs=err" | "inputsrcs=warn" => {}
+            _ => return Err(anyhow!("unknown warning flag '{}'", warning)),
+        }
+    }
+
+    // Change directory if specified, as paths in the build file are relative
+    // to the build directory.
+    cli.resolve_paths(&env::current_dir()?)?;
+    cli.read_targets_from()?;
+    env::set_current_dir(&cli.build_dir)?;
+
     // Handle subtool if specified
     if let Some(tool) = cli.tool.clone() {
         return subtool(&cli, &tool);
     }
 
-    match build(&cli) {
-        Ok(derived_file) => {
+    if let Some(target) = &cli.print_cmdline {
+        let loader = load_file(&cli)?;
+        print!("{}", print_cmdline(&loader.graph, target, cli.all)?);
+        return Ok(0);
+    }
+
+    if cli.rebuild_if_newer && output_up_to_date(&cli)? {
+        tracing::info!("nix-ninja: no work to do.");
+        return Ok(0);
+    }
+
+    match build_with_validations(&cli) {
+        Ok((derived_files, validations)) => {
             if cli.is_output_derivation {
+                let derived_file = output_derivation_target(&derived_files, &validations)?;
                 let out = env::var("out").map_err(|_| anyhow!("Expected $out to be set"))?;
                 fs::copy(&derived_file.path.store_path().path(), out)?;
             } else {
-                nix_build(&cli, &derived_file)?;
+                // Like ninja, validations are built along with the targets
+                // and fail the build when they fail.
+                for derived_file in derived_files.iter().chain(&validations) {
+                    nix_build(&cli, derived_file)?;
+                }
             }
             Ok(0)
         }
         Err(err) => {
-            println!("nix-ninja: {}", err);
+            tracing::error!("nix-ninja: {}", err);
             Ok(1)
         }
     }
 }
 
-fn build(cli: &Cli) -> Result<DerivedFile> {
-    let build_dir = std::env::current_dir()?;
-    let config = BuildConfig {
-        build_dir,
+fn parse_rule_jobs(s: &str) -> Result<(String, usize), String> {
+    let (rule, jobs) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected RULE=N but got '{}'", s))?;
+    let jobs: usize = jobs.parse().map_err(|err| format!("{}: {}", jobs, err))?;
+    if jobs == 0 {
+        return Err(format!("jobs for rule '{}' must be at least 1", rule));
+    }
+    Ok((rule.to_string(), jobs))
+}
+
+fn build(cli: &Cli, targets: Vec<String>) -> Result<Vec<DerivedFile>> {
+    Ok(build_targets(
+        &cli.build_filename,
+        &targets,
+        build_config(cli)?,
+    )?)
+}
+
+// Build the targets, also returning the outputs of the validations they need.
+fn build_with_validations(cli: &Cli) -> Result<(Vec<DerivedFile>, Vec<DerivedFile>)> {
+    let mut session = Session::new(&cli.build_filename.to_string_lossy(), build_config(cli)?)?;
+    let derived_files = session.build(cli.targets.clone())?;
+    Ok((derived_files, session.validations().to_vec()))
+}
+
+fn build_config(cli: &Cli) -> Result<BuildConfig> {
+    let build_system = cli
+        .build_system
+        .clone()
+        .unwrap_or_else(build::current_system);
+    Ok(BuildConfig {
+        build_dir: cli.build_dir.clone(),
+        source_root: cli.source_root().to_path_buf(),
         store_dir: cli.store_dir.clone(),
+        host_system: cli.host_system.clone().unwrap_or(build_system.clone()),
+        build_system,
         nix_tool: cli.nix_tool.clone(),
         extra_inputs: cli.extra_inputs.clone(),
-    };
+        toolchain_manifest: cli.toolchain_manifest.clone(),
+        keep_rspfile: cli.debug.iter().any(|mode| mode == "keeprsp"),
+        check_inputs: cli.debug.iter().any(|mode| mode == "checkinputs"),
+        deterministic: cli.deterministic,
+        prefix_task_output: cli.prefix_task_output,
+        env_closure: cli.env_closure,
+        task_timeout: cli.task_timeout,
+        shell: cli.shell.clone(),
+        offline: cli.offline,
+        jobs: cli.jobs,
+        keep_going: cli.keep_going,
+        jobs_per_rule: cli.jobs_per_rule.iter().cloned().collect(),
+        max_inline_input_chars: cli.max_inline_input_chars,
+        max_input_srcs: cli.max_input_srcs,
+        strict_input_srcs: strict_input_srcs(&cli.warnings),
+        warn_dupbuild: warn_dupbuild(&cli.warnings),
+        forward_env: cli.forward_env.clone(),
+        build_dir_exclude: cli.build_dir_exclude.clone(),
+        cache_file: Some(cli.build_dir.join(CACHE_FILE)),
+        dump_graph_json: cli.dump_graph_json.clone(),
+        report: cli.report.clone(),
+        verbose: cli.verbose > 0,
+        quiet: cli.quiet,
+    })
+}
 
-    build::build(
+// Like ninja's warning flags, the last of `-w inputsrcs=...` wins.
+fn strict_input_srcs(warnings: &[String]) -> bool {
+    warnings
+        .iter()
+        .rev()
+        .find_map(|warning| match warning.as_str() {
+            "inputsrcs=err" => Some(true),
+            "inputsrcs=warn" => Some(false),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+// Like ninja, duplicate build statements are errors unless the last of
+// `-w dupbuild=...` is `warn`.
+fn warn_dupbuild(warnings: &[String]) -> bool {
+    warnings
+        .iter()
+        .rev()
+        .find_map(|warning| match warning.as_str() {
+            "dupbuild=err" => Some(false),
+            "dupbuild=warn" => Some(true),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+// Load the build file for the subtools, which don't go through a session.
+fn load_file(cli: &Cli) -> Result<load::Loader> {
+    build::load_file(
         &cli.build_filename.to_string_lossy(),
-        cli.targets.clone(),
-        config,
+        warn_dupbuild(&cli.warnings),
     )
 }
 
-fn nix_build(cli: &Cli, derived_file: &DerivedFile) -> Result<()> {
-    let nix = NixTool::new(StoreConfig {
+fn nix_tool(cli: &Cli) -> NixTool {
+    NixTool::new(store_config(cli))
+}
+
+fn store_config(cli: &Cli) -> StoreConfig {
+    StoreConfig {
         nix_tool: cli.nix_tool.clone(),
         extra_args: Vec::new(),
+        offline: cli.offline,
+        realise: cli.realise,
+    }
+}
+
+// Mount the directory failed tasks keep their source tree in where they look
+// for it, which unlike passing it to the tasks leaves derivations unchanged.
+fn keep_failed_args(cli: &Cli) -> Result<Vec<String>> {
+    let Some(keep_failed_dir) = &cli.keep_failed_dir else {
+        return Ok(Vec::new());
+    };
+    fs::create_dir_all(keep_failed_dir)
+        .map_err(|err| anyhow!("create {}: {}", keep_failed_dir.display(), err))?;
+    Ok(vec![
+        "--option".to_string(),
+        "extra-sandbox-paths".to_string(),
+        format!("{}={}", KEEP_FAILED_DIR, keep_failed_dir.display()),
+    ])
+}
+
+// The derived file of the only target built, for modes that produce a single
+// derivation.
+fn single_target(derived_files: &[DerivedFile]) -> Result<&DerivedFile> {
+    match derived_files {
+        [derived_file] => Ok(derived_file),
+        _ => Err(anyhow!(
+            "expected a single target but got {}",
+            derived_files.len()
+        )),
+    }
+}
+
+// The derived file of the target whose derivation is the output derivation.
+// Nothing builds validations along with it, so they are rejected rather than
+// skipped.
+fn output_derivation_target<'a>(
+    derived_files: &'a [DerivedFile],
+    validations: &[DerivedFile],
+) -> Result<&'a DerivedFile> {
+    let derived_file = single_target(derived_files)?;
+    if !validations.is_empty() {
+        let names: Vec<String> = validations
+            .iter()
+            .map(|validation| validation.source.display().to_string())
+            .collect();
+        return Err(anyhow!(
+            "{} has validations, which can't be built with an output derivation: {}",
+            derived_file.source.display(),
+            names.join(", ")
+        ));
+    }
+    Ok(derived_file)
+}
+
+// Whether the output symlinks of the targets from a previous build are newer
+// than their sources, including the headers that build discovered.
+fn output_up_to_date(cli: &Cli) -> Result<bool> {
+    if cli.is_output_derivation {
+        return Ok(false);
+    }
+    let loader = load_file(cli)?;
+    let cache = BuildCache::load(&cli.build_dir.join(CACHE_FILE));
+    build::output_up_to_date(
+        &loader.graph,
+        &cache,
+        &cli.build_dir,
+        cli.source_root(),
+        &cli.build_filename,
+        &cli.targets,
+    )
+}
+
+/// The evaluated commands of the build of `target`, or with `all` of every
+/// build needed for it in build order, each preceded by its description.
+fn print_cmdline(graph: &Graph, target: &str, all: bool) -> Result<String> {
+    let fid = graph
+        .files
+        .lookup(&canon::to_owned_canon_path(target))
+        .ok_or_else(|| anyhow!("unknown path requested: {}", target))?;
+    let builds = if all {
+        build::reachable_builds(graph, &[fid])
+    } else {
+        graph.files.by_id[fid].input.into_iter().collect()
+    };
+    if builds.is_empty() {
+        return Err(anyhow!("{} is not an output of any build", target));
+    }
+
+    let mut out = String::new();
+    for bid in builds {
+        let build = &graph.builds[bid];
+        // Phony builds have no command.
+        let Some(cmdline) = &build.cmdline else {
+            continue;
+        };
+        if let Some(desc) = &build.desc {
+            out.push_str(&format!("# {}\n", desc));
+        }
+        out.push_str(cmdline);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+fn nix_build(cli: &Cli, derived_file: &DerivedFile) -> Result<()> {
+    if cli.dry_run {
+        println!("{}", derived_file.to_string());
+        return Ok(());
+    }
+
+    let nix = NixTool::new(StoreConfig {
+        extra_args: keep_failed_args(cli)?,
+        ..store_config(cli)
     });
 
-    let output = nix.build(&derived_file.path)?;
-    let stdout = str::from_utf8(&output.stdout)?;
-    let drv_output = StorePath::new(stdout.trim())?;
+    let drv_output = nix.build_output(&derived_file.path)?;
+    replace_symlink(drv_output.path(), &derived_file.source)?;
+    // Nix links the symlink again to the same output, registering it as a GC
+    // root so that the output isn't collected while the symlink exists.
+    nix.add_gc_root(&drv_output, &derived_file.source)?;
 
-    if derived_file.source.exists() {
-        fs::remove_file(&derived_file.source)?;
+    if let Some(to) = &cli.push_to {
+        nix.copy_closure(&drv_output, to)?;
     }
-    symlink(&drv_output.path(), &derived_file.source)?;
 
     Ok(())
 }
 
+/// Point the symlink `link` to `target`, creating it at a temporary name and
+/// renaming it over `link` so that `link` is never missing, even if
+/// interrupted. Anything other than a symlink at `link`, like a source file,
+/// is left alone and is an error.
+fn replace_symlink(target: &Path, link: &Path) -> Result<()> {
+    match fs::symlink_metadata(link) {
+        Ok(metadata) if !metadata.file_type().is_symlink() => {
+            return Err(anyhow!(
+                "refusing to replace {} with a link to {}: not a symlink",
+                link.display(),
+                target.display()
+            ));
+        }
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(anyhow!("stat {}: {}", link.display(), err)),
+    }
+
+    let temp = temp_link_path(link)?;
+    // A previous run may have been interrupted before renaming.
+    let _ = fs::remove_file(&temp);
+    let result = symlink(target, &temp).and_then(|_| fs::rename(&temp, link));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.map_err(|err| anyhow!("link {} to {}: {}", link.display(), target.display(), err))
+}
+
+// The temporary name of `link` while it is replaced, next to it so that it
+// can be renamed over it.
+fn temp_link_path(link: &Path) -> Result<PathBuf> {
+    let file_name = link
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid output path: {}", link.display()))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".tmp-{}", std::process::id()));
+    Ok(link.with_file_name(temp_name))
+}
+
 fn subtool(cli: &Cli, tool: &str) -> Result<i32> {
     match tool {
         "list" => {
             println!("nix-ninja subtools:");
-            println!("  drv     show Nix derivation generated for a target");
+            println!("  drv       show Nix derivation generated for a target");
+            println!("            [--json] [--pretty] [--field inputs|outputs|env]");
+            println!("  printdrv  print the derivation generated for targets, as JSON");
+            println!("  drvdiff   show what changed in the derivation of a target since");
+            println!("            the previous build [--drv DRV]");
+            println!("  inputs    show the inputs of the derivation generated for targets,");
+            println!("            with where each was found");
+            println!("  clean     remove output symlinks into the Nix store");
+            println!("  graph     output graphviz dot file for targets");
+            println!("  compdb    dump JSON compilation database to stdout");
+            println!("            [-x] [--compile-db-command-style string|arguments] [-o FILE] [rules or target globs...]");
+            println!("  targets   list targets by their rule or depth in the DAG");
+            println!("            [depth N | rule [RULE] | all]");
+            println!("  rules     list all rules [-d]");
         }
         "drv" => {
-            let nix = NixTool::new(StoreConfig {
-                nix_tool: cli.nix_tool.clone(),
-                extra_args: Vec::new(),
-            });
+            let args = DrvArgs::try_parse_from(subtool_args(tool, cli))?;
+            let nix = nix_tool(cli);
 
-            let derived_file = build(cli)?;
+            let derived_files = build(cli, args.targets)?;
+            let derived_file = single_target(&derived_files)?;
             let output = nix.derivation_show(&derived_file.path.store_path())?;
-            let stdout = str::from_utf8(&output.stdout)?;
-            println!("{}", stdout);
+            if !args.json && !args.pretty && args.field.is_none() {
+                let stdout = str::from_utf8(&output.stdout)?;
+                println!("{}", stdout);
+                return Ok(0);
+            }
+
+            let drvs: HashMap<String, Derivation> = serde_json::from_slice(&output.stdout)?;
+            let value = match args.field {
+                Some(field) => {
+                    let drv = drvs
+                        .values()
+                        .next()
+                        .ok_or_else(|| anyhow!("nix derivation show returned no derivation"))?;
+                    select_drv_field(drv, field)?
+                }
+                None => serde_json::to_value(&drvs)?,
+            };
+            if args.pretty {
+                println!("{}", serde_json::to_string_pretty(&value)?);
+            } else {
+                println!("{}", serde_json::to_string(&value)?);
+            }
+        }
+        "drvdiff" => {
+            let args = DrvDiffArgs::try_parse_from(subtool_args(tool, cli))?;
+            let nix = nix_tool(cli);
+
+            // Building replaces the derivation of the previous build in the
+            // cache, so it is loaded first.
+            let cache = BuildCache::load(&cli.build_dir.join(CACHE_FILE));
+            let derived_files = build(cli, vec![args.target])?;
+            let derived_file = single_target(&derived_files)?;
+            let old = match &args.drv {
+                Some(drv) => show_derivation(&nix, &StorePath::new(drv)?)?,
+                None => previous_derivation(&cache, derived_file)?,
+            };
+            let new = show_derivation(&nix, &derived_file.path.store_path())?;
+            let diff = old.diff(&new);
+            if diff.is_empty() {
+                println!("{} is unchanged", derived_file.source.display());
+            } else {
+                print!("{}", diff);
+            }
+        }
+        "printdrv" => {
+            // Derivations are printed as they are generated, so that the
+            // ones nix rejects are printed too.
+            Session::new(&cli.build_filename.to_string_lossy(), build_config(cli)?)?
+                .print_derivations(io::stdout())
+                .build(cli.targets.clone())?;
+        }
+        "inputs" => {
+            let mut session =
+                Session::new(&cli.build_filename.to_string_lossy(), build_config(cli)?)?;
+            let derived_files = session.build(cli.targets.clone())?;
+            for derived_file in &derived_files {
+                let target = derived_file.source.to_string_lossy();
+                let inputs = session
+                    .derivation_inputs(&target)
+                    .ok_or_else(|| anyhow!("no derivation generated for {}", target))?;
+                if derived_files.len() > 1 {
+                    println!("{}:", target);
+                }
+                for input in inputs {
+                    println!("{}", input);
+                }
+            }
+        }
+        "clean" => {
+            let removed = clean::clean(
+                &cli.build_filename.to_string_lossy(),
+                warn_dupbuild(&cli.warnings),
+                &cli.store_dir,
+            )?;
+            if !cli.quiet {
+                for path in &removed {
+                    println!("Remove {}", path.display());
+                }
+                println!("Cleaning... {} files.", removed.len());
+            }
+        }
+        "graph" => {
+            let loader = load_file(cli)?;
+            print!("{}", graphviz::graph(&loader.graph, &cli.targets)?);
+        }
+        "compdb" => {
+            let args = CompdbArgs::try_parse_from(subtool_args(tool, cli))?;
+            let loader = load_file(cli)?;
+            // Commands resolve their paths against the source root, which was
+            // made absolute by resolve_paths.
+            let db = compdb::compdb(
+                &loader.graph,
+                cli.source_root(),
+                &args.rules,
+                args.expand_rspfile,
+                args.command_style,
+            )?;
+            compdb::write(&db, args.output.as_deref())?;
+        }
+        "targets" => {
+            let mode = TargetsMode::parse(&cli.targets)?;
+            let loader = load_file(cli)?;
+            print!("{}", targets::targets(&loader.graph, &mode)?);
+        }
+        "rules" => {
+            let args = RulesArgs::try_parse_from(subtool_args(tool, cli))?;
+            let loader = load_file(cli)?;
+            print!("{}", targets::rules(&loader, args.descriptions)?);
         }
         // Meson compatibility tools.
-        "restat" | "clean" | "cleandead" | "compdb" => {
-            // TODO: Implement what's necessary, I think only compdb needs to
-            // work and the rest can no-op.
+        "restat" | "cleandead" => {
+            // These only maintain ninja's own state, so they can no-op.
         }
         _ => {
             println!(
@@ -175,3 +841,583 @@ fn subtool(cli: &Cli, tool: &str) -> Result<i32> {
     }
     Ok(0)
 }
+
+/// Arguments of the `-t drv` subtool.
+#[derive(Parser)]
+#[command(name = "nix-ninja -t drv", disable_version_flag = true)]
+struct DrvArgs {
+    /// Print the parsed derivation as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// Print the parsed derivation as pretty-printed JSON
+    #[arg(long)]
+    pretty: bool,
+
+    /// Print only a single field of the derivation
+    #[arg(long, value_enum)]
+    field: Option<DrvField>,
+
+    /// Target to show the derivation for
+    targets: Vec<String>,
+}
+
+/// Arguments of the `-t drvdiff` subtool.
+#[derive(Parser)]
+#[command(name = "nix-ninja -t drvdiff", disable_version_flag = true)]
+struct DrvDiffArgs {
+    /// Derivation to compare with, instead of the one generated for the
+    /// target by the previous build
+    #[arg(long)]
+    drv: Option<String>,
+
+    /// Target to compare the derivation of
+    target: String,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DrvField {
+    /// Input derivations and input sources
+    Inputs,
+    /// Output specifications
+    Outputs,
+    /// Environment variables
+    Env,
+}
+
+/// Arguments of the `-t compdb` subtool.
+#[derive(Parser)]
+#[command(name = "nix-ninja -t compdb", disable_version_flag = true)]
+struct CompdbArgs {
+    /// Expand @rspfile style response file invocations
+    #[arg(short = 'x')]
+    expand_rspfile: bool,
+
+    /// Write commands as a single string or as an array of arguments
+    #[arg(long = "compile-db-command-style", value_enum, default_value_t)]
+    command_style: CommandStyle,
+
+    /// Write the database to FILE instead of stdout, e.g.
+    /// compile_commands.json
+    #[arg(short = 'o', value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Only include builds using these rules, or matching these globs of
+    /// rules or outputs, like `*.o`
+    rules: Vec<String>,
+}
+
+/// Arguments of the `-t rules` subtool.
+#[derive(Parser)]
+#[command(name = "nix-ninja -t rules", disable_version_flag = true)]
+struct RulesArgs {
+    /// Also print the description of each rule
+    #[arg(short = 'd')]
+    descriptions: bool,
+}
+
+/// Subtools parse their own arguments from the remaining targets, like ninja.
+fn subtool_args(tool: &str, cli: &Cli) -> Vec<String> {
+    std::iter::once(tool.to_string())
+        .chain(cli.targets.iter().cloned())
+        .collect()
+}
+
+// The derivation generated for `target` by the previous build, as recorded in
+// the cache of generated derivations. It's kept there in full, so it doesn't
+// matter if it was garbage collected from the store since.
+fn previous_derivation(cache: &BuildCache, target: &DerivedFile) -> Result<Derivation> {
+    let source = target.source.to_string_lossy();
+    let entry = cache.get(&source).ok_or_else(|| {
+        anyhow!(
+            "no previous derivation of {} in the cache, pass one with --drv",
+            source
+        )
+    })?;
+    Ok(entry.derivation.clone())
+}
+
+// The derivation at `drv_path` as the store has it.
+fn show_derivation(nix: &NixTool, drv_path: &StorePath) -> Result<Derivation> {
+    nix.derivation_show_parsed(drv_path)?
+        .into_values()
+        .next()
+        .ok_or_else(|| {
+            anyhow!(
+                "nix derivation show returned no derivation for {}",
+                drv_path
+            )
+        })
+}
+
+fn select_drv_field(drv: &Derivation, field: DrvField) -> Result<serde_json::Value> {
+    let value = serde_json::to_value(drv)?;
+    Ok(match field {
+        DrvField::Inputs => {
+            let mut input_srcs = drv
+                .input_srcs
+                .iter()
+                .map(StorePath::new)
+                .collect::<Result<Vec<_>>>()?;
+            sort_by_name(&mut input_srcs);
+            let input_srcs: Vec<String> = input_srcs.iter().map(|p| format!("{}", p)).collect();
+            json!({
+                "inputDrvs": value["inputDrvs"],
+                "inputSrcs": input_srcs,
+            })
+        }
+        DrvField::Outputs => value["outputs"].clone(),
+        DrvField::Env => value["env"].clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheEntry, CachedFile};
+    use nix_libstore::derivation::{HashAlgorithm, OutputHashMode};
+    use nix_libstore::derived_path::{SingleDerivedPath, SingleDerivedPathBuilt};
+
+    fn test_drv() -> Derivation {
+        let mut drv = Derivation::new(
+            "ninja-build-main.o",
+            "x86_64-linux",
+            "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-nix-ninja-task/bin/nix-ninja-task",
+        );
+        drv.add_env("NIX_NINJA_INPUTS", "foo")
+            .add_input_src("/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c")
+            .add_input_drv(
+                "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv",
+                vec!["out".to_string()],
+            )
+            .add_ca_output("main.o", HashAlgorithm::Sha256, OutputHashMode::Nar)
+            .unwrap();
+        drv
+    }
+
+    #[test]
+    fn test_select_drv_inputs() {
+        let value = select_drv_field(&test_drv(), DrvField::Inputs).unwrap();
+        assert_eq!(
+            value["inputSrcs"],
+            json!(["/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-main.c"])
+        );
+        assert_eq!(
+            value["inputDrvs"]["/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv"]["outputs"],
+            json!(["out"])
+        );
+        assert!(value.get("env").is_none());
+    }
+
+    #[test]
+    fn test_select_drv_outputs() {
+        let value = select_drv_field(&test_drv(), DrvField::Outputs).unwrap();
+        assert_eq!(value["main.o"]["hashAlgo"], json!("sha256"));
+        assert_eq!(value["main.o"]["method"], json!("nar"));
+    }
+
+    #[test]
+    fn test_select_drv_env() {
+        let value = select_drv_field(&test_drv(), DrvField::Env).unwrap();
+        assert_eq!(value, json!({"NIX_NINJA_INPUTS": "foo"}));
+    }
+
+    #[test]
+    fn test_resolve_paths() {
+        let dir = crate::testutil::test_dir("resolve-paths");
+        fs::create_dir_all(dir.join("subdir")).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+
+        let mut cli =
+            Cli::try_parse_from(["nix-ninja", "-C", "subdir", "-f", "build.ninja"]).unwrap();
+        cli.resolve_paths(&dir).unwrap();
+        assert_eq!(cli.build_dir, dir.join("subdir"));
+        assert_eq!(cli.build_filename, dir.join("subdir/build.ninja"));
+        assert_eq!(build_config(&cli).unwrap().build_dir, dir.join("subdir"));
+        assert_eq!(build_config(&cli).unwrap().source_root, dir.join("subdir"));
+
+        // Sources can be rooted elsewhere than the build directory.
+        let mut cli =
+            Cli::try_parse_from(["nix-ninja", "-C", "subdir", "--source-root", "."]).unwrap();
+        cli.resolve_paths(&dir).unwrap();
+        assert_eq!(cli.build_dir, dir.join("subdir"));
+        assert_eq!(build_config(&cli).unwrap().source_root, dir);
+
+        // Without -C, the build runs in the working directory.
+        let mut cli = Cli::try_parse_from(["nix-ninja", "-f", "../build.ninja"]).unwrap();
+        cli.resolve_paths(&dir.join("subdir")).unwrap();
+        assert_eq!(cli.build_dir, dir.join("subdir"));
+        assert_eq!(cli.build_filename, dir.join("subdir/../build.ninja"));
+
+        let mut cli = Cli::try_parse_from(["nix-ninja", "-C", "missing"]).unwrap();
+        assert!(cli.resolve_paths(&dir).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_nix_build_dry_run() {
+        let dir = crate::testutil::test_dir("dry-run");
+        let mut cli = Cli::try_parse_from([
+            "nix-ninja",
+            "-n",
+            "--nix-tool",
+            "/nonexistent/nix",
+            "main.o",
+        ])
+        .unwrap();
+        cli.resolve_paths(&dir).unwrap();
+
+        // Nix isn't called and no output symlink is created.
+        let derived_file = DerivedFile {
+            path: SingleDerivedPath::Built(SingleDerivedPathBuilt {
+                drv_path: StorePath::new(
+                    "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-ninja-build-main.o.drv",
+                )
+                .unwrap(),
+                output: "main.o".to_string(),
+            }),
+            source: dir.join("main.o"),
+        };
+        nix_build(&cli, &derived_file).unwrap();
+        assert!(!dir.join("main.o").exists());
+
+        cli.dry_run = false;
+        assert!(nix_build(&cli, &derived_file).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_nix_build_push_to() {
+        let dir = crate::testutil::test_dir("push-to");
+        let nix_tool = crate::testutil::fake_nix(&dir);
+        let mut cli =
+            Cli::try_parse_from(["nix-ninja", "--nix-tool", &nix_tool.to_string_lossy()]).unwrap();
+        cli.resolve_paths(&dir).unwrap();
+        let derived_file = DerivedFile {
+            path: SingleDerivedPath::Built(SingleDerivedPathBuilt {
+                drv_path: StorePath::new(
+                    "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-ninja-build-main.o.drv",
+                )
+                .unwrap(),
+                output: "main.o".to_string(),
+            }),
+            source: dir.join("main.o"),
+        };
+
+        // Nothing is copied without --push-to.
+        nix_build(&cli, &derived_file).unwrap();
+        assert_eq!(crate::testutil::nix_calls(&dir, "copy --to"), 0);
+
+        // The output symlink is a GC root.
+        let output = dir.join("store/0123456789abcd0123456789abcd0123-out");
+        assert_eq!(fs::read_link(dir.join("main.o")).unwrap(), output);
+        assert_eq!(crate::testutil::nix_calls(&dir, "build --out-link"), 1);
+        assert!(crate::testutil::nix_args(&dir).contains(&format!(
+            "build --out-link {} {}",
+            dir.join("main.o").display(),
+            output.display()
+        )));
+
+        cli.push_to = Some("file:///tmp/cache".to_string());
+        nix_build(&cli, &derived_file).unwrap();
+        assert_eq!(crate::testutil::nix_calls(&dir, "copy --to"), 1);
+
+        // Errors of the destination store are surfaced.
+        cli.push_to = Some("unreachable".to_string());
+        let err = nix_build(&cli, &derived_file).err().unwrap();
+        assert!(err.to_string().contains("cannot connect to 'unreachable'"));
+
+        // The directory failed tasks keep their tree in is mounted into the
+        // sandbox rather than passed to the derivations.
+        cli.push_to = None;
+        cli.keep_failed_dir = Some(dir.join("failed"));
+        nix_build(&cli, &derived_file).unwrap();
+        assert!(dir.join("failed").is_dir());
+        let args = crate::testutil::nix_args(&dir);
+        assert!(args.last().unwrap().starts_with(&format!(
+            "--option extra-sandbox-paths {}={} build",
+            KEEP_FAILED_DIR,
+            dir.join("failed").display()
+        )));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replace_symlink() {
+        let dir = crate::testutil::test_dir("replace-symlink");
+        let link = dir.join("out");
+        replace_symlink(Path::new("/nix/store/a"), &link).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("/nix/store/a"));
+
+        // A temporary link left behind by an interrupted replace is not where
+        // the link is, and is replaced by the next one.
+        let temp = temp_link_path(&link).unwrap();
+        symlink("/nix/store/stale", &temp).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("/nix/store/a"));
+        replace_symlink(Path::new("/nix/store/b"), &link).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("/nix/store/b"));
+        assert!(fs::symlink_metadata(&temp).is_err());
+
+        // Dangling links are replaced too.
+        replace_symlink(Path::new("/nix/store/c"), &link).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("/nix/store/c"));
+
+        // Source files are never deleted.
+        let source = dir.join("main.c");
+        fs::write(&source, "int main() {}").unwrap();
+        assert!(replace_symlink(Path::new("/nix/store/a"), &source).is_err());
+        assert_eq!(fs::read_to_string(&source).unwrap(), "int main() {}");
+        assert!(fs::symlink_metadata(temp_link_path(&source).unwrap()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_targets_from() {
+        let dir = crate::testutil::test_dir("targets-from");
+        let targets_file = dir.join("targets.txt");
+        fs::write(&targets_file, "util.o\n\n  app \n").unwrap();
+
+        let mut cli = Cli::try_parse_from([
+            "nix-ninja",
+            "--targets-from",
+            &targets_file.to_string_lossy(),
+            "main.o",
+        ])
+        .unwrap();
+        cli.read_targets_from().unwrap();
+        assert_eq!(cli.targets, vec!["main.o", "util.o", "app"]);
+
+        cli.targets_from = Some(dir.join("missing.txt"));
+        assert!(cli.read_targets_from().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_subtool_args_passthrough() {
+        let cli =
+            Cli::try_parse_from(["nix-ninja", "-t", "drv", "--field", "env", "hello"]).unwrap();
+        assert_eq!(cli.tool.as_deref(), Some("drv"));
+        assert_eq!(
+            subtool_args("drv", &cli),
+            vec!["drv", "--field", "env", "hello"]
+        );
+    }
+
+    #[test]
+    fn test_output_derivation_target() {
+        let derived_file = |name: &str| DerivedFile {
+            path: SingleDerivedPath::Built(SingleDerivedPathBuilt {
+                drv_path: StorePath::new(format!(
+                    "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-ninja-build-{}.drv",
+                    name
+                ))
+                .unwrap(),
+                output: name.to_string(),
+            }),
+            source: PathBuf::from(name),
+        };
+        let app = [derived_file("app")];
+        assert_eq!(
+            output_derivation_target(&app, &[]).unwrap().source,
+            Path::new("app")
+        );
+
+        // Validations would go unbuilt.
+        let err = output_derivation_target(&app, &[derived_file("lint")])
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "app has validations, which can't be built with an output derivation: lint"
+        );
+    }
+
+    #[test]
+    fn test_previous_derivation() {
+        let dir = crate::testutil::test_dir("previous-drv");
+        let drv_path = "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-ninja-build-main.o.drv";
+        let target = DerivedFile {
+            path: SingleDerivedPath::Opaque(StorePath::new(drv_path).unwrap()),
+            source: PathBuf::from("main.o"),
+        };
+        let mut cache = BuildCache::load(&dir.join(CACHE_FILE));
+        assert!(previous_derivation(&cache, &target).is_err());
+
+        // The derivation doesn't have to be in the store anymore.
+        let derivation = Derivation::new("ninja-build-main.o", "x86_64-linux", "/bin/sh");
+        cache.insert(
+            "main.o".to_string(),
+            CacheEntry {
+                key: String::new(),
+                derivation: derivation.clone(),
+                discovered: Vec::new(),
+                outputs: vec![CachedFile {
+                    path: format!("{}^main.o", drv_path),
+                    source: PathBuf::from("main.o"),
+                }],
+                inputs: Vec::new(),
+            },
+        );
+        assert_eq!(previous_derivation(&cache, &target).unwrap(), derivation);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_drv_args() {
+        let args = DrvArgs::try_parse_from(["drv", "--field", "env", "--pretty", "hello"]).unwrap();
+        assert!(matches!(args.field, Some(DrvField::Env)));
+        assert!(args.pretty);
+        assert_eq!(args.targets, vec!["hello".to_string()]);
+    }
+
+    #[test]
+    fn test_version_flags() {
+        // Meson probes --version for the ninja version it is compatible with.
+        let cli = Cli::try_parse_from(["nix-ninja", "--version"]).unwrap();
+        assert!(cli.print_version);
+        assert!(!cli.print_nix_ninja_version);
+
+        let cli = Cli::try_parse_from(["nix-ninja", "--nix-ninja-version"]).unwrap();
+        assert!(cli.print_nix_ninja_version);
+        assert!(!cli.print_version);
+        assert!(nix_ninja_version().starts_with(env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn test_strict_input_srcs() {
+        let warnings =
+            |flags: &[&str]| -> Vec<String> { flags.iter().map(|f| f.to_string()).collect() };
+        assert!(!strict_input_srcs(&warnings(&[])));
+        assert!(strict_input_srcs(&warnings(&["inputsrcs=err"])));
+        assert!(!strict_input_srcs(&warnings(&[
+            "inputsrcs=err",
+            "inputsrcs=warn"
+        ])));
+        assert!(strict_input_srcs(&warnings(&[
+            "inputsrcs=warn",
+            "dupbuild=err",
+            "inputsrcs=err"
+        ])));
+    }
+
+    #[test]
+    fn test_warn_dupbuild() {
+        let warnings =
+            |flags: &[&str]| -> Vec<String> { flags.iter().map(|f| f.to_string()).collect() };
+        assert!(!warn_dupbuild(&warnings(&[])));
+        assert!(warn_dupbuild(&warnings(&["dupbuild=warn"])));
+        assert!(!warn_dupbuild(&warnings(&[
+            "dupbuild=warn",
+            "dupbuild=err"
+        ])));
+        assert!(warn_dupbuild(&warnings(&[
+            "dupbuild=err",
+            "inputsrcs=err",
+            "dupbuild=warn"
+        ])));
+    }
+
+    #[test]
+    fn test_forward_env() {
+        let cli = Cli::try_parse_from([
+            "nix-ninja",
+            "--forward-env",
+            "PKG_CONFIG_PATH",
+            "--forward-env",
+            "NIX_*",
+        ])
+        .unwrap();
+        assert_eq!(cli.forward_env, vec!["PKG_CONFIG_PATH", "NIX_*"]);
+    }
+
+    #[test]
+    fn test_verbosity() {
+        let log_filter = |args: &[&str]| {
+            let args = ["nix-ninja"].iter().chain(args);
+            Cli::try_parse_from(args).unwrap().log_filter()
+        };
+        assert_eq!(log_filter(&[]), "info");
+        assert_eq!(log_filter(&["-v"]), "info");
+        assert_eq!(log_filter(&["-vv"]), "debug");
+        assert_eq!(log_filter(&["-v", "-v", "-v"]), "trace");
+        assert_eq!(log_filter(&["-q"]), "warn");
+        assert!(Cli::try_parse_from(["nix-ninja", "-q", "-v"]).is_err());
+    }
+
+    #[test]
+    fn test_jobs_per_rule() {
+        let cli = Cli::try_parse_from([
+            "nix-ninja",
+            "--jobs-per-rule",
+            "link=1",
+            "--jobs-per-rule",
+            "c_COMPILER=4",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.jobs_per_rule,
+            vec![("link".to_string(), 1), ("c_COMPILER".to_string(), 4)]
+        );
+
+        assert!(Cli::try_parse_from(["nix-ninja", "--jobs-per-rule", "link"]).is_err());
+        assert!(Cli::try_parse_from(["nix-ninja", "--jobs-per-rule", "link=0"]).is_err());
+    }
+
+    #[test]
+    fn test_compdb_args() {
+        let cli = Cli::try_parse_from(["nix-ninja", "-t", "compdb", "-x", "c_COMPILER"]).unwrap();
+        let args = CompdbArgs::try_parse_from(subtool_args("compdb", &cli)).unwrap();
+        assert!(args.expand_rspfile);
+        assert_eq!(args.command_style, CommandStyle::String);
+        assert_eq!(args.rules, vec!["c_COMPILER".to_string()]);
+
+        let args =
+            CompdbArgs::try_parse_from(["compdb", "--compile-db-command-style", "arguments"])
+                .unwrap();
+        assert_eq!(args.command_style, CommandStyle::Arguments);
+        assert_eq!(args.output, None);
+
+        let args =
+            CompdbArgs::try_parse_from(["compdb", "-o", "compile_commands.json", "cc"]).unwrap();
+        assert_eq!(args.output, Some(PathBuf::from("compile_commands.json")));
+        assert_eq!(args.rules, vec!["cc".to_string()]);
+    }
+
+    #[test]
+    fn test_print_cmdline() {
+        let mut loader = n2::load::Loader::new();
+        let ninja = "rule cc
+  command = cc -c $in -o $out
+  description = CC $out
+rule link
+  command = cc $in -o $out
+build main.o: cc src/main.c
+build util.o: cc src/util.c
+build app: link main.o util.o
+build all: phony app
+\0";
+        loader
+            .parse(PathBuf::from("build.ninja"), ninja.as_bytes())
+            .unwrap();
+
+        let out = print_cmdline(&loader.graph, "main.o", false).unwrap();
+        assert_eq!(out, "# CC main.o\ncc -c src/main.c -o main.o\n");
+
+        let out = print_cmdline(&loader.graph, "all", true).unwrap();
+        assert_eq!(
+            out,
+            "# CC main.o\ncc -c src/main.c -o main.o\n\
+             # CC util.o\ncc -c src/util.c -o util.o\n\
+             cc main.o util.o -o app\n"
+        );
+
+        assert!(print_cmdline(&loader.graph, "src/main.c", false).is_err());
+        assert!(print_cmdline(&loader.graph, "missing", false).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use deps_infer::glob::glob_matches;
+use n2::graph::{Build, Graph};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// How the command of a compilation database entry is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum CommandStyle {
+    /// A single `command` string
+    #[default]
+    String,
+    /// An `arguments` array of the shell-split command
+    Arguments,
+}
+
+/// Generate a compilation database (`compile_commands.json`) of the builds
+/// selected by one of `selectors`, or of all builds with a command if there
+/// are none. Like ninja, a selector is a rule name, but it may also be a glob
+/// matched against rule names and output paths, like `*.o` or `cxx_*`.
+/// Nothing matching gives an empty database.
+///
+/// Like ninja, `expand_rspfile` inlines the contents of response files
+/// referenced as `@rspfile` in commands. `directory` should be absolute for
+/// tools like clangd to resolve the relative paths of entries.
+pub fn compdb(
+    graph: &Graph,
+    directory: &Path,
+    selectors: &[String],
+    expand_rspfile: bool,
+    style: CommandStyle,
+) -> Result<Value> {
+    let directory = directory.to_string_lossy();
+
+    let mut entries = Vec::new();
+    for bid in graph.builds.all_ids() {
+        let build = &graph.builds[bid];
+        if !selectors.is_empty()
+            && !selectors
+                .iter()
+                .any(|selector| selects(graph, build, selector))
+        {
+            continue;
+        }
+        let Some(command) = command(build, expand_rspfile) else {
+            continue;
+        };
+        let (Some(&input), Some(&output)) = (build.explicit_ins().first(), build.outs().first())
+        else {
+            continue;
+        };
+
+        let mut entry = json!({
+            "directory": directory,
+            "file": graph.files.by_id[input].name,
+            "output": graph.files.by_id[output].name,
+        });
+        match style {
+            CommandStyle::String => entry["command"] = json!(command),
+            CommandStyle::Arguments => entry["arguments"] = json!(shell_words::split(&command)?),
+        }
+        entries.push(entry);
+    }
+
+    Ok(Value::Array(entries))
+}
+
+/// Write a compilation database to `output`, or to stdout if there is none.
+///
+/// The file is written next to `output` first and then renamed over it, so
+/// that editors watching it never read a partial database.
+pub fn write(db: &Value, output: Option<&Path>) -> Result<()> {
+    let Some(output) = output else {
+        let mut stdout = BufWriter::new(io::stdout().lock());
+        serde_json::to_writer_pretty(&mut stdout, db)?;
+        writeln!(stdout)?;
+        return Ok(());
+    };
+
+    let file_name = output
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid compilation database path: {}", output.display()))?;
+    let mut temp_name = file_name.to_os_string();
+    temp_name.push(format!(".tmp-{}", std::process::id()));
+    let temp = output.with_file_name(temp_name);
+
+    let result = (|| -> Result<()> {
+        let mut file = BufWriter::new(fs::File::create(&temp)?);
+        serde_json::to_writer_pretty(&mut file, db)?;
+        writeln!(file)?;
+        file.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        fs::rename(&temp, output)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.map_err(|err| anyhow!("write {}: {}", output.display(), err))
+}
+
+// Whether the build uses the rule `selector` or matches it as a glob.
+fn selects(graph: &Graph, build: &Build, selector: &str) -> bool {
+    glob_matches(selector, &build.rule)
+        || build
+            .outs()
+            .iter()
+            .any(|&fid| glob_matches(selector, &graph.files.by_id[fid].name))
+}
+
+fn command(build: &Build, expand_rspfile: bool) -> Option<String> {
+    let cmdline = build.cmdline.clone()?;
+    let Some(rspfile) = build.rspfile.as_ref().filter(|_| expand_rspfile) else {
+        return Some(cmdline);
+    };
+
+    let reference = format!("@{}", rspfile.path.display());
+    let content = rspfile.content.replace('\n', " ");
+    Some(cmdline.replace(&reference, &content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use n2::load;
+    use std::path::PathBuf;
+
+    fn load(ninja: &str) -> load::Loader {
+        let mut loader = load::Loader::new();
+        let bytes = format!("{}\0", ninja).into_bytes();
+        loader.parse(PathBuf::from("build.ninja"), &bytes).unwrap();
+        loader
+    }
+
+    const NINJA: &str = "rule cc
+  command = cc -DNAME='\"hello world\"' -c $in -o $out
+rule link
+  command = cc @$out.rsp -o $out
+  rspfile = $out.rsp
+  rspfile_content = $in
+build main.o: cc ../src/main.c | config.h
+build app: link main.o
+";
+
+    #[test]
+    fn test_compdb_command_string() {
+        let loader = load(NINJA);
+        let db = compdb(
+            &loader.graph,
+            Path::new("/src/build"),
+            &["cc".to_string()],
+            false,
+            CommandStyle::String,
+        )
+        .unwrap();
+        assert_eq!(
+            db,
+            json!([{
+                "directory": "/src/build",
+                "command": "cc -DNAME='\"hello world\"' -c ../src/main.c -o main.o",
+                "file": "../src/main.c",
+                "output": "main.o",
+            }])
+        );
+    }
+
+    #[test]
+    fn test_compdb_command_arguments() {
+        let loader = load(NINJA);
+        let db = compdb(
+            &loader.graph,
+            Path::new("/src/build"),
+            &["cc".to_string()],
+            false,
+            CommandStyle::Arguments,
+        )
+        .unwrap();
+        assert_eq!(
+            db,
+            json!([{
+                "directory": "/src/build",
+                "arguments": ["cc", "-DNAME=\"hello world\"", "-c", "../src/main.c", "-o", "main.o"],
+                "file": "../src/main.c",
+                "output": "main.o",
+            }])
+        );
+    }
+
+    #[test]
+    fn test_compdb_all_rules_expand_rspfile() {
+        let loader = load(NINJA);
+        let db = compdb(
+            &loader.graph,
+            Path::new("/src/build"),
+            &[],
+            true,
+            CommandStyle::String,
+        )
+        .unwrap();
+        assert_eq!(db.as_array().unwrap().len(), 2);
+        assert_eq!(db[1]["command"], json!("cc main.o -o app"));
+    }
+
+    #[test]
+    fn test_compdb_selectors() {
+        let loader = load(&format!("{}build util.o: cc ../src/util.c\n", NINJA));
+        let outputs = |selectors: &[&str]| -> Vec<Value> {
+            let selectors: Vec<String> = selectors.iter().map(|s| s.to_string()).collect();
+            let db = compdb(
+                &loader.graph,
+                Path::new("/src/build"),
+                &selectors,
+                false,
+                CommandStyle::String,
+            )
+            .unwrap();
+            db.as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["output"].clone())
+                .collect()
+        };
+
+        assert_eq!(outputs(&["cc"]), vec![json!("main.o"), json!("util.o")]);
+        assert_eq!(outputs(&["l*"]), vec![json!("app")]);
+        assert_eq!(outputs(&["util.*"]), vec![json!("util.o")]);
+        assert_eq!(
+            outputs(&["main.o", "link"]),
+            vec![json!("main.o"), json!("app")]
+        );
+        assert!(outputs(&["cxx_*"]).is_empty());
+    }
+
+    #[test]
+    fn test_write_to_file() {
+        let dir = crate::testutil::test_dir("compdb-write");
+        let output = dir.join("compile_commands.json");
+        fs::write(&output, "stale").unwrap();
+
+        let db = json!([{"directory": "/src/build", "command": "cc -c main.c"}]);
+        write(&db, Some(&output)).unwrap();
+        let written: Value = serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+        assert_eq!(written, db);
+        // Only the database is left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert!(write(&db, Some(&dir.join("missing/compile_commands.json"))).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
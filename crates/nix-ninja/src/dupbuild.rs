@@ -0,0 +1,222 @@
+use crate::bindings::{eval, join_continuations, outputs, split_binding};
+use anyhow::{bail, Result};
+use n2::{canon, scanner};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Drop the build statements whose outputs are also outputs of later ones,
+/// for `-w dupbuild=warn`, warning about each duplicate output.
+///
+/// n2 rejects duplicate outputs while loading, so the build file is rewritten
+/// before it's loaded instead: `include`d files are inlined and `subninja`s
+/// are rewritten to copies in `temp_dir`, without the dropped statements.
+/// Returns the contents of the rewritten build file, or None if there are no
+/// duplicates.
+pub fn keep_last(build_filename: &Path, temp_dir: &Path) -> Result<Option<String>> {
+    let mut statements = Vec::new();
+    scan_file(build_filename, &mut HashMap::new(), &mut statements)?;
+
+    let mut last: HashMap<&str, usize> = HashMap::new();
+    for (i, statement) in statements.iter().enumerate() {
+        for output in &statement.outputs {
+            last.insert(output, i);
+        }
+    }
+
+    let mut dropped = HashSet::new();
+    for (i, statement) in statements.iter().enumerate() {
+        let duplicates: Vec<&String> = statement
+            .outputs
+            .iter()
+            .filter(|output| last[output.as_str()] != i)
+            .collect();
+        for output in &duplicates {
+            tracing::warn!(
+                "nix-ninja: warning: multiple rules generate {}, keeping the last one; \
+                 builds involving this target will not be correct",
+                output
+            );
+        }
+        if !duplicates.is_empty() {
+            dropped.insert((statement.file.clone(), statement.line));
+        }
+    }
+    if dropped.is_empty() {
+        return Ok(None);
+    }
+
+    fs::create_dir_all(temp_dir)?;
+    let mut rewriter = Rewriter {
+        dropped,
+        temp_dir: temp_dir.to_path_buf(),
+        subninjas: 0,
+    };
+    let text = rewriter.rewrite_file(build_filename, &mut HashMap::new())?;
+    Ok(Some(text))
+}
+
+// A build statement, by the file and logical line it's on.
+struct Statement {
+    file: PathBuf,
+    line: usize,
+    outputs: Vec<String>,
+}
+
+fn read_lines(path: &Path) -> Result<Vec<String>> {
+    let bytes = match scanner::read_file_with_nul(path) {
+        Ok(b) => b,
+        Err(e) => bail!("read {}: {}", path.display(), e),
+    };
+    let text = String::from_utf8_lossy(&bytes);
+    Ok(join_continuations(text.trim_end_matches('\0')))
+}
+
+fn scan_file(
+    path: &Path,
+    vars: &mut HashMap<String, String>,
+    statements: &mut Vec<Statement>,
+) -> Result<()> {
+    for (line, text) in read_lines(path)?.iter().enumerate() {
+        if text.starts_with(' ') || text.starts_with('#') {
+            continue;
+        }
+        if let Some(rest) = text.strip_prefix("build ") {
+            statements.push(Statement {
+                file: path.to_path_buf(),
+                line,
+                outputs: outputs(rest, vars)
+                    .into_iter()
+                    .map(canon::to_owned_canon_path)
+                    .collect(),
+            });
+        } else if let Some(file) = text.strip_prefix("include ") {
+            scan_file(Path::new(&eval(file.trim(), vars)), vars, statements)?;
+        } else if let Some(file) = text.strip_prefix("subninja ") {
+            let file = eval(file.trim(), vars);
+            scan_file(Path::new(&file), &mut vars.clone(), statements)?;
+        } else if let Some((name, value)) = split_binding(text) {
+            let value = eval(value, vars);
+            vars.insert(name.to_string(), value);
+        }
+    }
+    Ok(())
+}
+
+struct Rewriter {
+    dropped: HashSet<(PathBuf, usize)>,
+    temp_dir: PathBuf,
+    subninjas: usize,
+}
+
+impl Rewriter {
+    fn rewrite_file(&mut self, path: &Path, vars: &mut HashMap<String, String>) -> Result<String> {
+        let mut out = String::new();
+        // Whether the bindings that follow belong to a dropped statement.
+        let mut dropping = false;
+        for (line, text) in read_lines(path)?.iter().enumerate() {
+            if text.starts_with(' ') && !text.trim().is_empty() {
+                if !dropping {
+                    out.push_str(text);
+                    out.push('\n');
+                }
+                continue;
+            }
+            dropping = false;
+
+            if text.starts_with("build ") {
+                dropping = self.dropped.contains(&(path.to_path_buf(), line));
+                if dropping {
+                    continue;
+                }
+            } else if let Some(file) = text.strip_prefix("include ") {
+                let file = eval(file.trim(), vars);
+                out.push_str(&self.rewrite_file(Path::new(&file), vars)?);
+                continue;
+            } else if let Some(file) = text.strip_prefix("subninja ") {
+                let file = eval(file.trim(), vars);
+                let text = self.rewrite_file(Path::new(&file), &mut vars.clone())?;
+                self.subninjas += 1;
+                let copy = self
+                    .temp_dir
+                    .join(format!("subninja-{}.ninja", self.subninjas));
+                fs::write(&copy, text)?;
+                out.push_str(&format!("subninja {}\n", escape(&copy)));
+                continue;
+            } else if let Some((name, value)) = split_binding(text) {
+                if !text.starts_with('#') {
+                    let value = eval(value, vars);
+                    vars.insert(name.to_string(), value);
+                }
+            }
+            out.push_str(text);
+            out.push('\n');
+        }
+        Ok(out)
+    }
+}
+
+// Escape a path for the build file.
+fn escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        if matches!(c, '$' | ' ' | ':') {
+            escaped.push('$');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::test_dir;
+
+    #[test]
+    fn test_keep_last() {
+        let dir = test_dir("keep-last");
+        let ninja = dir.join("build.ninja");
+        fs::write(
+            &ninja,
+            format!(
+                "root = {}\n\
+                 rule cc\n  command = cc $in -o $out\n\
+                 build main.o: cc main.c\n  \
+                   pool = console\n\
+                 build util.o: cc util.c\n\
+                 include $root/rules.ninja\n\
+                 subninja $root/lib.ninja\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("rules.ninja"), "build ./main.o: cc other.c\n").unwrap();
+        fs::write(
+            dir.join("lib.ninja"),
+            "build lib.o: cc lib.c\nbuild util.o: cc lib/util.c\n",
+        )
+        .unwrap();
+
+        let temp_dir = dir.join("temp");
+        let text = keep_last(&ninja, &temp_dir).unwrap().unwrap();
+        // The earlier statements and their bindings are dropped, the included
+        // file inlined and the subninja copied.
+        assert!(!text.contains("main.c") && !text.contains("pool"));
+        assert!(!text.contains("build util.o: cc util.c"));
+        assert!(text.contains("build ./main.o: cc other.c"));
+        assert!(text.contains(&format!("subninja {}/subninja-1.ninja", temp_dir.display())));
+        let lib = fs::read_to_string(temp_dir.join("subninja-1.ninja")).unwrap();
+        assert_eq!(lib, "build lib.o: cc lib.c\nbuild util.o: cc lib/util.c\n");
+
+        // Build files without duplicates are loaded as they are.
+        fs::write(
+            &ninja,
+            "rule cc\n  command = cc $in -o $out\nbuild a.o: cc a.c\n",
+        )
+        .unwrap();
+        assert!(keep_last(&ninja, &temp_dir).unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use crate::build::DependencyCycle;
+use n2::graph::BuildId;
+use nix_tool::CommandError;
+use thiserror::Error;
+
+/// Errors of generating derivations for a build, for programs embedding
+/// nix-ninja to tell apart. Anything else is `Other`.
+#[derive(Debug, Error)]
+pub enum NixNinjaError {
+    /// Targets that aren't files of the build graph.
+    #[error("{}", unknown_targets_message(.0))]
+    UnknownTargets(Vec<String>),
+
+    /// A dependency cycle between files of the build graph.
+    #[error(transparent)]
+    DependencyCycle(#[from] DependencyCycle),
+
+    /// A Nix command failed outside of a build task, like adding the
+    /// toolchain to the store.
+    #[error(transparent)]
+    NixCommand(#[from] CommandError),
+
+    /// Generating the derivation of some builds failed.
+    #[error("{}", build_failed_message(.0))]
+    BuildFailed(Vec<BuildFailure>),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+/// A build whose derivation couldn't be generated.
+#[derive(Debug)]
+pub struct BuildFailure {
+    pub build: BuildId,
+    /// The first output of the build, naming it.
+    pub target: String,
+    pub error: anyhow::Error,
+}
+
+impl BuildFailure {
+    /// The Nix command that failed the build, if that's what failed it.
+    pub fn nix_command(&self) -> Option<&CommandError> {
+        self.error.downcast_ref()
+    }
+}
+
+// Recover the kind of errors that went through anyhow.
+impl From<anyhow::Error> for NixNinjaError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<NixNinjaError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<DependencyCycle>() {
+            Ok(cycle) => return NixNinjaError::DependencyCycle(cycle),
+            Err(err) => err,
+        };
+        match err.downcast::<CommandError>() {
+            Ok(command) => NixNinjaError::NixCommand(command),
+            Err(err) => NixNinjaError::Other(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for NixNinjaError {
+    fn from(err: std::io::Error) -> Self {
+        NixNinjaError::Other(err.into())
+    }
+}
+
+fn unknown_targets_message(targets: &[String]) -> String {
+    match targets {
+        [target] => format!("unknown path requested: {}", target),
+        targets => format!("unknown paths requested: {}", targets.join(", ")),
+    }
+}
+
+fn build_failed_message(failures: &[BuildFailure]) -> String {
+    if let [failure] = failures {
+        return format!(
+            "Failed to build task derivation for {}: {}",
+            failure.target, failure.error
+        );
+    }
+
+    let mut message = format!("{} tasks failed:", failures.len());
+    for failure in failures {
+        message.push_str(&format!("\n  {}: {}", failure.target, failure.error));
+    }
+    message
+}
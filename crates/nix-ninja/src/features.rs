@@ -0,0 +1,170 @@
+use crate::bindings::BuildBindings;
+use anyhow::{anyhow, Result};
+use n2::load;
+use std::collections::BTreeMap;
+
+/// Ninja features that nix-ninja doesn't fully support yet.
+///
+/// Features that are fully supported, like response files, aren't listed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Feature {
+    /// Dynamic dependencies discovered from a dyndep file.
+    Dyndep,
+    /// Builds in the console pool.
+    ConsolePool,
+}
+
+impl Feature {
+    fn name(&self) -> &'static str {
+        match self {
+            Feature::Dyndep => "dyndep",
+            Feature::ConsolePool => "pool = console",
+        }
+    }
+
+    /// Why the feature is only partially supported, or None if builds using
+    /// it can't be turned into derivations at all.
+    fn limitation(&self) -> Option<&'static str> {
+        match self {
+            Feature::Dyndep => None,
+            Feature::ConsolePool => Some("builds run one at a time, without the console"),
+        }
+    }
+}
+
+/// Count the builds in the loaded graph using each unsupported feature.
+pub fn scan(loader: &load::Loader, bindings: &BuildBindings) -> BTreeMap<Feature, usize> {
+    let mut features = BTreeMap::new();
+    for bid in loader.graph.builds.all_ids() {
+        let build = &loader.graph.builds[bid];
+        let rule = loader.rules.get(&build.rule);
+
+        let mut used = Vec::new();
+        if rule.is_some_and(|vars| vars.get("dyndep").is_some())
+            || bindings.get(bid, "dyndep").is_some()
+        {
+            used.push(Feature::Dyndep);
+        }
+        if build.pool.as_deref() == Some("console") {
+            used.push(Feature::ConsolePool);
+        }
+
+        for feature in used {
+            *features.entry(feature).or_insert(0) += 1;
+        }
+    }
+    features
+}
+
+/// Warn about features used by the build that aren't fully supported, and
+/// fail if any of them aren't supported at all.
+pub fn check(loader: &load::Loader, bindings: &BuildBindings) -> Result<()> {
+    let mut unsupported = Vec::new();
+    for (feature, builds) in scan(loader, bindings) {
+        match feature.limitation() {
+            Some(limitation) => tracing::warn!(
+                "nix-ninja: warning: {} build(s) use {}, which is partially supported: {}",
+                builds,
+                feature.name(),
+                limitation
+            ),
+            None => {
+                tracing::warn!(
+                    "nix-ninja: warning: {} build(s) use {}, which is unsupported",
+                    builds,
+                    feature.name()
+                );
+                unsupported.push(feature.name());
+            }
+        }
+    }
+
+    if !unsupported.is_empty() {
+        return Err(anyhow!(
+            "build uses unsupported ninja features: {}",
+            unsupported.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::load_file;
+    use crate::testutil::test_dir;
+    use std::fs;
+
+    fn load(name: &str, ninja: &str) -> (load::Loader, BuildBindings) {
+        let dir = test_dir(name);
+        let path = dir.join("build.ninja");
+        fs::write(&path, ninja).unwrap();
+        let path = path.to_string_lossy();
+        let loader = load_file(&path, false).unwrap();
+        let bindings = BuildBindings::read(&loader, &path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (loader, bindings)
+    }
+
+    #[test]
+    fn test_scan_features() {
+        let (loader, bindings) = load(
+            "features",
+            "rule cc
+  command = cc $in -o $out
+  rspfile = $out.rsp
+  rspfile_content = $in
+rule regen
+  command = meson --internal regenerate
+  generator = 1
+rule dyn
+  command = cc $in -o $out
+  dyndep = $in.dd
+build a.o: cc a.c
+build b.o: dyn b.c
+build c.o: cc c.c
+  pool = console
+build build.ninja: regen meson.build
+",
+        );
+        let features = scan(&loader, &bindings);
+        assert_eq!(features.get(&Feature::ConsolePool), Some(&1));
+        assert_eq!(features.len(), 2);
+        assert_eq!(features.get(&Feature::Dyndep), Some(&1));
+        assert!(check(&loader, &bindings).is_err());
+    }
+
+    #[test]
+    fn test_check_partial_features() {
+        let (loader, bindings) = load(
+            "partial-features",
+            "rule regen
+  command = meson --internal regenerate
+  generator = 1
+rule link
+  command = cc $in -o $out
+build build.ninja: regen meson.build
+build app: link main.o
+  pool = console
+",
+        );
+        assert_eq!(scan(&loader, &bindings).len(), 1);
+        assert!(check(&loader, &bindings).is_ok());
+    }
+
+    #[test]
+    fn test_scan_build_dyndep() {
+        let (loader, bindings) = load(
+            "build-dyndep",
+            "rule cc
+  command = cc $in -o $out
+build a.o: cc a.c
+build b.o: cc b.c
+  dyndep = b.dd
+",
+        );
+        let features = scan(&loader, &bindings);
+        assert_eq!(features.get(&Feature::Dyndep), Some(&1));
+        assert!(check(&loader, &bindings).is_err());
+    }
+}
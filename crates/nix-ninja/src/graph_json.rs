@@ -0,0 +1,126 @@
+use crate::task::ResolvedBuild;
+use anyhow::Result;
+use n2::graph::{BuildId, Graph};
+use nix_ninja_task::derived_file::DerivedFile;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Version of the format written by `write`, bumped on incompatible changes.
+pub const VERSION: u32 = 1;
+
+/// Write the builds resolved to derivations as JSON to `path`.
+///
+/// Builds are written in build file order, one per line, with their inputs
+/// and outputs sorted by name, so that the same build file always results in
+/// the same output and large graphs can be read line by line:
+///
+/// ```json
+/// {"version":1,"builds":[
+/// {"rule":"cc","derivation":"/nix/store/...-ninja-build-main.o.drv","inputs":[...],"outputs":[...]}
+/// ]}
+/// ```
+pub fn write(path: &Path, graph: &Graph, resolved: &HashMap<BuildId, ResolvedBuild>) -> Result<()> {
+    let mut out = BufWriter::new(fs::File::create(path)?);
+    writeln!(out, "{{\"version\":{},\"builds\":[", VERSION)?;
+
+    let mut first = true;
+    for bid in graph.builds.all_ids() {
+        let Some(build) = resolved.get(&bid) else {
+            continue;
+        };
+        let Some(output) = build.outputs.first() else {
+            continue;
+        };
+        if !first {
+            writeln!(out, ",")?;
+        }
+        first = false;
+
+        let entry = json!({
+            "rule": build.rule,
+            "derivation": format!("{}", output.path.store_path()),
+            "inputs": files(&build.inputs),
+            "outputs": files(&build.outputs),
+        });
+        serde_json::to_writer(&mut out, &entry)?;
+    }
+
+    if !first {
+        writeln!(out)?;
+    }
+    writeln!(out, "]}}")?;
+    out.flush()?;
+    Ok(())
+}
+
+fn files(derived_files: &[DerivedFile]) -> Vec<Value> {
+    let mut derived_files: Vec<&DerivedFile> = derived_files.iter().collect();
+    derived_files.sort_by(|a, b| a.source.cmp(&b.source));
+    derived_files.dedup_by(|a, b| a.source == b.source);
+    derived_files
+        .into_iter()
+        .map(|derived_file| {
+            json!({
+                "name": derived_file.source.to_string_lossy(),
+                "path": derived_file.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::{test_dir, test_session};
+    use serde_json::Value;
+    use std::fs;
+
+    #[test]
+    fn test_dump_graph_json() {
+        let dir = test_dir("graph-json");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+
+        let path = dir.join("graph.json");
+        let mut session = test_session(
+            &dir,
+            "rule cc\n  command = @CC@ $in -o $out\n\
+             rule link\n  command = @CC@ $in -o $out\n\
+             build main.o: cc ../src/main.c\n\
+             build app: link main.o\n",
+            |config| config.dump_graph_json = Some(path.clone()),
+        );
+        session.build(vec!["app".to_string()]).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        // One build per line between the header and the footer.
+        assert_eq!(contents.lines().count(), 4);
+
+        let json: Value = serde_json::from_str(&contents).unwrap();
+        assert_eq!(json["version"], super::VERSION);
+        let builds = json["builds"].as_array().unwrap();
+        assert_eq!(builds.len(), 2);
+
+        let (compile, link) = (&builds[0], &builds[1]);
+        assert_eq!(compile["rule"], "cc");
+        assert_eq!(compile["inputs"][0]["name"], "../src/main.c");
+        assert_eq!(compile["outputs"][0]["name"], "main.o");
+        let derivation = compile["derivation"].as_str().unwrap();
+        assert!(derivation.ends_with(".drv"));
+        assert_eq!(
+            compile["outputs"][0]["path"],
+            format!("{}^main.o", derivation)
+        );
+
+        // The link consumes the output of the compile, besides the files
+        // found in the build directory like build.ninja.
+        assert_eq!(link["rule"], "link");
+        let inputs = link["inputs"].as_array().unwrap();
+        assert!(inputs.contains(&compile["outputs"][0]));
+        assert_eq!(link["outputs"][0]["name"], "app");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
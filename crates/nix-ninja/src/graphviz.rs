@@ -0,0 +1,183 @@
+use crate::build::{reachable_builds, BuildStates, DependencyCycle};
+use anyhow::{anyhow, Result};
+use n2::canon;
+use n2::densemap::Index;
+use n2::graph::{BuildId, FileId, Graph};
+use std::collections::HashSet;
+use std::fmt::Write;
+
+// Edge colors by kind of build input.
+const EXPLICIT_COLOR: &str = "black";
+const IMPLICIT_COLOR: &str = "blue";
+const ORDER_ONLY_COLOR: &str = "gray";
+
+// Color of nodes that are part of a dependency cycle.
+const CYCLE_COLOR: &str = "red";
+
+/// Render the part of the build graph needed by `targets` as a Graphviz dot
+/// graph, or the whole build graph if there are no targets.
+///
+/// Files are boxes and builds are ellipses labelled by their rule. Files and
+/// builds that are part of a dependency cycle are colored red.
+pub fn graph(graph: &Graph, targets: &[String]) -> Result<String> {
+    let mut roots = Vec::new();
+    for name in targets {
+        let fid = graph
+            .files
+            .lookup(&canon::to_owned_canon_path(name))
+            .ok_or_else(|| anyhow!("unknown target '{}'", name))?;
+        roots.push(fid);
+    }
+    if roots.is_empty() {
+        roots = graph.files.all_ids().collect();
+    }
+
+    let cycle = find_cycle(graph, &roots)?;
+    let builds: HashSet<BuildId> = reachable_builds(graph, &roots).into_iter().collect();
+
+    let mut files: HashSet<FileId> = HashSet::new();
+    for &bid in &builds {
+        let build = &graph.builds[bid];
+        files.extend(build.ordering_ins());
+        files.extend(build.outs());
+    }
+    if !targets.is_empty() {
+        files.extend(&roots);
+    }
+
+    let mut out = String::new();
+    writeln!(out, "digraph ninja {{")?;
+    writeln!(out, "rankdir=\"LR\"")?;
+    writeln!(out, "node [fontsize=10, shape=box, height=0.25]")?;
+    writeln!(out, "edge [fontsize=10]")?;
+
+    for fid in graph.files.all_ids().filter(|fid| files.contains(fid)) {
+        let file = &graph.files.by_id[fid];
+        write!(out, "\"f{}\" [label={}", fid.index(), quote(&file.name))?;
+        if cycle.contains(&fid) {
+            write!(out, ", color={}", CYCLE_COLOR)?;
+        }
+        writeln!(out, "]")?;
+    }
+
+    for bid in graph.builds.all_ids().filter(|bid| builds.contains(bid)) {
+        let build = &graph.builds[bid];
+        write!(
+            out,
+            "\"b{}\" [label={}, shape=ellipse",
+            bid.index(),
+            quote(&build.rule)
+        )?;
+        if build.outs().iter().any(|fid| cycle.contains(fid)) {
+            write!(out, ", color={}", CYCLE_COLOR)?;
+        }
+        writeln!(out, "]")?;
+
+        let explicit = build.explicit_ins().len();
+        let dirtying = build.dirtying_ins().len();
+        for (i, fid) in build.ordering_ins().iter().enumerate() {
+            let color = if i < explicit {
+                EXPLICIT_COLOR
+            } else if i < dirtying {
+                IMPLICIT_COLOR
+            } else {
+                ORDER_ONLY_COLOR
+            };
+            writeln!(
+                out,
+                "\"f{}\" -> \"b{}\" [color={}]",
+                fid.index(),
+                bid.index(),
+                color
+            )?;
+        }
+        for fid in build.outs() {
+            writeln!(out, "\"b{}\" -> \"f{}\"", bid.index(), fid.index())?;
+        }
+    }
+
+    writeln!(out, "}}")?;
+    Ok(out)
+}
+
+// Walks the graph the same way the scheduler does to find a dependency cycle,
+// returning the files that form it.
+fn find_cycle(graph: &Graph, roots: &[FileId]) -> Result<HashSet<FileId>> {
+    let mut build_states = BuildStates::new(graph.builds.next_id(), &HashSet::new());
+    for &fid in roots {
+        let mut stack = Vec::new();
+        if let Err(err) = build_states.want_file(graph, &mut stack, fid) {
+            let cycle = err.downcast::<DependencyCycle>()?;
+            return Ok(cycle.files.into_iter().collect());
+        }
+    }
+    Ok(HashSet::new())
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use n2::load;
+    use std::path::PathBuf;
+
+    fn load(ninja: &str) -> load::Loader {
+        let mut loader = load::Loader::new();
+        let bytes = format!("{}\0", ninja).into_bytes();
+        loader.parse(PathBuf::from("build.ninja"), &bytes).unwrap();
+        loader
+    }
+
+    const NINJA: &str = "rule cc
+  command = cc $in -o $out
+build main.o: cc main.c | config.h || gen
+build other.o: cc other.c
+build app: cc main.o
+";
+
+    #[test]
+    fn test_graph_edge_kinds() {
+        let loader = load(NINJA);
+        let dot = graph(&loader.graph, &[]).unwrap();
+        assert!(dot.starts_with("digraph ninja {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("[label=\"main.c\"]"));
+        assert!(dot.contains("[label=\"cc\", shape=ellipse]"));
+
+        let edge = |from: &str, color: &str| {
+            let fid = loader.graph.files.lookup(from).unwrap();
+            format!("\"f{}\" -> \"b0\" [color={}]", fid.index(), color)
+        };
+        assert!(dot.contains(&edge("main.c", EXPLICIT_COLOR)));
+        assert!(dot.contains(&edge("config.h", IMPLICIT_COLOR)));
+        assert!(dot.contains(&edge("gen", ORDER_ONLY_COLOR)));
+        assert!(!dot.contains(CYCLE_COLOR));
+    }
+
+    #[test]
+    fn test_graph_targets() {
+        let loader = load(NINJA);
+        let dot = graph(&loader.graph, &["app".to_string()]).unwrap();
+        assert!(dot.contains("[label=\"main.o\"]"));
+        assert!(!dot.contains("other"));
+
+        assert!(graph(&loader.graph, &["missing".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_graph_cycle() {
+        let loader = load(
+            "rule cc
+  command = cc $in -o $out
+build a: cc b
+build b: cc a
+",
+        );
+        let dot = graph(&loader.graph, &["a".to_string()]).unwrap();
+        assert!(dot.contains("[label=\"a\", color=red]"));
+        assert!(dot.contains("[label=\"b\", color=red]"));
+    }
+}
@@ -1,4 +1,23 @@
+mod bindings;
 mod build;
+mod cache;
+mod clean;
 pub mod cli;
+mod compdb;
+mod dupbuild;
+mod error;
+mod features;
+mod graph_json;
+mod graphviz;
+pub mod progress;
 mod relative_from;
+mod report;
+mod targets;
 mod task;
+#[cfg(test)]
+mod testutil;
+mod toolchain;
+
+pub use build::{build_targets, BuildConfig, DependencyCycle, Session};
+pub use error::{BuildFailure, NixNinjaError};
+pub use nix_ninja_task::derived_file::DerivedFile;
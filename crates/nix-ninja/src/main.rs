@@ -1,10 +1,25 @@
-use nix_ninja::cli;
+use clap::Parser;
+use nix_ninja::cli::{self, Cli};
+use tracing_subscriber::EnvFilter;
 
 fn main() {
-    let exit_code = match cli::run() {
+    let cli = Cli::parse();
+
+    // Summary lines are logged at info, which -q hides. Set -vv or
+    // RUST_LOG=nix_ninja=debug to see what every task does.
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(cli.log_filter()));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .without_time()
+        .with_target(false)
+        .init();
+
+    let exit_code = match cli::run(cli) {
         Ok(code) => code,
         Err(err) => {
-            println!("nix-ninja: err: {}", err);
+            tracing::error!("nix-ninja: err: {}", err);
             1
         }
     };
@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+// Minimum delay between two status lines when not verbose.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of builds in each state for the current build.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counts {
+    /// Builds whose derivation has been written.
+    pub done: usize,
+    /// Builds whose derivation is being written.
+    pub running: usize,
+    /// Builds that haven't had a derivation generated yet, including running
+    /// ones.
+    pub pending: usize,
+}
+
+impl Counts {
+    pub fn total(&self) -> usize {
+        self.done + self.pending
+    }
+}
+
+/// Progress is notified by the scheduler as builds move through their states.
+///
+/// It is called from the scheduler loop while tasks keep running on their own
+/// threads, so implementations should return quickly.
+pub trait Progress {
+    /// Called whenever the number of builds in some state changed.
+    fn update(&mut self, counts: &Counts);
+
+    /// Called when the derivation of a build starts being generated, with the
+    /// command line of the build, or its rule when it has none.
+    fn task_started(&mut self, counts: &Counts, command: &str);
+
+    /// Called once all the derivations have been generated.
+    fn finish(&mut self, counts: &Counts);
+}
+
+/// Progress that isn't reported anywhere.
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn update(&mut self, _: &Counts) {}
+
+    fn task_started(&mut self, _: &Counts, _: &str) {}
+
+    fn finish(&mut self, _: &Counts) {}
+}
+
+/// Progress that prints `[done/total]` status lines to stderr, at most every
+/// `UPDATE_INTERVAL`. When verbose, the command line of every build is printed
+/// as it starts instead.
+pub struct ConsoleProgress {
+    verbose: bool,
+    last_update: Option<Instant>,
+    last_counts: Option<Counts>,
+}
+
+impl ConsoleProgress {
+    pub fn new(verbose: bool) -> Self {
+        ConsoleProgress {
+            verbose,
+            last_update: None,
+            last_counts: None,
+        }
+    }
+
+    fn print(&mut self, counts: &Counts, message: &str) {
+        tracing::info!("[{}/{}] {}", counts.done, counts.total(), message);
+        self.last_update = Some(Instant::now());
+        self.last_counts = Some(*counts);
+    }
+
+    fn throttled(&self) -> bool {
+        self.last_update
+            .is_some_and(|last| last.elapsed() < UPDATE_INTERVAL)
+    }
+}
+
+impl Progress for ConsoleProgress {
+    fn update(&mut self, counts: &Counts) {
+        if self.verbose || self.throttled() || self.last_counts == Some(*counts) {
+            return;
+        }
+        self.print(counts, "generating derivations");
+    }
+
+    fn task_started(&mut self, counts: &Counts, command: &str) {
+        if self.verbose {
+            self.print(counts, command);
+        }
+    }
+
+    fn finish(&mut self, counts: &Counts) {
+        if counts.total() > 0 && self.last_counts != Some(*counts) {
+            self.print(counts, "generated all derivations");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_console_progress_throttled() {
+        let mut progress = ConsoleProgress::new(false);
+        let counts = Counts {
+            done: 1,
+            running: 1,
+            pending: 2,
+        };
+        assert!(!progress.throttled());
+
+        progress.update(&counts);
+        assert_eq!(progress.last_counts, Some(counts));
+        assert!(progress.throttled());
+
+        // Updates within the interval are dropped.
+        let next = Counts { done: 2, ..counts };
+        progress.update(&next);
+        assert_eq!(progress.last_counts, Some(counts));
+
+        // The final counts are always reported.
+        progress.finish(&next);
+        assert_eq!(progress.last_counts, Some(next));
+    }
+}
@@ -0,0 +1,193 @@
+use crate::task::TaskStats;
+use anyhow::Result;
+use n2::graph::Graph;
+use nix_ninja_task::derived_file::DerivedFile;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Version of the format written by `write`, bumped on incompatible changes.
+pub const VERSION: u32 = 1;
+
+/// Summary of a build, written as JSON by `--report` for tools like CI
+/// dashboards. Unlike the progress, it is written once the build finished,
+/// whether or not it succeeded:
+///
+/// ```json
+/// {"version":1,"success":true,"duration_ms":812,"builds":3,"cached":1,
+///  "failed":0,"derivations_added":2,
+///  "targets":[{"name":"app","path":"/nix/store/...-ninja-build-app.drv^out"}],
+///  "tasks":[{"target":"main.o","rule":"cc","status":"cached","duration_ms":3},...]}
+/// ```
+#[derive(Debug, Serialize)]
+pub struct BuildReport {
+    pub version: u32,
+    pub success: bool,
+    pub duration_ms: u64,
+    /// Builds whose derivation was generated or reused from the cache by the
+    /// build, including failed ones.
+    pub builds: usize,
+    /// Builds whose derivation was reused from the cache.
+    pub cached: usize,
+    pub failed: usize,
+    /// Derivations added to the store, which builds with the same derivation
+    /// share.
+    pub derivations_added: usize,
+    /// The derived paths of the targets, unless the build failed.
+    pub targets: Vec<ReportTarget>,
+    /// The builds in build file order, named by their first output.
+    pub tasks: Vec<ReportTask>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportTarget {
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportTask {
+    pub target: String,
+    pub rule: String,
+    pub status: TaskStatus,
+    /// Time from starting the task to receiving its derivation.
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskStatus {
+    Generated,
+    Cached,
+    Failed,
+}
+
+impl BuildReport {
+    pub fn new(
+        graph: &Graph,
+        mut stats: Vec<TaskStats>,
+        derivations_added: usize,
+        targets: Option<&[DerivedFile]>,
+        duration: Duration,
+    ) -> Self {
+        stats.sort_by_key(|stats| stats.bid);
+        let tasks: Vec<ReportTask> = stats
+            .iter()
+            .map(|stats| {
+                let build = &graph.builds[stats.bid];
+                ReportTask {
+                    target: match build.outs().first() {
+                        Some(&fid) => graph.files.by_id[fid].name.clone(),
+                        None => format!("{:?}", stats.bid),
+                    },
+                    rule: build.rule.clone(),
+                    status: stats.status,
+                    duration_ms: millis(stats.duration),
+                }
+            })
+            .collect();
+        let count = |status| tasks.iter().filter(|task| task.status == status).count();
+
+        BuildReport {
+            version: VERSION,
+            success: targets.is_some(),
+            duration_ms: millis(duration),
+            builds: tasks.len(),
+            cached: count(TaskStatus::Cached),
+            failed: count(TaskStatus::Failed),
+            derivations_added,
+            targets: targets
+                .unwrap_or_default()
+                .iter()
+                .map(|derived_file| ReportTarget {
+                    name: derived_file.source.to_string_lossy().into_owned(),
+                    path: format!("{}", derived_file.path),
+                })
+                .collect(),
+            tasks,
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)? + "\n")?;
+        Ok(())
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::CACHE_FILE;
+    use crate::testutil::{test_dir, test_session};
+    use serde_json::Value;
+    use std::fs;
+
+    #[test]
+    fn test_report() {
+        let dir = test_dir("report");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}").unwrap();
+        fs::write(dir.join("src/util.c"), "int util() {}").unwrap();
+
+        let path = dir.join("report.json");
+        let ninja = "rule cc\n  command = @CC@ $in -o $out\n\
+                     rule missing\n  command = nix-ninja-missing-tool $in -o $out\n\
+                     build main.o: cc ../src/main.c\n\
+                     build util.o: cc ../src/util.c\n\
+                     build app: cc main.o util.o\n\
+                     build broken: missing ../src/main.c\n";
+        let cache_file = dir.join("build").join(CACHE_FILE);
+        let build = |targets: &[&str], keep_going| {
+            let mut session = test_session(&dir, ninja, |config| {
+                config.report = Some(path.clone());
+                config.cache_file = Some(cache_file.clone());
+                config.keep_going = keep_going;
+            });
+            let targets = targets.iter().map(|target| target.to_string()).collect();
+            let result = session.build(targets);
+            let report: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+            (result, report)
+        };
+
+        let (result, report) = build(&["app"], 1);
+        let derived_files = result.unwrap();
+        assert_eq!(report["version"], super::VERSION);
+        assert_eq!(report["success"], true);
+        assert_eq!(report["builds"], 3);
+        assert_eq!(report["cached"], 0);
+        assert_eq!(report["failed"], 0);
+        assert_eq!(report["derivations_added"], 3);
+        assert_eq!(report["targets"][0]["name"], "app");
+        assert_eq!(
+            report["targets"][0]["path"],
+            format!("{}", derived_files[0].path)
+        );
+        let tasks = report["tasks"].as_array().unwrap();
+        let names: Vec<&str> = tasks
+            .iter()
+            .map(|task| task["target"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["main.o", "util.o", "app"]);
+        assert!(tasks.iter().all(|task| task["status"] == "generated"));
+        assert!(tasks[0]["duration_ms"].is_u64());
+
+        // Another run hits the cache, and a failing build is still reported
+        // when keeping going.
+        let (result, report) = build(&["app", "broken"], 0);
+        assert!(result.is_err());
+        assert_eq!(report["success"], false);
+        assert_eq!(report["builds"], 4);
+        assert_eq!(report["cached"], 3);
+        assert_eq!(report["failed"], 1);
+        assert_eq!(report["derivations_added"], 0);
+        assert_eq!(report["targets"].as_array().unwrap().len(), 0);
+        assert_eq!(report["tasks"][3]["target"], "broken");
+        assert_eq!(report["tasks"][3]["status"], "failed");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
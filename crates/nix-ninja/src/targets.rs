@@ -0,0 +1,236 @@
+use anyhow::{anyhow, Result};
+use n2::graph::{FileId, Graph};
+use n2::load;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Write;
+
+/// What `-t targets` lists, parsed from its arguments like ninja does.
+#[derive(Debug, PartialEq)]
+pub enum TargetsMode {
+    /// The root targets and their inputs up to this depth, 0 for unlimited.
+    Depth(usize),
+    /// The outputs of builds using this rule, or the source files if none.
+    Rule(Option<String>),
+    /// Every output with the rule building it.
+    All,
+}
+
+impl TargetsMode {
+    /// Parse `[depth N | rule [RULE] | all]`, defaulting to `depth 1`.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let Some(mode) = args.first() else {
+            return Ok(TargetsMode::Depth(1));
+        };
+        match mode.as_str() {
+            "depth" => {
+                let depth = match args.get(1) {
+                    Some(depth) => depth
+                        .parse()
+                        .map_err(|_| anyhow!("invalid depth '{}'", depth))?,
+                    None => 1,
+                };
+                Ok(TargetsMode::Depth(depth))
+            }
+            "rule" => Ok(TargetsMode::Rule(args.get(1).cloned())),
+            "all" => Ok(TargetsMode::All),
+            _ => Err(anyhow!(
+                "unknown target tool mode '{}', use 'depth', 'rule' or 'all'",
+                mode
+            )),
+        }
+    }
+}
+
+/// List the targets of the graph in the same format as `ninja -t targets`.
+pub fn targets(graph: &Graph, mode: &TargetsMode) -> Result<String> {
+    let mut out = String::new();
+    match mode {
+        TargetsMode::Depth(depth) => {
+            let roots = root_files(graph);
+            if roots.is_empty() {
+                return Err(anyhow!("could not determine root nodes of build graph"));
+            }
+            write_tree(graph, &roots, *depth, 0, &mut out)?;
+        }
+        TargetsMode::Rule(Some(rule)) => {
+            let names: BTreeSet<&str> = graph
+                .builds
+                .all_ids()
+                .map(|bid| &graph.builds[bid])
+                .filter(|build| &build.rule == rule)
+                .flat_map(|build| build.outs())
+                .map(|fid| graph.files.by_id[*fid].name.as_str())
+                .collect();
+            for name in names {
+                writeln!(out, "{}", name)?;
+            }
+        }
+        TargetsMode::Rule(None) => {
+            let mut seen = HashSet::new();
+            for bid in graph.builds.all_ids() {
+                for &fid in graph.builds[bid].ordering_ins() {
+                    let file = &graph.files.by_id[fid];
+                    if file.input.is_none() && seen.insert(fid) {
+                        writeln!(out, "{}", file.name)?;
+                    }
+                }
+            }
+        }
+        TargetsMode::All => {
+            for bid in graph.builds.all_ids() {
+                let build = &graph.builds[bid];
+                for &fid in build.outs() {
+                    writeln!(out, "{}: {}", graph.files.by_id[fid].name, build.rule)?;
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// List the rule names in the same format as `ninja -t rules`, with their
+/// unevaluated description if `descriptions` is set.
+pub fn rules(loader: &load::Loader, descriptions: bool) -> Result<String> {
+    let mut names: Vec<&String> = loader.rules.keys().collect();
+    names.sort();
+
+    let mut out = String::new();
+    for name in names {
+        write!(out, "{}", name)?;
+        if descriptions {
+            if let Some(desc) = loader.rules[name].get("description") {
+                let desc = desc.to_string();
+                if !desc.is_empty() {
+                    write!(out, ": {}", desc)?;
+                }
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(out)
+}
+
+// Outputs that no build depends on, in build order.
+fn root_files(graph: &Graph) -> Vec<FileId> {
+    graph
+        .builds
+        .all_ids()
+        .flat_map(|bid| graph.builds[bid].outs())
+        .copied()
+        .filter(|&fid| graph.files.by_id[fid].dependents.is_empty())
+        .collect()
+}
+
+fn write_tree(
+    graph: &Graph,
+    files: &[FileId],
+    depth: usize,
+    indent: usize,
+    out: &mut String,
+) -> Result<()> {
+    for &fid in files {
+        let file = &graph.files.by_id[fid];
+        write!(out, "{:width$}{}", "", file.name, width = indent * 2)?;
+        match file.input {
+            Some(bid) => {
+                let build = &graph.builds[bid];
+                writeln!(out, ": {}", build.rule)?;
+                if depth != 1 {
+                    let ins = build.ordering_ins();
+                    write_tree(graph, ins, depth.saturating_sub(1), indent + 1, out)?;
+                }
+            }
+            None => writeln!(out)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_loader() -> load::Loader {
+        let ninja = "rule cc\n  \
+                       command = cc -c $in -o $out\n  \
+                       description = CC $out\n\
+                     rule link\n  \
+                       command = cc $in -o $out\n\
+                     build main.o: cc main.c | config.h\n\
+                     build util.o: cc util.c\n\
+                     build app: link main.o util.o\n\
+                     build all: phony app\n";
+        let mut loader = load::Loader::new();
+        loader
+            .parse(
+                PathBuf::from("build.ninja"),
+                format!("{}\0", ninja).as_bytes(),
+            )
+            .unwrap();
+        loader
+    }
+
+    fn targets_of(args: &[&str]) -> String {
+        let loader = test_loader();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        targets(&loader.graph, &TargetsMode::parse(&args).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        let parse = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            TargetsMode::parse(&args)
+        };
+        assert_eq!(parse(&[]).unwrap(), TargetsMode::Depth(1));
+        assert_eq!(parse(&["depth"]).unwrap(), TargetsMode::Depth(1));
+        assert_eq!(parse(&["depth", "0"]).unwrap(), TargetsMode::Depth(0));
+        assert_eq!(parse(&["rule"]).unwrap(), TargetsMode::Rule(None));
+        assert_eq!(
+            parse(&["rule", "cc"]).unwrap(),
+            TargetsMode::Rule(Some("cc".to_string()))
+        );
+        assert_eq!(parse(&["all"]).unwrap(), TargetsMode::All);
+        assert!(parse(&["depth", "x"]).is_err());
+        assert!(parse(&["leaves"]).is_err());
+    }
+
+    #[test]
+    fn test_targets_depth() {
+        assert_eq!(targets_of(&[]), "all: phony\n");
+        assert_eq!(targets_of(&["depth", "2"]), "all: phony\n  app: link\n");
+        assert_eq!(
+            targets_of(&["depth", "0"]),
+            "all: phony\n  \
+               app: link\n    \
+                 main.o: cc\n      \
+                   main.c\n      \
+                   config.h\n    \
+                 util.o: cc\n      \
+                   util.c\n"
+        );
+    }
+
+    #[test]
+    fn test_targets_rule() {
+        assert_eq!(targets_of(&["rule", "cc"]), "main.o\nutil.o\n");
+        assert_eq!(targets_of(&["rule", "missing"]), "");
+        assert_eq!(targets_of(&["rule"]), "main.c\nconfig.h\nutil.c\n");
+    }
+
+    #[test]
+    fn test_targets_all() {
+        assert_eq!(
+            targets_of(&["all"]),
+            "main.o: cc\nutil.o: cc\napp: link\nall: phony\n"
+        );
+    }
+
+    #[test]
+    fn test_rules() {
+        let loader = test_loader();
+        assert_eq!(rules(&loader, false).unwrap(), "cc\nlink\nphony\n");
+        assert_eq!(rules(&loader, true).unwrap(), "cc: CC $out\nlink\nphony\n");
+    }
+}
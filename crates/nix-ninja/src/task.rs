@@ -1,20 +1,31 @@
+use crate::cache::{BuildCache, CacheEntry, CachedFile};
 use crate::relative_from::relative_from;
+use crate::report::TaskStatus;
+use crate::toolchain::Toolchain;
 use anyhow::{anyhow, Error, Result};
-use deps_infer::c_include_parser;
+use deps_infer::normalize::normalize_path;
+use deps_infer::{c_include_parser, cl_include_parser, glob};
 use n2::{
-    canon,
-    graph::{self, Build, BuildDependencies, BuildId, File, FileId},
+    densemap::Index,
+    graph::{self, Build, BuildDependencies, BuildId, FileId, RspFile},
 };
 use nix_libstore::prelude::*;
-use nix_ninja_task::derived_file::{DerivedFile, DerivedOutput};
+use nix_ninja_task::derived_file::{decode_pair, DerivedFile, DerivedOutput};
 use nix_tool::NixTool;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{HashMap, HashSet},
-    env, fs,
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt, fs,
+    io::Write,
     ops::Deref,
-    path::PathBuf,
-    sync::mpsc,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 use walkdir::WalkDir;
 use which::which;
@@ -24,6 +35,30 @@ pub struct Tools {
     pub nix: NixTool,
     pub coreutils: StorePath,
     pub nix_ninja_task: StorePath,
+    pub shell: Shell,
+    pub toolchain: Toolchain,
+}
+
+/// The shell nix-ninja-task runs commands with, like ninja runs them with
+/// `/bin/sh -c`.
+#[derive(Clone)]
+pub struct Shell {
+    /// Path of the shell binary in the store.
+    pub path: PathBuf,
+    /// Store path providing the shell.
+    pub store_path: StorePath,
+}
+
+impl Shell {
+    /// Resolve `shell`, a name to look up on `$PATH` or a path, to the shell
+    /// binary in the store. Toolchain manifests pinned before the shell was
+    /// resolved through them get an entry for it.
+    pub fn resolve(toolchain: &Toolchain, shell: &str) -> Result<Self> {
+        let store_path = toolchain.resolve_or_record(shell)?;
+        let path = which(shell).map_err(|err| anyhow!("Failed to find {}: {}", shell, err))?;
+        let path = store_binary_path(&path, &store_path)?;
+        Ok(Shell { path, store_path })
+    }
 }
 
 /// Task represents a fully evaluated Ninja build target.
@@ -33,9 +68,13 @@ pub struct Tools {
 struct Task {
     name: String,
     system: String,
+    /// Set if the toolchain produces artifacts for another system than the
+    /// one running the task.
+    host_system: Option<String>,
     env_vars: HashMap<String, String>,
+    forward_env: Vec<String>,
 
-    build_dir: PathBuf,
+    source_root: PathBuf,
     build_deps: BuildDependencies,
     store_dir: PathBuf,
     store_regex: Regex,
@@ -43,12 +82,56 @@ struct Task {
     cmdline: Option<String>,
     desc: Option<String>,
     deps: Option<String>,
-
-    files: HashMap<FileId, File>,
+    msvc_deps_prefix: Option<String>,
+    pool: Option<String>,
+    rspfile: Option<RspFile>,
+    keep_rspfile: bool,
+    check_inputs: bool,
+    deterministic: bool,
+    prefix_output: bool,
+    quiet: bool,
+    env_closure: bool,
+    task_timeout: Option<u64>,
+    max_inline_input_chars: usize,
+    max_input_srcs: usize,
+    strict_input_srcs: bool,
+
+    sources: SourceCache,
+    derivations: DerivationCache,
+    closures: ClosureCache,
+    /// Where to print the derivation before adding it to the store, if
+    /// anywhere.
+    printer: Option<DerivationPrinter>,
     inputs: Vec<DerivedFile>,
+    /// Where each input was found, by source.
+    input_origins: HashMap<PathBuf, InputOrigin>,
+    /// Sources of inputs that are only order-only dependencies of the build.
+    order_only: HashSet<PathBuf>,
     outputs: Vec<DerivedOutput>,
 }
 
+impl Task {
+    // Source inputs processed by the compiler, whose headers are discovered.
+    // Order-only inputs are merely available to it.
+    fn compiler_inputs(&self) -> HashSet<PathBuf> {
+        self.inputs
+            .iter()
+            .filter(|input| matches!(input.path, SingleDerivedPath::Opaque(_)))
+            .map(|input| input.source.clone())
+            .filter(|source| !self.order_only.contains(source))
+            .collect()
+    }
+
+    // Whether the environment variable is propagated to the task.
+    fn forwards_env(&self, key: &str) -> bool {
+        DEFAULT_FORWARD_ENV
+            .iter()
+            .copied()
+            .chain(self.forward_env.iter().map(String::as_str))
+            .any(|pattern| glob::glob_matches(pattern, key))
+    }
+}
+
 impl Deref for Task {
     type Target = BuildDependencies;
 
@@ -57,27 +140,253 @@ impl Deref for Task {
     }
 }
 
+/// Where an input of a task's derivation was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputOrigin {
+    /// An explicit, implicit or order-only input of the build.
+    Explicit,
+    /// A file of the build directory, which every task gets.
+    BuildDir,
+    /// An input added with `--extra-inputs`.
+    Extra,
+    /// A header found by dependency discovery, or an executable of the build
+    /// run as the command.
+    Discovered,
+    /// A store path referenced by a forwarded environment variable.
+    Env,
+    /// A store path or build file referenced by the command line.
+    Cmdline,
+    /// A tool needed to run the task, like coreutils or the compiler.
+    Tool,
+}
+
+impl fmt::Display for InputOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            InputOrigin::Explicit => "explicit",
+            InputOrigin::BuildDir => "build-dir",
+            InputOrigin::Extra => "extra",
+            InputOrigin::Discovered => "discovered",
+            InputOrigin::Env => "env",
+            InputOrigin::Cmdline => "cmdline",
+            InputOrigin::Tool => "tool",
+        };
+        f.pad(name)
+    }
+}
+
+/// An input of a task's derivation and why it is one.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TaskInput {
+    /// The input store path, or `drv^output` for the output of a derivation.
+    pub path: String,
+    pub origin: InputOrigin,
+    /// The file, environment variable or binary the input was found from.
+    pub source: Option<String>,
+}
+
+impl TaskInput {
+    fn derived(derived_file: &DerivedFile, origin: InputOrigin) -> Self {
+        TaskInput {
+            path: format!("{}", derived_file.path),
+            origin,
+            source: Some(derived_file.source.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+impl fmt::Display for TaskInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<10} {}", self.origin, self.path)?;
+        if let Some(source) = &self.source {
+            write!(f, " ({})", source)?;
+        }
+        Ok(())
+    }
+}
+
+/// TaskOutput is what a Task generated for its build.
+#[derive(Default)]
+pub struct TaskOutput {
+    /// Discovered inputs and outputs of the build.
+    pub derived_files: Vec<DerivedFile>,
+    /// Derivation of the build as it was added to the store.
+    pub derivation: Option<Derivation>,
+    /// Inputs of the derivation, sorted.
+    pub inputs: Vec<TaskInput>,
+    /// Entry to cache for the build, unless it was reused from the cache.
+    pub cache_entry: Option<CacheEntry>,
+    /// Whether the derivation was reused from the cache.
+    pub cached: bool,
+}
+
 /// BuildResult is the output of a Task.
 pub struct BuildResult {
     pub bid: BuildId,
     pub derived_files: Vec<DerivedFile>,
+    pub derivation: Option<Derivation>,
+    pub inputs: Vec<TaskInput>,
+    pub cache_entry: Option<CacheEntry>,
+    pub cached: bool,
     pub err: Option<Error>,
 }
 
+/// How the task of a build went, for build reports.
+pub struct TaskStats {
+    pub bid: BuildId,
+    pub status: TaskStatus,
+    /// Time from starting the task to receiving its result.
+    pub duration: Duration,
+}
+
+/// ResolvedBuild is a build whose derivation was generated, with the store
+/// paths of its inputs and outputs.
+pub struct ResolvedBuild {
+    pub rule: String,
+    pub inputs: Vec<DerivedFile>,
+    pub outputs: Vec<DerivedFile>,
+    /// Store path of the derivation once it was generated.
+    pub drv_path: Option<StorePath>,
+    /// Inputs of the derivation with where they were found.
+    pub derivation_inputs: Vec<TaskInput>,
+}
+
 pub struct RunnerConfig {
-    pub system: String,
-    pub build_dir: PathBuf,
+    /// System of the derivations, where nix-ninja-task runs.
+    pub build_system: String,
+
+    /// System the toolchain produces artifacts for, which differs from the
+    /// build system when cross-compiling.
+    pub host_system: String,
+
+    /// Directory the relative paths of the build file are resolved against,
+    /// the build directory unless sources are rooted elsewhere.
+    pub source_root: PathBuf,
     pub store_dir: PathBuf,
+    pub keep_rspfile: bool,
+
+    /// Whether tasks verify their inputs exist before running.
+    pub check_inputs: bool,
+
+    /// Whether tasks run with a normalized environment.
+    pub deterministic: bool,
+
+    /// Whether tasks prefix the lines their command outputs.
+    pub prefix_task_output: bool,
+
+    /// Whether tasks only print the output of their command and failures.
+    pub quiet: bool,
+
+    /// Whether the store paths referenced by forwarded environment variables
+    /// are added along with their closure, rather than alone.
+    pub env_closure: bool,
+
+    /// Seconds after which the command of a task is killed.
+    pub task_timeout: Option<u64>,
+
+    /// Maximum number of tasks running concurrently.
+    pub jobs: usize,
+
+    /// Maximum number of tasks running concurrently for builds of a rule,
+    /// within the overall limit of `jobs`.
+    pub jobs_per_rule: HashMap<String, usize>,
+
+    /// Depth of the pools limiting how many of their builds run concurrently,
+    /// within the overall limit of `jobs`. Pools without a depth aren't listed.
+    pub pool_depths: HashMap<String, usize>,
+
+    /// Inputs longer than this are passed to nix-ninja-task via a file.
+    pub max_inline_input_chars: usize,
+
+    /// Number of inputSrcs of a derivation above which a warning is logged,
+    /// 0 for no limit.
+    pub max_input_srcs: usize,
+
+    /// Fail the task instead of warning when its derivation has more than
+    /// `max_input_srcs` inputSrcs.
+    pub strict_input_srcs: bool,
+
+    /// Patterns of environment variables propagated to tasks, besides
+    /// `DEFAULT_FORWARD_ENV`.
+    pub forward_env: Vec<String>,
+
+    /// Prefix of the `/showIncludes` lines naming headers for builds with
+    /// `deps = msvc`, by build.
+    pub msvc_deps_prefix: HashMap<BuildId, String>,
+
+    /// Patterns of paths in the build directory that aren't inputs of tasks,
+    /// besides `DEFAULT_BUILD_DIR_EXCLUDE`.
+    pub build_dir_exclude: Vec<String>,
+
+    /// File caching the generated derivations across runs, if any.
+    pub cache_file: Option<PathBuf>,
 }
 
-/// Runner is an async runtime that spawns threads for each task.
+/// The pool of builds that need the console, which ninja gives a depth of 1.
+pub const CONSOLE_POOL: &str = "console";
+
+/// Environment variables always propagated to tasks, as the compiler wrappers
+/// of nixpkgs read flags and store paths from them.
+pub const DEFAULT_FORWARD_ENV: &[&str] = &["NIX_LDFLAGS", "NIX_CFLAGS_COMPILE", "NIX_CC_WRAPPER*"];
+
+/// Where the derivations of some builds are printed as JSON, shared with the
+/// task threads.
+pub type DerivationPrinter = Arc<Mutex<Box<dyn Write + Send>>>;
+
+/// Paths in the build directory that are never inputs of tasks.
+pub const DEFAULT_BUILD_DIR_EXCLUDE: &[&str] = &[".git", ".nix-ninja_cache*"];
+
+/// Runner is an async runtime that spawns threads for each task, bounded by
+/// the number of jobs.
 pub struct Runner {
     pub derived_files: HashMap<FileId, DerivedFile>,
+
+    /// Builds whose derivation was generated.
+    pub resolved: HashMap<BuildId, ResolvedBuild>,
+
     build_dir_inputs: HashMap<FileId, DerivedFile>,
     extra_inputs: HashMap<BuildId, Vec<DerivedFile>>,
 
+    /// Modification times of source files when they were added to the store,
+    /// so that sources changed since can be detected between builds.
+    source_mtimes: HashMap<FileId, SystemTime>,
+
+    /// Source files added to the store, shared with the task threads.
+    sources: SourceCache,
+
+    /// Derivations added to the store, shared with the task threads.
+    derivations: DerivationCache,
+
+    /// Closures of store paths queried from the store, shared with the task
+    /// threads.
+    closures: ClosureCache,
+
+    /// Derivations generated by previous runs.
+    cache: Option<BuildCache>,
+
+    /// Builds whose derivation is printed before it's added to the store.
+    print_derivations: HashMap<BuildId, DerivationPrinter>,
+
+    /// Builds consuming source files that aren't declared in the build graph,
+    /// such as discovered headers and extra inputs.
+    undeclared_consumers: HashMap<FileId, HashSet<BuildId>>,
+
     tx: mpsc::Sender<BuildResult>,
     rx: mpsc::Receiver<BuildResult>,
+    running: usize,
+
+    /// Running tasks of builds whose rule has its own job limit.
+    running_rules: HashMap<BuildId, String>,
+
+    /// Running tasks of builds in a pool with a depth.
+    running_pools: HashMap<BuildId, String>,
+
+    /// When the running tasks were started.
+    started: HashMap<BuildId, Instant>,
+
+    /// Tasks that finished since the stats were last taken.
+    stats: Vec<TaskStats>,
     tools: Tools,
     config: RunnerConfig,
     env_vars: HashMap<String, String>,
@@ -86,12 +395,7 @@ pub struct Runner {
 
 impl Runner {
     pub fn new(tools: Tools, config: RunnerConfig) -> Result<Self> {
-        let store_dir_str = config.store_dir.to_string_lossy();
-        let pattern = format!(
-            r"{}\/[a-z0-9]{{32}}-[0-9a-zA-Z\+\-\._\?=]+",
-            regex::escape(&store_dir_str)
-        );
-        let store_regex = Regex::new(&pattern)?;
+        let store_regex = new_store_regex(&config.store_dir)?;
 
         let mut env_vars = HashMap::new();
         for (key, value) in env::vars() {
@@ -101,10 +405,23 @@ impl Runner {
         let (tx, rx) = mpsc::channel();
         Ok(Runner {
             derived_files: HashMap::new(),
+            resolved: HashMap::new(),
             build_dir_inputs: HashMap::new(),
             extra_inputs: HashMap::new(),
+            source_mtimes: HashMap::new(),
+            sources: SourceCache::default(),
+            derivations: DerivationCache::default(),
+            closures: ClosureCache::default(),
+            cache: config.cache_file.as_deref().map(BuildCache::load),
+            print_derivations: HashMap::new(),
+            undeclared_consumers: HashMap::new(),
             tx,
             rx,
+            running: 0,
+            running_rules: HashMap::new(),
+            running_pools: HashMap::new(),
+            started: HashMap::new(),
+            stats: Vec::new(),
             tools,
             config,
             env_vars,
@@ -115,120 +432,385 @@ impl Runner {
     // Build systems like Meson may generate files via `configure_file that are
     // not listed as implicit inputs in the build.ninja file. So we must read
     // the build directory and consider them implict inputs for all tasks.
+    //
+    // The walk starts at the source root, which is the build directory unless
+    // sources are rooted elsewhere. Excluded directories are pruned from the
+    // walk. Symlinks to files are inputs like regular files, except for the
+    // output symlinks into the store left by previous builds.
     pub fn read_build_dir(&mut self, files: &mut graph::GraphFiles) -> Result<()> {
-        for entry in WalkDir::new(&self.config.build_dir) {
+        let mut paths = Vec::new();
+        let walk = WalkDir::new(&self.config.source_root)
+            .into_iter()
+            .filter_entry(|entry| entry.depth() == 0 || !self.is_excluded(entry.path()));
+        for entry in walk {
             let entry = entry?;
-            if !entry.file_type().is_file() {
-                continue;
+            let file_type = entry.file_type();
+            if file_type.is_file()
+                || (file_type.is_symlink() && self.is_input_symlink(entry.path()))
+            {
+                paths.push(entry.into_path());
             }
+        }
 
-            let path = entry.into_path();
-            let derived_file =
-                new_opaque_file(&self.tools.nix, &self.config.build_dir, path.clone())?;
+        // The files are added to the store concurrently, but the graph is
+        // only updated once all of them were added.
+        for derived_file in self.add_sources(paths)? {
             let fid = self.add_derived_file(files, derived_file.clone());
             self.build_dir_inputs.insert(fid, derived_file);
         }
         Ok(())
     }
 
+    // Whether a symlink resolves to a file outside of the store. Symlinks into
+    // the store are outputs of previous builds, not sources.
+    fn is_input_symlink(&self, path: &Path) -> bool {
+        let Ok(target) = fs::canonicalize(path) else {
+            return false;
+        };
+        let store_dir =
+            fs::canonicalize(&self.config.store_dir).unwrap_or(self.config.store_dir.clone());
+        target.is_file()
+            && !target.starts_with(&self.config.store_dir)
+            && !target.starts_with(store_dir)
+    }
+
+    // Whether a path in the build directory matches one of the exclude
+    // patterns, either by its path relative to the build directory or, for
+    // patterns without a `/`, by its file name.
+    fn is_excluded(&self, path: &Path) -> bool {
+        let relative = path.strip_prefix(&self.config.source_root).unwrap_or(path);
+        DEFAULT_BUILD_DIR_EXCLUDE
+            .iter()
+            .copied()
+            .chain(self.config.build_dir_exclude.iter().map(String::as_str))
+            .any(|pattern| glob::path_matches(pattern, relative))
+    }
+
+    // Add source files to the store using up to `jobs` threads, returning
+    // their derived files in the same order as `paths`.
+    fn add_sources(&self, paths: Vec<PathBuf>) -> Result<Vec<DerivedFile>> {
+        let (sources, nix, source_root) =
+            (&self.sources, &self.tools.nix, &self.config.source_root);
+        let threads = self.config.jobs.clamp(1, paths.len().max(1));
+        let next = AtomicUsize::new(0);
+
+        let added = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| -> Result<Vec<(usize, DerivedFile)>> {
+                        let mut added = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(path) = paths.get(i) else {
+                                return Ok(added);
+                            };
+                            match sources.add(nix, source_root, path.clone()) {
+                                Ok(derived_file) => added.push((i, derived_file)),
+                                Err(err) => {
+                                    // Stop the other workers early.
+                                    next.store(paths.len(), Ordering::Relaxed);
+                                    return Err(err);
+                                }
+                            }
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut added: Vec<(usize, DerivedFile)> = added.into_iter().flatten().collect();
+        added.sort_by_key(|&(i, _)| i);
+        Ok(added
+            .into_iter()
+            .map(|(_, derived_file)| derived_file)
+            .collect())
+    }
+
+    /// Add extra inputs given as SELECTOR:PATH to the builds matching the
+    /// selector, which is either a target, a rule name, or a glob matching
+    /// outputs.
     pub fn add_extra_inputs(
         &mut self,
-        files: &mut graph::GraphFiles,
+        graph: &mut graph::Graph,
         encoded_inputs: Vec<String>,
     ) -> Result<()> {
         for encoded in encoded_inputs {
-            // Split by colon to separate path from source
-            let parts: Vec<&str> = encoded.split(':').collect();
-            if parts.len() != 2 {
-                return Err(anyhow!(
-                    "Expected one ':' in encoded input but got {}",
-                    encoded
-                ));
-            }
-            let (target, extra_input_path) = (parts[0], PathBuf::from(parts[1]));
-
-            let Some(fid) = files.lookup(target) else {
-                return Err(anyhow!("Could not find target in extra input: {}", target));
-            };
-
-            let file = &files.by_id[fid];
-            let Some(bid) = file.input else {
-                return Err(anyhow!(
-                    "Target in extra input is not an output of a build: {}",
-                    target
-                ));
-            };
-
-            let mut extra_inputs = match self.extra_inputs.get(&bid) {
-                Some(extra_inputs) => extra_inputs.to_owned(),
-                None => Vec::new(),
-            };
-
-            let derived_file = new_opaque_file(
+            // Split by colon to separate selector from source, where colons in
+            // either are escaped with a backslash.
+            let (selector, extra_input_path) = decode_pair(&encoded)
+                .map_err(|err| anyhow!("Invalid encoded extra input: {}", err))?;
+            let extra_input_path = PathBuf::from(extra_input_path);
+
+            let bids = select_builds(graph, &selector)?;
+            let derived_file = self.sources.add(
                 &self.tools.nix,
-                &self.config.build_dir,
+                &self.config.source_root,
                 extra_input_path.clone(),
             )?;
-            self.add_derived_file(files, derived_file.clone());
-
-            extra_inputs.push(derived_file);
-            self.extra_inputs.insert(bid, extra_inputs);
+            let fid = self.add_derived_file(&mut graph.files, derived_file.clone());
+            for bid in bids {
+                self.undeclared_consumers
+                    .entry(fid)
+                    .or_default()
+                    .insert(bid);
+                self.extra_inputs
+                    .entry(bid)
+                    .or_default()
+                    .push(derived_file.clone());
+            }
         }
 
         Ok(())
     }
 
+    /// Whether another task can be started without exceeding the job limit.
+    pub fn can_start(&self) -> bool {
+        self.running < self.config.jobs
+    }
+
+    /// Whether a task for a build of `rule` can be started without exceeding
+    /// the job limit of the rule.
+    pub fn can_start_rule(&self, rule: &str) -> bool {
+        let Some(&jobs) = self.config.jobs_per_rule.get(rule) else {
+            return true;
+        };
+        let running = self
+            .running_rules
+            .values()
+            .filter(|running| running.as_str() == rule)
+            .count();
+        running < jobs
+    }
+
+    /// Whether a task for a build in `pool` can be started without exceeding
+    /// the depth of the pool.
+    pub fn can_start_pool(&self, pool: Option<&str>) -> bool {
+        let Some(&depth) = pool.and_then(|pool| self.config.pool_depths.get(pool)) else {
+            return true;
+        };
+        let running = self
+            .running_pools
+            .values()
+            .filter(|running| Some(running.as_str()) == pool)
+            .count();
+        running < depth
+    }
+
     pub fn start(
         &mut self,
         files: &mut graph::GraphFiles,
         bid: BuildId,
         build: &Build,
     ) -> Result<()> {
-        let tx = self.tx.clone();
-
         let tools = self.tools.clone();
         let task = self.new_task(files, bid, build)?;
+        // Events of the task thread are attributed to its build.
+        let span = tracing::info_span!("task", bid = bid.index(), name = %task.name);
+        let cached = self.cache.as_ref().map(|cache| {
+            let target = task
+                .outputs
+                .first()
+                .map(|output| output.source.to_string_lossy());
+            target.and_then(|target| cache.get(&target)).cloned()
+        });
+
+        self.started.insert(bid, Instant::now());
+        self.resolved.insert(
+            bid,
+            ResolvedBuild {
+                rule: build.rule.clone(),
+                inputs: task.inputs.clone(),
+                outputs: Vec::new(),
+                drv_path: None,
+                derivation_inputs: Vec::new(),
+            },
+        );
+        self.spawn(bid, &build.rule, move || {
+            span.in_scope(|| match cached {
+                Some(cached) => build_cached_task_derivation(tools, task, cached),
+                None => build_task_derivation(tools, task),
+            })
+        });
+        if let Some(pool) = build.pool.as_ref() {
+            if self.config.pool_depths.contains_key(pool) {
+                self.running_pools.insert(bid, pool.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn spawn<F>(&mut self, bid: BuildId, rule: &str, f: F)
+    where
+        F: FnOnce() -> Result<TaskOutput> + Send + 'static,
+    {
+        let tx = self.tx.clone();
+        self.running += 1;
+        if self.config.jobs_per_rule.contains_key(rule) {
+            self.running_rules.insert(bid, rule.to_string());
+        }
 
         std::thread::spawn(move || {
-            let (derived_files, err) = match build_task_derivation(tools, task) {
-                Ok(derived_files) => (derived_files, None),
-                Err(err) => (Vec::new(), Some(err)),
+            let (output, err) = match f() {
+                Ok(output) => (output, None),
+                Err(err) => (TaskOutput::default(), Some(err)),
             };
 
             let result = BuildResult {
                 bid,
-                derived_files,
+                derived_files: output.derived_files,
+                derivation: output.derivation,
+                inputs: output.inputs,
+                cache_entry: output.cache_entry,
+                cached: output.cached,
                 err,
             };
             let _ = tx.send(result);
         });
-
-        Ok(())
     }
 
-    pub fn wait(&mut self, files: &mut graph::GraphFiles) -> Result<BuildId> {
+    fn recv(&mut self) -> BuildResult {
         let result = self.rx.recv().unwrap();
+        self.running -= 1;
+        self.running_rules.remove(&result.bid);
+        self.running_pools.remove(&result.bid);
+        result
+    }
+
+    /// Whether tasks are still running.
+    pub fn is_running(&self) -> bool {
+        self.running > 0
+    }
+
+    /// Wait for a task to finish, returning its build and whether its
+    /// derivation was generated.
+    pub fn wait(&mut self, files: &mut graph::GraphFiles) -> (BuildId, Result<()>) {
+        let result = self.recv();
+        let started = self.started.remove(&result.bid);
+        self.stats.push(TaskStats {
+            bid: result.bid,
+            status: match (&result.err, result.cached) {
+                (Some(_), _) => TaskStatus::Failed,
+                (None, true) => TaskStatus::Cached,
+                (None, false) => TaskStatus::Generated,
+            },
+            duration: started.map_or(Duration::ZERO, |started| started.elapsed()),
+        });
         if let Some(err) = result.err {
-            eprintln!("Error: {}", err);
+            self.resolved.remove(&result.bid);
+            // The scheduler reports the error, the causes and backtrace are
+            // only useful when debugging.
+            tracing::debug!(bid = result.bid.index(), "task failed: {:?}", err);
+            return (result.bid, Err(err));
+        }
 
-            eprintln!("Caused by:");
-            for cause in err.chain().skip(1) {
-                eprintln!("    {}", cause);
+        let mut discovered = Vec::new();
+        let mut outputs = Vec::new();
+        for derived_file in result.derived_files {
+            let is_discovered = matches!(derived_file.path, SingleDerivedPath::Opaque(_));
+            let fid = self.add_derived_file(files, derived_file.clone());
+            if is_discovered {
+                self.undeclared_consumers
+                    .entry(fid)
+                    .or_default()
+                    .insert(result.bid);
+                discovered.push(derived_file);
+            } else {
+                outputs.push(derived_file);
             }
+        }
 
-            eprintln!("Backtrace: {}", err.backtrace());
-            return Err(anyhow!(
-                "Failed to build task derivation for {:?}: {}",
-                result.bid,
-                err
-            ));
+        if let (Some(cache), Some(entry), Some(output)) =
+            (self.cache.as_mut(), result.cache_entry, outputs.first())
+        {
+            cache.insert(output.source.to_string_lossy().into_owned(), entry);
         }
 
-        for derived_file in result.derived_files {
-            self.add_derived_file(files, derived_file.clone());
+        if let Some(resolved) = self.resolved.get_mut(&result.bid) {
+            resolved.inputs.extend(discovered);
+            resolved.drv_path = result
+                .derivation
+                .and(outputs.first())
+                .map(|output| output.path.store_path());
+            resolved.outputs = outputs;
+            resolved.derivation_inputs = result.inputs;
+        }
+
+        (result.bid, Ok(()))
+    }
+
+    /// The stats of the tasks that finished since they were last taken, and
+    /// the number of derivations they added to the store.
+    pub fn take_stats(&mut self) -> (Vec<TaskStats>, usize) {
+        let added = self.derivations.added_count.swap(0, Ordering::Relaxed);
+        (std::mem::take(&mut self.stats), added)
+    }
+
+    /// Print the derivations of `bids` to `printer` as they are generated,
+    /// before they are added to the store.
+    pub fn print_derivations(
+        &mut self,
+        bids: impl IntoIterator<Item = BuildId>,
+        printer: DerivationPrinter,
+    ) {
+        for bid in bids {
+            self.print_derivations.insert(bid, printer.clone());
+        }
+    }
+
+    /// Write the derivations generated so far to the cache file, if any.
+    pub fn save_cache(&mut self) -> Result<()> {
+        match &mut self.cache {
+            Some(cache) => cache.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the source files that were modified since they were added to
+    /// the store, forgetting their derived files so they are added again.
+    pub fn take_changed_sources(&mut self) -> Vec<FileId> {
+        let mut changed = Vec::new();
+        for (fid, mtime) in &self.source_mtimes {
+            let Some(derived_file) = self.derived_files.get(fid) else {
+                continue;
+            };
+            if source_mtime(&self.config.source_root, derived_file).as_ref() != Some(mtime) {
+                changed.push(*fid);
+            }
         }
 
-        Ok(result.bid)
+        for fid in &changed {
+            self.forget(*fid);
+        }
+        changed
+    }
+
+    /// Whether the file was found in the build directory, which makes it an
+    /// input of every task.
+    pub fn is_build_dir_input(&self, fid: FileId) -> bool {
+        self.build_dir_inputs.contains_key(&fid)
+    }
+
+    /// Builds that consumed the file without it being declared in the graph.
+    pub fn undeclared_consumers(&self, fid: FileId) -> impl Iterator<Item = BuildId> + '_ {
+        self.undeclared_consumers
+            .get(&fid)
+            .into_iter()
+            .flat_map(|bids| bids.iter().copied())
+    }
+
+    /// Forget the derived file of a file, e.g. because the build producing it
+    /// has to generate its derivation again.
+    pub fn forget(&mut self, fid: FileId) {
+        if let Some(derived_file) = self.derived_files.remove(&fid) {
+            if let SingleDerivedPath::Opaque(_) = derived_file.path {
+                self.sources.remove(&derived_file.source);
+            }
+        }
+        self.source_mtimes.remove(&fid);
     }
 
     fn add_derived_file(
@@ -243,6 +825,11 @@ impl Runner {
         };
 
         if let None = self.derived_files.get(&fid) {
+            if let SingleDerivedPath::Opaque(_) = derived_file.path {
+                if let Some(mtime) = source_mtime(&self.config.source_root, &derived_file) {
+                    self.source_mtimes.insert(fid, mtime);
+                }
+            }
             self.derived_files.insert(fid, derived_file);
         }
 
@@ -257,16 +844,14 @@ impl Runner {
     ) -> Result<Task> {
         let store_dir = self.config.store_dir.to_string_lossy().into_owned();
 
-        // Provide the task access to all the original files for explicit
-        // inputs and implicit/explicit outputs.
-        let mut build_files: HashMap<FileId, File> = HashMap::new();
-        for fid in build.ordering_ins().iter().chain(build.outs()) {
-            build_files.insert(*fid, files.by_id[*fid].clone());
-        }
-
         // Iterate over all explict, implicit and order-only dependencies as
         // they must all be linked into the derivation's source directory.
+        // Order-only dependencies are only made available to the command, so
+        // they are remembered to keep them out of dependency discovery.
+        let dirtying_ins: HashSet<&FileId> = build.dirtying_ins().iter().collect();
         let mut input_set: HashMap<PathBuf, DerivedFile> = HashMap::new();
+        let mut input_origins: HashMap<PathBuf, InputOrigin> = HashMap::new();
+        let mut order_only: HashSet<PathBuf> = HashSet::new();
         for fid in build.ordering_ins() {
             // TODO: what about phony inputs?
             let input = match self.derived_files.get(fid) {
@@ -285,15 +870,19 @@ impl Runner {
                         continue;
                     }
 
-                    let input = new_opaque_file(
+                    let input = self.sources.add(
                         &self.tools.nix,
-                        &self.config.build_dir,
+                        &self.config.source_root,
                         file.name.clone().into(),
                     )?;
                     self.add_derived_file(files, input.clone().to_owned());
                     input.to_owned()
                 }
             };
+            if !dirtying_ins.contains(fid) {
+                order_only.insert(input.source.clone());
+            }
+            input_origins.insert(input.source.clone(), InputOrigin::Explicit);
             input_set.insert(input.source.clone(), input.clone());
         }
 
@@ -303,16 +892,18 @@ impl Runner {
         let primary_file = &files.by_id[*primary_fid];
         let name = normalize_output(&primary_file.name);
 
+        // Implicit outputs are often side artifacts like depfiles or linker
+        // maps that the command writes without referencing `$out`. They get
+        // outputs just like explicit outputs, and nix-ninja-task copies all of
+        // them out of the build directory.
         let mut outputs: Vec<DerivedOutput> = Vec::new();
         for fid in build.outs() {
             let file = &files.by_id[*fid];
             let normalized_name = normalize_output(&file.name);
-            let placeholder = Placeholder::standard_output(&normalized_name);
-            let output = DerivedOutput {
-                placeholder,
-                source: PathBuf::from(&file.name),
-            };
-            outputs.push(output);
+            outputs.push(DerivedOutput::new(
+                &normalized_name,
+                PathBuf::from(&file.name),
+            ));
         }
 
         // TODO: Can we avoid this? Technically the build rule isn't complete.
@@ -327,15 +918,16 @@ impl Runner {
                 let Some(fid) = files.lookup(&arg) else {
                     continue;
                 };
-                let input = match self.derived_files.get(&fid) {
-                    Some(derived_file) => derived_file,
+                let (input, origin) = match self.derived_files.get(&fid) {
+                    Some(derived_file) => (derived_file, InputOrigin::Cmdline),
                     None => match self.build_dir_inputs.get(&fid) {
-                        Some(derived_file) => derived_file,
+                        Some(derived_file) => (derived_file, InputOrigin::BuildDir),
                         None => {
                             continue;
                         }
                     },
                 };
+                input_origins.entry(input.source.clone()).or_insert(origin);
                 input_set.insert(input.source.clone(), input.clone());
             }
         }
@@ -350,11 +942,17 @@ impl Runner {
         // One way is to parse all the includes, then add it to our search
         // path above.
         for (_, input) in &self.build_dir_inputs {
+            input_origins
+                .entry(input.source.clone())
+                .or_insert(InputOrigin::BuildDir);
             input_set.insert(input.source.clone(), input.clone());
         }
 
         if let Some(extra_inputs) = self.extra_inputs.get(&bid) {
             for input in extra_inputs {
+                input_origins
+                    .entry(input.source.clone())
+                    .or_insert(InputOrigin::Extra);
                 input_set.insert(input.source.clone(), input.clone());
             }
         }
@@ -364,129 +962,300 @@ impl Runner {
 
         Ok(Task {
             name: format!("ninja-build-{}", name),
-            system: self.config.system.clone(),
+            system: self.config.build_system.clone(),
+            host_system: Some(self.config.host_system.clone())
+                .filter(|host_system| *host_system != self.config.build_system),
             env_vars: self.env_vars.clone(),
-            build_dir: self.config.build_dir.clone(),
+            forward_env: self.config.forward_env.clone(),
+            source_root: self.config.source_root.clone(),
             build_deps: build.dependencies.clone(),
             store_dir: self.config.store_dir.clone(),
             store_regex: self.store_regex.clone(),
             cmdline: build.cmdline.clone(),
             desc: build.desc.clone(),
             deps: build.deps.clone(),
-            files: build_files,
+            msvc_deps_prefix: self.config.msvc_deps_prefix.get(&bid).cloned(),
+            pool: build.pool.clone(),
+            rspfile: build.rspfile.clone(),
+            keep_rspfile: self.config.keep_rspfile,
+            check_inputs: self.config.check_inputs,
+            deterministic: self.config.deterministic,
+            prefix_output: self.config.prefix_task_output,
+            quiet: self.config.quiet,
+            env_closure: self.config.env_closure,
+            task_timeout: self.config.task_timeout,
+            max_inline_input_chars: self.config.max_inline_input_chars,
+            max_input_srcs: self.config.max_input_srcs,
+            strict_input_srcs: self.config.strict_input_srcs,
+            sources: self.sources.clone(),
+            derivations: self.derivations.clone(),
+            printer: self.print_derivations.get(&bid).cloned(),
+            closures: self.closures.clone(),
             inputs,
+            input_origins,
+            order_only,
             outputs,
         })
     }
 }
 
-fn build_task_derivation(tools: Tools, task: Task) -> Result<Vec<DerivedFile>> {
+fn build_task_derivation(tools: Tools, task: Task) -> Result<TaskOutput> {
     let cmdline = match &task.cmdline {
         Some(c) => c,
         None => {
             return process_phony(tools, task);
         }
     };
+    tracing::debug!(cmdline = %cmdline, "generating derivation");
 
     let mut drv = Derivation::new(
         &task.name,
         &task.system,
-        &format!("{}/bin/nix-ninja-task", tools.nix_ninja_task.to_string()),
+        &format!("{}/bin/nix-ninja-task", tools.nix_ninja_task),
     );
     drv.add_arg(&cmdline);
+    // Every input of the derivation with where it was found.
+    let mut task_inputs: Vec<TaskInput> = Vec::new();
 
     if let Some(desc) = &task.desc {
         drv.add_arg(&format!("--description={}", &desc));
     }
 
+    // Response files are written by nix-ninja-task right before running the
+    // command.
+    if let Some(rspfile) = &task.rspfile {
+        drv.add_env("NIX_NINJA_RSPFILE", &rspfile.path.to_string_lossy());
+        drv.add_env("NIX_NINJA_RSPFILE_CONTENT", &rspfile.content);
+        if task.keep_rspfile {
+            drv.add_arg("--keep-rspfile");
+        }
+    }
+
+    if let Some(host_system) = &task.host_system {
+        drv.add_env("NIX_NINJA_HOST_SYSTEM", host_system);
+    }
+
+    if task.check_inputs {
+        drv.add_arg("--check-inputs");
+    }
+    if task.deterministic {
+        drv.add_arg("--deterministic");
+    }
+    if task.prefix_output {
+        drv.add_arg("--prefix-output");
+    }
+    if task.quiet {
+        drv.add_env("NIX_NINJA_QUIET", "true");
+    }
+    if let Some(timeout) = task.task_timeout {
+        drv.add_env("NIX_NINJA_TASK_TIMEOUT", &timeout.to_string());
+    }
+
+    // There's no console in the Nix sandbox, but builds in the console pool
+    // may still expect to run on the user's machine rather than a remote
+    // builder.
+    if task.pool.as_deref() == Some(CONSOLE_POOL) {
+        drv.add_env("preferLocalBuild", "1");
+    }
+
     // Propagate env var from build environment to the task.
     for (key, value) in &task.env_vars {
-        // TODO: Currently necessary because we're using a gcc wrapped by
+        // TODO: By default necessary because we're using a gcc wrapped by
         // nixpkgs that has implicit deps inside env vars like NIX_LDFLAGS,
         // NIX_CFLAGS_COMPILE. Is there a better way?
-        if !vec!["NIX_LDFLAGS".to_string(), "NIX_CFLAGS_COMPILE".to_string()].contains(key)
-            && !key.starts_with("NIX_CC_WRAPPER")
-        {
+        if !task.forwards_env(key) {
             continue;
         }
 
         drv.add_env(key, value);
-        let found_store_paths = extract_store_paths(&task.store_regex, &value)?;
-        for store_path in found_store_paths {
-            drv.add_input_src(&store_path.to_string());
+        let references = extract_store_paths(&task.store_regex, value)?;
+        let mut references = resolve_unrealized(&tools.nix, &task.env_vars, references)?;
+        if task.env_closure {
+            references = expand_closures(&tools.nix, &task.closures, references)?;
         }
+        task_inputs.extend(reference_inputs(&references, InputOrigin::Env, Some(key)));
+        add_store_references(&mut drv, references);
     }
 
     // Needed by all tasks.
-    drv.add_input_src(&tools.coreutils.to_string())
-        .add_input_src(&tools.nix_ninja_task.to_string());
+    drv.add_env("NIX_NINJA_SHELL", &tools.shell.path.to_string_lossy());
+    drv.add_input_src(&format!("{}", tools.coreutils))
+        .add_input_src(&format!("{}", tools.nix_ninja_task))
+        .add_input_src(&format!("{}", tools.shell.store_path));
+    for (tool, name) in [
+        (&tools.coreutils, "coreutils"),
+        (&tools.nix_ninja_task, "nix-ninja-task"),
+        (&tools.shell.store_path, "shell"),
+    ] {
+        task_inputs.push(TaskInput {
+            path: format!("{}", tool),
+            origin: InputOrigin::Tool,
+            source: Some(name.to_string()),
+        });
+    }
 
     // Add all ninja build inputs.
     let mut input_set: HashSet<String> = HashSet::new();
     for input in &task.inputs {
         // Declare input for derivation.
         add_derived_path(&mut drv, input);
+        let origin = task
+            .input_origins
+            .get(&input.source)
+            .copied()
+            .unwrap_or(InputOrigin::Explicit);
+        task_inputs.push(TaskInput::derived(input, origin));
 
         // Encode input for nix-ninja-task.
         let encoded = &input.to_encoded();
         input_set.insert(encoded.clone());
     }
 
-    // Handle when rule's dep = gcc, which means we need to find all the
-    // implicit header dependencies normally handled by gcc's depfiles.
+    // Handle when rule's deps = gcc or msvc, which means we need to find all
+    // the implicit header dependencies normally handled by gcc's depfiles or
+    // cl's /showIncludes output.
     let mut discovered_inputs: Vec<DerivedFile> = Vec::new();
-    if let Some(deps) = &task.deps {
-        if deps == "gcc" {
-            let mut file_set: HashSet<PathBuf> = HashSet::new();
-            // Only explict inputs are processed by gcc.
-            for input in &task.inputs {
-                let source = match input.path {
-                    SingleDerivedPath::Opaque(_) => input.source.clone(),
-                    SingleDerivedPath::Built(_) => {
-                        continue;
-                    }
-                };
-                file_set.insert(source);
-            }
+    if let Some(deps) = task
+        .deps
+        .as_deref()
+        .filter(|&deps| deps == "gcc" || deps == "msvc")
+    {
+        let file_set = task.compiler_inputs();
 
+        let c_includes = if deps == "gcc" {
             let files: Vec<PathBuf> = file_set.clone().into_iter().collect();
-            let c_includes = c_include_parser::retrieve_c_includes(&cmdline, files)?;
+            c_include_parser::retrieve_c_includes(cmdline, files, &task.source_root)?
+        } else {
+            let deps_prefix = task
+                .msvc_deps_prefix
+                .as_deref()
+                .unwrap_or(cl_include_parser::DEFAULT_DEPS_PREFIX);
+            cl_include_parser::retrieve_cl_includes(cmdline, &task.source_root, deps_prefix)?
+        };
 
-            for include in c_includes {
-                if let Ok(relative) = include.strip_prefix(&task.store_dir) {
-                    if let Some(hash_path) = relative.components().next().map(|c| c.as_os_str()) {
-                        let store_path = task.store_dir.join(hash_path);
-                        drv.add_input_src(&store_path.to_string_lossy());
-                        continue;
-                    }
-                }
+        for include in c_includes {
+            // Leftovers of the command line that the compiler doesn't expand,
+            // like unescaped ninja variables, resolve to paths that don't
+            // exist and must not become inputs.
+            if !task.source_root.join(&include).is_file() {
+                tracing::debug!(include = %include.display(), "skipping missing include");
+                continue;
+            }
 
-                let derived_file = new_opaque_file(&tools.nix, &task.build_dir, include)?;
-                // Skip paths that are already in the task inputs.
-                if file_set.contains(&derived_file.source) {
+            if let Ok(relative) = include.strip_prefix(&task.store_dir) {
+                let hash_path = relative.components().next().map(|c| c.as_os_str());
+                if let Some((hash, name)) = hash_path
+                    .and_then(|hash_path| hash_path.to_str())
+                    .and_then(|hash_path| hash_path.split_once('-'))
+                {
+                    let store_path = StorePath::from_parts(&task.store_dir, hash, name)?;
+                    drv.add_input_src(&format!("{}", store_path));
+                    task_inputs.push(TaskInput {
+                        path: format!("{}", store_path),
+                        origin: InputOrigin::Discovered,
+                        source: Some(include.to_string_lossy().into_owned()),
+                    });
                     continue;
                 }
+            }
 
-                let encoded = &derived_file.to_encoded();
-                // Should be source-linked.
-                input_set.insert(encoded.clone());
-                // Should be included as an input to derivation.
-                add_derived_path(&mut drv, &derived_file);
-                // Should be returned back to the Runner as a discovered input.
-                discovered_inputs.push(derived_file);
+            let mode = discovered_mode(&task.source_root.join(&include))?;
+            let derived_file =
+                task.sources
+                    .add_with_mode(&tools.nix, &task.source_root, include, mode)?;
+            // Skip paths that are already in the task inputs.
+            if file_set.contains(&derived_file.source)
+                || task.order_only.contains(&derived_file.source)
+            {
+                continue;
+            }
+            tracing::trace!(include = %derived_file.source.display(), "discovered input");
+
+            let encoded = &derived_file.to_encoded();
+            // Should be source-linked.
+            input_set.insert(encoded.clone());
+            // Should be included as an input to derivation.
+            add_derived_path(&mut drv, &derived_file);
+            task_inputs.push(TaskInput::derived(&derived_file, InputOrigin::Discovered));
+            // Should be returned back to the Runner as a discovered input.
+            discovered_inputs.push(derived_file);
+        }
+    }
+
+    {
+        // Prepare $PATH to have coreutils.
+        let mut path: Vec<String> = vec![format!("{}/bin", tools.coreutils)];
+
+        let cmdline_binary = cmdline
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| anyhow!("No command found in cmdline"))?;
+
+        match local_executable(&task.source_root, cmdline_binary) {
+            // Executables of the build, like generated binaries or scripts,
+            // are run from the task's source tree rather than $PATH.
+            Some(executable) => {
+                let key = source_key(&executable);
+                if !task
+                    .inputs
+                    .iter()
+                    .any(|input| source_key(&input.source) == key)
+                {
+                    if !task.source_root.join(&executable).is_file() {
+                        return Err(anyhow!(
+                            "Command {} is not an input nor a file of the build directory",
+                            cmdline_binary
+                        ));
+                    }
+                    let derived_file = task.sources.add(&tools.nix, &task.source_root, key)?;
+                    input_set.insert(derived_file.to_encoded());
+                    add_derived_path(&mut drv, &derived_file);
+                    task_inputs.push(TaskInput::derived(&derived_file, InputOrigin::Discovered));
+                    discovered_inputs.push(derived_file);
+                }
+            }
+            None => {
+                let cmdline_path = tools.toolchain.resolve(cmdline_binary)?;
+                drv.add_input_src(&format!("{}", cmdline_path));
+                task_inputs.push(TaskInput {
+                    path: format!("{}", cmdline_path),
+                    origin: InputOrigin::Tool,
+                    source: Some(cmdline_binary.to_string()),
+                });
+                path.push(format!("{}/bin", cmdline_path));
             }
         }
+        drv.add_env("PATH", &path.join(":"));
     }
 
     let inputs: Vec<String> = input_set.into_iter().collect();
-    drv.add_env("NIX_NINJA_INPUTS", &inputs.join(" "));
+    add_inputs_env(&mut drv, &inputs.join(" "), task.max_inline_input_chars);
 
-    // Add all ninja build outputs.
+    // Add all ninja build outputs. Each of them must be a distinct output
+    // whose placeholder nix-ninja-task copies it to, or Nix would drop it.
     let mut outputs: Vec<String> = Vec::new();
     for output in &task.outputs {
+        if !output.is_consistent() {
+            return Err(anyhow!(
+                "Output {} isn't encoded with the placeholder of output {}",
+                output.source.display(),
+                output.output_name
+            ));
+        }
+        if drv.outputs.contains_key(&output.output_name) {
+            return Err(anyhow!(
+                "Outputs conflict on output name {}: {}",
+                output.output_name,
+                output.source.display()
+            ));
+        }
+
         // Declare a content addressed output.
-        let normalized_name = normalize_output(&output.source.to_string_lossy());
-        drv.add_ca_output(&normalized_name, HashAlgorithm::Sha256, OutputHashMode::Nar);
+        drv.add_ca_output(
+            &output.output_name,
+            HashAlgorithm::Sha256,
+            OutputHashMode::Nar,
+        )?;
 
         // Encode output for nix-ninja-task.
         let encoded = &output.to_encoded();
@@ -494,102 +1263,629 @@ fn build_task_derivation(tools: Tools, task: Task) -> Result<Vec<DerivedFile>> {
     }
     drv.add_env("NIX_NINJA_OUTPUTS", &outputs.join(" "));
 
-    {
-        // Prepare $PATH to have coreutils.
-        let mut path: Vec<String> = vec![format!("{}/bin", tools.coreutils.to_string())];
-
-        let cmdline_binary = cmdline
-            .split_whitespace()
-            .next()
-            .ok_or_else(|| anyhow!("No command found in cmdline"))?;
-
-        // TODO: If you don't find it it's ok, e.g. ./generated_binary
-        let cmdline_path = which_store_path(&cmdline_binary)?;
-
-        drv.add_input_src(&cmdline_path.to_string());
-        path.push(format!("{}/bin", cmdline_path.to_string()));
-        drv.add_env("PATH", &path.join(":"));
-    }
-
     // The cmdline may refer to hardcoded store paths as they were found
     // by the build.ninja generator (e.g. meson). We need to extract them
-    // and add as inputSrcs.
-    let found_store_paths = extract_store_paths(&task.store_regex, &cmdline)?;
-    for store_path in found_store_paths {
-        drv.add_input_src(&store_path.to_string());
-    }
+    // and add as inputSrcs, or inputDrvs for derivation outputs.
+    let references = extract_store_paths(&task.store_regex, cmdline)?;
+    let references = resolve_unrealized(&tools.nix, &task.env_vars, references)?;
+    task_inputs.extend(reference_inputs(&references, InputOrigin::Cmdline, None));
+    add_store_references(&mut drv, references);
+    check_input_srcs(&task, &drv)?;
 
-    // let json = &drv.to_json_pretty()?;
-    // println!("Derivation:\n{}", json);
+    // Print the derivation first, so that it can be inspected even if the
+    // store rejects it.
+    print_derivation(&task, &drv)?;
 
     // Add the derivation to the Nix store.
-    let drv_path = tools.nix.derivation_add(&drv)?;
+    let drv_path = task.derivations.add(&tools.nix, &drv)?;
+    tracing::debug!(drv_path = %drv_path, "added derivation");
 
     // Collect all the built outputs of the derivation so it can be referenced
     // as inputs by dependent builds.
-    let mut drv_outputs: Vec<DerivedFile> = Vec::new();
-    for fid in task.outs() {
-        let file = &task.files[fid];
-        let built_file = new_built_file(&drv_path, file.name.clone().into());
-        drv_outputs.push(built_file);
-    }
+    let drv_outputs: Vec<DerivedFile> = task
+        .outputs
+        .iter()
+        .map(|output| new_built_file(&drv_path, &output.output_name, output.source.clone()))
+        .collect();
 
     // Return both discovered inputs & derivation outputs.
     discovered_inputs.extend(drv_outputs);
-    Ok(discovered_inputs)
+    task_inputs.sort();
+    task_inputs.dedup();
+    Ok(TaskOutput {
+        derived_files: discovered_inputs,
+        derivation: Some(drv),
+        inputs: task_inputs,
+        cache_entry: None,
+        cached: false,
+    })
 }
 
-fn process_phony(_: Tools, _: Task) -> Result<Vec<DerivedFile>> {
-    Err(anyhow!("Unimplemented"))
+// Print the derivation of the task as JSON if it was asked for.
+fn print_derivation(task: &Task, drv: &Derivation) -> Result<()> {
+    if let Some(printer) = &task.printer {
+        writeln!(printer.lock().unwrap(), "{}", drv.to_json_pretty()?)?;
+    }
+    Ok(())
 }
 
-pub fn which_store_path(binary_name: &str) -> Result<StorePath> {
-    let binary_path =
-        which(binary_name).map_err(|err| anyhow!("Failed to find {}: {}", binary_name, err))?;
+// Every inputSrc grows the derivation and slows adding it to the store, which
+// mostly happens when too much of the build directory ends up as inputs.
+fn check_input_srcs(task: &Task, drv: &Derivation) -> Result<()> {
+    let count = drv.input_srcs.len();
+    if task.max_input_srcs == 0 || count <= task.max_input_srcs {
+        return Ok(());
+    }
 
-    // Canonicalize will resolve all symlinks and return an absolute path
-    let canonical_path = std::fs::canonicalize(binary_path)?;
+    let target = task.outputs.first().map_or(task.name.clone(), |output| {
+        output.source.display().to_string()
+    });
+    let message = format!(
+        "derivation of {} has {} inputSrcs, more than {}, consider excluding \
+         files with --build-dir-exclude",
+        target, count, task.max_input_srcs
+    );
+    if task.strict_input_srcs {
+        return Err(anyhow!("{}", message));
+    }
+    tracing::warn!("nix-ninja: warning: {}", message);
+    Ok(())
+}
+
+// Reuse the derivation a previous run generated for the build if it was
+// generated from the same task, otherwise generate it and return the entry to
+// cache.
+fn build_cached_task_derivation(
+    tools: Tools,
+    task: Task,
+    cached: Option<CacheEntry>,
+) -> Result<TaskOutput> {
+    if task.cmdline.is_none() {
+        return build_task_derivation(tools, task);
+    }
 
-    let store_path = canonical_path
-        .parent() // Get bin/ directory
-        .and_then(|p| p.parent()) // Get the store path ($out)
-        .ok_or_else(|| anyhow!("Cannot determine store path from binary: {}", binary_name))?;
+    let key = cache_key(&tools, &task)?;
+    if let Some(entry) = cached.filter(|entry| entry.key == key) {
+        if let Some(output) = reuse_cache_entry(&tools, &task, &entry)? {
+            tracing::debug!("reusing cached derivation");
+            print_derivation(&task, &entry.derivation)?;
+            return Ok(output);
+        }
+    }
 
-    StorePath::new(store_path)
+    let mut output = build_task_derivation(tools, task)?;
+    let (discovered, outputs): (Vec<&DerivedFile>, Vec<&DerivedFile>) = output
+        .derived_files
+        .iter()
+        .partition(|derived_file| matches!(derived_file.path, SingleDerivedPath::Opaque(_)));
+    output.cache_entry = output.derivation.clone().map(|derivation| CacheEntry {
+        key,
+        derivation,
+        discovered: discovered.into_iter().map(CachedFile::from).collect(),
+        outputs: outputs.into_iter().map(CachedFile::from).collect(),
+        inputs: output.inputs.clone(),
+    });
+    Ok(output)
 }
 
-fn extract_store_paths(store_regex: &Regex, s: &str) -> Result<Vec<StorePath>> {
-    let mut store_paths = Vec::new();
-    for cap in store_regex.find_iter(s) {
-        let store_path = StorePath::new(cap.as_str())?;
-        if store_path.is_derivation() {
-            continue;
+// The output of a cached derivation, unless an input discovered while
+// generating it changed or the derivation is no longer in the store.
+fn reuse_cache_entry(tools: &Tools, task: &Task, entry: &CacheEntry) -> Result<Option<TaskOutput>> {
+    let outputs = entry
+        .outputs
+        .iter()
+        .map(DerivedFile::try_from)
+        .collect::<Result<Vec<_>>>()?;
+    if !outputs
+        .first()
+        .is_some_and(|output| output.path.store_path().path().exists())
+    {
+        return Ok(None);
+    }
+
+    let mut derived_files = Vec::new();
+    for cached in &entry.discovered {
+        if !task.source_root.join(&cached.source).is_file() {
+            return Ok(None);
         }
-        if !store_path.path().exists() {
-            continue;
+        let mode = discovered_mode(&task.source_root.join(&cached.source))?;
+        let derived_file = task.sources.add_with_mode(
+            &tools.nix,
+            &task.source_root,
+            cached.source.clone(),
+            mode,
+        )?;
+        if CachedFile::from(&derived_file) != *cached {
+            return Ok(None);
         }
-        store_paths.push(store_path);
+        derived_files.push(derived_file);
     }
-    Ok(store_paths)
+    derived_files.extend(outputs);
+
+    Ok(Some(TaskOutput {
+        derived_files,
+        derivation: Some(entry.derivation.clone()),
+        inputs: entry.inputs.clone(),
+        cache_entry: None,
+        cached: true,
+    }))
 }
 
-fn new_opaque_file(nix: &NixTool, build_dir: &PathBuf, path: PathBuf) -> Result<DerivedFile> {
-    let relative_path = relative_from(&path, build_dir).unwrap_or(path);
-    let mut path = relative_path.to_string_lossy().into_owned();
-    canon::canonicalize_path(&mut path);
+// A hash of everything the derivation of a task is generated from, besides
+// the inputs discovered while generating it. Inputs are hashed by their store
+// paths, so a changed input or a regenerated dependency changes the key.
+fn cache_key(tools: &Tools, task: &Task) -> Result<String> {
+    let cmdline = task.cmdline.as_deref().unwrap_or_default();
+    let mut parts: Vec<String> = vec![
+        env!("CARGO_PKG_VERSION").to_string(),
+        task.name.clone(),
+        task.system.clone(),
+        task.host_system.clone().unwrap_or_default(),
+        cmdline.to_string(),
+        task.desc.clone().unwrap_or_default(),
+        task.deps.clone().unwrap_or_default(),
+        task.msvc_deps_prefix.clone().unwrap_or_default(),
+        task.pool.clone().unwrap_or_default(),
+        task.source_root.to_string_lossy().into_owned(),
+        task.store_dir.to_string_lossy().into_owned(),
+        format!(
+            "{} {} {} {} {} {} {:?} {} {} {}",
+            task.keep_rspfile,
+            task.check_inputs,
+            task.deterministic,
+            task.prefix_output,
+            task.quiet,
+            task.env_closure,
+            task.task_timeout,
+            task.max_inline_input_chars,
+            task.max_input_srcs,
+            task.strict_input_srcs
+        ),
+        format!("{}", tools.coreutils),
+        format!("{}", tools.nix_ninja_task),
+        tools.shell.path.to_string_lossy().into_owned(),
+    ];
+    if let Some(rspfile) = &task.rspfile {
+        parts.push(rspfile.path.to_string_lossy().into_owned());
+        parts.push(rspfile.content.clone());
+    }
 
-    let canonical_path = fs::canonicalize(&path)?;
-    let store_path = nix.store_add(&canonical_path)?;
-    Ok(DerivedFile {
-        path: SingleDerivedPath::Opaque(store_path.clone()),
-        source: relative_path,
-    })
+    // The compiler, unless the command is a file of the build, which is then
+    // a discovered input.
+    let binary = cmdline.split_whitespace().next().unwrap_or_default();
+    if local_executable(&task.source_root, binary).is_none() {
+        parts.push(format!("{}", tools.toolchain.resolve(binary)?));
+    }
+
+    let mut env: Vec<String> = task
+        .env_vars
+        .iter()
+        .filter(|(key, _)| task.forwards_env(key))
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    env.sort();
+    parts.extend(env);
+
+    for input in &task.inputs {
+        let origin = task.input_origins.get(&input.source);
+        let order_only = task.order_only.contains(&input.source);
+        parts.push(format!(
+            "{} {:?} {}",
+            input.to_encoded(),
+            origin,
+            order_only
+        ));
+    }
+    for output in &task.outputs {
+        parts.push(output.to_encoded());
+    }
+
+    Ok(format!("{:x}", Sha256::digest(parts.join("\0"))))
+}
+
+fn process_phony(_: Tools, _: Task) -> Result<TaskOutput> {
+    Err(anyhow!("Unimplemented"))
+}
+
+// The builds an extra input selector applies to. An exact target takes
+// precedence over a rule of the same name, and selectors with wildcards match
+// the outputs of builds.
+fn select_builds(graph: &graph::Graph, selector: &str) -> Result<Vec<BuildId>> {
+    if let Some(fid) = graph.files.lookup(selector) {
+        return match graph.files.by_id[fid].input {
+            Some(bid) => Ok(vec![bid]),
+            None => Err(anyhow!(
+                "Target in extra input is not an output of a build: {}",
+                selector
+            )),
+        };
+    }
+
+    let is_glob = selector.contains(['*', '?']);
+    let bids: Vec<BuildId> = graph
+        .builds
+        .all_ids()
+        .filter(|&bid| {
+            let build = &graph.builds[bid];
+            if is_glob {
+                build
+                    .outs()
+                    .iter()
+                    .any(|&fid| glob::glob_matches(selector, &graph.files.by_id[fid].name))
+            } else {
+                build.rule == selector
+            }
+        })
+        .collect();
+    if bids.is_empty() {
+        return Err(anyhow!(
+            "Extra input selector matched no target, rule or output: {}",
+            selector
+        ));
+    }
+    Ok(bids)
+}
+
+/// Find the store path in `store_dir` that provides `binary_name` on `$PATH`,
+/// or None if the binary found isn't in the store.
+pub fn which_store_path(binary_name: &str, store_dir: &Path) -> Result<Option<StorePath>> {
+    let binary_path =
+        which(binary_name).map_err(|err| anyhow!("Failed to find {}: {}", binary_name, err))?;
+    binary_store_path(&binary_path, store_dir)
+}
+
+// The path relative to `source_root` of a command that is a path rather than a
+// name to look up on $PATH, unless it's an absolute path outside of the source
+// root.
+fn local_executable(source_root: &Path, binary: &str) -> Option<PathBuf> {
+    if !binary.contains('/') {
+        return None;
+    }
+    let path = Path::new(binary);
+    if path.is_absolute() {
+        return path.strip_prefix(source_root).ok().map(Path::to_path_buf);
+    }
+    Some(path.to_path_buf())
+}
+
+// Find the store path that provides the binary at `binary_path`.
+//
+// Binaries aren't necessarily at `$out/bin` of the store path providing them:
+// they may be symlinks from a profile or wrapper into another store path,
+// like a split `-bin` output, or live deeper like `$out/libexec/gcc/cc1`. So
+// all symlinks are resolved and the store path is the directory right under
+// the store directory, rather than the parent of `bin/`.
+fn binary_store_path(binary_path: &Path, store_dir: &Path) -> Result<Option<StorePath>> {
+    // Canonicalize will resolve all symlinks and return an absolute path, so
+    // the store directory is too in case it's reached through a symlink.
+    let canonical_path = std::fs::canonicalize(binary_path)?;
+    let store_dir = std::fs::canonicalize(store_dir).unwrap_or(store_dir.to_path_buf());
+
+    Ok(canonical_path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.parent() == Some(store_dir.as_path()))
+        .and_then(|ancestor| StorePath::new(ancestor).ok()))
+}
+
+// The path of the binary at `binary_path` inside `store_path`, following
+// symlinks one at a time until it is in the store. Unlike canonicalizing, this
+// keeps the name the binary is invoked as, e.g. `sh` rather than `bash`,
+// unless the store itself is only reached through a symlink.
+fn store_binary_path(binary_path: &Path, store_path: &StorePath) -> Result<PathBuf> {
+    let mut path = binary_path.to_path_buf();
+    // Like the kernel, give up on long chains of symlinks.
+    for _ in 0..40 {
+        if path.starts_with(store_path.path()) {
+            return Ok(path);
+        }
+        let Ok(target) = std::fs::read_link(&path) else {
+            break;
+        };
+        path = match path.parent() {
+            Some(parent) => parent.join(target),
+            None => target,
+        };
+    }
+    Ok(std::fs::canonicalize(binary_path)?)
+}
+
+/// A reference to the Nix store found in a string.
+#[derive(Clone, PartialEq)]
+enum StoreReference {
+    /// A store path that exists, or an output of a derivation in the store
+    /// referenced as `drv^output`.
+    Derived(SingleDerivedPath),
+    /// A store path that isn't in the store (yet), like the output of a
+    /// derivation that isn't built.
+    Unrealized(StorePath),
+}
+
+// Matches store paths, optionally followed by `^output1,output2` to select
+// outputs of a derivation. Hashes are matched in Nix base32 only, so that a
+// lookalike path isn't taken for a store path.
+fn new_store_regex(store_dir: &Path) -> Result<Regex> {
+    let name = r"[0-9a-zA-Z\+\-\._\?=]+";
+    let pattern = format!(
+        r"(?P<path>{}\/[0-9a-df-np-sv-z]{{32}}-{name})(?:\^(?P<outputs>{name}(?:,{name})*))?",
+        regex::escape(&store_dir.to_string_lossy()),
+    );
+    Ok(Regex::new(&pattern)?)
+}
+
+fn extract_store_paths(store_regex: &Regex, s: &str) -> Result<Vec<StoreReference>> {
+    let mut references = Vec::new();
+    for cap in store_regex.captures_iter(s) {
+        let store_path = StorePath::new(&cap["path"])?;
+        if !store_path.path().exists() {
+            references.push(StoreReference::Unrealized(store_path));
+            continue;
+        }
+
+        match cap.name("outputs") {
+            Some(outputs) if store_path.is_derivation() => {
+                for output in outputs.as_str().split(',') {
+                    references.push(StoreReference::Derived(SingleDerivedPath::Built(
+                        SingleDerivedPathBuilt {
+                            drv_path: store_path.clone(),
+                            output: output.to_string(),
+                        },
+                    )));
+                }
+            }
+            // A derivation itself isn't needed to run the task.
+            _ if store_path.is_derivation() => {}
+            _ => references.push(StoreReference::Derived(SingleDerivedPath::Opaque(
+                store_path,
+            ))),
+        }
+    }
+    Ok(references)
+}
+
+// The inputs added for the store references found in `source`.
+fn reference_inputs<'a>(
+    references: &'a [StoreReference],
+    origin: InputOrigin,
+    source: Option<&'a str>,
+) -> impl Iterator<Item = TaskInput> + 'a {
+    references
+        .iter()
+        .filter_map(move |reference| match reference {
+            StoreReference::Derived(path) => Some(TaskInput {
+                path: format!("{}", path),
+                origin,
+                source: source.map(str::to_string),
+            }),
+            StoreReference::Unrealized(_) => None,
+        })
+}
+
+// Replace the store paths among `references` by their closure, so that the
+// paths they reference, like the libraries of a `-dev` output, are inputs
+// too. Outputs of derivations are left as they are, since their closure is
+// only known once they are built.
+fn expand_closures(
+    nix: &NixTool,
+    closures: &ClosureCache,
+    references: Vec<StoreReference>,
+) -> Result<Vec<StoreReference>> {
+    let mut expanded = Vec::new();
+    for reference in references {
+        let StoreReference::Derived(SingleDerivedPath::Opaque(store_path)) = &reference else {
+            expanded.push(reference);
+            continue;
+        };
+        for path in closures.closure(nix, store_path)? {
+            let reference = StoreReference::Derived(SingleDerivedPath::Opaque(path));
+            if !expanded.contains(&reference) {
+                expanded.push(reference);
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+// Replace the unrealized store paths among `references` by the output of the
+// derivation in the store that outputs them, failing for paths that no such
+// derivation outputs. The outputs of the derivation running nix-ninja, like
+// the rpath of the package being built, are left as they are, since tasks
+// can't depend on them.
+fn resolve_unrealized(
+    nix: &NixTool,
+    env_vars: &HashMap<String, String>,
+    references: Vec<StoreReference>,
+) -> Result<Vec<StoreReference>> {
+    let own_outputs: HashSet<&str> = env_vars
+        .get("outputs")
+        .map_or("", String::as_str)
+        .split_whitespace()
+        .filter_map(|output| env_vars.get(output).map(String::as_str))
+        .collect();
+
+    let mut resolved = Vec::new();
+    for reference in references {
+        let StoreReference::Unrealized(store_path) = &reference else {
+            resolved.push(reference);
+            continue;
+        };
+        if own_outputs.contains(format!("{}", store_path).as_str()) {
+            resolved.push(reference);
+            continue;
+        }
+
+        let built = nix
+            .valid_derivers(store_path)?
+            .into_iter()
+            .find_map(|drv_path| {
+                let drvs = nix.derivation_show_parsed(&drv_path).ok()?;
+                let output = drvs.values().find_map(|drv| {
+                    drv.outputs.iter().find_map(|(name, output)| {
+                        let path = Path::new(output.path.as_deref()?);
+                        (path.file_name() == store_path.path().file_name()).then(|| name.clone())
+                    })
+                })?;
+                Some(SingleDerivedPathBuilt { drv_path, output })
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} isn't in the store and no derivation in the store outputs it",
+                    store_path
+                )
+            })?;
+        resolved.push(StoreReference::Derived(SingleDerivedPath::Built(built)));
+    }
+    Ok(resolved)
 }
 
-fn new_built_file(drv_path: &StorePath, path: PathBuf) -> DerivedFile {
+fn add_store_references(drv: &mut Derivation, references: Vec<StoreReference>) {
+    for reference in references {
+        match reference {
+            StoreReference::Derived(SingleDerivedPath::Opaque(store_path)) => {
+                drv.add_input_src(&format!("{}", store_path));
+            }
+            StoreReference::Derived(SingleDerivedPath::Built(built)) => {
+                drv.add_input_drv(&format!("{}", built.drv_path), vec![built.output]);
+            }
+            // The outputs of the derivation running nix-ninja, left by
+            // resolve_unrealized, can't be depended on.
+            StoreReference::Unrealized(_) => {}
+        }
+    }
+}
+
+/// SourceCache remembers the source files added to the store, so that a file
+/// referenced by many tasks is only added once and the same store path is used
+/// everywhere. It is shared with the threads generating task derivations.
+#[derive(Clone, Default)]
+struct SourceCache {
+    added: Arc<Mutex<HashMap<(PathBuf, OutputHashMode), DerivedFile>>>,
+}
+
+impl SourceCache {
+    fn add(&self, nix: &NixTool, source_root: &Path, path: PathBuf) -> Result<DerivedFile> {
+        self.add_with_mode(nix, source_root, path, OutputHashMode::Nar)
+    }
+
+    fn add_with_mode(
+        &self,
+        nix: &NixTool,
+        source_root: &Path,
+        path: PathBuf,
+        mode: OutputHashMode,
+    ) -> Result<DerivedFile> {
+        let relative_path = relative_from(&path, source_root).unwrap_or(path);
+        let key = (source_key(&relative_path), mode);
+        if let Some(derived_file) = self.added.lock().unwrap().get(&key) {
+            return Ok(derived_file.clone());
+        }
+
+        // Don't hold the lock while adding to the store. If another thread
+        // added the same file meanwhile, keep its entry.
+        let derived_file = new_opaque_file(nix, source_root, relative_path, mode)?;
+        let mut added = self.added.lock().unwrap();
+        Ok(added.entry(key).or_insert(derived_file).clone())
+    }
+
+    fn remove(&self, source: &Path) {
+        let source = source_key(source);
+        self.added
+            .lock()
+            .unwrap()
+            .retain(|(path, _), _| *path != source);
+    }
+}
+
+/// DerivationCache remembers the derivations added to the store by their
+/// fingerprint, so that builds generating an identical derivation reuse the
+/// store path of the first one instead of adding it again.
+#[derive(Clone, Default)]
+struct DerivationCache {
+    added: Arc<Mutex<HashMap<String, StorePath>>>,
+    /// Number of derivations added to the store, for build reports.
+    added_count: Arc<AtomicUsize>,
+}
+
+impl DerivationCache {
+    fn add(&self, nix: &NixTool, drv: &Derivation) -> Result<StorePath> {
+        let fingerprint = drv.fingerprint()?;
+        if let Some(drv_path) = self.added.lock().unwrap().get(&fingerprint) {
+            return Ok(drv_path.clone());
+        }
+
+        // Like sources, don't hold the lock while adding to the store.
+        let drv_path = nix.derivation_add(drv)?;
+        self.added_count.fetch_add(1, Ordering::Relaxed);
+        let mut added = self.added.lock().unwrap();
+        Ok(added.entry(fingerprint).or_insert(drv_path).clone())
+    }
+}
+
+/// ClosureCache remembers the closure of store paths, which are mostly the
+/// same for every task since they come from the environment, so that each is
+/// only queried from the store once.
+#[derive(Clone, Default)]
+struct ClosureCache {
+    queried: Arc<Mutex<BTreeMap<StorePath, Vec<StorePath>>>>,
+}
+
+impl ClosureCache {
+    fn closure(&self, nix: &NixTool, store_path: &StorePath) -> Result<Vec<StorePath>> {
+        if let Some(closure) = self.queried.lock().unwrap().get(store_path) {
+            return Ok(closure.clone());
+        }
+
+        let closure = nix.requisites(store_path)?;
+        let mut queried = self.queried.lock().unwrap();
+        Ok(queried.entry(store_path.clone()).or_insert(closure).clone())
+    }
+}
+
+fn source_key(source: &Path) -> PathBuf {
+    normalize_path(source)
+}
+
+fn new_opaque_file(
+    nix: &NixTool,
+    source_root: &Path,
+    path: PathBuf,
+    mode: OutputHashMode,
+) -> Result<DerivedFile> {
+    let relative_path = relative_from(&path, source_root).unwrap_or(path);
+    let source = normalize_path(&relative_path);
+
+    let canonical_path = fs::canonicalize(source_root.join(&source))?;
+    let store_path = nix.store_add_with_mode(&canonical_path, mode)?;
+    Ok(DerivedFile {
+        path: SingleDerivedPath::Opaque(store_path.clone()),
+        source,
+    })
+}
+
+// Headers discovered by the compiler are added flat, which is cheaper than
+// hashing a NAR. Other sources are added as a NAR like `nix store add` does, as
+// are discovered files that are executable, since a flat file loses the
+// executable bit.
+fn discovered_mode(path: &Path) -> Result<OutputHashMode> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = fs::metadata(path)?;
+    if metadata.is_file() && metadata.permissions().mode() & 0o111 == 0 {
+        Ok(OutputHashMode::Flat)
+    } else {
+        Ok(OutputHashMode::Nar)
+    }
+}
+
+fn source_mtime(source_root: &Path, derived_file: &DerivedFile) -> Option<SystemTime> {
+    let metadata = fs::metadata(source_root.join(&derived_file.source)).ok()?;
+    metadata.modified().ok()
+}
+
+fn new_built_file(drv_path: &StorePath, output_name: &str, path: PathBuf) -> DerivedFile {
     let derived_built = SingleDerivedPathBuilt {
         drv_path: drv_path.clone(),
-        output: normalize_output(&path.to_string_lossy()),
+        output: output_name.to_string(),
     };
     DerivedFile {
         path: SingleDerivedPath::Built(derived_built),
@@ -597,14 +1893,24 @@ fn new_built_file(drv_path: &StorePath, path: PathBuf) -> DerivedFile {
     }
 }
 
+// Tasks with many inputs can exceed the limits on the size of environment
+// variables, so Nix is asked to pass them to nix-ninja-task as a file instead
+// which is then found at $NIX_NINJA_INPUTSPath.
+fn add_inputs_env(drv: &mut Derivation, inputs: &str, max_inline_input_chars: usize) {
+    drv.add_env("NIX_NINJA_INPUTS", inputs);
+    if inputs.len() > max_inline_input_chars {
+        drv.add_env("passAsFile", "NIX_NINJA_INPUTS");
+    }
+}
+
 fn add_derived_path(drv: &mut Derivation, derived_file: &DerivedFile) {
     match &derived_file.path {
         SingleDerivedPath::Opaque(store_path) => {
-            drv.add_input_src(&store_path.to_string());
+            drv.add_input_src(&format!("{}", store_path));
         }
         SingleDerivedPath::Built(derived_built) => {
             drv.add_input_drv(
-                &derived_built.drv_path.to_string(),
+                &format!("{}", derived_built.drv_path),
                 vec![derived_built.output.clone()],
             );
         }
@@ -612,7 +1918,1141 @@ fn add_derived_path(drv: &mut Derivation, derived_file: &DerivedFile) {
 }
 
 // Derivation outputs cannot have `/` in them as its suffixed to the derivation
-// store path.
+// store path. Paths are mapped to output names injectively so that distinct
+// outputs like `a/b` and `a-b` never share an output: `/` becomes `-`, while
+// literal `-` and `=` are escaped as `=-` and `==`.
+//
+// mkMesonPackage.nix mirrors this encoding to select the target's output.
 fn normalize_output(output: &str) -> String {
-    output.replace('/', "-")
+    let mut normalized = String::with_capacity(output.len());
+    for c in output.chars() {
+        match c {
+            '/' => normalized.push('-'),
+            '-' => normalized.push_str("=-"),
+            '=' => normalized.push_str("=="),
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{fake_cc, fake_nix, nix_args, nix_calls, test_dir, write_executable};
+    use n2::load;
+    use nix_tool::StoreConfig;
+    use std::os::unix::fs::symlink;
+    use std::time::Duration;
+
+    // Runner using the fake nix in `dir`, with `dir/build` as build directory
+    // and `dir/store` as store.
+    fn test_runner(dir: &Path, jobs: usize) -> Runner {
+        let store_dir = dir.join("store");
+        let tools = Tools {
+            nix: NixTool::new(StoreConfig {
+                nix_tool: dir.join("nix").to_string_lossy().into_owned(),
+                extra_args: Vec::new(),
+                offline: false,
+                realise: false,
+            }),
+            coreutils: StorePath::new(store_dir.join("ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-coreutils"))
+                .unwrap(),
+            nix_ninja_task: StorePath::new(
+                store_dir.join("q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-nix-ninja-task"),
+            )
+            .unwrap(),
+            shell: Shell {
+                path: store_dir.join("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bash/bin/sh"),
+                store_path: StorePath::new(store_dir.join("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bash"))
+                    .unwrap(),
+            },
+            toolchain: Toolchain::new(&store_dir),
+        };
+        let config = RunnerConfig {
+            build_system: "x86_64-linux".to_string(),
+            host_system: "x86_64-linux".to_string(),
+            source_root: dir.join("build"),
+            store_dir,
+            keep_rspfile: false,
+            check_inputs: false,
+            deterministic: false,
+            prefix_task_output: false,
+            quiet: false,
+            env_closure: false,
+            task_timeout: None,
+            jobs,
+            jobs_per_rule: HashMap::new(),
+            pool_depths: HashMap::new(),
+            max_inline_input_chars: 32768,
+            max_input_srcs: 0,
+            strict_input_srcs: false,
+            forward_env: Vec::new(),
+            build_dir_exclude: Vec::new(),
+            msvc_deps_prefix: HashMap::new(),
+            cache_file: None,
+        };
+        Runner::new(tools, config).unwrap()
+    }
+
+    // Generate the derivation of the first build in `ninja`, where `@CC@` is
+    // replaced by a fake compiler, returning it with the build's outputs.
+    fn generate(dir: &Path, ninja: &str) -> (Derivation, Vec<DerivedFile>) {
+        fake_nix(dir);
+        let ninja = ninja.replace("@CC@", &fake_cc(dir).to_string_lossy());
+        let mut loader = load::Loader::new();
+        let bytes = format!("{}\0", ninja).into_bytes();
+        loader.parse(PathBuf::from("build.ninja"), &bytes).unwrap();
+
+        let mut runner = test_runner(dir, 1);
+        let graph = &mut loader.graph;
+        let bid = BuildId::from(0);
+        runner
+            .start(&mut graph.files, bid, &graph.builds[bid])
+            .unwrap();
+        runner.wait(&mut graph.files).1.unwrap();
+
+        let outputs: Vec<DerivedFile> = graph.builds[bid]
+            .outs()
+            .iter()
+            .map(|fid| runner.derived_files[fid].clone())
+            .collect();
+        let drv_path = outputs[0].path.store_path();
+        let drv = Derivation::from_json(&fs::read_to_string(drv_path.path()).unwrap()).unwrap();
+        (drv, outputs)
+    }
+
+    #[test]
+    fn test_jobs_bound_concurrency() {
+        let jobs = 3;
+        let mut runner = test_runner(Path::new("/nonexistent"), jobs);
+
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+
+        // Drive the runner the same way the scheduler does.
+        let mut pending: Vec<BuildId> = (0..20).map(BuildId::from).collect();
+        let mut finished = 0;
+        while finished < 20 {
+            while runner.can_start() {
+                let Some(bid) = pending.pop() else {
+                    break;
+                };
+                let active = active.clone();
+                let max_active = max_active.clone();
+                runner.spawn(bid, "cc", move || {
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(5));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    Ok(TaskOutput::default())
+                });
+            }
+
+            runner.recv();
+            finished += 1;
+        }
+
+        assert!(max_active.load(Ordering::SeqCst) <= jobs);
+        assert_eq!(runner.running, 0);
+    }
+
+    #[test]
+    fn test_jobs_per_rule_bound_concurrency() {
+        let mut runner = test_runner(Path::new("/nonexistent"), 4);
+        runner.config.jobs_per_rule.insert("link".to_string(), 1);
+
+        let active_links = Arc::new(AtomicUsize::new(0));
+        let max_active_links = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+
+        // Drive the runner the same way the scheduler does, deferring builds
+        // whose rule is at its limit.
+        let rule = |bid: BuildId| if bid.index() < 10 { "link" } else { "cc" };
+        let mut pending: Vec<BuildId> = (0..20).map(BuildId::from).collect();
+        let mut finished = 0;
+        while finished < 20 {
+            let mut deferred = Vec::new();
+            while runner.can_start() {
+                let Some(bid) = pending.pop() else {
+                    break;
+                };
+                if !runner.can_start_rule(rule(bid)) {
+                    deferred.push(bid);
+                    continue;
+                }
+                let is_link = rule(bid) == "link";
+                let active_links = active_links.clone();
+                let max_active_links = max_active_links.clone();
+                runner.spawn(bid, rule(bid), move || {
+                    if is_link {
+                        let now = active_links.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_active_links.fetch_max(now, Ordering::SeqCst);
+                    }
+                    std::thread::sleep(Duration::from_millis(5));
+                    if is_link {
+                        active_links.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    Ok(TaskOutput::default())
+                });
+                max_running.fetch_max(runner.running, Ordering::SeqCst);
+            }
+            pending.extend(deferred.into_iter().rev());
+
+            runner.recv();
+            finished += 1;
+        }
+
+        assert_eq!(max_active_links.load(Ordering::SeqCst), 1);
+        assert!(max_running.load(Ordering::SeqCst) > 1);
+        assert!(runner.running_rules.is_empty());
+    }
+
+    #[test]
+    fn test_can_start_pool() {
+        let mut runner = test_runner(Path::new("/nonexistent"), 4);
+        runner.config.pool_depths.insert("link_pool".to_string(), 2);
+        runner
+            .config
+            .pool_depths
+            .insert(CONSOLE_POOL.to_string(), 1);
+
+        let (tx, rx) = mpsc::channel::<()>();
+        let rx = Arc::new(Mutex::new(rx));
+        for (bid, pool) in [(0, "link_pool"), (1, "link_pool"), (2, CONSOLE_POOL)] {
+            let bid = BuildId::from(bid);
+            let rx = rx.clone();
+            runner.spawn(bid, "cc", move || {
+                rx.lock().unwrap().recv().unwrap();
+                Ok(TaskOutput::default())
+            });
+            runner.running_pools.insert(bid, pool.to_string());
+        }
+
+        assert!(!runner.can_start_pool(Some("link_pool")));
+        assert!(!runner.can_start_pool(Some(CONSOLE_POOL)));
+        assert!(runner.can_start_pool(Some("other")));
+        assert!(runner.can_start_pool(None));
+
+        for _ in 0..3 {
+            tx.send(()).unwrap();
+            runner.recv();
+        }
+        assert!(runner.running_pools.is_empty());
+        assert!(runner.can_start_pool(Some(CONSOLE_POOL)));
+    }
+
+    fn inputs_passed_as_file(inputs: &str, max_inline_input_chars: usize) -> bool {
+        let mut drv = Derivation::new("test", "x86_64-linux", "/bin/sh");
+        add_inputs_env(&mut drv, inputs, max_inline_input_chars);
+        assert_eq!(drv.env.get("NIX_NINJA_INPUTS").unwrap(), inputs);
+        drv.env.contains_key("passAsFile")
+    }
+
+    #[test]
+    fn test_read_build_dir_concurrently() {
+        let dir = test_dir("read-build-dir");
+        fake_nix(&dir);
+        let build_dir = dir.join("build");
+        fs::create_dir_all(build_dir.join("sub")).unwrap();
+        for i in 0..20 {
+            fs::write(build_dir.join(format!("sub/{}.h", i)), i.to_string()).unwrap();
+        }
+
+        let mut files = graph::GraphFiles::default();
+        let mut runner = test_runner(&dir, 4);
+        runner.read_build_dir(&mut files).unwrap();
+        assert_eq!(runner.build_dir_inputs.len(), 20);
+        assert_eq!(nix_calls(&dir, "store add"), 20);
+        for i in 0..20 {
+            let fid = files.lookup(&format!("sub/{}.h", i)).unwrap();
+            assert!(runner.is_build_dir_input(fid));
+            assert_eq!(
+                runner.derived_files[&fid].source,
+                Path::new(&format!("sub/{}.h", i))
+            );
+        }
+
+        // Failing to add a file fails the whole read.
+        fs::write(dir.join("nix"), "#!/bin/sh\nexit 1\n").unwrap();
+        let mut runner = test_runner(&dir, 4);
+        assert!(runner
+            .read_build_dir(&mut graph::GraphFiles::default())
+            .is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_build_dir_exclude() {
+        let dir = test_dir("build-dir-exclude");
+        fake_nix(&dir);
+        let build_dir = dir.join("build");
+        for path in [
+            "config.h",
+            ".git/HEAD",
+            "subprojects/zlib/zlib.h",
+            "src/gen.h",
+            "src/gen.h.tmp",
+        ] {
+            fs::create_dir_all(build_dir.join(path).parent().unwrap()).unwrap();
+            fs::write(build_dir.join(path), path).unwrap();
+        }
+
+        let mut files = graph::GraphFiles::default();
+        let mut runner = test_runner(&dir, 2);
+        runner.config.build_dir_exclude = vec!["subprojects/*".to_string(), "*.tmp".to_string()];
+        runner.read_build_dir(&mut files).unwrap();
+
+        let mut sources: Vec<String> = runner
+            .build_dir_inputs
+            .values()
+            .map(|input| input.source.to_string_lossy().into_owned())
+            .collect();
+        sources.sort();
+        assert_eq!(sources, vec!["config.h", "src/gen.h"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_build_dir_skips_store_symlinks() {
+        let dir = test_dir("build-dir-symlinks");
+        fake_nix(&dir);
+        let build_dir = dir.join("build");
+        fs::create_dir_all(&build_dir).unwrap();
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/config.h.in"), "").unwrap();
+        let output = dir.join("store/0123456789abcd0123456789abcd0123-main.o");
+        fs::create_dir_all(output.parent().unwrap()).unwrap();
+        fs::write(&output, "").unwrap();
+
+        // The output symlink left by a previous run isn't an input, unlike
+        // a symlink to a source file.
+        symlink(&output, build_dir.join("main.o")).unwrap();
+        symlink(dir.join("src/config.h.in"), build_dir.join("config.h")).unwrap();
+        symlink(dir.join("src"), build_dir.join("src")).unwrap();
+        symlink(dir.join("missing"), build_dir.join("dangling")).unwrap();
+
+        let mut files = graph::GraphFiles::default();
+        let mut runner = test_runner(&dir, 2);
+        runner.read_build_dir(&mut files).unwrap();
+        let sources: Vec<&Path> = runner
+            .build_dir_inputs
+            .values()
+            .map(|input| input.source.as_path())
+            .collect();
+        assert_eq!(sources, vec![Path::new("config.h")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_inline_input_chars() {
+        let inputs = "a".repeat(100);
+        assert!(!inputs_passed_as_file(&inputs, 101));
+        assert!(!inputs_passed_as_file(&inputs, 100));
+        assert!(inputs_passed_as_file(&inputs, 99));
+        assert!(inputs_passed_as_file(&inputs, 0));
+        assert!(!inputs_passed_as_file("", 0));
+    }
+
+    #[test]
+    fn test_normalize_output_injective() {
+        assert_eq!(normalize_output("src/a/b.o"), "src-a-b.o");
+        assert_eq!(normalize_output("src/a-b.o"), "src-a=-b.o");
+        assert_eq!(normalize_output("a=-b"), "a===-b");
+        assert_eq!(normalize_output("a/-b"), "a-=-b");
+
+        let paths = [
+            "a/b", "a-b", "a=b", "a=-b", "a/-b", "a-/b", "a==b", "a=/b", "a//b", "a--b",
+        ];
+        let normalized: HashSet<String> = paths.iter().map(|p| normalize_output(p)).collect();
+        assert_eq!(normalized.len(), paths.len());
+    }
+
+    #[test]
+    fn test_derivation_cache_adds_once() {
+        let dir = test_dir("derivations");
+        let nix = NixTool::new(StoreConfig {
+            nix_tool: fake_nix(&dir).to_string_lossy().into_owned(),
+            extra_args: Vec::new(),
+            offline: false,
+            realise: false,
+        });
+        let new = || {
+            let mut drv = Derivation::new("ninja-build-main.o", "x86_64-linux", "/bin/sh");
+            drv.add_env("PATH", "/bin")
+                .add_ca_output("main.o", HashAlgorithm::Sha256, OutputHashMode::Nar)
+                .unwrap();
+            drv
+        };
+
+        let derivations = DerivationCache::default();
+        let first = derivations.add(&nix, &new()).unwrap();
+        let second = derivations.add(&nix, &new()).unwrap();
+        assert_eq!(nix_calls(&dir, "derivation add"), 1);
+        assert!(first == second);
+
+        let mut other = new();
+        other.add_env("CC", "cc");
+        derivations.add(&nix, &other).unwrap();
+        assert_eq!(nix_calls(&dir, "derivation add"), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_source_cache_adds_once() {
+        let dir = test_dir("sources");
+        let nix = NixTool::new(StoreConfig {
+            nix_tool: fake_nix(&dir).to_string_lossy().into_owned(),
+            extra_args: Vec::new(),
+            offline: false,
+            realise: false,
+        });
+        fs::create_dir_all(dir.join("build/include")).unwrap();
+        fs::write(dir.join("build/include/util.h"), "int util();").unwrap();
+
+        let build_dir = dir.join("build");
+        let sources = SourceCache::default();
+        let first = sources
+            .add(&nix, &build_dir, build_dir.join("include/util.h"))
+            .unwrap();
+        let second = sources
+            .add(&nix, &build_dir, PathBuf::from("./include/util.h"))
+            .unwrap();
+        assert_eq!(nix_calls(&dir, "store add"), 1);
+        assert!(first == second);
+
+        // Paths through `..` are the same source, without the `..`.
+        let third = sources
+            .add(&nix, &build_dir, PathBuf::from("src/../include/util.h"))
+            .unwrap();
+        assert_eq!(nix_calls(&dir, "store add"), 1);
+        assert_eq!(third.source, PathBuf::from("include/util.h"));
+
+        // Tasks share the cache with the runner.
+        let shared = sources.clone();
+        std::thread::spawn(move || {
+            shared
+                .add(&nix, &build_dir, PathBuf::from("include/util.h"))
+                .unwrap();
+            shared.remove(Path::new("include/util.h"));
+        })
+        .join()
+        .unwrap();
+        assert_eq!(nix_calls(&dir, "store add"), 1);
+        assert!(sources.added.lock().unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_add_mode() {
+        let dir = test_dir("store-add-mode");
+        let nix = NixTool::new(StoreConfig {
+            nix_tool: fake_nix(&dir).to_string_lossy().into_owned(),
+            extra_args: Vec::new(),
+            offline: false,
+            realise: false,
+        });
+        let build_dir = dir.join("build");
+        fs::create_dir_all(&build_dir).unwrap();
+        fs::write(build_dir.join("util.h"), "int util();").unwrap();
+        write_executable(&build_dir.join("gen.sh"), "#!/bin/sh\n");
+
+        // Sources are added as a NAR by default, discovered headers flat
+        // unless they are executable.
+        let sources = SourceCache::default();
+        let add_discovered = |source: &str| {
+            let mode = discovered_mode(&build_dir.join(source)).unwrap();
+            sources
+                .add_with_mode(&nix, &build_dir, PathBuf::from(source), mode)
+                .unwrap()
+        };
+        sources
+            .add(&nix, &build_dir, PathBuf::from("util.h"))
+            .unwrap();
+        add_discovered("util.h");
+        add_discovered("gen.sh");
+        assert_eq!(sources.added.lock().unwrap().len(), 3);
+        let args = nix_args(&dir);
+        assert_eq!(args.len(), 3);
+        assert!(args[0].ends_with("util.h --mode nar"), "{}", args[0]);
+        assert!(args[1].ends_with("util.h --mode flat"), "{}", args[1]);
+        assert!(args[2].ends_with("gen.sh --mode nar"), "{}", args[2]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_binary_store_path_symlinks() {
+        let dir = test_dir("binary-store-path");
+        let store_dir = dir.join("store");
+
+        // A compiler in the `-bin` output, exposed by a wrapper and a profile
+        // through a chain of symlinks.
+        let gcc_bin = store_dir.join("ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-gcc-14.2.1-bin");
+        write_executable(&gcc_bin.join("libexec/gcc/cc1"), "#!/bin/sh\n");
+        let wrapper = store_dir.join("q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-gcc-wrapper-14.2.1");
+        fs::create_dir_all(wrapper.join("bin")).unwrap();
+        symlink(
+            "../../ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-gcc-14.2.1-bin/libexec/gcc/cc1",
+            wrapper.join("bin/cc1"),
+        )
+        .unwrap();
+        fs::create_dir_all(dir.join("profile/bin")).unwrap();
+        symlink(wrapper.join("bin/cc1"), dir.join("profile/bin/cc1")).unwrap();
+
+        let canonical_gcc_bin = fs::canonicalize(&gcc_bin).unwrap();
+        for binary in [
+            gcc_bin.join("libexec/gcc/cc1"),
+            wrapper.join("bin/cc1"),
+            dir.join("profile/bin/cc1"),
+        ] {
+            let store_path = binary_store_path(&binary, &store_dir).unwrap().unwrap();
+            assert_eq!(store_path.path(), &canonical_gcc_bin);
+        }
+
+        // Binaries outside of the store have no store path.
+        write_executable(&dir.join("local/bin/cc"), "#!/bin/sh\n");
+        assert!(binary_store_path(&dir.join("local/bin/cc"), &store_dir)
+            .unwrap()
+            .is_none());
+
+        // Nor do binaries under directories that merely look like store paths,
+        // outside of the store directory.
+        let lookalike = dir.join("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-opt/bin/cc");
+        write_executable(&lookalike, "#!/bin/sh\n");
+        assert!(binary_store_path(&lookalike, &store_dir).unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_binary_path() {
+        let dir = fs::canonicalize(test_dir("store-binary-path")).unwrap();
+        let bash = dir.join("store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-bash-5.2");
+        write_executable(&bash.join("bin/bash"), "#!/bin/sh\n");
+        symlink("bash", bash.join("bin/sh")).unwrap();
+        fs::create_dir_all(dir.join("profile/bin")).unwrap();
+        symlink(bash.join("bin/sh"), dir.join("profile/bin/sh")).unwrap();
+
+        // The shell keeps being invoked as `sh`, which bash behaves
+        // differently as.
+        let store_path = binary_store_path(&dir.join("profile/bin/sh"), &dir.join("store"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            store_binary_path(&dir.join("profile/bin/sh"), &store_path).unwrap(),
+            bash.join("bin/sh")
+        );
+        assert_eq!(
+            store_binary_path(&bash.join("bin/sh"), &store_path).unwrap(),
+            bash.join("bin/sh")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_store_paths() {
+        let dir = test_dir("extract");
+        let store_dir = dir.join("store");
+        let zlib = store_dir.join("ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-zlib-1.3.1");
+        let drv = store_dir.join("q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-openssl-3.4.drv");
+        let missing = store_dir.join("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-nix-2.28");
+        fs::create_dir_all(&zlib).unwrap();
+        fs::write(&drv, "").unwrap();
+
+        // Not a store path, as 'e' isn't a Nix base32 character.
+        let lookalike = store_dir.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-x");
+        let cmdline = format!(
+            "cc -I{}/include -I{}^dev,out/include {} -Wl,-rpath,{}/lib not/a/store/path {}",
+            zlib.display(),
+            drv.display(),
+            drv.display(),
+            missing.display(),
+            lookalike.display(),
+        );
+        let store_regex = new_store_regex(&store_dir).unwrap();
+        let references = extract_store_paths(&store_regex, &cmdline).unwrap();
+
+        let built = |output: &str| {
+            StoreReference::Derived(SingleDerivedPath::Built(SingleDerivedPathBuilt {
+                drv_path: StorePath::new(&drv).unwrap(),
+                output: output.to_string(),
+            }))
+        };
+        assert!(
+            references
+                == vec![
+                    StoreReference::Derived(SingleDerivedPath::Opaque(
+                        StorePath::new(&zlib).unwrap()
+                    )),
+                    built("dev"),
+                    built("out"),
+                    StoreReference::Unrealized(StorePath::new(&missing).unwrap()),
+                ]
+        );
+
+        let mut derivation = Derivation::new("test", "x86_64-linux", "/bin/sh");
+        add_store_references(&mut derivation, references);
+        assert!(derivation
+            .input_srcs
+            .contains(&zlib.to_string_lossy().into_owned()));
+        assert_eq!(
+            derivation.input_drvs[&drv.to_string_lossy().into_owned()].outputs,
+            vec!["dev".to_string(), "out".to_string()]
+        );
+        assert_eq!(derivation.input_srcs.len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_unrealized() {
+        let dir = test_dir("unrealized");
+        let store_dir = dir.join("store");
+        let unbuilt = store_dir.join("ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-libfoo-1.0-lib");
+        let drv = store_dir.join("q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-libfoo-1.0.drv");
+        let own = store_dir.join("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-app");
+        let orphan = store_dir.join("0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-orphan");
+        fs::create_dir_all(&store_dir).unwrap();
+        let mut libfoo = Derivation::new("libfoo-1.0", "x86_64-linux", "/bin/sh");
+        libfoo.outputs.insert(
+            "lib".to_string(),
+            nix_libstore::derivation::Output {
+                path: Some(unbuilt.to_string_lossy().into_owned()),
+                hash_algo: None,
+                method: None,
+                hash: None,
+            },
+        );
+        fs::write(&drv, libfoo.to_json().unwrap()).unwrap();
+
+        // Stands in for nix-store, which is looked up next to the nix tool.
+        write_executable(
+            &dir.join("nix-store"),
+            &format!(
+                "#!/bin/sh
+                 case \"$3\" in
+                     {unbuilt}) echo {drv} ;;
+                 esac
+",
+                unbuilt = unbuilt.display(),
+                drv = drv.display(),
+            ),
+        );
+        let nix = NixTool::new(StoreConfig {
+            nix_tool: fake_nix(&dir).to_string_lossy().into_owned(),
+            extra_args: Vec::new(),
+            offline: false,
+            realise: false,
+        });
+        let env_vars = HashMap::from([
+            ("outputs".to_string(), "out".to_string()),
+            ("out".to_string(), own.to_string_lossy().into_owned()),
+        ]);
+
+        // Unbuilt outputs become inputs through their derivation, while the
+        // outputs of the derivation running nix-ninja are left alone.
+        let store_regex = new_store_regex(&store_dir).unwrap();
+        let value = format!(
+            "-L{}/lib -Wl,-rpath,{}/lib",
+            unbuilt.display(),
+            own.display()
+        );
+        let references = extract_store_paths(&store_regex, &value).unwrap();
+        let resolved = resolve_unrealized(&nix, &env_vars, references).unwrap();
+        assert!(
+            resolved
+                == vec![
+                    StoreReference::Derived(SingleDerivedPath::Built(SingleDerivedPathBuilt {
+                        drv_path: StorePath::new(&drv).unwrap(),
+                        output: "lib".to_string(),
+                    })),
+                    StoreReference::Unrealized(StorePath::new(&own).unwrap()),
+                ]
+        );
+
+        // Other paths that aren't in the store are errors.
+        let value = format!("-L{}/lib", orphan.display());
+        let references = extract_store_paths(&store_regex, &value).unwrap();
+        let err = resolve_unrealized(&nix, &env_vars, references)
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains("no derivation in the store outputs it"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand_closures() {
+        let dir = test_dir("closures");
+        let store_dir = dir.join("store");
+        let dev = store_dir.join("ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-zlib-1.3.1-dev");
+        let lib = store_dir.join("q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-zlib-1.3.1");
+        let glibc = store_dir.join("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-glibc-2.40");
+        let drv = store_dir.join("0c7fqpfsb1khrxs5ra7gx4wxrk2xqpqm-openssl-3.4.drv");
+        for path in [&dev, &lib, &glibc] {
+            fs::create_dir_all(path).unwrap();
+        }
+        fs::write(&drv, "").unwrap();
+
+        // Stands in for nix-store, which is looked up next to the nix tool.
+        write_executable(
+            &dir.join("nix-store"),
+            &format!(
+                "#!/bin/sh
+                 echo \"$@\" >> {dir}/nix-store.log
+                 case \"$3\" in
+                     {dev}) printf '%s\\n' {glibc} {lib} {dev} ;;
+                     {lib}) printf '%s\\n' {glibc} {lib} ;;
+                     *) exit 1 ;;
+                 esac
+",
+                dir = dir.display(),
+                dev = dev.display(),
+                lib = lib.display(),
+                glibc = glibc.display(),
+            ),
+        );
+        let nix = NixTool::new(StoreConfig {
+            nix_tool: dir.join("nix").to_string_lossy().into_owned(),
+            extra_args: Vec::new(),
+            offline: false,
+            realise: false,
+        });
+
+        let store_regex = new_store_regex(&store_dir).unwrap();
+        let value = format!(
+            "-L{}/lib -isystem {}/include -I{}^dev/include",
+            lib.display(),
+            dev.display(),
+            drv.display()
+        );
+        let closures = ClosureCache::default();
+        let references = extract_store_paths(&store_regex, &value).unwrap();
+        let expanded = expand_closures(&nix, &closures, references).unwrap();
+
+        let opaque = |path: &Path| {
+            StoreReference::Derived(SingleDerivedPath::Opaque(StorePath::new(path).unwrap()))
+        };
+        assert!(
+            expanded
+                == vec![
+                    opaque(&glibc),
+                    opaque(&lib),
+                    opaque(&dev),
+                    StoreReference::Derived(SingleDerivedPath::Built(SingleDerivedPathBuilt {
+                        drv_path: StorePath::new(&drv).unwrap(),
+                        output: "dev".to_string(),
+                    })),
+                ]
+        );
+
+        // Closures are only queried once.
+        let references = extract_store_paths(&store_regex, &value).unwrap();
+        expand_closures(&nix, &closures, references).unwrap();
+        let queries = fs::read_to_string(dir.join("nix-store.log")).unwrap();
+        assert_eq!(queries.lines().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_msvc_deps() {
+        let dir = test_dir("msvc-deps");
+        fs::create_dir_all(dir.join("build/include")).unwrap();
+        fs::write(dir.join("build/main.c"), "#include \"util.h\"").unwrap();
+        fs::write(dir.join("build/include/util.h"), "").unwrap();
+        let sdk = dir.join("store/0123456789abcd0123456789abcd0123-sdk");
+        fs::create_dir_all(sdk.join("include")).unwrap();
+        fs::write(sdk.join("include/stdio.h"), "").unwrap();
+
+        // Stands in for cl, printing the headers included by the source and
+        // logging its arguments.
+        let cl = dir.join("store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-cl/bin/cl");
+        write_executable(
+            &cl,
+            &format!(
+                "#!/bin/sh\necho \"$@\" > {}\n\
+                 echo main.c\n\
+                 echo 'Note: including file: include/util.h'\n\
+                 echo 'Note: including file:  {}/include/stdio.h'\n",
+                dir.join("cl.log").display(),
+                sdk.display()
+            ),
+        );
+
+        let (drv, _) = generate(
+            &dir,
+            &format!(
+                "rule cl
+  command = {} /nologo /Iinclude /c $in /Fo$out
+  deps = msvc
+build main.obj: cl main.c
+",
+                cl.display()
+            ),
+        );
+
+        // Nothing is compiled while looking for headers.
+        let args = fs::read_to_string(dir.join("cl.log")).unwrap();
+        assert_eq!(args.trim(), "/nologo /Iinclude /c main.c /showIncludes /Zs");
+
+        // Headers in the build directory are inputs of the task, and headers in
+        // the store are input sources.
+        assert!(drv.env["NIX_NINJA_INPUTS"].contains(":include/util.h"));
+        assert!(drv.input_srcs.contains(&sdk.to_string_lossy().into_owned()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_includes_skipped() {
+        let dir = test_dir("missing-includes");
+        fs::create_dir_all(dir.join("build/include")).unwrap();
+        fs::write(dir.join("build/main.c"), "#include \"util.h\"").unwrap();
+        fs::write(dir.join("build/include/util.h"), "").unwrap();
+
+        // Stands in for cl, naming headers under an include dir that doesn't
+        // exist, and one left with a literal ninja variable.
+        let cl = dir.join("store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-cl/bin/cl");
+        write_executable(
+            &cl,
+            "#!/bin/sh\n\
+             echo 'Note: including file: include/util.h'\n\
+             echo 'Note: including file: nonexistent/stdio.h'\n\
+             echo 'Note: including file: $in/config.h'\n",
+        );
+
+        let (drv, _) = generate(
+            &dir,
+            &format!(
+                "rule cl
+  command = {} /nologo /Inonexistent /c $in /Fo$out
+  deps = msvc
+build main.obj: cl main.c
+",
+                cl.display()
+            ),
+        );
+        let inputs = &drv.env["NIX_NINJA_INPUTS"];
+        assert!(inputs.contains(":include/util.h"));
+        assert!(!inputs.contains("stdio.h"));
+        assert!(!inputs.contains("config.h"));
+
+        // The same goes for gcc-style discovery with a nonexistent include dir.
+        let (drv, _) = generate(
+            &dir,
+            "rule cc
+  command = @CC@ -I/nonexistent/include -Iinclude -I'$in' -c $in -o $out
+  deps = gcc
+build main.o: cc main.c
+",
+        );
+        let inputs = &drv.env["NIX_NINJA_INPUTS"];
+        assert!(inputs.contains(":include/util.h"));
+        assert!(!inputs.contains("nonexistent"));
+        assert!(!drv.input_srcs.iter().any(|src| src.contains("nonexistent")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_add_extra_inputs_selectors() {
+        let dir = test_dir("extra-inputs");
+        fake_nix(&dir);
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::write(dir.join("build/finally.hh"), "").unwrap();
+        let mut loader = load::Loader::new();
+        let ninja = "rule cxx_COMPILER
+  command = c++ -c $in -o $out
+rule bison
+  command = bison $in -o $out
+build parser.tab.cc: bison parser.y
+build lexer.tab.cc: bison lexer.l
+build parser.o: cxx_COMPILER parser.tab.cc
+build main.o: cxx_COMPILER main.cc
+\0";
+        loader
+            .parse(PathBuf::from("build.ninja"), ninja.as_bytes())
+            .unwrap();
+        let graph = &mut loader.graph;
+        let bid = |graph: &graph::Graph, target: &str| {
+            graph.files.by_id[graph.files.lookup(target).unwrap()]
+                .input
+                .unwrap()
+        };
+        let targets = |runner: &Runner, graph: &graph::Graph| {
+            let mut targets: Vec<String> = runner
+                .extra_inputs
+                .keys()
+                .map(|&bid| graph.files.by_id[graph.builds[bid].outs()[0]].name.clone())
+                .collect();
+            targets.sort();
+            targets
+        };
+
+        // By rule name.
+        let mut runner = test_runner(&dir, 1);
+        runner
+            .add_extra_inputs(graph, vec!["cxx_COMPILER:finally.hh".to_string()])
+            .unwrap();
+        assert_eq!(targets(&runner, graph), vec!["main.o", "parser.o"]);
+        let fid = graph.files.lookup("finally.hh").unwrap();
+        assert_eq!(runner.undeclared_consumers(fid).count(), 2);
+
+        // By a glob of outputs.
+        let mut runner = test_runner(&dir, 1);
+        runner
+            .add_extra_inputs(graph, vec!["*.tab.cc:finally.hh".to_string()])
+            .unwrap();
+        assert_eq!(
+            targets(&runner, graph),
+            vec!["lexer.tab.cc", "parser.tab.cc"]
+        );
+
+        // By exact target, which takes precedence.
+        let mut runner = test_runner(&dir, 1);
+        runner
+            .add_extra_inputs(graph, vec!["parser.o:finally.hh".to_string()])
+            .unwrap();
+        assert!(runner.extra_inputs.contains_key(&bid(graph, "parser.o")));
+        assert_eq!(runner.extra_inputs.len(), 1);
+
+        // Selectors have to match some build.
+        for selector in ["link:finally.hh", "*.so:finally.hh", "parser.y:finally.hh"] {
+            let mut runner = test_runner(&dir, 1);
+            assert!(runner
+                .add_extra_inputs(graph, vec![selector.to_string()])
+                .is_err());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_forward_env_defaults() {
+        let dir = test_dir("forward-env");
+        fake_nix(&dir);
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::write(dir.join("build/main.c"), "").unwrap();
+        let mut loader = load::Loader::new();
+        let ninja = "rule cc\n  command = cc -c $in -o $out\nbuild main.o: cc main.c\n\0";
+        loader
+            .parse(PathBuf::from("build.ninja"), ninja.as_bytes())
+            .unwrap();
+
+        let mut runner = test_runner(&dir, 1);
+        runner.config.forward_env = vec!["PKG_CONFIG_*".to_string()];
+        let graph = &mut loader.graph;
+        let bid = BuildId::from(0);
+        let task = runner
+            .new_task(&mut graph.files, bid, &graph.builds[bid])
+            .unwrap();
+
+        // Patterns given by the user add to the defaults.
+        assert!(task.forwards_env("PKG_CONFIG_PATH"));
+        assert!(task.forwards_env("NIX_CFLAGS_COMPILE"));
+        assert!(task.forwards_env("NIX_CC_WRAPPER_TARGET_HOST_x86_64_unknown_linux_gnu"));
+        assert!(!task.forwards_env("HOME"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_order_only_inputs() {
+        let dir = test_dir("order-only");
+        fake_nix(&dir);
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::write(dir.join("build/main.c"), "").unwrap();
+        fs::write(dir.join("build/util.h"), "").unwrap();
+        fs::write(dir.join("build/gen.c"), "").unwrap();
+        let mut loader = load::Loader::new();
+        let ninja = "rule cc
+  command = cc -c $in -o $out
+  deps = gcc
+build main.o: cc main.c | util.h || gen.c
+\0";
+        loader
+            .parse(PathBuf::from("build.ninja"), ninja.as_bytes())
+            .unwrap();
+
+        let mut runner = test_runner(&dir, 1);
+        let graph = &mut loader.graph;
+        let bid = BuildId::from(0);
+        let task = runner
+            .new_task(&mut graph.files, bid, &graph.builds[bid])
+            .unwrap();
+
+        // Order-only inputs are linked, but not processed by the compiler.
+        let inputs: Vec<&Path> = task.inputs.iter().map(|i| i.source.as_path()).collect();
+        assert_eq!(
+            inputs,
+            vec![Path::new("gen.c"), Path::new("main.c"), Path::new("util.h")]
+        );
+        assert_eq!(task.order_only, HashSet::from([PathBuf::from("gen.c")]));
+        assert_eq!(
+            task.compiler_inputs(),
+            HashSet::from([PathBuf::from("main.c"), PathBuf::from("util.h")])
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_local_executable() {
+        let build_dir = Path::new("/src/build");
+        assert_eq!(local_executable(build_dir, "cc"), None);
+        assert_eq!(
+            local_executable(build_dir, "./gen"),
+            Some(PathBuf::from("./gen"))
+        );
+        assert_eq!(
+            local_executable(build_dir, "/src/build/tools/gen"),
+            Some(PathBuf::from("tools/gen"))
+        );
+        assert_eq!(local_executable(build_dir, "/usr/bin/cc"), None);
+
+        // Scripts of the build directory are linked into the task.
+        let dir = test_dir("local-executable");
+        write_executable(&dir.join("build/tools/gen.sh"), "#!/bin/sh\ncp $1 $2\n");
+        fs::write(dir.join("build/in.txt"), "").unwrap();
+        let (drv, _) = generate(
+            &dir,
+            "rule gen
+  command = ./tools/gen.sh $in $out
+build out.h: gen in.txt
+",
+        );
+        assert!(drv.env["NIX_NINJA_INPUTS"].contains(":tools/gen.sh"));
+        assert!(drv.input_srcs.iter().any(|src| src.ends_with("-gen.sh")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_implicit_outputs() {
+        let dir = test_dir("implicit-outputs");
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::write(dir.join("build/main.c"), "int main() {}").unwrap();
+
+        let (drv, outputs) = generate(
+            &dir,
+            "rule cc
+  command = @CC@ -MD -MF $out.d -Wl,-Map,main.map -c $in -o $out
+build main.o | main.o.d main.map: cc main.c
+",
+        );
+
+        // Implicit outputs get their own content addressed outputs, and are
+        // copied out by the task like explicit outputs.
+        let mut output_names: Vec<&String> = drv.outputs.keys().collect();
+        output_names.sort();
+        assert_eq!(output_names, vec!["main.map", "main.o", "main.o.d"]);
+        let task_outputs = &drv.env["NIX_NINJA_OUTPUTS"];
+        for name in ["main.o", "main.o.d", "main.map"] {
+            assert!(task_outputs.contains(&format!(":{}", name)));
+        }
+
+        // The command runs with the shell of the tools, which is an input.
+        let store_dir = dir.join("store");
+        assert_eq!(
+            drv.env["NIX_NINJA_SHELL"],
+            store_dir
+                .join("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bash/bin/sh")
+                .to_string_lossy()
+        );
+        assert!(drv.input_srcs.contains(
+            &store_dir
+                .join("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bash")
+                .to_string_lossy()
+                .into_owned()
+        ));
+
+        let sources: Vec<PathBuf> = outputs.iter().map(|o| o.source.clone()).collect();
+        assert_eq!(
+            sources,
+            vec![
+                PathBuf::from("main.o"),
+                PathBuf::from("main.o.d"),
+                PathBuf::from("main.map")
+            ]
+        );
+        for output in &outputs {
+            assert!(matches!(output.path, SingleDerivedPath::Built(_)));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_outputs_round_trip() {
+        let dir = test_dir("outputs-round-trip");
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::write(dir.join("build/main.c"), "int main() {}").unwrap();
+
+        let (drv, outputs) = generate(
+            &dir,
+            "rule cc
+  command = @CC@ -MD -MF $out.d -c $in -o $out
+build obj/main.o | obj/main.o.d gen/main-map.txt: cc main.c
+",
+        );
+        assert_eq!(drv.outputs.len(), 3);
+
+        // Like Nix, replace the placeholder of every output by its path.
+        let mut task_outputs = drv.env["NIX_NINJA_OUTPUTS"].clone();
+        for name in drv.outputs.keys() {
+            let placeholder = Placeholder::standard_output(name).render();
+            task_outputs = task_outputs.replace(
+                placeholder.to_str().unwrap(),
+                &format!("/nix/store/{}", name),
+            );
+        }
+
+        // nix-ninja-task copies each output to the path of its own output,
+        // which dependent builds reference.
+        let mut copies: Vec<(String, String)> = task_outputs
+            .split_whitespace()
+            .map(|encoded| decode_pair(encoded).unwrap())
+            .map(|(path, source)| (source, path))
+            .collect();
+        copies.sort();
+        assert_eq!(
+            copies,
+            vec![
+                (
+                    "gen/main-map.txt".to_string(),
+                    "/nix/store/gen-main=-map.txt".to_string()
+                ),
+                (
+                    "obj/main.o".to_string(),
+                    "/nix/store/obj-main.o".to_string()
+                ),
+                (
+                    "obj/main.o.d".to_string(),
+                    "/nix/store/obj-main.o.d".to_string()
+                ),
+            ]
+        );
+        for output in &outputs {
+            let SingleDerivedPath::Built(built) = &output.path else {
+                panic!("{} isn't a built output", output.source.display());
+            };
+            let (_, path) = copies
+                .iter()
+                .find(|(source, _)| Path::new(source) == output.source)
+                .unwrap();
+            assert_eq!(*path, format!("/nix/store/{}", built.output));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
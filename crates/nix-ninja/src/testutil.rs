@@ -0,0 +1,187 @@
+//! Helpers for tests that run nix-ninja against a fake Nix store.
+
+use crate::build::{BuildConfig, Session};
+use crate::task::{Shell, Tools};
+use crate::toolchain::Toolchain;
+use nix_libstore::store_path::StorePath;
+use nix_tool::{NixTool, StoreConfig};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+// Stands in for nix, adding files and derivations to a fake store and logging
+// each call to nix.log, and its full arguments to nix-args.log. Derivations are
+// written to the store as JSON and every build has the same output.
+const FAKE_NIX: &str = r#"#!/bin/sh
+echo "$@" >> @DIR@/nix-args.log
+while [ "${1#-}" != "$1" ]; do if [ "$1" = --option ]; then shift 2; fi; shift; done
+echo "$1 $2" >> @DIR@/nix.log
+case "$1" in
+  store) hash=$(sha256sum < "$3" | tr -dc 0-9a-d | head -c 32); echo "@DIR@/store/$hash-$(basename "$3")" ;;
+  derivation)
+    if [ "$2" = show ]; then printf '{"%s":' "$3"; cat "$3"; printf '}'; exit; fi
+    json=$(cat)
+    hash=$(printf "%s" "$json" | sha256sum | tr -dc 0-9a-d | head -c 32)
+    mkdir -p @DIR@/store
+    printf "%s" "$json" > "@DIR@/store/$hash-task.drv"
+    echo "@DIR@/store/$hash-task.drv" ;;
+  build) if [ "$2" = "--out-link" ]; then ln -sfn "$4" "$3"; exit; fi
+    mkdir -p @DIR@/store; touch @DIR@/store/0123456789abcd0123456789abcd0123-out; echo "@DIR@/store/0123456789abcd0123456789abcd0123-out" ;;
+  copy) if [ "$3" = "unreachable" ]; then echo "cannot connect to '$3'" >&2; exit 1; fi ;;
+  *) exit 1 ;;
+esac
+"#;
+
+/// Create an empty directory for a test under the system temp directory.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nix-ninja-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write a fake nix tool into `dir`, whose store is `dir/store`.
+pub fn fake_nix(dir: &Path) -> PathBuf {
+    let nix_tool = dir.join("nix");
+    write_executable(
+        &nix_tool,
+        &FAKE_NIX.replace("@DIR@", &dir.to_string_lossy()),
+    );
+    nix_tool
+}
+
+/// Write a fake compiler into the fake store of `dir`.
+pub fn fake_cc(dir: &Path) -> PathBuf {
+    let cc = dir.join("store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-cc/bin/cc");
+    write_executable(&cc, "#!/bin/sh\n");
+    cc
+}
+
+/// Session building `ninja` in `dir/build` with the fake nix of `dir`, where
+/// `@CC@` is replaced by a fake compiler. `configure` can adjust the config.
+pub fn test_session(dir: &Path, ninja: &str, configure: impl FnOnce(&mut BuildConfig)) -> Session {
+    let build_dir = dir.join("build");
+    let store_dir = dir.join("store");
+    fs::create_dir_all(&build_dir).unwrap();
+
+    let nix_tool = fake_nix(dir);
+    let ninja = ninja.replace("@CC@", &fake_cc(dir).to_string_lossy());
+    let build_filename = build_dir.join("build.ninja");
+    fs::write(&build_filename, ninja).unwrap();
+
+    let mut config = BuildConfig {
+        source_root: build_dir.clone(),
+        build_dir,
+        store_dir: store_dir.clone(),
+        build_system: "x86_64-linux".to_string(),
+        host_system: "x86_64-linux".to_string(),
+        nix_tool: nix_tool.to_string_lossy().into_owned(),
+        extra_inputs: Vec::new(),
+        toolchain_manifest: None,
+        keep_rspfile: false,
+        check_inputs: false,
+        deterministic: false,
+        prefix_task_output: false,
+        env_closure: false,
+        task_timeout: None,
+        shell: "sh".to_string(),
+        offline: false,
+        jobs: 2,
+        keep_going: 1,
+        jobs_per_rule: HashMap::new(),
+        max_inline_input_chars: 32768,
+        max_input_srcs: 0,
+        strict_input_srcs: false,
+        warn_dupbuild: false,
+        forward_env: Vec::new(),
+        build_dir_exclude: Vec::new(),
+        cache_file: None,
+        dump_graph_json: None,
+        report: None,
+        verbose: false,
+        quiet: false,
+    };
+    configure(&mut config);
+
+    let tools = Tools {
+        nix: NixTool::new(StoreConfig {
+            nix_tool: config.nix_tool.clone(),
+            extra_args: Vec::new(),
+            offline: config.offline,
+            realise: false,
+        }),
+        coreutils: StorePath::new(store_dir.join("q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-coreutils"))
+            .unwrap(),
+        nix_ninja_task: StorePath::new(
+            store_dir.join("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-nix-ninja-task"),
+        )
+        .unwrap(),
+        shell: Shell {
+            path: store_dir.join("ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-bash/bin/sh"),
+            store_path: StorePath::new(store_dir.join("ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-bash"))
+                .unwrap(),
+        },
+        toolchain: Toolchain::new(&store_dir),
+    };
+    Session::with_tools(&build_filename.to_string_lossy(), config, tools)
+}
+
+/// Number of times the fake nix tool in `dir` was called with `command`,
+/// e.g. "store add".
+pub fn nix_calls(dir: &Path, command: &str) -> usize {
+    fs::read_to_string(dir.join("nix.log"))
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| *line == command)
+        .count()
+}
+
+/// Arguments of each call to the fake nix tool in `dir`.
+pub fn nix_args(dir: &Path) -> Vec<String> {
+    fs::read_to_string(dir.join("nix-args.log"))
+        .unwrap_or_default()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+// Warnings logged by every test since the first call to `logged_warnings`.
+static WARNINGS: OnceLock<&'static Mutex<Vec<u8>>> = OnceLock::new();
+
+struct WarningsWriter(&'static Mutex<Vec<u8>>);
+
+impl std::io::Write for WarningsWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Warnings logged so far by any test, once capturing started with a first
+/// call. The subscriber is global because tasks log from their own threads,
+/// so tests should look for warnings only they can log.
+pub fn logged_warnings() -> String {
+    let warnings = WARNINGS.get_or_init(|| {
+        let warnings: &'static Mutex<Vec<u8>> = Box::leak(Box::default());
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::WARN)
+            .with_ansi(false)
+            .with_writer(move || WarningsWriter(warnings))
+            .finish();
+        tracing::subscriber::set_global_default(subscriber).unwrap();
+        warnings
+    });
+    String::from_utf8_lossy(&warnings.lock().unwrap()).into_owned()
+}
+
+pub fn write_executable(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
@@ -0,0 +1,279 @@
+use crate::task::which_store_path;
+use anyhow::{anyhow, Context, Result};
+use nix_libstore::store_path::StorePath;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Toolchain resolves binaries to the store paths that provide them.
+///
+/// A toolchain can be backed by a manifest of binary names to store paths.
+/// On the first run the manifest is recorded from what was found on `$PATH`,
+/// and on subsequent runs it pins resolution so that the generated derivations
+/// are identical across machines.
+///
+/// Binaries are only looked up once, since the compiler of every task is
+/// resolved and clones share what was resolved with the task threads.
+#[derive(Clone)]
+pub struct Toolchain {
+    manifest: Arc<Mutex<BTreeMap<String, String>>>,
+    pinned: bool,
+    /// Set when entries were recorded in a pinned manifest lacking them.
+    amended: Arc<AtomicBool>,
+    resolved: Arc<Mutex<HashMap<String, StorePath>>>,
+    store_dir: PathBuf,
+}
+
+impl Toolchain {
+    /// Create a toolchain resolving binaries to store paths in `store_dir`,
+    /// recording what was resolved.
+    pub fn new(store_dir: &Path) -> Self {
+        Toolchain {
+            manifest: Arc::default(),
+            pinned: false,
+            amended: Arc::default(),
+            resolved: Arc::default(),
+            store_dir: store_dir.to_path_buf(),
+        }
+    }
+
+    /// Load a toolchain manifest from `path`. If the manifest doesn't exist
+    /// yet, resolved binaries are recorded so it can be written afterwards.
+    pub fn from_manifest(path: &Path, store_dir: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Toolchain::new(store_dir));
+        }
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read toolchain manifest {}", path.display()))?;
+        let manifest: BTreeMap<String, String> = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse toolchain manifest {}", path.display()))?;
+
+        Ok(Toolchain {
+            manifest: Arc::new(Mutex::new(manifest)),
+            pinned: true,
+            ..Toolchain::new(store_dir)
+        })
+    }
+
+    /// Resolve the store path providing `binary_name`, checking it against the
+    /// manifest if the toolchain is pinned.
+    pub fn resolve(&self, binary_name: &str) -> Result<StorePath> {
+        self.resolve_with(binary_name, |name| which_store_path(name, &self.store_dir))
+    }
+
+    /// Resolve like `resolve`, but record `binary_name` when the manifest is
+    /// pinned without an entry for it, like manifests written before the
+    /// binary was resolved through the toolchain.
+    pub fn resolve_or_record(&self, binary_name: &str) -> Result<StorePath> {
+        self.resolve_or_record_with(binary_name, |name| which_store_path(name, &self.store_dir))
+    }
+
+    fn resolve_or_record_with(
+        &self,
+        binary_name: &str,
+        lookup: impl FnOnce(&str) -> Result<Option<StorePath>>,
+    ) -> Result<StorePath> {
+        let store_path = self.lookup(binary_name, lookup)?;
+        {
+            let mut manifest = self.manifest.lock().unwrap();
+            if self.pinned && !manifest.contains_key(binary_name) {
+                tracing::warn!(
+                    "nix-ninja: warning: toolchain manifest has no entry for {}, recording {}",
+                    binary_name,
+                    format!("{}", store_path)
+                );
+                manifest.insert(binary_name.to_string(), format!("{}", store_path));
+                self.amended.store(true, Ordering::Relaxed);
+                return Ok(store_path);
+            }
+        }
+        self.pin(binary_name, store_path)
+    }
+
+    // Resolve `binary_name` with `lookup` and check it against the manifest.
+    fn resolve_with(
+        &self,
+        binary_name: &str,
+        lookup: impl FnOnce(&str) -> Result<Option<StorePath>>,
+    ) -> Result<StorePath> {
+        let store_path = self.lookup(binary_name, lookup)?;
+        self.pin(binary_name, store_path)
+    }
+
+    // Look up `binary_name` with `lookup`, unless it was resolved before.
+    fn lookup(
+        &self,
+        binary_name: &str,
+        lookup: impl FnOnce(&str) -> Result<Option<StorePath>>,
+    ) -> Result<StorePath> {
+        let resolved = self.resolved.lock().unwrap().get(binary_name).cloned();
+        let store_path = match resolved {
+            Some(store_path) => store_path,
+            None => {
+                // Don't hold the lock while looking up the binary. If another
+                // thread resolved it meanwhile, it found the same.
+                let store_path = lookup(binary_name)?
+                    .ok_or_else(|| anyhow!("{} is not provided by the Nix store", binary_name))?;
+                self.resolved
+                    .lock()
+                    .unwrap()
+                    .insert(binary_name.to_string(), store_path.clone());
+                store_path
+            }
+        };
+        Ok(store_path)
+    }
+
+    fn pin(&self, binary_name: &str, store_path: StorePath) -> Result<StorePath> {
+        let mut manifest = self.manifest.lock().unwrap();
+        if !self.pinned {
+            manifest.insert(binary_name.to_string(), format!("{}", store_path));
+            return Ok(store_path);
+        }
+
+        match manifest.get(binary_name) {
+            Some(expected) if *expected == format!("{}", store_path) => Ok(store_path),
+            Some(expected) => Err(anyhow!(
+                "Toolchain mismatch for {}: manifest pins {} but found {}",
+                binary_name,
+                expected,
+                format!("{}", store_path)
+            )),
+            None => Err(anyhow!(
+                "Toolchain manifest has no entry for {} (found {})",
+                binary_name,
+                format!("{}", store_path)
+            )),
+        }
+    }
+
+    /// Write out the recorded manifest. Pinned manifests are left untouched,
+    /// unless entries missing from them were recorded.
+    pub fn write_manifest(&self, path: &Path) -> Result<()> {
+        if self.pinned && !self.amended.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let manifest = self.manifest.lock().unwrap();
+        let json = serde_json::to_string_pretty(&*manifest)?;
+        fs::write(path, json)
+            .with_context(|| format!("Failed to write toolchain manifest {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::test_dir;
+
+    const BASH: &str = "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bash-5.2";
+    const GCC: &str = "/nix/store/ac8da0sqpg4pyhzyr0qgl26d5dnpn7qp-gcc-wrapper-14.2.1";
+    const OTHER_GCC: &str = "/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-gcc-wrapper-13.3.0";
+
+    fn pinned(entries: &[(&str, &str)]) -> Toolchain {
+        let manifest = entries
+            .iter()
+            .map(|(name, path)| (name.to_string(), path.to_string()))
+            .collect();
+        Toolchain {
+            manifest: Arc::new(Mutex::new(manifest)),
+            pinned: true,
+            ..unpinned()
+        }
+    }
+
+    fn unpinned() -> Toolchain {
+        Toolchain::new(Path::new("/nix/store"))
+    }
+
+    #[test]
+    fn test_resolve_once() {
+        let toolchain = unpinned();
+        let lookups = std::cell::Cell::new(0);
+        let lookup = |_: &str| {
+            lookups.set(lookups.get() + 1);
+            Ok(Some(StorePath::new(GCC).unwrap()))
+        };
+        for _ in 0..3 {
+            let store_path = toolchain.resolve_with("g++", lookup).unwrap();
+            assert_eq!(format!("{}", store_path), GCC);
+        }
+        assert_eq!(lookups.get(), 1);
+
+        // Clones share what was resolved.
+        toolchain.clone().resolve_with("g++", lookup).unwrap();
+        assert_eq!(lookups.get(), 1);
+        toolchain.resolve_with("cc", lookup).unwrap();
+        assert_eq!(lookups.get(), 2);
+
+        // Binaries that aren't in the store are looked up again.
+        let missing = |_: &str| Ok(None);
+        assert!(toolchain.resolve_with("make", missing).is_err());
+        assert!(toolchain.resolve_with("make", lookup).is_ok());
+        assert_eq!(lookups.get(), 3);
+    }
+
+    #[test]
+    fn test_unpinned_records() {
+        let toolchain = unpinned();
+        let store_path = StorePath::new(GCC).unwrap();
+        assert!(toolchain.pin("g++", store_path).is_ok());
+        assert_eq!(
+            toolchain.manifest.lock().unwrap().get("g++"),
+            Some(&GCC.to_string())
+        );
+    }
+
+    #[test]
+    fn test_pinned_match() {
+        let toolchain = pinned(&[("g++", GCC)]);
+        let store_path = StorePath::new(GCC).unwrap();
+        assert!(toolchain.pin("g++", store_path).is_ok());
+    }
+
+    #[test]
+    fn test_pinned_mismatch_rejected() {
+        let toolchain = pinned(&[("g++", GCC)]);
+        let store_path = StorePath::new(OTHER_GCC).unwrap();
+        let err = toolchain.pin("g++", store_path).err().unwrap();
+        assert!(err.to_string().contains("Toolchain mismatch for g++"));
+    }
+
+    #[test]
+    fn test_pinned_unknown_rejected() {
+        let toolchain = pinned(&[("g++", GCC)]);
+        let store_path = StorePath::new(OTHER_GCC).unwrap();
+        assert!(toolchain.pin("clang++", store_path).is_err());
+    }
+
+    #[test]
+    fn test_pinned_unknown_recorded() {
+        let dir = test_dir("toolchain-record");
+        let path = dir.join("toolchain.json");
+        fs::write(&path, format!("{{\"g++\": \"{}\"}}", GCC)).unwrap();
+        let toolchain = Toolchain::from_manifest(&path, Path::new("/nix/store")).unwrap();
+        let lookup = |_: &str| Ok(Some(StorePath::new(BASH).unwrap()));
+
+        // A manifest written before the shell was pinned gets an entry for it.
+        let store_path = toolchain.resolve_or_record_with("sh", lookup).unwrap();
+        assert_eq!(format!("{}", store_path), BASH);
+        toolchain.write_manifest(&path).unwrap();
+        let manifest: BTreeMap<String, String> =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(manifest["sh"], BASH);
+        assert_eq!(manifest["g++"], GCC);
+
+        // Entries that are there are still checked.
+        let other = |_: &str| Ok(Some(StorePath::new(OTHER_GCC).unwrap()));
+        assert!(toolchain.resolve_or_record_with("g++", other).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
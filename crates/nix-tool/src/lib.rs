@@ -1,11 +1,33 @@
 use anyhow::{anyhow, Context, Result};
-use nix_libstore::derivation::Derivation;
+use nix_libstore::derivation::{Derivation, OutputHashMode};
 use nix_libstore::derived_path::SingleDerivedPath;
 use nix_libstore::store_path::StorePath;
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::Path;
 use std::process::{Command, Output};
+use thiserror::Error;
+
+/// Error of a Nix command that ran but exited unsuccessfully, as opposed to
+/// one that couldn't be run at all
+#[derive(Debug, Error)]
+#[error("{}", command_error_message(.action, .stderr))]
+pub struct CommandError {
+    /// What was being done, like `Failed to store add main.c`
+    pub action: String,
+
+    /// What the command printed to stderr, empty if it wasn't captured
+    pub stderr: String,
+}
+
+fn command_error_message(action: &str, stderr: &str) -> String {
+    if stderr.is_empty() {
+        action.to_string()
+    } else {
+        format!("{}:\n{}", action, stderr)
+    }
+}
 
 /// Configuration for Nix store operations
 #[derive(Debug, Clone)]
@@ -15,6 +37,14 @@ pub struct StoreConfig {
 
     /// Extra arguments to pass to Nix commands
     pub extra_args: Vec<String>,
+
+    /// Pass `--offline` to Nix commands so that they fail instead of
+    /// fetching from substituters
+    pub offline: bool,
+
+    /// Realise derived paths with `nix-store --realise` instead of
+    /// `nix build`, for setups where the nix command is restricted
+    pub realise: bool,
 }
 
 impl Default for StoreConfig {
@@ -22,6 +52,8 @@ impl Default for StoreConfig {
         Self {
             nix_tool: "nix".to_string(),
             extra_args: Vec::new(),
+            offline: false,
+            realise: false,
         }
     }
 }
@@ -37,26 +69,88 @@ impl NixTool {
     }
 
     pub fn build(&self, derived_path: &SingleDerivedPath) -> Result<Output> {
-        let installable = &derived_path.to_string();
-        let output = Command::new(&self.config.nix_tool)
-            .args(&self.config.extra_args)
+        let installable = &format!("{}", derived_path);
+        let output = self
+            .command()
             .args(&["build", "-L", "--no-link", "--print-out-paths", installable])
             .stderr(std::process::Stdio::inherit())
             .output()?;
 
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(anyhow!("Failed to build:\n{}", stderr));
+            return Err(CommandError {
+                action: format!("Failed to build {}", installable),
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+            .into());
         }
 
         Ok(output)
     }
 
-    /// Add a file to the Nix store
-    pub fn store_add(&self, path: &PathBuf) -> Result<StorePath> {
-        let output = self
-            .run_nix_command(&["store", "add", &path.to_string_lossy()])
-            .map_err(|err| anyhow!("Failed to store add {}: {}", &path.to_string_lossy(), err))?;
+    /// Build a derived path and return its output, with `nix build` or
+    /// `nix-store --realise` depending on the configuration
+    pub fn build_output(&self, derived_path: &SingleDerivedPath) -> Result<StorePath> {
+        if self.config.realise {
+            return self.realise(derived_path);
+        }
+
+        let output = self.build(derived_path)?;
+        let stdout = String::from_utf8(output.stdout).context("Failed to parse command output")?;
+        StorePath::new(stdout.trim()).context("Failed to parse store path")
+    }
+
+    /// Realise a derived path with `nix-store --realise`, which doesn't need
+    /// the nix-command experimental feature, and return its output
+    pub fn realise(&self, derived_path: &SingleDerivedPath) -> Result<StorePath> {
+        // nix-store separates the derivation from its output with `!`.
+        let installable = match derived_path {
+            SingleDerivedPath::Opaque(store_path) => format!("{}", store_path),
+            SingleDerivedPath::Built(built) => format!("{}!{}", built.drv_path, built.output),
+        };
+
+        let mut command = self.nix_store_command();
+        if self.config.offline {
+            command.args(["--option", "substitute", "false"]);
+        }
+        let output = command
+            .args(["--realise", &installable])
+            .stderr(std::process::Stdio::inherit())
+            .output()?;
+
+        if !output.status.success() {
+            return Err(CommandError {
+                action: format!("Failed to realise {}", installable),
+                stderr: String::new(),
+            }
+            .into());
+        }
+
+        let stdout = String::from_utf8(output.stdout).context("Failed to parse command output")?;
+        let store_path_str = stdout
+            .lines()
+            .next()
+            .ok_or_else(|| anyhow!("nix-store --realise {} printed no output", installable))?;
+        StorePath::new(store_path_str.trim()).context("Failed to parse store path")
+    }
+
+    /// Add a file to the Nix store, hashing it as a NAR like `nix store add`
+    /// does by default
+    pub fn store_add(&self, path: &Path) -> Result<StorePath> {
+        self.store_add_with_mode(path, OutputHashMode::Nar)
+    }
+
+    /// Add a file to the Nix store with the given hashing mode, where flat only
+    /// works for regular files and drops their executable bit
+    pub fn store_add_with_mode(&self, path: &Path, mode: OutputHashMode) -> Result<StorePath> {
+        let mode = match mode {
+            OutputHashMode::Flat => "flat",
+            OutputHashMode::Nar => "nar",
+            OutputHashMode::Text => "text",
+        };
+        let output = self.run_nix_command(
+            &format!("Failed to store add {}", path.to_string_lossy()),
+            &["store", "add", &path.to_string_lossy(), "--mode", mode],
+        )?;
 
         let store_path_str = String::from_utf8(output.stdout)
             .context("Failed to parse command output")?
@@ -67,25 +161,35 @@ impl NixTool {
     }
 
     pub fn derivation_show(&self, drv_path: &StorePath) -> Result<Output> {
-        self.run_nix_command(&["derivation", "show", &drv_path.to_string()])
-            .map_err(|err| {
-                anyhow!(
-                    "Failed to derivation show {}: {}",
-                    &drv_path.to_string(),
-                    err
-                )
-            })
+        self.run_nix_command(
+            &format!("Failed to derivation show {}", drv_path),
+            &["derivation", "show", &format!("{}", drv_path)],
+        )
+    }
+
+    /// Show a derivation parsed from `nix derivation show`, which maps the
+    /// store path of the derivation to its contents
+    pub fn derivation_show_parsed(
+        &self,
+        drv_path: &StorePath,
+    ) -> Result<HashMap<String, Derivation>> {
+        let output = self.derivation_show(drv_path)?;
+        let json = String::from_utf8(output.stdout).context("Failed to parse command output")?;
+        Derivation::from_show_json(&json)
+            .with_context(|| format!("Failed to parse derivation {}", drv_path))
     }
 
     /// Add a derivation to the Nix store
     pub fn derivation_add(&self, drv: &Derivation) -> Result<StorePath> {
+        drv.validate()
+            .map_err(|err| anyhow!("Invalid derivation {}: {}", drv.name, err))?;
+
         // Serialize the drv to JSON
         let json = drv.to_json()?;
 
         // Create a command with piped stdin/stdout/stderr
-        let mut command = Command::new(&self.config.nix_tool);
+        let mut command = self.command();
         command
-            .args(&self.config.extra_args)
             .args(&["derivation", "add"])
             .stdin(std::process::Stdio::piped())
             .stdout(std::process::Stdio::piped())
@@ -103,8 +207,11 @@ impl NixTool {
         let output = child.wait_with_output()?;
 
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(anyhow!("Failed to derivation add {}: {}", drv.name, stderr));
+            return Err(CommandError {
+                action: format!("Failed to derivation add {}", drv.name),
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+            .into());
         }
 
         // Parse the store path from stdout
@@ -116,18 +223,243 @@ impl NixTool {
         StorePath::new(store_path_str).context("Failed to parse store path")
     }
 
-    /// Run a Nix command and return its output
-    fn run_nix_command<S: AsRef<OsStr>>(&self, args: &[S]) -> Result<Output> {
-        let output = Command::new(&self.config.nix_tool)
-            .args(&self.config.extra_args)
+    /// Point `link` to `path` and register it as an indirect GC root, like
+    /// `nix build --out-link`, so that the garbage collector keeps `path` for
+    /// as long as `link` exists
+    pub fn add_gc_root(&self, path: &StorePath, link: &Path) -> Result<()> {
+        let action = format!("Failed to add GC root {} to {}", link.display(), path);
+        if !self.config.realise {
+            let link = link.as_os_str();
+            let path = format!("{}", path);
+            self.run_nix_command(
+                &action,
+                &[
+                    OsStr::new("build"),
+                    OsStr::new("--out-link"),
+                    link,
+                    OsStr::new(&path),
+                ],
+            )?;
+            return Ok(());
+        }
+
+        let output = self
+            .nix_store_command()
+            .args(["--realise", &format!("{}", path), "--add-root"])
+            .arg(link)
+            .output()
+            .map_err(|err| anyhow!("{}: {}", action, err))?;
+        if !output.status.success() {
+            return Err(CommandError {
+                action,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Query the closure of a store path with `nix-store --query --requisites`,
+    /// which is the path itself and every path it references, recursively
+    pub fn requisites(&self, path: &StorePath) -> Result<Vec<StorePath>> {
+        let action = format!("Failed to query requisites of {}", path);
+        let output = self
+            .nix_store_command()
+            .args(["--query", "--requisites", &format!("{}", path)])
+            .output()
+            .map_err(|err| anyhow!("{}: {}", action, err))?;
+        if !output.status.success() {
+            return Err(CommandError {
+                action,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+            .into());
+        }
+
+        let stdout = String::from_utf8(output.stdout).context("Failed to parse command output")?;
+        stdout
+            .lines()
+            .map(|line| StorePath::new(line.trim()).context("Failed to parse store path"))
+            .collect()
+    }
+
+    /// Query the derivations in the store that output a store path with
+    /// `nix-store --query --valid-derivers`, which works for outputs that
+    /// aren't built yet
+    pub fn valid_derivers(&self, path: &StorePath) -> Result<Vec<StorePath>> {
+        let action = format!("Failed to query derivers of {}", path);
+        let output = self
+            .nix_store_command()
+            .args(["--query", "--valid-derivers", &format!("{}", path)])
+            .output()
+            .map_err(|err| anyhow!("{}: {}", action, err))?;
+        if !output.status.success() {
+            return Err(CommandError {
+                action,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+            .into());
+        }
+
+        let stdout = String::from_utf8(output.stdout).context("Failed to parse command output")?;
+        stdout
+            .lines()
+            .map(|line| StorePath::new(line.trim()).context("Failed to parse store path"))
+            .collect()
+    }
+
+    /// Copy the closure of a store path to another store, such as a binary
+    /// cache
+    pub fn copy_closure(&self, path: &StorePath, to: &str) -> Result<()> {
+        self.run_nix_command(
+            &format!("Failed to copy {} to {}", path, to),
+            &["copy", "--to", to, &format!("{}", path)],
+        )?;
+        Ok(())
+    }
+
+    /// Create a Nix command with the arguments common to all commands
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.config.nix_tool);
+        command.args(&self.config.extra_args);
+        if self.config.offline {
+            command.arg("--offline");
+        }
+        command
+    }
+
+    /// Create a nix-store command, from the same directory as the Nix
+    /// executable if it is a path. Of the extra arguments, only the
+    /// `--option NAME VALUE` settings are passed, as nix-store doesn't take
+    /// the flags of the nix command
+    fn nix_store_command(&self) -> Command {
+        let nix_tool = Path::new(&self.config.nix_tool);
+        let mut command = if nix_tool
+            .parent()
+            .is_some_and(|dir| !dir.as_os_str().is_empty())
+        {
+            Command::new(nix_tool.with_file_name("nix-store"))
+        } else {
+            Command::new("nix-store")
+        };
+        command.args(nix_store_options(&self.config.extra_args));
+        command
+    }
+
+    /// Run a Nix command and return its output, describing failures with
+    /// `action`
+    fn run_nix_command<S: AsRef<OsStr>>(&self, action: &str, args: &[S]) -> Result<Output> {
+        let output = self
+            .command()
             .args(args)
-            .output()?;
+            .output()
+            .map_err(|err| anyhow!("{}: {}", action, err))?;
 
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(anyhow!("Nix command failed:\n{}", stderr));
+            return Err(CommandError {
+                action: action.to_string(),
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+            .into());
         }
 
         Ok(output)
     }
 }
+
+// The `--option NAME VALUE` settings among the arguments of the nix command.
+fn nix_store_options(extra_args: &[String]) -> Vec<&str> {
+    let mut options = Vec::new();
+    let mut args = extra_args.iter();
+    while let Some(arg) = args.next() {
+        if arg != "--option" {
+            continue;
+        }
+        let (Some(name), Some(value)) = (args.next(), args.next()) else {
+            break;
+        };
+        options.extend(["--option", name, value]);
+    }
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_nix_store_options() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
+        assert_eq!(
+            nix_store_options(&args(&[
+                "--extra-experimental-features",
+                "dynamic-derivations",
+                "--option",
+                "sandbox",
+                "false",
+                "--impure",
+                "--option",
+                "cores",
+            ])),
+            vec!["--option", "sandbox", "false"]
+        );
+    }
+
+    #[test]
+    fn test_realise() {
+        let dir = std::env::temp_dir().join(format!("nix-tool-realise-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // Stands in for nix-store, which is looked up next to the nix tool,
+        // logging its arguments and printing the realised output.
+        let nix_store = dir.join("nix-store");
+        fs::write(
+            &nix_store,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {}/args\necho /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-out\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&nix_store, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let nix = NixTool::new(StoreConfig {
+            nix_tool: dir.join("nix").to_string_lossy().into_owned(),
+            extra_args: vec![
+                "--extra-experimental-features".to_string(),
+                "dynamic-derivations".to_string(),
+                "--option".to_string(),
+                "sandbox".to_string(),
+                "false".to_string(),
+            ],
+            offline: true,
+            realise: true,
+        });
+        let drv_path =
+            StorePath::new("/nix/store/q3lv9bi7r4di3kxdjhy7kvwgvpmanfza-app.drv").unwrap();
+        let output = nix
+            .realise(&SingleDerivedPath::Built(
+                nix_libstore::derived_path::SingleDerivedPathBuilt {
+                    drv_path: drv_path.clone(),
+                    output: "out".to_string(),
+                },
+            ))
+            .unwrap();
+        assert_eq!(
+            format!("{}", output),
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-out"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("args")).unwrap().trim(),
+            format!(
+                "--option sandbox false --option substitute false --realise {}!out",
+                drv_path
+            )
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
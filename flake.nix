@@ -78,6 +78,8 @@
         inherit cargoArtifacts;
         pname = "nix-ninja";
         cargoExtraArgs = "-p nix-ninja";
+        # Reported by `nix-ninja --nix-ninja-version`.
+        NIX_NINJA_GIT_REV = inputs.self.shortRev or inputs.self.dirtyShortRev or "";
       });
 
       nix-ninja-task = craneLib.buildPackage (commonArgs // {
@@ -16,7 +16,8 @@
 }@args':
 
 let
-  normalizedTarget = builtins.replaceStrings ["/"] ["-"] target;
+  # Must match normalize_output in nix-ninja's task.rs.
+  normalizedTarget = builtins.replaceStrings ["/" "-" "="] ["-" "=-" "=="] target;
 
   extraInputs = builtins.concatStringsSep "," nixNinjaExtraInputs;
 